@@ -0,0 +1,6 @@
+fn main() {
+    // the gRPC stubs are only generated with grpc_support, so nothing else needs protoc
+    #[cfg(feature = "grpc_support")]
+    tonic_build::compile_protos("proto/compass.proto")
+        .expect("couldn't compile proto/compass.proto");
+}
@@ -0,0 +1,189 @@
+use super::*;
+
+use serde::Serialize;
+
+use std::collections::BTreeMap;
+
+#[derive(Serialize, Debug, Clone)]
+pub struct IndexInfo {
+    pub name: String,
+    pub definition: String,
+    pub bytes: i64,
+    pub scans: i64,
+    pub valid: bool,
+    // pgstattuple's avg_leaf_density for btree indexes, when asked for and the extension is installed.
+    // a fresh index sits around 90; much lower means bloat
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub leaf_density: Option<f64>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct SchemaInfo {
+    pub name: String,
+    pub table: String,
+    pub fields: usize,
+    pub exists: bool,
+    pub rows_estimate: i64, // from the planner's statistics, so as fresh as the last ANALYZE
+    pub total_bytes: i64,
+    pub table_bytes: i64,
+    pub index_bytes: i64,
+    pub indexes: Vec<IndexInfo>,
+    pub missing_indexes: Vec<String>, // ones migrate would create that aren't there
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub in_flight: Option<usize>, // searches holding a throttle or quota permit, when either is on
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub throttle_open: bool,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ConnectionInfo {
+    pub pool_size: u32, // database.pool_size, what the embedder's pool is configured for
+    pub max_connections: i64,
+    pub by_state: BTreeMap<String, i64>, // this database's connections in pg_stat_activity
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub in_flight: Option<usize>, // requests inside the shutdown drain, when one is passed in
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Introspection {
+    pub schemas: Vec<SchemaInfo>,
+    pub connections: ConnectionInfo,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cache: Option<CacheStats>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub slow_queries: Option<usize>, // queries in the slow query log
+}
+
+// the indexes migrate creates for a schema
+fn expected_indexes(schema: &Schema) -> Vec<String> {
+    let mut expected = vec![format!("{}_object_idx", schema.table)];
+    for (name, _) in schema.fields.iter().filter(|(_, f)| f.suggest) {
+        expected.push(format!("{}_{}_suggest_idx", schema.table, name).replace('.', "_"));
+    }
+    expected
+}
+
+fn schema_info<C: Connection>(
+    client: &mut C,
+    name: &str,
+    schema: &Schema,
+    with_bloat: bool,
+) -> Result<SchemaInfo, CompassError> {
+    let client = client.client()?;
+
+    let sizes = client.query_opt(
+        "SELECT c.reltuples::bigint, pg_total_relation_size(c.oid), pg_relation_size(c.oid), pg_indexes_size(c.oid) \
+         FROM pg_class c WHERE c.oid = to_regclass($1)",
+        &[&schema.table],
+    )?;
+    let (exists, rows_estimate, total_bytes, table_bytes, index_bytes) = match sizes {
+        Some(row) => (true, row.get(0), row.get(1), row.get(2), row.get(3)),
+        None => (false, 0, 0, 0, 0),
+    };
+
+    let stattuple = with_bloat
+        && client
+            .query_opt(
+                "SELECT 1 FROM pg_extension WHERE extname = 'pgstattuple'",
+                &[],
+            )?
+            .is_some();
+
+    let mut indexes = Vec::new();
+    for row in client.query(
+        "SELECT c.relname::text, pg_get_indexdef(i.indexrelid), pg_relation_size(i.indexrelid), \
+         coalesce(s.idx_scan, 0), i.indisvalid, am.amname = 'btree' \
+         FROM pg_index i JOIN pg_class c ON c.oid = i.indexrelid JOIN pg_am am ON am.oid = c.relam \
+         LEFT JOIN pg_stat_user_indexes s ON s.indexrelid = i.indexrelid \
+         WHERE i.indrelid = to_regclass($1) ORDER BY c.relname",
+        &[&schema.table],
+    )? {
+        let name: String = row.get(0);
+        let btree: bool = row.get(5);
+        // pgstatindex reads the whole index, hence only on request
+        let leaf_density = if stattuple && btree {
+            let density = client.query_one(
+                "SELECT avg_leaf_density FROM pgstatindex(to_regclass($1))",
+                &[&name],
+            )?;
+            Some(density.get(0))
+        } else {
+            None
+        };
+        indexes.push(IndexInfo {
+            name,
+            definition: row.get(1),
+            bytes: row.get(2),
+            scans: row.get(3),
+            valid: row.get(4),
+            leaf_density,
+        });
+    }
+
+    let missing_indexes = expected_indexes(schema)
+        .into_iter()
+        .filter(|expected| !indexes.iter().any(|index| index.name == *expected))
+        .collect();
+
+    Ok(SchemaInfo {
+        name: name.to_owned(),
+        table: schema.table.clone(),
+        fields: schema.fields.len(),
+        exists,
+        rows_estimate,
+        total_bytes,
+        table_bytes,
+        index_bytes,
+        indexes,
+        missing_indexes,
+        in_flight: schema
+            .throttle
+            .as_ref()
+            .map(|t| t.in_flight())
+            .or_else(|| schema.quota.as_ref().map(|q| q.in_flight())),
+        throttle_open: schema.throttle.as_ref().map_or(false, |t| t.is_open()),
+    })
+}
+
+// everything an admin dashboard needs to show about a running instance: each schema's table, sizes and
+// indexes, connections, and the cache and slow query log. `with_bloat` also measures btree indexes with
+// pgstattuple, which reads them in full
+pub fn introspect<C: Connection>(
+    client: &mut C,
+    loaded: &LoadedConfig,
+    drain: Option<&Drain>,
+    with_bloat: bool,
+) -> Result<Introspection, CompassError> {
+    let mut names: Vec<&String> = loaded.schemas.keys().collect();
+    names.sort();
+    let schemas = names
+        .into_iter()
+        .map(|name| schema_info(client, name, &loaded.schemas[name], with_bloat))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let pg = client.client()?;
+    let max_connections: i32 = pg
+        .query_one("SELECT current_setting('max_connections')::int", &[])?
+        .get(0);
+    let by_state = pg
+        .query(
+            "SELECT coalesce(state, 'unknown'), count(*) FROM pg_stat_activity \
+             WHERE datname = current_database() GROUP BY 1",
+            &[],
+        )?
+        .iter()
+        .map(|row| (row.get(0), row.get(1)))
+        .collect();
+
+    Ok(Introspection {
+        schemas,
+        connections: ConnectionInfo {
+            pool_size: loaded.config.database.pool_size,
+            max_connections: max_connections as i64,
+            by_state,
+            in_flight: drain.map(Drain::in_flight),
+        },
+        cache: loaded.cache.as_ref().map(|cache| cache.stats()),
+        slow_queries: loaded.slow_log.as_ref().map(|log| log.recent().len()),
+    })
+}
@@ -0,0 +1,590 @@
+use super::*;
+
+use chrono_tz::Tz;
+use postgres::fallible_iterator::FallibleIterator;
+use postgres::types::ToSql;
+use postgres::types::Type as PostgresType;
+use postgres::Row;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use std::collections::HashMap;
+use std::time::Instant;
+
+fn invalid(msg: String) -> CompassError {
+    CompassError::InvalidAggregate(msg)
+}
+
+// `30s`, `5m`, `1h`, `1d`, `1w` -> seconds
+pub(crate) fn parse_bucket(s: &str) -> Result<i64, CompassError> {
+    let (n, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()));
+    let n = n
+        .parse::<i64>()
+        .map_err(|_| invalid(format!("bucket '{}' should look like 15m, 1h or 1d", s)))?;
+    let unit = match unit {
+        "s" | "" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => {
+            return Err(invalid(format!(
+                "bucket '{}' should look like 15m, 1h or 1d",
+                s
+            )))
+        }
+    };
+
+    match n.checked_mul(unit) {
+        Some(secs) if secs > 0 => Ok(secs),
+        _ => Err(invalid(format!("bucket '{}' is out of range", s))),
+    }
+}
+
+// the search parameters with this endpoint's own taken out, so they don't show up as ignored (or get
+// rejected in strict mode)
+pub(crate) fn without(fields: &HashMap<String, String>, own: &[&str]) -> HashMap<String, String> {
+    fields
+        .iter()
+        .filter(|(k, _)| !own.contains(&k.as_str()))
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect()
+}
+
+pub(crate) fn run_plan<C: Connection>(
+    client: &mut C,
+    schema: &Schema,
+    sql: &str,
+    plan: &QueryPlan,
+) -> Result<Vec<Value>, CompassError> {
+    let started = Instant::now();
+
+    let statement = client
+        .prepare_typed(sql, &plan.param_types(&[PostgresType::TEXT]))
+        .map_err(pg_error(schema))?;
+
+    let params: Vec<&dyn ToSql> = std::iter::once(&plan.json_query as &dyn ToSql)
+        .chain(plan.bindings.iter().map(Binding::as_sql))
+        .collect();
+
+    let rows: Vec<Row> = client
+        .client()
+        .map_err(pg_error(schema))?
+        .query_raw(&statement, params.iter().copied())
+        .map_err(pg_error(schema))?
+        .collect()
+        .map_err(pg_error(schema))?;
+
+    if let Some(ref log) = schema.slow_log {
+        log.record(
+            &schema.table,
+            sql,
+            || params.iter().map(|p| format!("{:?}", p)).collect(),
+            started.elapsed(),
+        );
+    }
+    record_usage(schema, rows.len(), started.elapsed());
+
+    Ok(rows.into_iter().map(|r| r.get::<usize, Value>(0)).collect())
+}
+
+// the `time` parameter's path, defaulting to the schema's default sort field, and the converter it's
+// stored with, if any
+fn time_field(
+    schema: &Schema,
+    fields: &HashMap<String, String>,
+) -> Result<(Vec<String>, Option<ConverterSchema>), CompassError> {
+    let time_name = match fields.get("time") {
+        Some(t) => t.clone(),
+        None => match schema.default_sort() {
+            SortKey::Path(path) => path.join("."),
+            SortKey::DocId | SortKey::Relevance => {
+                return Err(invalid(
+                    "this needs a time field, like time=created".to_owned(),
+                ))
+            }
+        },
+    };
+    let time_path = field_path(schema, &time_name).map_err(invalid)?;
+    let time_converter = schema
+        .fields
+        .get(&time_path[0])
+        .and_then(|f| f.converter)
+        .filter(|_| time_path.len() == 1);
+    Ok((time_path, time_converter))
+}
+
+// one aggregated point per time bucket for graphing:
+//
+//   bucket=1h&agg=avg&metric=runs&time=created&season=12
+//
+// `time` defaults to the schema's default sort field and has to hold epoch seconds, or millis if its
+// converter stores TimestampMillis. `agg` defaults to avg (count doesn't need a metric). everything
+// else filters like a search. each point is {bucket, value, count}, with `bucket` rendered the same way
+// as the time field in documents
+pub fn downsample<C: Connection>(
+    client: &mut C,
+    schema: &Schema,
+    fields: &HashMap<String, String>,
+) -> Result<Vec<Value>, CompassError> {
+    Ok(downsample_response(client, schema, fields)?.data)
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct DownsampleResponse {
+    pub data: Vec<Value>,
+    pub meta: AggregateMeta,
+}
+
+// downsample, along with where the points came from. counts are served from a fresh rollup that can
+// answer them, if the schema has one, and meta.rollup says how old it is
+pub fn downsample_response<C: Connection>(
+    client: &mut C,
+    schema: &Schema,
+    fields: &HashMap<String, String>,
+) -> Result<DownsampleResponse, CompassError> {
+    let client = &mut throttle_permit(client, schema)?;
+
+    let bucket_secs = parse_bucket(
+        fields
+            .get("bucket")
+            .ok_or_else(|| invalid("downsampling needs a bucket, like bucket=1h".to_owned()))?,
+    )?;
+    let op = match fields.get("agg") {
+        Some(op) => op.parse::<AggregateOp>()?,
+        None => AggregateOp::Avg,
+    };
+
+    let (time_path, time_converter) = time_field(schema, fields)?;
+    let width = match time_converter.map(|c| c.to) {
+        Some(ConvertTo::TimestampMillis) => bucket_secs * 1000,
+        _ => bucket_secs,
+    };
+
+    let filters = without(fields, &["bucket", "agg", "metric", "time"]);
+
+    let mut meta = AggregateMeta::default();
+    let rolled_up = match op {
+        AggregateOp::Count => rollup_downsample(client, schema, &filters, &time_path, width)?,
+        _ => None,
+    };
+    let mut points = match rolled_up {
+        Some((points, freshness)) => {
+            meta.rollup = Some(freshness);
+            points
+        }
+        None => downsample_points(client, schema, fields, &filters, op, time_path, width)?,
+    };
+
+    let max_buckets = schema.limits.max_limit;
+    if points.len() as i64 > max_buckets {
+        return Err(CompassError::QueryTooComplex(format!(
+            "more than {} buckets; use a wider bucket or a narrower filter",
+            max_buckets
+        )));
+    }
+
+    if let Some(conv) = time_converter {
+        for point in points.iter_mut() {
+            if let Some(bucket) = point.get_mut("bucket") {
+                convert_field(&conv, bucket);
+            }
+        }
+    }
+
+    Ok(DownsampleResponse { data: points, meta })
+}
+
+// downsampled points computed from the table, one past the bucket limit at most
+fn downsample_points<C: Connection>(
+    client: &mut C,
+    schema: &Schema,
+    fields: &HashMap<String, String>,
+    filters: &HashMap<String, String>,
+    op: AggregateOp,
+    time_path: Vec<String>,
+    width: i64,
+) -> Result<Vec<Value>, CompassError> {
+    let mut plan = generate_where(schema, filters, 2, false)?;
+
+    let time = format!("(object #> {})", plan.bind(Binding::TextArray(time_path)));
+    plan.and_where(&format!("jsonb_typeof({}) = 'number'", time));
+    let width = plan.bind(Binding::Int(width));
+
+    let value = match (op, fields.get("metric")) {
+        (AggregateOp::Count, _) => "COUNT(*)".to_owned(),
+        (op, Some(metric)) => {
+            let path = field_path(schema, metric).map_err(invalid)?;
+            let metric = numeric_expr(&format!(
+                "(object #> {})",
+                plan.bind(Binding::TextArray(path))
+            ));
+            match op {
+                AggregateOp::CountDistinct => format!("COUNT(DISTINCT {})", metric),
+                AggregateOp::Sum => format!("SUM({})", metric),
+                AggregateOp::Avg => format!("AVG({})", metric),
+                AggregateOp::Min => format!("MIN({})", metric),
+                AggregateOp::Max => format!("MAX({})", metric),
+                AggregateOp::Count => unreachable!(),
+            }
+        }
+        (op, None) => return Err(invalid(format!("agg {:?} needs a metric", op))),
+    };
+
+    // one past the cap, to tell "exactly max_limit buckets" from "too many"
+    let max_buckets = schema.limits.max_limit;
+    let sql = format!(
+        "SELECT jsonb_build_object('bucket', bucket, 'value', value, 'count', count) FROM (\
+         SELECT floor(({time} #>> '{{}}')::numeric / {width}) * {width} AS bucket, {value} AS value, COUNT(*) AS count \
+         FROM {table} {where_clause} GROUP BY 1) buckets ORDER BY bucket LIMIT {limit}",
+        time = time,
+        width = width,
+        value = value,
+        table = schema.table,
+        where_clause = plan.where_clause,
+        limit = max_buckets + 1
+    );
+
+    run_plan(client, schema, &sql, &plan)
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Heatmap {
+    pub timezone: String,
+    pub counts: Vec<Vec<i64>>, // counts[day][hour], monday first
+    pub total: i64,
+}
+
+// how many documents fall in each hour of each day of the week, for activity heatmaps:
+//
+//   time=created&tz=America/New_York&season=12
+//
+// `time` works like downsample's. hours are in `tz`, or the time field's converter timezone, or utc.
+// everything else filters like a search. the matrix is always 7x24, with zeroes where nothing matched
+pub fn heatmap<C: Connection>(
+    client: &mut C,
+    schema: &Schema,
+    fields: &HashMap<String, String>,
+) -> Result<Heatmap, CompassError> {
+    let client = &mut throttle_permit(client, schema)?;
+
+    let (time_path, time_converter) = time_field(schema, fields)?;
+    let timezone = match fields.get("tz") {
+        Some(tz) => tz
+            .parse::<Tz>()
+            .map_err(|_| invalid(format!("unknown timezone '{}'", tz)))?,
+        None => time_converter.and_then(|c| c.timezone).unwrap_or(Tz::UTC),
+    };
+    let per_second = match time_converter.map(|c| c.to) {
+        Some(ConvertTo::TimestampMillis) => 1000,
+        _ => 1,
+    };
+
+    let filters = without(fields, &["time", "tz"]);
+    let mut plan = generate_where(schema, &filters, 2, false)?;
+
+    let time = format!("(object #> {})", plan.bind(Binding::TextArray(time_path)));
+    plan.and_where(&format!("jsonb_typeof({}) = 'number'", time));
+    let local = format!(
+        "(to_timestamp(({time} #>> '{{}}')::float8 / {per_second}) AT TIME ZONE {tz})",
+        time = time,
+        per_second = plan.bind(Binding::Int(per_second)),
+        tz = plan.bind(Binding::Text(timezone.name().to_owned()))
+    );
+
+    let sql = format!(
+        "SELECT jsonb_build_object('day', day, 'hour', hour, 'count', count) FROM (\
+         SELECT extract(isodow FROM {local})::int AS day, extract(hour FROM {local})::int AS hour, COUNT(*) AS count \
+         FROM {table} {where_clause} GROUP BY 1, 2) cells",
+        local = local,
+        table = schema.table,
+        where_clause = plan.where_clause
+    );
+
+    let mut heatmap = Heatmap {
+        timezone: timezone.name().to_owned(),
+        counts: vec![vec![0; 24]; 7],
+        total: 0,
+    };
+    for cell in run_plan(client, schema, &sql, &plan)? {
+        let day = cell["day"].as_u64().unwrap_or(1) as usize;
+        let hour = cell["hour"].as_u64().unwrap_or(0) as usize;
+        let count = cell["count"].as_i64().unwrap_or(0);
+        // isodow counts from monday = 1
+        heatmap.counts[day.saturating_sub(1).min(6)][hour.min(23)] = count;
+        heatmap.total += count;
+    }
+
+    Ok(heatmap)
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct DistinctCount {
+    pub field: String,
+    pub count: i64,
+    pub approximate: bool,
+}
+
+// how many different values a field has among the documents matching the other parameters:
+//
+//   count_distinct=playerId&season=12
+//
+// exact by default. `approximate=true` estimates it with the hll extension instead (within a couple
+// percent, without sorting every value), and is refused if the extension isn't installed. documents
+// without the field don't count
+pub fn count_distinct<C: Connection>(
+    client: &mut C,
+    schema: &Schema,
+    fields: &HashMap<String, String>,
+) -> Result<DistinctCount, CompassError> {
+    let client = &mut throttle_permit(client, schema)?;
+
+    let name = fields
+        .get("count_distinct")
+        .ok_or_else(|| invalid("this needs a field, like count_distinct=playerId".to_owned()))?;
+    let path = field_path(schema, name).map_err(invalid)?;
+    let approximate = fields.get("approximate").map_or(false, |a| a == "true");
+
+    if approximate {
+        let installed = client
+            .client()
+            .map_err(pg_error(schema))?
+            .query_opt("SELECT 1 FROM pg_extension WHERE extname = 'hll'", &[])
+            .map_err(pg_error(schema))?
+            .is_some();
+        if !installed {
+            return Err(invalid(
+                "approximate counts need the hll extension installed in the database".to_owned(),
+            ));
+        }
+    }
+
+    let filters = without(fields, &["count_distinct", "approximate"]);
+    let mut plan = generate_where(schema, &filters, 2, false)?;
+    let value = format!("(object #> {})", plan.bind(Binding::TextArray(path)));
+    let count = if approximate {
+        format!(
+            "round(coalesce(hll_cardinality(hll_add_agg(hll_hash_text({}::text))), 0))::bigint",
+            value
+        )
+    } else {
+        format!("COUNT(DISTINCT {})", value)
+    };
+
+    let sql = format!(
+        "SELECT to_jsonb({count}) FROM {table} {where_clause}",
+        count = count,
+        table = schema.table,
+        where_clause = plan.where_clause
+    );
+
+    let count = run_plan(client, schema, &sql, &plan)?
+        .first()
+        .and_then(Value::as_i64)
+        .unwrap_or(0);
+
+    Ok(DistinctCount {
+        field: name.clone(),
+        count,
+        approximate,
+    })
+}
+
+// the k best documents by a numeric field, optionally per group
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TopK {
+    pub by: String,
+    pub k: i64,
+    #[serde(default)]
+    pub order: PipelineOrder, // desc: highest first
+    pub partition: Option<String>, // a field to rank within, e.g. a top 5 per team
+    #[serde(default)]
+    pub with_ties: bool, // also return documents tied with the k-th, so you can get more than k
+}
+
+// documents get a `_rank`; tied documents share one (1, 2, 2, 4), and come back in doc_id order so the
+// same query always returns the same list. documents where `by` isn't a number are left out
+pub fn top_k<C: Connection>(
+    client: &mut C,
+    schema: &Schema,
+    fields: &HashMap<String, String>,
+    top: &TopK,
+) -> Result<Vec<Value>, CompassError> {
+    let client = &mut throttle_permit(client, schema)?;
+
+    if top.k < 1 || top.k > schema.limits.max_limit {
+        return Err(CompassError::LimitOutOfRange {
+            value: top.k,
+            max: schema.limits.max_limit,
+        });
+    }
+
+    let mut plan = generate_where(schema, fields, 2, false)?;
+
+    let by_path = field_path(schema, &top.by).map_err(invalid)?;
+    let metric = numeric_expr(&format!(
+        "(object #> {})",
+        plan.bind(Binding::TextArray(by_path))
+    ));
+    plan.and_where(&format!("{} IS NOT NULL", metric));
+
+    let partition = match top.partition {
+        Some(ref p) => {
+            let path = field_path(schema, p).map_err(invalid)?;
+            format!("(object #> {})", plan.bind(Binding::TextArray(path)))
+        }
+        None => "NULL::jsonb".to_owned(),
+    };
+    let partition_by = match top.partition {
+        Some(_) => format!("PARTITION BY {} ", partition),
+        None => String::new(),
+    };
+
+    let dir = match top.order {
+        PipelineOrder::Asc => "ASC",
+        PipelineOrder::Desc => "DESC",
+    };
+    let cutoff = if top.with_ties { "rnk" } else { "rn" };
+
+    let sql = format!(
+        "SELECT object || jsonb_build_object('_rank', rnk) FROM (\
+         SELECT object, doc_id, {metric} AS metric, {partition} AS part, \
+         RANK() OVER ({partition_by}ORDER BY {metric} {dir}) AS rnk, \
+         ROW_NUMBER() OVER ({partition_by}ORDER BY {metric} {dir}, doc_id) AS rn \
+         FROM {table} {where_clause}) ranked \
+         WHERE {cutoff} <= {k} ORDER BY part, metric {dir}, doc_id LIMIT {limit}",
+        metric = metric,
+        partition = partition,
+        partition_by = partition_by,
+        dir = dir,
+        table = schema.table,
+        where_clause = plan.where_clause,
+        cutoff = cutoff,
+        k = top.k,
+        limit = schema.limits.max_limit
+    );
+
+    let mut docs = run_plan(client, schema, &sql, &plan)?;
+    for doc in docs.iter_mut() {
+        convert_document(schema, doc);
+    }
+    Ok(docs)
+}
+
+// past this many rows (by postgres' own estimate), stats read a sample of about SAMPLE_ROWS
+const SAMPLE_ABOVE: i64 = 1_000_000;
+const SAMPLE_ROWS: i64 = 100_000;
+
+// a TABLESAMPLE clause for tables too big to read whole, and whether there was one
+pub(crate) fn sample_clause<C: Connection>(
+    client: &mut C,
+    schema: &Schema,
+) -> Result<(String, bool), CompassError> {
+    let estimate: f32 = client
+        .client()
+        .map_err(pg_error(schema))?
+        .query_one(
+            "SELECT reltuples FROM pg_class WHERE oid = $1::text::regclass",
+            &[&schema.table],
+        )
+        .map_err(pg_error(schema))?
+        .get(0);
+
+    if estimate as i64 > SAMPLE_ABOVE {
+        Ok((
+            format!(
+                "TABLESAMPLE SYSTEM ({})",
+                100.0 * SAMPLE_ROWS as f64 / estimate as f64
+            ),
+            true,
+        ))
+    } else {
+        Ok((String::new(), false))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FieldStats {
+    pub count: i64,   // documents looked at
+    pub missing: i64, // of those, how many don't have the field
+    pub cardinality: i64,
+    pub min: Option<Value>, // numbers if the field holds any, strings otherwise
+    pub max: Option<Value>,
+    pub top: Vec<Value>, // the most common values as {value, count}
+    #[serde(default)]
+    pub sampled: bool, // counts are of a random sample of the table, not all of it
+}
+
+// an overview of one field for data explorers: `field=season&top=10`, plus the usual filters
+pub fn field_stats<C: Connection>(
+    client: &mut C,
+    schema: &Schema,
+    fields: &HashMap<String, String>,
+) -> Result<FieldStats, CompassError> {
+    let client = &mut throttle_permit(client, schema)?;
+
+    let name = fields
+        .get("field")
+        .ok_or_else(|| invalid("stats need a field, like field=season".to_owned()))?;
+    let path = field_path(schema, name).map_err(invalid)?;
+    let top = match fields.get("top") {
+        Some(t) => t.parse::<i64>().map_err(CompassError::InvalidNumberError)?,
+        None => 10,
+    };
+    if top < 0 || top > schema.limits.max_limit {
+        return Err(CompassError::LimitOutOfRange {
+            value: top,
+            max: schema.limits.max_limit,
+        });
+    }
+
+    let (sample, sampled) = sample_clause(client, schema)?;
+
+    let filters = without(fields, &["field", "top"]);
+    let mut plan = generate_where(schema, &filters, 2, false)?;
+    let value = format!(
+        "(object #> {})",
+        plan.bind(Binding::TextArray(path.clone()))
+    );
+
+    let sql = format!(
+        "WITH vals AS (SELECT {value} AS v FROM {table} {sample} {where_clause}) \
+         SELECT jsonb_build_object(\
+         'count', (SELECT COUNT(*) FROM vals), \
+         'missing', (SELECT COUNT(*) FROM vals WHERE v IS NULL OR v = 'null'::jsonb), \
+         'cardinality', (SELECT COUNT(DISTINCT v) FROM vals WHERE v <> 'null'::jsonb), \
+         'min', (SELECT coalesce(to_jsonb(MIN({num})), to_jsonb(MIN(v #>> '{{}}') FILTER (WHERE jsonb_typeof(v) = 'string'))) FROM vals), \
+         'max', (SELECT coalesce(to_jsonb(MAX({num})), to_jsonb(MAX(v #>> '{{}}') FILTER (WHERE jsonb_typeof(v) = 'string'))) FROM vals), \
+         'top', (SELECT coalesce(jsonb_agg(jsonb_build_object('value', v, 'count', n) ORDER BY n DESC, v), '[]'::jsonb) \
+                 FROM (SELECT v, COUNT(*) AS n FROM vals WHERE v <> 'null'::jsonb GROUP BY v ORDER BY n DESC, v LIMIT {top}) t))",
+        value = value,
+        table = schema.table,
+        sample = sample,
+        where_clause = plan.where_clause,
+        num = numeric_expr("v"),
+        top = top
+    );
+
+    let row = run_plan(client, schema, &sql, &plan)?
+        .pop()
+        .unwrap_or(Value::Null);
+    let mut stats: FieldStats = serde_json::from_value(row)
+        .map_err(|e| CompassError::ConversionError(format!("unexpected stats row: {}", e)))?;
+    stats.sampled = sampled;
+
+    // shown the way documents show the field
+    if let Some(conv) = schema.fields.get(&path[0]).and_then(|f| f.converter) {
+        if path.len() == 1 {
+            for v in stats.min.iter_mut().chain(stats.max.iter_mut()) {
+                convert_field(&conv, v);
+            }
+            for entry in stats.top.iter_mut() {
+                if let Some(v) = entry.get_mut("value") {
+                    convert_field(&conv, v);
+                }
+            }
+        }
+    }
+
+    Ok(stats)
+}
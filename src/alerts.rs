@@ -0,0 +1,253 @@
+use super::*;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// how many notified doc ids each alert remembers, so re-ingesting a document doesn't alert twice
+const DEDUP_WINDOW: usize = 10_000;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum AlertTarget {
+    Webhook { url: String }, // gets {alert, schema, documents, suppressed} as json
+    Discord { url: String }, // a discord webhook; gets a short message
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AlertRule {
+    pub schema: String,
+    pub params: HashMap<String, String>, // filters, same as a search's
+    pub target: AlertTarget,
+    #[serde(default = "default_max_per_minute")]
+    pub max_per_minute: u32, // notifications past this are dropped and counted as suppressed
+}
+
+fn default_max_per_minute() -> u32 {
+    10
+}
+
+struct AlertState {
+    rule: AlertRule,
+    seen: HashSet<Uuid>,
+    seen_order: VecDeque<Uuid>,
+    window_start: Instant,
+    sent_in_window: u32,
+    suppressed: u64, // dropped since the last notification that went out
+}
+
+impl AlertState {
+    // the documents not alerted on yet, remembering them from now on
+    fn unseen(&mut self, docs: Vec<Value>) -> Vec<Value> {
+        let mut fresh = Vec::new();
+        for doc in docs {
+            let id = match doc
+                .get("_doc_id")
+                .and_then(Value::as_str)
+                .and_then(|id| Uuid::parse_str(id).ok())
+            {
+                Some(id) => id,
+                None => continue,
+            };
+            if !self.seen.insert(id) {
+                continue;
+            }
+            self.seen_order.push_back(id);
+            if self.seen_order.len() > DEDUP_WINDOW {
+                if let Some(old) = self.seen_order.pop_front() {
+                    self.seen.remove(&old);
+                }
+            }
+            fresh.push(doc);
+        }
+        fresh
+    }
+
+    fn allow(&mut self) -> bool {
+        if self.window_start.elapsed() > Duration::from_secs(60) {
+            self.window_start = Instant::now();
+            self.sent_in_window = 0;
+        }
+        if self.sent_in_window >= self.rule.max_per_minute {
+            self.suppressed += 1;
+            return false;
+        }
+        self.sent_in_window += 1;
+        true
+    }
+}
+
+// saved searches that fire a notification when newly ingested documents match them. compass doesn't
+// have a change feed to watch, so whatever inserts documents calls `check` with their ids afterwards
+#[derive(Default)]
+pub struct Alerts {
+    rules: Mutex<HashMap<u64, AlertState>>,
+    next_id: Mutex<u64>,
+    webhooks: Option<Arc<Webhooks>>,
+}
+
+impl Alerts {
+    pub fn new() -> Alerts {
+        Alerts::default()
+    }
+
+    // also sends every notification to the registered webhooks that want it, as an "alert" event
+    pub fn with_webhooks(mut self, webhooks: Arc<Webhooks>) -> Alerts {
+        self.webhooks = Some(webhooks);
+        self
+    }
+
+    pub fn register(&self, rule: AlertRule) -> u64 {
+        let mut next_id = self.next_id.lock().unwrap();
+        *next_id += 1;
+        self.rules.lock().unwrap().insert(
+            *next_id,
+            AlertState {
+                rule,
+                seen: HashSet::new(),
+                seen_order: VecDeque::new(),
+                window_start: Instant::now(),
+                sent_in_window: 0,
+                suppressed: 0,
+            },
+        );
+        *next_id
+    }
+
+    pub fn unregister(&self, id: u64) -> bool {
+        self.rules.lock().unwrap().remove(&id).is_some()
+    }
+
+    pub fn list(&self) -> Vec<(u64, AlertRule)> {
+        self.rules
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, state)| (*id, state.rule.clone()))
+            .collect()
+    }
+
+    // runs every alert on `schema_name` against the given (just inserted) documents and sends what
+    // matched. a failed delivery is logged rather than returned, so one bad webhook doesn't stop the rest.
+    // returns how many notifications went out
+    pub fn check<C: Connection>(
+        &self,
+        client: &mut C,
+        schemas: &HashMap<String, Schema>,
+        schema_name: &str,
+        doc_ids: &[Uuid],
+    ) -> Result<usize, CompassError> {
+        let schema = match schemas.get(schema_name) {
+            Some(schema) => schema,
+            None => return Ok(0),
+        };
+        let rules: Vec<(u64, AlertRule)> = self
+            .list()
+            .into_iter()
+            .filter(|(_, rule)| rule.schema == schema_name)
+            .collect();
+
+        let mut sent = 0;
+        for (id, rule) in rules {
+            let mut matched = Vec::new();
+            for chunk in doc_ids.chunks(schema.limits.max_limit.max(1) as usize) {
+                matched.extend(matching(client, schema, &rule.params, chunk)?);
+            }
+
+            // dedup and rate limiting happen under the lock, delivery outside it
+            let (docs, suppressed) = {
+                let mut rules = self.rules.lock().unwrap();
+                let state = match rules.get_mut(&id) {
+                    Some(state) => state,
+                    None => continue, // unregistered meanwhile
+                };
+                let docs = state.unseen(matched);
+                if docs.is_empty() || !state.allow() {
+                    continue;
+                }
+                (docs, std::mem::take(&mut state.suppressed))
+            };
+
+            if let Some(ref webhooks) = self.webhooks {
+                let body = json!({
+                    "alert": id,
+                    "schema": rule.schema,
+                    "documents": docs,
+                    "suppressed": suppressed,
+                });
+                webhooks.dispatch("alert", &id.to_string(), &body);
+            }
+            match deliver(id, &rule, docs, suppressed) {
+                Ok(()) => sent += 1,
+                Err(e) => eprintln!("compass: alert {} delivery failed: {}", id, e),
+            }
+        }
+
+        Ok(sent)
+    }
+}
+
+// which of `ids` match `params`, with their ids in `_doc_id`
+fn matching<C: Connection>(
+    client: &mut C,
+    schema: &Schema,
+    params: &HashMap<String, String>,
+    ids: &[Uuid],
+) -> Result<Vec<Value>, CompassError> {
+    let mut plan = generate_where(schema, params, 2, false)?;
+    let ids = plan.bind(Binding::TextArray(
+        ids.iter().map(Uuid::to_string).collect(),
+    ));
+    plan.and_where(&format!("doc_id = ANY({}::uuid[])", ids));
+
+    let sql = format!(
+        "SELECT object || jsonb_build_object('_doc_id', doc_id) FROM {} {}",
+        schema.table, plan.where_clause
+    );
+
+    let mut docs = run_plan(client, schema, &sql, &plan)?;
+    for doc in docs.iter_mut() {
+        convert_document(schema, doc);
+    }
+    Ok(docs)
+}
+
+fn deliver(
+    id: u64,
+    rule: &AlertRule,
+    documents: Vec<Value>,
+    suppressed: u64,
+) -> Result<(), ureq::Error> {
+    let (url, body) = match rule.target {
+        AlertTarget::Webhook { ref url } => (
+            url,
+            json!({
+                "alert": id,
+                "schema": rule.schema,
+                "documents": documents,
+                "suppressed": suppressed,
+            }),
+        ),
+        AlertTarget::Discord { ref url } => {
+            let mut content = format!(
+                "{} new {} document(s) matched alert {}",
+                documents.len(),
+                rule.schema,
+                id
+            );
+            if suppressed > 0 {
+                content += &format!(" ({} earlier notifications suppressed)", suppressed);
+            }
+            (url, json!({ "content": content }))
+        }
+    };
+
+    ureq::post(url)
+        .timeout(Duration::from_secs(10))
+        .send_json(body)?;
+    Ok(())
+}
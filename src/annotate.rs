@@ -0,0 +1,185 @@
+use super::*;
+
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+use std::collections::HashMap;
+
+fn invalid(msg: &str) -> CompassError {
+    CompassError::InvalidAnnotation(msg.to_owned())
+}
+
+// values to write into every document a filter matches, e.g. `{"set": {"redacted": true}}` or
+// `{"set": {"curation.tag": "featured"}, "note": "ticket 123"}`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Annotation {
+    pub set: IndexMap<String, Value>, // dotted path -> value. a nested path's parent has to exist already
+    #[serde(default)]
+    pub note: Option<String>, // goes in the audit log with it
+    #[serde(default = "default_batch_size")]
+    pub batch_size: i64, // documents per UPDATE, each its own transaction
+}
+
+fn default_batch_size() -> i64 {
+    1000
+}
+
+// what annotate would do, without doing it
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct AnnotationPreview {
+    pub matching: i64,
+    pub unchanged: i64, // of those, the ones that already hold every value
+}
+
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct Annotated {
+    pub updated: i64,
+    pub batches: usize,
+    pub audit_id: i64, // the compass_audit entry recording it
+}
+
+// the filtered plan, the condition for "already annotated" and the jsonb_set chain writing the annotation
+fn annotation_plan(
+    schema: &Schema,
+    annotation: &Annotation,
+    fields: &HashMap<String, String>,
+) -> Result<(QueryPlan, String, String), CompassError> {
+    if annotation.set.is_empty() {
+        return Err(invalid("an annotation needs at least one value to set"));
+    }
+    // without any filter this would annotate the whole table, which is more likely a mistake
+    if fields.keys().all(|k| RESERVED_PARAMS.contains(&k.as_str())) {
+        return Err(invalid("annotating needs at least one filter"));
+    }
+
+    let mut plan = generate_where(schema, fields, 2, false)?;
+
+    let mut unchanged = Vec::new();
+    let mut update = "object".to_owned();
+    for (path, value) in annotation.set.iter() {
+        // setting the tenant field, or a parent of it, would move documents to another tenant
+        let tenant_field = match schema.tenancy {
+            Some(Tenancy::Field(ref field)) => {
+                field == path || field.starts_with(&format!("{}.", path))
+            }
+            _ => false,
+        };
+        if !is_sql_identifier(path) || tenant_field {
+            return Err(CompassError::InvalidAnnotation(format!(
+                "can't annotate '{}': paths are plain dotted names, and not the tenant field",
+                path
+            )));
+        }
+        let path = plan.bind(Binding::TextArray(
+            path.split('.').map(str::to_owned).collect(),
+        ));
+        let value = plan.bind(Binding::Json(value.clone()));
+        unchanged.push(format!(
+            "(object #> {}) IS NOT DISTINCT FROM {}",
+            path, value
+        ));
+        update = format!("jsonb_set({}, {}, {}, true)", update, path, value);
+    }
+
+    Ok((plan, unchanged.join(" AND "), update))
+}
+
+// how many documents an annotation would touch
+pub fn preview_annotation<C: Connection>(
+    client: &mut C,
+    schema: &Schema,
+    annotation: &Annotation,
+    fields: &HashMap<String, String>,
+) -> Result<AnnotationPreview, CompassError> {
+    let client = &mut throttle_permit(client, schema)?;
+
+    let (plan, unchanged, _) = annotation_plan(schema, annotation, fields)?;
+    let sql = format!(
+        "SELECT jsonb_build_object('matching', COUNT(*), 'unchanged', COUNT(*) FILTER (WHERE {unchanged})) \
+         FROM {table} {where_clause}",
+        unchanged = unchanged,
+        table = schema.table,
+        where_clause = plan.where_clause
+    );
+
+    let row = run_plan(client, schema, &sql, &plan)?
+        .pop()
+        .unwrap_or(Value::Null);
+    Ok(AnnotationPreview {
+        matching: row["matching"].as_i64().unwrap_or(0),
+        unchanged: row["unchanged"].as_i64().unwrap_or(0),
+    })
+}
+
+// writes the annotation into every matching document that doesn't already hold it, `batch_size` at a
+// time in doc_id order so no one transaction locks the whole match, then records what it did in the
+// audit log. preview_annotation first to see how many that is. a failed batch leaves the ones before
+// it written, and is recorded as well
+pub fn annotate<C: Connection>(
+    client: &mut C,
+    schema: &Schema,
+    annotation: &Annotation,
+    fields: &HashMap<String, String>,
+) -> Result<Annotated, CompassError> {
+    // held for every batch, so the writes count against the schema's throttle and quota like any query
+    let client = &mut throttle_permit(client, schema)?;
+
+    let (mut plan, unchanged, update) = annotation_plan(schema, annotation, fields)?;
+    let batch_size = annotation.batch_size.max(1);
+
+    // the keyset to continue after, rebound before every batch
+    let after = plan.bindings.len();
+    let after_placeholder = plan.bind(Binding::Text(Uuid::nil().to_string()));
+    plan.and_where(&format!(
+        "doc_id > {}::uuid AND NOT ({})",
+        after_placeholder, unchanged
+    ));
+
+    let sql = format!(
+        "UPDATE {table} SET object = {update} WHERE doc_id IN (\
+         SELECT doc_id FROM {table} {where_clause} ORDER BY doc_id LIMIT {batch_size}) \
+         RETURNING to_jsonb(doc_id)",
+        table = schema.table,
+        update = update,
+        where_clause = plan.where_clause,
+        batch_size = batch_size
+    );
+
+    scope_session(client, schema)?;
+
+    let mut annotated = Annotated::default();
+    let result = loop {
+        let ids = match run_plan(client, schema, &sql, &plan) {
+            Ok(ids) => ids,
+            Err(e) => break Err(e),
+        };
+        annotated.updated += ids.len() as i64;
+        annotated.batches += 1;
+
+        // doc_id order in postgres is byte order, which is the same as Uuid's
+        let last = ids
+            .iter()
+            .filter_map(|id| serde_json::from_value::<Uuid>(id.clone()).ok())
+            .max();
+        match last {
+            Some(last) if ids.len() as i64 == batch_size => {
+                plan.bindings[after] = Binding::Text(last.to_string());
+            }
+            _ => break Ok(()),
+        }
+    };
+
+    let detail = json!({
+        "set": annotation.set,
+        "note": annotation.note,
+        "filters": fields,
+        "updated": annotated.updated,
+        "batches": annotated.batches,
+        "error": result.as_ref().err().map(|e| e.to_string()),
+    });
+    annotated.audit_id = record_audit(client, schema, "annotate", &detail)?;
+
+    result.map(|_| annotated)
+}
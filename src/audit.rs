@@ -0,0 +1,91 @@
+use super::*;
+
+use chrono::{DateTime, TimeZone, Utc};
+use postgres::Client;
+use serde::Serialize;
+use serde_json::Value;
+
+// one row per write compass made on someone's behalf (annotate, ...), created by migrate
+pub const AUDIT_TABLE: &str = "compass_audit";
+
+#[derive(Serialize, Debug, Clone)]
+pub struct AuditEntry {
+    pub id: i64,
+    pub at: DateTime<Utc>,
+    pub key: Option<String>, // fingerprint of the api key that asked, see key_fingerprint
+    pub tenant: Option<String>,
+    pub table: String,
+    pub action: String,
+    pub detail: Value, // what the action was asked to do and what it did
+}
+
+pub fn provision_audit(client: &mut Client) -> Result<(), CompassError> {
+    client.batch_execute(&format!(
+        "CREATE TABLE IF NOT EXISTS {table} (id BIGSERIAL PRIMARY KEY, at TIMESTAMPTZ NOT NULL DEFAULT now(), \
+         key TEXT, tenant TEXT, table_name TEXT NOT NULL, action TEXT NOT NULL, detail JSONB NOT NULL); \
+         CREATE INDEX IF NOT EXISTS {table}_table_idx ON {table} (table_name, id)",
+        table = AUDIT_TABLE
+    ))?;
+    Ok(())
+}
+
+// writes an entry for an action on the schema's table, returning its id
+pub(crate) fn record_audit<C: Connection>(
+    client: &mut C,
+    schema: &Schema,
+    action: &str,
+    detail: &Value,
+) -> Result<i64, CompassError> {
+    let row = client.client()?.query_one(
+        format!(
+            "INSERT INTO {} (key, tenant, table_name, action, detail) VALUES ($1, $2, $3, $4, $5) RETURNING id",
+            AUDIT_TABLE
+        )
+        .as_str(),
+        &[
+            &schema.key,
+            &schema.tenant,
+            &schema.table,
+            &action,
+            detail,
+        ],
+    )?;
+    Ok(row.get(0))
+}
+
+// the newest entries first, for one schema's table or (with None) every table. a schema scoped to a
+// tenant only sees that tenant's entries
+pub fn audit_log<C: Connection>(
+    client: &mut C,
+    schema: Option<&Schema>,
+    limit: i64,
+) -> Result<Vec<AuditEntry>, CompassError> {
+    let table = schema.map(|s| s.table.as_str());
+    let tenant = schema.and_then(|s| s.tenant.as_deref());
+    let rows = client.client()?.query(
+        format!(
+            "SELECT id, extract(epoch FROM at)::int8, key, tenant, table_name, action, detail FROM {} \
+             WHERE ($1::text IS NULL OR table_name = $1) AND ($2::text IS NULL OR tenant = $2) \
+             ORDER BY id DESC LIMIT $3",
+            AUDIT_TABLE
+        )
+        .as_str(),
+        &[&table, &tenant, &limit.max(0)],
+    )?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| AuditEntry {
+            id: row.get(0),
+            at: Utc
+                .timestamp_opt(row.get(1), 0)
+                .single()
+                .unwrap_or_else(Utc::now),
+            key: row.get(2),
+            tenant: row.get(3),
+            table: row.get(4),
+            action: row.get(5),
+            detail: row.get(6),
+        })
+        .collect())
+}
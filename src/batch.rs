@@ -0,0 +1,153 @@
+use super::*;
+
+use postgres::error::SqlState;
+use serde::{Deserialize, Serialize};
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct BatchConfig {
+    pub max_queries: usize,
+    pub parallelism: usize, // connections a batch runs on at once, capped by database.pool_size
+    pub query_timeout_ms: u64, // statement_timeout for each query; 0 for none
+}
+
+impl Default for BatchConfig {
+    fn default() -> Self {
+        BatchConfig {
+            max_queries: 50,
+            parallelism: 4,
+            query_timeout_ms: 10_000,
+        }
+    }
+}
+
+// one search in a batch: which schema, and its query parameters as they'd be in a GET
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BatchQuery {
+    pub schema: String,
+    #[serde(default)]
+    pub params: HashMap<String, String>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum BatchResult {
+    Ok(SearchResponse),
+    Error { error: String },
+    Timeout { timeout_ms: u64 },
+}
+
+// results are in the same order as the queries. one query failing doesn't fail the others
+#[derive(Serialize, Debug)]
+pub struct BatchResponse {
+    pub results: Vec<BatchResult>,
+    pub failed: usize,
+}
+
+// runs independent searches side by side, each worker on its own connection taking the next query
+// until there are none left
+pub fn json_batch(
+    database: &DatabaseConfig,
+    schemas: &HashMap<String, Schema>,
+    config: &BatchConfig,
+    queries: &[BatchQuery],
+) -> Result<BatchResponse, CompassError> {
+    if queries.len() > config.max_queries {
+        return Err(CompassError::QueryTooComplex(format!(
+            "batch has {} queries, the limit is {}",
+            queries.len(),
+            config.max_queries
+        )));
+    }
+
+    let workers = config
+        .parallelism
+        .min(database.pool_size as usize)
+        .min(queries.len())
+        .max(1);
+    let next = AtomicUsize::new(0);
+    let results: Mutex<Vec<Option<BatchResult>>> =
+        Mutex::new(queries.iter().map(|_| None).collect());
+
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| {
+                let mut client = batch_connection(database, config);
+                loop {
+                    let i = next.fetch_add(1, Ordering::SeqCst);
+                    if i >= queries.len() {
+                        break;
+                    }
+
+                    let result = match client {
+                        Ok(ref mut client) => run_query(client, schemas, config, &queries[i]),
+                        Err(ref e) => BatchResult::Error {
+                            error: format!("couldn't connect: {}", e),
+                        },
+                    };
+                    results.lock().unwrap()[i] = Some(result);
+                }
+            });
+        }
+    });
+
+    let results: Vec<BatchResult> = results
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .map(|r| r.expect("every query is taken by a worker"))
+        .collect();
+    let failed = results
+        .iter()
+        .filter(|r| !matches!(r, BatchResult::Ok(_)))
+        .count();
+
+    Ok(BatchResponse { results, failed })
+}
+
+fn batch_connection(
+    database: &DatabaseConfig,
+    config: &BatchConfig,
+) -> Result<postgres::Client, CompassError> {
+    let mut client = database.connect()?;
+    client.batch_execute(&format!(
+        "SET statement_timeout = {}",
+        config.query_timeout_ms
+    ))?;
+    Ok(client)
+}
+
+fn run_query(
+    client: &mut postgres::Client,
+    schemas: &HashMap<String, Schema>,
+    config: &BatchConfig,
+    query: &BatchQuery,
+) -> BatchResult {
+    let schema = match schemas.get(&query.schema) {
+        Some(schema) => schema,
+        None => {
+            return BatchResult::Error {
+                error: format!("unknown schema '{}'", query.schema),
+            }
+        }
+    };
+
+    // a quota's statement timeout replaces the batch's for that one query, and the batch's comes back
+    // after it
+    match json_search_response(client, schema, &query.params, None) {
+        Ok(response) => BatchResult::Ok(response),
+        Err(CompassError::PGError(ref e)) if e.code() == Some(&SqlState::QUERY_CANCELED) => {
+            BatchResult::Timeout {
+                timeout_ms: config.query_timeout_ms,
+            }
+        }
+        Err(e) => BatchResult::Error {
+            error: e.to_string(),
+        },
+    }
+}
@@ -0,0 +1,273 @@
+use super::*;
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::Serialize;
+
+use std::collections::HashMap;
+use std::io::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// brotli quality and window. 11 squeezes out a few more percent for many times the cpu; a cache that
+// compresses once per fill doesn't need it
+const BROTLI_QUALITY: u32 = 5;
+const BROTLI_WINDOW: u32 = 22;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+    Identity,
+}
+
+impl Encoding {
+    // the best encoding an Accept-Encoding header allows. q=0 rules one out
+    pub fn negotiate(accept_encoding: Option<&str>) -> Encoding {
+        let accepted: Vec<&str> = accept_encoding
+            .unwrap_or("")
+            .split(',')
+            .filter_map(|part| {
+                let mut pieces = part.split(';').map(str::trim);
+                let name = pieces.next()?;
+                let refused = pieces.any(|p| {
+                    p.strip_prefix("q=")
+                        .and_then(|q| q.parse::<f32>().ok())
+                        .map_or(false, |q| q == 0.0)
+                });
+                if refused {
+                    None
+                } else {
+                    Some(name)
+                }
+            })
+            .collect();
+
+        if accepted.contains(&"br") {
+            Encoding::Brotli
+        } else if accepted.contains(&"gzip") {
+            Encoding::Gzip
+        } else {
+            Encoding::Identity
+        }
+    }
+
+    // the Content-Encoding header value
+    pub fn header(self) -> Option<&'static str> {
+        match self {
+            Encoding::Brotli => Some("br"),
+            Encoding::Gzip => Some("gzip"),
+            Encoding::Identity => None,
+        }
+    }
+}
+
+// a serialized search response, in every encoding we serve
+#[derive(Debug)]
+pub struct CachedResponse {
+    pub json: Vec<u8>,
+    pub gzip: Vec<u8>,
+    pub brotli: Vec<u8>,
+}
+
+impl CachedResponse {
+    pub fn new(json: Vec<u8>) -> Result<CachedResponse, CompassError> {
+        let mut gzip = GzEncoder::new(Vec::new(), Compression::default());
+        gzip.write_all(&json)?;
+        let gzip = gzip.finish()?;
+
+        let mut brotli = Vec::new();
+        {
+            let mut writer =
+                brotli::CompressorWriter::new(&mut brotli, 4096, BROTLI_QUALITY, BROTLI_WINDOW);
+            writer.write_all(&json)?;
+        }
+
+        Ok(CachedResponse { json, gzip, brotli })
+    }
+
+    pub fn body(&self, encoding: Encoding) -> &[u8] {
+        match encoding {
+            Encoding::Brotli => &self.brotli,
+            Encoding::Gzip => &self.gzip,
+            Encoding::Identity => &self.json,
+        }
+    }
+}
+
+// search responses by CanonicalQuery::full_key, kept for cache.ttl_secs. a hit is bytes ready to send,
+// so it costs neither serialization nor compression
+#[derive(Debug)]
+pub struct ResponseCache {
+    capacity: usize,
+    ttl: Duration,
+    entries: Mutex<HashMap<String, (Instant, Arc<CachedResponse>)>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct CacheStats {
+    pub entries: usize,
+    pub capacity: usize,
+    pub ttl_secs: u64,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl ResponseCache {
+    pub fn new(config: &CacheConfig) -> ResponseCache {
+        ResponseCache {
+            capacity: config.capacity,
+            ttl: Duration::from_secs(config.ttl_secs),
+            entries: Mutex::new(HashMap::new()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<Arc<CachedResponse>> {
+        let mut entries = self.entries.lock().unwrap();
+        let found = match entries.get(key) {
+            Some((at, response)) if at.elapsed() < self.ttl => Some(response.clone()),
+            Some(_) => {
+                entries.remove(key);
+                None
+            }
+            None => None,
+        };
+        let counter = if found.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        found
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            entries: self.entries.lock().unwrap().len(),
+            capacity: self.capacity,
+            ttl_secs: self.ttl.as_secs(),
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn insert(&self, key: String, response: Arc<CachedResponse>) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= self.capacity && !entries.contains_key(&key) {
+            // expired entries go first; if there are none, the oldest one does
+            let ttl = self.ttl;
+            entries.retain(|_, (at, _)| at.elapsed() < ttl);
+            if entries.len() >= self.capacity {
+                let oldest = entries
+                    .iter()
+                    .min_by_key(|(_, (at, _))| *at)
+                    .map(|(key, _)| key.clone());
+                if let Some(oldest) = oldest {
+                    entries.remove(&oldest);
+                }
+            }
+        }
+        entries.insert(key, (Instant::now(), response));
+    }
+
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+
+    // json_search_raw through the cache. misses are serialized and compressed once, then shared
+    pub fn search<C: Connection>(
+        &self,
+        client: &mut C,
+        schema: &Schema,
+        fields: &HashMap<String, String>,
+        raw_query: Option<RawQuery>,
+    ) -> Result<Arc<CachedResponse>, CompassError> {
+        // checked before the lookup, or a rejected query could be answered from a privileged caller's entry
+        let raw_query = match raw_query {
+            Some(q) => Some(RawQuery {
+                query: q.checked(&schema.raw_query)?,
+                privileged: true,
+            }),
+            None => None,
+        };
+        let key =
+            CanonicalQuery::new(schema, fields, raw_query.as_ref().map(|q| q.query.as_str()))?
+                .full_key();
+        if let Some(response) = self.get(&key) {
+            return Ok(response);
+        }
+
+        let response = json_search_raw(client, schema, fields, raw_query)?;
+        let response = Arc::new(CachedResponse::new(serde_json::to_vec(&response)?)?);
+        self.insert(key, response.clone());
+        Ok(response)
+    }
+}
+
+// a cached response in the encoding the request asked for
+pub struct CachedBody {
+    pub response: Arc<CachedResponse>,
+    pub encoding: Encoding,
+}
+
+impl AsRef<[u8]> for CachedBody {
+    fn as_ref(&self) -> &[u8] {
+        self.response.body(self.encoding)
+    }
+}
+
+#[cfg(feature = "rocket_support")]
+use rocket::{
+    http::{ContentType, Status},
+    response::{self, Responder, Response},
+    Request,
+};
+#[cfg(feature = "rocket_support")]
+impl<'r> Responder<'r, 'static> for CachedBody {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        let encoding = self.encoding;
+        let len = self.response.body(encoding).len();
+        let mut response = Response::build();
+        response
+            .status(Status::Ok)
+            .header(ContentType::JSON)
+            .raw_header("Vary", "Accept-Encoding");
+        if let Some(encoding) = encoding.header() {
+            response.raw_header("Content-Encoding", encoding);
+        }
+        // the cached bytes themselves are the body, nothing gets copied
+        response.sized_body(len, std::io::Cursor::new(self)).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    #[test]
+    fn tenants_dont_share_entries() {
+        let cache = ResponseCache::new(&CacheConfig {
+            enabled: true,
+            ..CacheConfig::default()
+        });
+        let schema = test_schema();
+        let request = params(&[("type", "1")]);
+        let key = |tenant: &str| {
+            CanonicalQuery::new(&schema.for_tenant(tenant), &request, None)
+                .unwrap()
+                .full_key()
+        };
+
+        let response = Arc::new(CachedResponse::new(b"[]".to_vec()).unwrap());
+        cache.insert(key("a"), response);
+        assert!(cache.get(&key("a")).is_some());
+        assert!(cache.get(&key("b")).is_none());
+        assert_eq!(cache.stats().entries, 1);
+    }
+}
@@ -0,0 +1,293 @@
+use super::*;
+
+use serde::Serialize;
+use serde_json::Value;
+
+use std::collections::{BTreeMap, HashMap};
+
+// a query with everything that doesn't change its meaning stripped out: parameter names in schema
+// spelling and sorted, values normalized, unknown parameters dropped and defaults filled in.
+// two requests with the same CanonicalQuery get the same results, so this is what caches and saved
+// queries should be keyed on
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct CanonicalQuery {
+    pub table: String,
+    pub filters: BTreeMap<String, String>,
+    pub sort: String,
+    pub order: String,
+    pub limit: i64,
+    pub offset: i64,
+    pub options: BTreeMap<String, String>, // reserved params that change the response, like debug
+    pub raw_query: Option<String>,
+    // the tenant a tenancy schema's copy is scoped to (see Schema::for_tenant): the same query for
+    // another tenant reads other documents
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+}
+
+impl CanonicalQuery {
+    pub fn new(
+        schema: &Schema,
+        fields: &HashMap<String, String>,
+        raw_query: Option<&str>,
+    ) -> Result<CanonicalQuery, CompassError> {
+        // a preset means the same as what it expands to
+        let fields = &*expand_presets(schema, fields)?;
+        let mut params: Vec<(&String, &String)> = fields.iter().collect();
+        params.sort();
+
+        let mut filters: BTreeMap<String, String> = BTreeMap::new();
+        let mut options = BTreeMap::new();
+
+        for (k, v) in params {
+            if RESERVED_PARAMS.contains(&k.as_str()) {
+                if !["sortby", "sortorder", "limit", "offset"].contains(&k.as_str()) {
+                    options.insert(k.clone(), v.clone());
+                }
+                continue;
+            }
+
+            // `field` is the key without its group, for canonical_value
+            let (key, field, query) = match (schema.canonical_key(k), schema.resolve_field(k)) {
+                (Some(key), Some((_, query))) => (key.clone(), key, query),
+                _ => match group_key(schema, k) {
+                    Some(grouped) => grouped,
+                    None => continue, // ignored by generate_where too
+                },
+            };
+
+            let value = canonical_value(schema, &field, &query, v);
+
+            // `Season=1&season=2` both end up as filters on season, and generate_where ANDs them
+            let value = match filters.remove(&key) {
+                Some(existing) => and_key_values(&key, &existing, &value),
+                None => value,
+            };
+            filters.insert(key, value);
+        }
+
+        let sort = match sort_key(schema, fields)? {
+            SortKey::DocId => "doc_id".to_owned(),
+            SortKey::Path(path) => path.join("."),
+            SortKey::Relevance => "relevance".to_owned(),
+        };
+        let (limit, offset) = page_bounds(schema, fields)?;
+
+        Ok(CanonicalQuery {
+            table: schema.table.clone(),
+            filters,
+            sort,
+            order: sort_order(fields),
+            limit,
+            offset,
+            options,
+            raw_query: raw_query.map(str::to_owned),
+            tenant: schema.tenant.clone(),
+        })
+    }
+
+    // back to plain query parameters, in canonical order
+    pub fn to_query_string(&self) -> String {
+        let mut parts: Vec<String> = self
+            .filters
+            .iter()
+            .map(|(k, v)| format!("{}={}", encode(k), encode(v)))
+            .collect();
+
+        parts.push(format!("sortby={}", encode(&self.sort)));
+        parts.push(format!("sortorder={}", self.order));
+        parts.push(format!("limit={}", self.limit));
+        parts.push(format!("offset={}", self.offset));
+        parts.extend(
+            self.options
+                .iter()
+                .map(|(k, v)| format!("{}={}", encode(k), encode(v))),
+        );
+
+        parts.join("&")
+    }
+
+    // the same query as a POST body: a filter document for the filters one can say, and sorting, paging,
+    // options and the remaining filters as params. CanonicalQuery::from_search_body gives this query back
+    pub fn to_search_body(&self, schema: &Schema) -> SearchBody {
+        let (filter, mut params) = filter_document(schema, self.filters.iter());
+
+        params.insert("sortby".to_owned(), self.sort.clone());
+        params.insert("sortorder".to_owned(), self.order.clone());
+        params.insert("limit".to_owned(), self.limit.to_string());
+        params.insert("offset".to_owned(), self.offset.to_string());
+        params.extend(self.options.iter().map(|(k, v)| (k.clone(), v.clone())));
+
+        SearchBody {
+            params,
+            filter: match filter {
+                Value::Object(ref map) if map.is_empty() => None,
+                filter => Some(filter),
+            },
+        }
+    }
+
+    // the canonical form of a POST body, as json_search_body would run it
+    pub fn from_search_body(
+        schema: &Schema,
+        body: &SearchBody,
+        raw_query: Option<&str>,
+    ) -> Result<CanonicalQuery, CompassError> {
+        CanonicalQuery::new(schema, &body_params(schema, body)?, raw_query)
+    }
+
+    // what identifies the query: the table, the query string, the raw query and, when there is one,
+    // the tenant. keys of schemas without tenancy are what they were before there was one
+    fn key_parts(&self) -> Vec<String> {
+        let mut parts = vec![
+            self.table.clone(),
+            self.to_query_string(),
+            self.raw_query.clone().unwrap_or_default(),
+        ];
+        parts.extend(self.tenant.clone());
+        parts
+    }
+
+    // FNV-1a over the canonical form. unlike std's hasher this stays the same across builds and
+    // processes, so it can be stored
+    pub fn stable_hash(&self) -> u64 {
+        let mut hash: u64 = 0xcbf29ce484222325;
+        for part in self.key_parts() {
+            for byte in part.bytes().chain(std::iter::once(0)) {
+                hash ^= byte as u64;
+                hash = hash.wrapping_mul(0x100000001b3);
+            }
+        }
+        hash
+    }
+
+    // the whole canonical form as one string. longer than key(), but two different queries can't share
+    // it, so it's what ResponseCache keys its entries on
+    pub fn full_key(&self) -> String {
+        self.key_parts().join("\0")
+    }
+
+    pub fn key(&self) -> String {
+        format!("{:016x}", self.stable_hash())
+    }
+}
+
+// `g1.or.Season` -> `g1.or.season`: the group and marker as generate_where reads them, the field in
+// schema spelling
+fn group_key(schema: &Schema, k: &str) -> Option<(String, String, FieldQuery)> {
+    let (group, or, inner) = split_group_key(k)?;
+    let field = schema.canonical_key(inner)?;
+    let (_, query) = schema.resolve_field(inner)?;
+    let marker = if or { "or." } else { "" };
+    Some((format!("{}.{}{}", group, marker, field), field, query))
+}
+
+fn canonical_value(schema: &Schema, key: &str, query: &FieldQuery, v: &str) -> String {
+    let query = match query {
+        FieldQuery::Not(inner) => inner,
+        q => q,
+    };
+
+    if let FieldQuery::Fulltext { .. } | FieldQuery::Vector { .. } = query {
+        return v.trim().to_owned();
+    }
+
+    let normalize = schema
+        .fields
+        .get(key.trim_end_matches('!'))
+        .or_else(|| key.split('.').next().and_then(|k| schema.fields.get(k)))
+        .and_then(|f| f.normalize);
+
+    let (terms, ops) = split_terms(v);
+    let mut terms: Vec<String> = terms
+        .into_iter()
+        .map(|t| match query {
+            // 012 and 12 are the same number, and so is whatever alias maps to 12
+            FieldQuery::Range { aliases, .. } | FieldQuery::NumericTag { aliases } => {
+                match aliases.get(&t.to_uppercase()) {
+                    Some(n) => n.to_string(),
+                    None => canonical_number(t),
+                }
+            }
+            FieldQuery::Min | FieldQuery::Max => canonical_number(t),
+            _ => match normalize {
+                Some(n) => n.apply(&t),
+                None => t,
+            },
+        })
+        .collect();
+
+    // a list joined by only one kind of operator doesn't care about order
+    if ops.windows(2).all(|w| w[0] == w[1]) {
+        terms.sort();
+        terms.dedup();
+        let op = format!("_{}_", ops.first().copied().unwrap_or("or"));
+        terms.join(&op)
+    } else {
+        let mut out = String::new();
+        for (i, term) in terms.iter().enumerate() {
+            if i > 0 {
+                out.push('_');
+                out.push_str(ops[i - 1]);
+                out.push('_');
+            }
+            out.push_str(term);
+        }
+        out
+    }
+}
+
+fn canonical_number(t: String) -> String {
+    match t.parse::<i128>() {
+        Ok(n) => n.to_string(),
+        Err(_) => t,
+    }
+}
+
+fn encode(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for byte in s.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'!' => {
+                out.push(byte as char)
+            }
+            _ => out.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    #[test]
+    fn tenants_get_their_own_keys() {
+        let schema = test_schema();
+        let request = params(&[("type", "1")]);
+        let key = |schema: &Schema| CanonicalQuery::new(schema, &request, None).unwrap().key();
+
+        let a = key(&schema.for_tenant("a"));
+        let b = key(&schema.for_tenant("b"));
+        assert_ne!(a, b);
+        assert_eq!(a, key(&schema.for_tenant("a")));
+        assert_ne!(key(&schema), a);
+    }
+
+    #[test]
+    fn repeated_keys_and_as_a_whole() {
+        let schema = test_schema();
+        let canonical = |request| CanonicalQuery::new(&schema, &request, None).unwrap();
+
+        let repeated = canonical(params(&[("Season", "1_or_2"), ("season", "3")]));
+        assert_eq!(repeated.filters["season"], "1_and_3_or_2_and_3");
+        let mixed = canonical(params(&[("season", "1_or_2_and_3")]));
+        assert_ne!(repeated.full_key(), mixed.full_key());
+        assert_ne!(repeated.key(), mixed.key());
+
+        // NOT 1 AND NOT 2 is NOT (1 OR 2)
+        let negated = canonical(params(&[("Type!", "1"), ("type!", "2")]));
+        assert_eq!(negated.filters["type!"], "1_or_2");
+    }
+}
@@ -0,0 +1,100 @@
+// documents as arrow record batches, for the flight service and parquet exports
+use super::*;
+
+use arrow_array::{ArrayRef, BooleanArray, Float64Array, Int64Array, RecordBatch, StringArray};
+use arrow_schema::{ArrowError, DataType, Field as ArrowField, Schema as ArrowSchema};
+use serde_json::Value;
+
+use std::sync::Arc;
+
+// one arrow column: a schema field and the type its values come out as
+#[derive(Debug, Clone)]
+pub(crate) struct Column {
+    path: Vec<String>,
+    data_type: DataType,
+}
+
+// a column for every field that's stored in the document, typed by how the schema queries it. converted
+// fields come out as they're rendered in json, and values that don't fit a column's type are null
+pub(crate) fn columns(schema: &Schema) -> Vec<(String, Column)> {
+    schema
+        .fields
+        .iter()
+        .filter_map(|(name, field)| {
+            let data_type = match field.query {
+                _ if field.converter.is_some() => DataType::Utf8,
+                FieldQuery::Range { .. } => DataType::Float64,
+                FieldQuery::NumericTag { .. } => DataType::Int64,
+                FieldQuery::Bool => DataType::Boolean,
+                FieldQuery::Fulltext { .. }
+                | FieldQuery::StringTag
+                | FieldQuery::AmbiguousTag
+                | FieldQuery::Nested => DataType::Utf8,
+                FieldQuery::Min
+                | FieldQuery::Max
+                | FieldQuery::Vector { .. }
+                | FieldQuery::Not(_) => return None,
+            };
+            let column = Column {
+                path: name.split('.').map(str::to_owned).collect(),
+                data_type,
+            };
+            Some((name.clone(), column))
+        })
+        .collect()
+}
+
+pub(crate) fn arrow_schema(columns: &[(String, Column)]) -> Arc<ArrowSchema> {
+    Arc::new(ArrowSchema::new(
+        columns
+            .iter()
+            .map(|(name, column)| ArrowField::new(name, column.data_type.clone(), true))
+            .collect::<Vec<_>>(),
+    ))
+}
+
+fn lookup<'a>(doc: &'a Value, path: &[String]) -> Option<&'a Value> {
+    path.iter().try_fold(doc, |v, key| v.get(key))
+}
+
+pub(crate) fn record_batch(
+    arrow_schema: &Arc<ArrowSchema>,
+    columns: &[(String, Column)],
+    docs: &[Value],
+) -> Result<RecordBatch, ArrowError> {
+    let arrays: Vec<ArrayRef> = columns
+        .iter()
+        .map(|(_, column)| {
+            let values = docs.iter().map(|doc| lookup(doc, &column.path));
+            let array: ArrayRef = match column.data_type {
+                DataType::Int64 => Arc::new(Int64Array::from(
+                    values
+                        .map(|v| v.and_then(Value::as_i64))
+                        .collect::<Vec<_>>(),
+                )),
+                DataType::Float64 => Arc::new(Float64Array::from(
+                    values
+                        .map(|v| v.and_then(Value::as_f64))
+                        .collect::<Vec<_>>(),
+                )),
+                DataType::Boolean => Arc::new(BooleanArray::from(
+                    values
+                        .map(|v| v.and_then(Value::as_bool))
+                        .collect::<Vec<_>>(),
+                )),
+                _ => Arc::new(StringArray::from(
+                    values
+                        .map(|v| match v {
+                            None | Some(Value::Null) => None,
+                            Some(Value::String(s)) => Some(s.clone()),
+                            Some(other) => Some(other.to_string()),
+                        })
+                        .collect::<Vec<_>>(),
+                )),
+            };
+            array
+        })
+        .collect();
+
+    RecordBatch::try_new(arrow_schema.clone(), arrays)
+}
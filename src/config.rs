@@ -0,0 +1,603 @@
+use super::*;
+
+use postgres::{Client, NoTls};
+use serde::{Deserialize, Serialize};
+
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Config {
+    #[serde(default)]
+    pub server: ServerConfig,
+    #[serde(default)]
+    pub database: DatabaseConfig,
+    #[serde(default)]
+    pub schemas: HashMap<String, PathBuf>, // schema name -> path to its yaml
+    #[serde(default)]
+    pub limits: Limits,
+    #[serde(default)]
+    pub cache: CacheConfig,
+    #[serde(default)]
+    pub shutdown: ShutdownConfig,
+    #[serde(default)]
+    pub slow_query: SlowQueryConfig,
+    #[serde(default)]
+    pub raw_query: RawQueryConfig,
+    #[serde(default)]
+    pub throttle: ThrottleConfig,
+    #[serde(default)]
+    pub quotas: HashMap<String, QuotaConfig>, // schema name -> its quota
+    #[serde(default)]
+    pub cursor: CursorConfig,
+    #[serde(default)]
+    pub batch: BatchConfig,
+    #[serde(default)]
+    pub sql: SqlConfig,
+    #[serde(default)]
+    pub webhooks: WebhookConfig,
+    #[serde(default)]
+    pub usage: UsageConfig,
+    #[serde(default)]
+    pub scheduled: Vec<ScheduledQuery>, // [[scheduled]] tables, run by Scheduler
+    #[serde(default)]
+    pub tenants: HashMap<String, String>, // api key -> tenant id, for schemas with tenancy
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct ServerConfig {
+    pub address: String,
+    pub port: u16,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            address: "127.0.0.1".to_owned(),
+            port: 8000,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct DatabaseConfig {
+    pub url: String,
+    pub pool_size: u32,
+    pub connect_timeout_secs: u64,
+    pub read_only: bool, // search connections refuse to write, whatever sql ends up being generated
+    pub read_only_url: Option<String>, // a separate role for search connections; falls back to url
+    pub pool_timeout_secs: u64, // how long CompassPool::get waits for a free connection
+    pub pool_idle_timeout_secs: u64, // pooled connections idle this long are closed; 0 keeps them
+    pub pool_max_lifetime_secs: u64, // and ones open this long are replaced; 0 never replaces them
+    pub pool_health_check: bool, // CompassPool runs `SELECT 1` on a connection before handing it out
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        DatabaseConfig {
+            url: "postgres://localhost/compass".to_owned(),
+            pool_size: 16,
+            connect_timeout_secs: 30,
+            read_only: false,
+            read_only_url: None,
+            pool_timeout_secs: 30,
+            pool_idle_timeout_secs: 600,
+            pool_max_lifetime_secs: 1800,
+            pool_health_check: true,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Limits {
+    pub default_limit: i64,
+    pub max_limit: i64,
+    pub max_offset: i64,
+    pub max_terms: usize,          // and/or terms in a single query parameter
+    pub max_total_terms: usize,    // and/or terms across the whole query
+    pub max_depth: usize,          // parenthesis nesting in the generated jsonpath
+    pub max_response_bytes: usize, // a search page stops early past this much json; 0 for no limit
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            default_limit: 100,
+            max_limit: 1000,
+            max_offset: 100_000,
+            max_terms: 100,
+            max_total_terms: 500,
+            max_depth: 32,
+            max_response_bytes: 64 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct CacheConfig {
+    pub enabled: bool,
+    pub capacity: usize,
+    pub ttl_secs: u64,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            enabled: false,
+            capacity: 1024,
+            ttl_secs: 60,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct CursorConfig {
+    pub secret: Option<String>, // signs pagination cursors; cursor pagination is off without one
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ShutdownConfig {
+    pub drain_timeout_secs: u64, // how long in-flight queries get to finish after SIGTERM
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        ShutdownConfig {
+            drain_timeout_secs: 30,
+        }
+    }
+}
+
+impl DatabaseConfig {
+    fn connect_to(&self, url: &str) -> Result<Client, postgres::Error> {
+        url.parse::<postgres::Config>()?
+            .connect_timeout(Duration::from_secs(self.connect_timeout_secs))
+            .connect(NoTls)
+    }
+
+    pub(crate) fn connect_search(&self) -> Result<Client, postgres::Error> {
+        if !self.read_only {
+            return self.connect_to(&self.url);
+        }
+
+        let mut client = self.connect_to(self.read_only_url.as_ref().unwrap_or(&self.url))?;
+        client.batch_execute("SET default_transaction_read_only = on")?;
+        Ok(client)
+    }
+
+    // a connection for serving searches. in read-only mode every transaction on it is read only,
+    // so a bug in query generation can't turn into a write
+    pub fn connect(&self) -> Result<Client, CompassError> {
+        Ok(self.connect_search()?)
+    }
+
+    // a connection that can write, for migrate and ingest
+    pub fn connect_writable(&self) -> Result<Client, CompassError> {
+        Ok(self.connect_to(&self.url)?)
+    }
+}
+
+fn env_override<T: FromStr>(target: &mut T, var: &str) -> Result<(), CompassError> {
+    if let Ok(val) = env::var(var) {
+        *target = val
+            .parse::<T>()
+            .map_err(|_| CompassError::ConfigError(format!("couldn't parse {}={}", var, val)))?;
+    }
+    Ok(())
+}
+
+impl Config {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Config, CompassError> {
+        let text = fs::read_to_string(path)?;
+        let mut config: Config = toml::from_str(&text)?;
+        config.apply_env_overrides()?;
+        Ok(config)
+    }
+
+    // COMPASS_* variables win over whatever the file says. DATABASE_URL is honored too since everything else reads it
+    pub fn apply_env_overrides(&mut self) -> Result<(), CompassError> {
+        env_override(&mut self.server.address, "COMPASS_ADDRESS")?;
+        env_override(&mut self.server.port, "COMPASS_PORT")?;
+
+        env_override(&mut self.database.url, "DATABASE_URL")?;
+        env_override(&mut self.database.url, "COMPASS_DATABASE_URL")?;
+        env_override(&mut self.database.pool_size, "COMPASS_POOL_SIZE")?;
+        env_override(
+            &mut self.database.connect_timeout_secs,
+            "COMPASS_CONNECT_TIMEOUT",
+        )?;
+        env_override(&mut self.database.pool_timeout_secs, "COMPASS_POOL_TIMEOUT")?;
+        env_override(&mut self.database.read_only, "COMPASS_READ_ONLY")?;
+        if let Ok(url) = env::var("COMPASS_READ_ONLY_DATABASE_URL") {
+            self.database.read_only_url = Some(url);
+        }
+
+        env_override(&mut self.limits.default_limit, "COMPASS_DEFAULT_LIMIT")?;
+        env_override(&mut self.limits.max_limit, "COMPASS_MAX_LIMIT")?;
+        env_override(&mut self.limits.max_offset, "COMPASS_MAX_OFFSET")?;
+        env_override(&mut self.limits.max_terms, "COMPASS_MAX_TERMS")?;
+        env_override(&mut self.limits.max_total_terms, "COMPASS_MAX_TOTAL_TERMS")?;
+        env_override(&mut self.limits.max_depth, "COMPASS_MAX_DEPTH")?;
+        env_override(
+            &mut self.limits.max_response_bytes,
+            "COMPASS_MAX_RESPONSE_BYTES",
+        )?;
+
+        env_override(&mut self.cache.enabled, "COMPASS_CACHE_ENABLED")?;
+        env_override(&mut self.cache.capacity, "COMPASS_CACHE_CAPACITY")?;
+        env_override(&mut self.cache.ttl_secs, "COMPASS_CACHE_TTL")?;
+
+        env_override(
+            &mut self.shutdown.drain_timeout_secs,
+            "COMPASS_DRAIN_TIMEOUT",
+        )?;
+
+        env_override(&mut self.sql.enabled, "COMPASS_SQL_ENABLED")?;
+        if let Ok(role) = env::var("COMPASS_SQL_ROLE") {
+            self.sql.role = Some(role);
+        }
+        if let Ok(secret) = env::var("COMPASS_CURSOR_SECRET") {
+            self.cursor.secret = Some(secret);
+        }
+
+        env_override(&mut self.slow_query.enabled, "COMPASS_SLOW_QUERY_LOG")?;
+        env_override(
+            &mut self.slow_query.threshold_ms,
+            "COMPASS_SLOW_QUERY_THRESHOLD",
+        )?;
+
+        // COMPASS_SCHEMAS=name=path,other=path
+        if let Ok(val) = env::var("COMPASS_SCHEMAS") {
+            self.schemas = val
+                .split(',')
+                .filter(|s| !s.is_empty())
+                .map(|s| match s.split_once('=') {
+                    Some((name, path)) => Ok((name.to_owned(), PathBuf::from(path))),
+                    None => Err(CompassError::ConfigError(format!(
+                        "COMPASS_SCHEMAS entry '{}' should look like name=path",
+                        s
+                    ))),
+                })
+                .collect::<Result<_, _>>()?;
+        }
+
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<(), CompassError> {
+        if self.limits.default_limit < 0 || self.limits.default_limit > self.limits.max_limit {
+            return Err(CompassError::ConfigError(
+                "limits.default_limit has to be between 0 and limits.max_limit".to_owned(),
+            ));
+        }
+
+        if self.limits.max_offset < 0 {
+            return Err(CompassError::ConfigError(
+                "limits.max_offset can't be negative".to_owned(),
+            ));
+        }
+
+        if self.database.pool_size == 0 {
+            return Err(CompassError::ConfigError(
+                "database.pool_size must be at least 1".to_owned(),
+            ));
+        }
+
+        if self.database.pool_timeout_secs == 0 {
+            return Err(CompassError::ConfigError(
+                "database.pool_timeout_secs must be at least 1".to_owned(),
+            ));
+        }
+
+        if self.database.read_only_url.is_some() && !self.database.read_only {
+            return Err(CompassError::ConfigError(
+                "database.read_only_url is only used with database.read_only = true".to_owned(),
+            ));
+        }
+
+        if self.throttle.enabled
+            && (self.throttle.max_in_flight == 0 || self.throttle.trip_after == 0)
+        {
+            return Err(CompassError::ConfigError(
+                "throttle.max_in_flight and throttle.trip_after have to be at least 1".to_owned(),
+            ));
+        }
+
+        for (name, quota) in self.quotas.iter() {
+            if !self.schemas.contains_key(name) {
+                return Err(CompassError::ConfigError(format!(
+                    "there's a quota for unknown schema '{}'",
+                    name
+                )));
+            }
+            if quota.max_rows < 0 {
+                return Err(CompassError::ConfigError(format!(
+                    "quotas.{}.max_rows can't be negative",
+                    name
+                )));
+            }
+        }
+
+        for job in self.scheduled.iter() {
+            if !self.schemas.contains_key(&job.schema) {
+                return Err(CompassError::ConfigError(format!(
+                    "scheduled query '{}' uses unknown schema '{}'",
+                    job.name, job.schema
+                )));
+            }
+            if job.count_only && job.changes_only {
+                return Err(CompassError::ConfigError(format!(
+                    "scheduled query '{}' can't be both count_only and changes_only",
+                    job.name
+                )));
+            }
+            job.parse_schedule()?;
+        }
+
+        if self.batch.parallelism == 0 {
+            return Err(CompassError::ConfigError(
+                "batch.parallelism must be at least 1".to_owned(),
+            ));
+        }
+
+        if self.sql.enabled && self.sql.role.as_deref().map_or(true, str::is_empty) {
+            return Err(CompassError::ConfigError(
+                "sql.enabled needs sql.role, a role that can only SELECT the schema tables"
+                    .to_owned(),
+            ));
+        }
+
+        if self.sql.enabled && self.sql.max_rows < 1 {
+            return Err(CompassError::ConfigError(
+                "sql.max_rows must be at least 1".to_owned(),
+            ));
+        }
+
+        if self.cursor.secret.as_ref().map_or(false, |s| s.len() < 16) {
+            return Err(CompassError::ConfigError(
+                "cursor.secret should be at least 16 characters".to_owned(),
+            ));
+        }
+
+        if self.cache.enabled && self.cache.capacity == 0 {
+            return Err(CompassError::ConfigError(
+                "cache.capacity must be at least 1 when the cache is enabled".to_owned(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    // reads every schema file, handing each one a copy of the server limits, lowered by its quota
+    pub fn load_schemas(&self) -> Result<HashMap<String, Schema>, CompassError> {
+        let tenant_keys = Arc::new(self.tenants.clone());
+        let mut schemas: HashMap<String, Schema> = self
+            .schemas
+            .iter()
+            .map(|(name, path)| {
+                let text = fs::read_to_string(path)?;
+                let mut schema: Schema = serde_yaml::from_str(&text)?;
+                schema.validate().map_err(|e| match e {
+                    CompassError::ConfigError(msg) => {
+                        CompassError::ConfigError(format!("schema '{}': {}", name, msg))
+                    }
+                    e => e,
+                })?;
+                schema.limits = self.limits.clone();
+                if let Some(quota) = self.quotas.get(name).filter(|q| q.max_rows > 0) {
+                    schema.limits.max_limit = schema.limits.max_limit.min(quota.max_rows);
+                    schema.limits.default_limit =
+                        schema.limits.default_limit.min(schema.limits.max_limit);
+                }
+                schema.raw_query = self.raw_query.clone();
+                schema.cursor_secret = self.cursor.secret.clone();
+                schema.tenant_keys = tenant_keys.clone();
+                Ok((name.clone(), schema))
+            })
+            .collect::<Result<_, CompassError>>()?;
+
+        let others = schemas.clone();
+        for (name, schema) in schemas.iter_mut() {
+            schema.resolve_joins(&others).map_err(|e| match e {
+                CompassError::ConfigError(msg) => {
+                    CompassError::ConfigError(format!("schema '{}': {}", name, msg))
+                }
+                e => e,
+            })?;
+        }
+
+        Ok(schemas)
+    }
+}
+
+#[derive(Debug)]
+pub struct LoadedConfig {
+    pub config: Config,
+    pub schemas: HashMap<String, Schema>,
+    pub slow_log: Option<Arc<SlowQueryLog>>, // shared by every schema, for listing from an admin route
+    pub cache: Option<Arc<ResponseCache>>,   // when cache.enabled; a reload starts an empty one
+    pub usage: Option<Arc<Usage>>,           // when usage.enabled; kept across reloads
+}
+
+impl LoadedConfig {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<LoadedConfig, CompassError> {
+        let (config, schemas) = LoadedConfig::read(path)?;
+        LoadedConfig::assemble(config, schemas, None)
+    }
+
+    // parses and validates the file and every schema without starting anything, so a reload that fails
+    // here leaves nothing behind
+    fn read<P: AsRef<Path>>(path: P) -> Result<(Config, HashMap<String, Schema>), CompassError> {
+        let config = Config::from_file(path)?;
+        config.validate()?;
+        let schemas = config.load_schemas()?;
+        Ok((config, schemas))
+    }
+
+    // hands the schemas their shared state. on a reload, `previous`'s usage counts, throttles and quotas
+    // carry on with the new settings instead of being started over, so the queries already running still
+    // count against them and the usage store isn't read back in behind the counts that haven't been saved
+    fn assemble(
+        mut config: Config,
+        mut schemas: HashMap<String, Schema>,
+        previous: Option<&LoadedConfig>,
+    ) -> Result<LoadedConfig, CompassError> {
+        let slow_log = if config.slow_query.enabled {
+            Some(Arc::new(SlowQueryLog::new(&config.slow_query)))
+        } else {
+            None
+        };
+
+        let usage = match previous {
+            Some(previous) if config.usage.enabled && previous.usage.is_some() => {
+                if config.usage != previous.config.usage {
+                    eprintln!("compass: usage.store and usage.flush_secs need a restart to apply");
+                    config.usage = previous.config.usage.clone();
+                }
+                previous.usage.clone()
+            }
+            _ if config.usage.enabled => Some(Usage::start(&config.usage)?),
+            _ => None,
+        };
+
+        for (name, schema) in schemas.iter_mut() {
+            let old = previous.and_then(|p| p.schemas.get(name));
+
+            schema.slow_log = slow_log.clone();
+            schema.usage = usage.clone();
+            if config.throttle.enabled {
+                schema.throttle = match old.and_then(|o| o.throttle.clone()) {
+                    Some(throttle) => {
+                        throttle.reconfigure(&config.throttle);
+                        Some(throttle)
+                    }
+                    None => Some(Arc::new(Throttle::new(&config.throttle))),
+                };
+            }
+            if let Some(quota) = config.quotas.get(name) {
+                schema.quota = match old.and_then(|o| o.quota.clone()) {
+                    Some(existing) => {
+                        existing.reconfigure(quota);
+                        Some(existing)
+                    }
+                    None => Some(Arc::new(Quota::new(quota))),
+                };
+            }
+            // registered hooks belong to the embedder, not the file, so schemas that are still there keep them
+            if let Some(old) = old {
+                schema.hooks = old.hooks.clone();
+            }
+        }
+
+        let cache = if config.cache.enabled {
+            Some(Arc::new(ResponseCache::new(&config.cache)))
+        } else {
+            None
+        };
+
+        Ok(LoadedConfig {
+            config,
+            schemas,
+            slow_log,
+            cache,
+            usage,
+        })
+    }
+}
+
+// shared, swappable view of the config. readers grab an Arc snapshot so a reload never pulls anything out from under a running query
+#[derive(Debug, Clone)]
+pub struct ConfigHandle {
+    path: PathBuf,
+    current: Arc<RwLock<Arc<LoadedConfig>>>,
+}
+
+impl ConfigHandle {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<ConfigHandle, CompassError> {
+        let loaded = LoadedConfig::load(&path)?;
+        Ok(ConfigHandle {
+            path: path.as_ref().to_owned(),
+            current: Arc::new(RwLock::new(Arc::new(loaded))),
+        })
+    }
+
+    pub fn current(&self) -> Arc<LoadedConfig> {
+        self.current.read().unwrap().clone()
+    }
+
+    pub fn schema(&self, name: &str) -> Option<Schema> {
+        self.current().schemas.get(name).cloned()
+    }
+
+    // re-reads the file and every schema. if anything fails to parse or validate the old config stays in place.
+    // listen address and database settings can't change without a restart, so those keep their old values
+    pub fn reload(&self) -> Result<(), CompassError> {
+        let (mut config, schemas) = LoadedConfig::read(&self.path)?;
+
+        let mut current = self.current.write().unwrap();
+        if config.server != current.config.server || config.database != current.config.database {
+            eprintln!("compass: server/database settings changed; those need a restart to apply");
+            config.server = current.config.server.clone();
+            config.database = current.config.database.clone();
+        }
+
+        let loaded = LoadedConfig::assemble(config, schemas, Some(&**current))?;
+        *current = Arc::new(loaded);
+        Ok(())
+    }
+
+    fn hooks(&self, schema: &str) -> Result<Arc<Hooks>, CompassError> {
+        self.current()
+            .schemas
+            .get(schema)
+            .map(|s| s.hooks.clone())
+            .ok_or_else(|| CompassError::ConfigError(format!("no schema '{}'", schema)))
+    }
+
+    // runs `transformer` over every result document of the schema from now on, reloads included
+    pub fn register_transformer(
+        &self,
+        schema: &str,
+        transformer: Arc<dyn ResultTransformer>,
+    ) -> Result<(), CompassError> {
+        self.hooks(schema)?.add_transformer(transformer);
+        Ok(())
+    }
+
+    // and `middleware` over the parameters of every query on it
+    pub fn register_middleware(
+        &self,
+        schema: &str,
+        middleware: Arc<dyn QueryMiddleware>,
+    ) -> Result<(), CompassError> {
+        self.hooks(schema)?.add_middleware(middleware);
+        Ok(())
+    }
+
+    // reloads every time the process gets SIGHUP
+    #[cfg(unix)]
+    pub fn reload_on_sighup(&self) -> Result<thread::JoinHandle<()>, CompassError> {
+        let mut signals = signal_hook::iterator::Signals::new(&[signal_hook::consts::SIGHUP])?;
+        let handle = self.clone();
+        Ok(thread::spawn(move || {
+            for _ in signals.forever() {
+                match handle.reload() {
+                    Ok(()) => eprintln!("compass: reloaded config from {}", handle.path.display()),
+                    Err(e) => eprintln!("compass: config reload failed, keeping old config: {}", e),
+                }
+            }
+        }))
+    }
+}
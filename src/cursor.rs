@@ -0,0 +1,230 @@
+use super::*;
+
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::Sha256;
+use uuid::Uuid;
+
+use std::collections::HashMap;
+
+type HmacSha256 = Hmac<Sha256>;
+
+// where the previous page ended. `filters` is the stable hash of the query it was made for, so a
+// cursor can't be replayed against different filters or a different sort
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PageCursor {
+    pub filters: String,
+    pub sort: String,
+    pub order: String,
+    pub value: Value, // the last document's sort value, as stored; null when it didn't have one
+    #[serde(default)]
+    pub secondary: Value, // and its secondary sort value, the same way
+    pub doc_id: Uuid,
+}
+
+fn invalid(msg: &str) -> CompassError {
+    CompassError::InvalidCursor(msg.to_owned())
+}
+
+fn mac(secret: &str) -> HmacSha256 {
+    // hmac takes keys of any length
+    HmacSha256::new_from_slice(secret.as_bytes()).unwrap()
+}
+
+// the hash a cursor is tied to: everything about the query except where the page starts and how long it is
+pub(crate) fn cursor_filters(
+    schema: &Schema,
+    fields: &HashMap<String, String>,
+    raw_query: Option<&str>,
+) -> Result<String, CompassError> {
+    let unpaged: HashMap<String, String> = fields
+        .iter()
+        .filter(|(k, _)| !["cursor", "limit", "k", "offset", "with_total"].contains(&k.as_str()))
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect();
+    Ok(CanonicalQuery::new(schema, &unpaged, raw_query)?.key())
+}
+
+impl PageCursor {
+    // base64 json, a dot, then the base64 hmac of that
+    pub fn encode(&self, secret: &str) -> Result<String, CompassError> {
+        let payload = base64::encode_config(serde_json::to_vec(self)?, base64::URL_SAFE_NO_PAD);
+        let mut mac = mac(secret);
+        mac.update(payload.as_bytes());
+        let signature = base64::encode_config(mac.finalize().into_bytes(), base64::URL_SAFE_NO_PAD);
+        Ok(format!("{}.{}", payload, signature))
+    }
+
+    pub fn decode(token: &str, secret: &str) -> Result<PageCursor, CompassError> {
+        let (payload, signature) = token
+            .split_once('.')
+            .ok_or_else(|| invalid("not a cursor this server issued"))?;
+        let signature = base64::decode_config(signature, base64::URL_SAFE_NO_PAD)
+            .map_err(|_| invalid("not a cursor this server issued"))?;
+
+        let mut mac = mac(secret);
+        mac.update(payload.as_bytes());
+        mac.verify_slice(&signature)
+            .map_err(|_| invalid("not a cursor this server issued"))?;
+
+        let json = base64::decode_config(payload, base64::URL_SAFE_NO_PAD)
+            .map_err(|_| invalid("not a cursor this server issued"))?;
+        Ok(serde_json::from_slice(&json)?)
+    }
+}
+
+// the rows that come after `value` (a placeholder, or None for null) in one ORDER BY column
+fn past(expr: &str, value: Option<&str>, descending: bool, nulls: Nulls) -> Option<String> {
+    let value = match (value, nulls) {
+        (Some(value), _) => value,
+        (None, Nulls::First) => return Some(format!("{} IS NOT NULL", expr)),
+        (None, Nulls::Last) => return None,
+    };
+    let beyond = format!("{} {} {}", expr, if descending { "<" } else { ">" }, value);
+    Some(match nulls {
+        Nulls::First => beyond,
+        Nulls::Last => format!("({} OR {} IS NULL)", beyond, expr),
+    })
+}
+
+// the keyset condition for "after the cursor", matching the ORDER BY generate_where builds: the sort
+// value in `order` with its nulls where `nulls` puts them, then the secondary sort value in `order`
+// with nulls last, then doc_id ascending
+pub(crate) fn after_cursor(
+    cursor: &PageCursor,
+    sort: &SortKey,
+    order: &str,
+    nulls: Nulls,
+    plan: &mut QueryPlan,
+) -> Result<String, CompassError> {
+    let doc_id = format!(
+        "{}::uuid",
+        plan.bind(Binding::Text(cursor.doc_id.to_string()))
+    );
+
+    match sort {
+        SortKey::DocId if order == "DESC" => Ok(format!("doc_id < {}", doc_id)),
+        SortKey::DocId => Ok(format!("doc_id > {}", doc_id)),
+        SortKey::Path(_) => {
+            let collation = plan.collation.clone();
+            let mut columns = vec![(
+                sort_expression(collation.as_deref()),
+                &cursor.value,
+                nulls,
+                collation.is_some(),
+            )];
+            if let Some(secondary) = plan.secondary.clone() {
+                columns.push((
+                    format!("(object #> {})", secondary),
+                    &cursor.secondary,
+                    Nulls::Last,
+                    false,
+                ));
+            }
+
+            // past the cursor in some column and tied with it in every column before that, or tied
+            // in all of them and past its doc_id
+            let mut alternatives = Vec::new();
+            let mut tied: Vec<String> = Vec::new();
+            for (expr, value, nulls, collated) in columns {
+                // a collated sort compares text, which is what #>> gives for the stored jsonb value
+                let value = match value {
+                    Value::Null => None,
+                    Value::String(s) if collated => Some(plan.bind(Binding::Text(s.clone()))),
+                    value if collated => Some(plan.bind(Binding::Text(value.to_string()))),
+                    value => Some(plan.bind(Binding::Json(value.clone()))),
+                };
+                if let Some(past) = past(&expr, value.as_deref(), order == "DESC", nulls) {
+                    let mut alternative = tied.clone();
+                    alternative.push(past);
+                    alternatives.push(alternative.join(" AND "));
+                }
+                tied.push(match value {
+                    Some(value) => format!("{} = {}", expr, value),
+                    None => format!("{} IS NULL", expr),
+                });
+            }
+            tied.push(format!("doc_id > {}", doc_id));
+            alternatives.push(tied.join(" AND "));
+
+            Ok(format!("(({}))", alternatives.join(") OR (")))
+        }
+        SortKey::Relevance => Err(invalid("relevance sorts page with offset, not cursors")),
+    }
+}
+
+// checks a `cursor=` search can page by cursor at all, and decodes where it left off. an empty
+// `cursor=` starts from the first page
+pub(crate) fn resume_cursor(
+    schema: &Schema,
+    fields: &HashMap<String, String>,
+    raw_query: Option<&str>,
+) -> Result<Option<PageCursor>, CompassError> {
+    let secret = schema
+        .cursor_secret
+        .as_deref()
+        .ok_or_else(|| invalid("cursor pagination isn't enabled on this server"))?;
+
+    if fields.contains_key("offset") {
+        return Err(invalid("cursor and offset can't be used together"));
+    }
+
+    let vector_sorted = fields.keys().any(|k| {
+        schema
+            .resolve_field(k)
+            .map_or(false, |field| matches!(field.1, FieldQuery::Vector { .. }))
+    });
+    if vector_sorted {
+        return Err(invalid(
+            "nearest-neighbour searches page with offset, not cursors",
+        ));
+    }
+    if let SortKey::Relevance = sort_key(schema, fields)? {
+        return Err(invalid("relevance sorts page with offset, not cursors"));
+    }
+
+    let token = match fields.get("cursor") {
+        Some(token) if !token.is_empty() => token,
+        _ => return Ok(None),
+    };
+    let cursor = PageCursor::decode(token, secret)?;
+
+    if cursor.filters != cursor_filters(schema, fields, raw_query)? {
+        return Err(invalid("it was issued for a different query"));
+    }
+    if cursor.sort != sort_name(fields) || cursor.order != sort_order(fields) {
+        return Err(invalid("it was issued for a different sort"));
+    }
+
+    Ok(Some(cursor))
+}
+
+fn sort_name(fields: &HashMap<String, String>) -> String {
+    fields.get("sortby").cloned().unwrap_or_default()
+}
+
+// the cursor that picks up after `doc_id`, whose sort values were `value` and `secondary`
+pub(crate) fn next_cursor(
+    schema: &Schema,
+    fields: &HashMap<String, String>,
+    raw_query: Option<&str>,
+    value: Option<Value>,
+    secondary: Option<Value>,
+    doc_id: Uuid,
+) -> Result<String, CompassError> {
+    let secret = schema
+        .cursor_secret
+        .as_deref()
+        .ok_or_else(|| invalid("cursor pagination isn't enabled on this server"))?;
+
+    PageCursor {
+        filters: cursor_filters(schema, fields, raw_query)?,
+        sort: sort_name(fields),
+        order: sort_order(fields),
+        value: value.unwrap_or(Value::Null),
+        secondary: secondary.unwrap_or(Value::Null),
+        doc_id,
+    }
+    .encode(secret)
+}
@@ -2,19 +2,522 @@ use super::*;
 
 use postgres::Client;
 
+use serde_json::value::{to_raw_value, RawValue};
 use serde_json::{json, Value};
 
+use postgres::error::SqlState;
 use postgres::fallible_iterator::FallibleIterator;
 use postgres::types::ToSql;
 use postgres::types::Type as PostgresType;
 use postgres::{Row, Statement};
 
+use std::borrow::Cow;
+use std::cmp::Ordering;
 use std::collections::HashMap;
+use std::num::IntErrorKind;
+use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 
-use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+use chrono::{NaiveDate, SecondsFormat, TimeZone, Utc};
+use indexmap::IndexMap;
 
 use uuid::Uuid;
 
+// quotes a value as a jsonpath string literal; jsonpath strings use json's escaping rules.
+// anything that ends up between quotes in a filter has to go through here, or a stray `"` lets a value rewrite the path expression.
+// the other pieces of a filter are typed before they're formatted (numbers through parse_number, bools
+// through parse) or are names held to validate_key. values aren't passed as jsonpath `$vars` because only
+// jsonb_path_match takes those, and unlike `object @@ $1` it can't use the table's GIN index
+fn jsonpath_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+// query parameters that control the search itself rather than filtering on a field
+pub const RESERVED_PARAMS: &[&str] = &[
+    "sortby",
+    "sortorder",
+    "nulls",
+    "collation",
+    "limit",
+    "offset",
+    "debug",
+    "join",
+    "window",
+    "similar_to",
+    "k",
+    "rank_normalization",
+    "recency_half_life",
+    "recency_field",
+    "snippets",
+    "snippet_words",
+    "snippet_fragments",
+    "snippet_delimiter",
+    "collapse",
+    "with_total",
+    "cursor",
+    "fields",
+    "q",
+    "preset",
+];
+
+// `g1.type` -> ("g1", false, "type") and `g1.or.weather!` -> ("g1", true, "weather!"): a filter in a
+// numbered query group, see generate_groups
+pub(crate) fn split_group_key(k: &str) -> Option<(&str, bool, &str)> {
+    let (group, rest) = k.split_once('.')?;
+    let number = group.strip_prefix('g')?;
+    if number.is_empty() || !number.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    match rest.strip_prefix("or.") {
+        Some(key) => Some((group, true, key)),
+        None => Some((group, false, rest)),
+    }
+}
+
+const MAX_KEY_LENGTH: usize = 128;
+const MAX_KEY_DEPTH: usize = 8;
+
+// query keys get spliced into jsonpath accessors, so only plain identifier segments make it through:
+// `player.id_v2`, optionally with the trailing `!` for negation
+fn validate_key(k: &str) -> Result<(), CompassError> {
+    let path = k.strip_suffix('!').unwrap_or(k);
+
+    let valid = k.len() <= MAX_KEY_LENGTH
+        && path.split('.').count() <= MAX_KEY_DEPTH
+        && path.split('.').all(|segment| {
+            segment
+                .chars()
+                .next()
+                .map_or(false, |c| c.is_ascii_alphabetic() || c == '_')
+                && segment
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_')
+        });
+
+    if valid {
+        Ok(())
+    } else {
+        Err(CompassError::InvalidKey(k.to_owned()))
+    }
+}
+
+// `lang: auto` fields use each document's detected language (see detect_language) instead of a fixed one
+pub(crate) fn ts_config(lang: &str) -> String {
+    if lang == AUTO_LANGUAGE {
+        LANGUAGE_COLUMN.to_owned()
+    } else {
+        format!("'{}'", lang)
+    }
+}
+
+// the sortby=relevance expression: the fulltext parameters' weighted ts_ranks, added up. two knobs:
+// rank_normalization is ts_rank's normalization bitmask (e.g. 1 to go easier on long documents), and
+// recency_half_life=7d halves a document's score for every 7 days its recency_field (the default sort
+// field if left out) lies in the past. documents without that field score 0
+fn relevance(
+    schema: &Schema,
+    fields: &HashMap<String, String>,
+    ranked: Vec<(String, FieldQuery, &String)>,
+    bindings: &mut Vec<Binding>,
+    bind_index: usize,
+) -> Result<String, CompassError> {
+    let normalization = match fields.get("rank_normalization") {
+        Some(n) => match n.parse::<u8>().map_err(CompassError::InvalidNumberError)? {
+            n if n < 64 => n,
+            n => {
+                return Err(CompassError::ConversionError(format!(
+                    "rank_normalization {} isn't a combination of 0, 1, 2, 4, 8, 16 and 32",
+                    n
+                )))
+            }
+        },
+        None => 0,
+    };
+
+    let mut ranks = Vec::new();
+    for (name, query, v) in ranked {
+        if let FieldQuery::Fulltext {
+            ref lang,
+            syntax,
+            ref target,
+            ref synonyms,
+            weight,
+            ..
+        } = query
+        {
+            bindings.push(Binding::Text(v.to_string()));
+            let query = format!(
+                "{function}({lang},${parameter})",
+                function = syntax,
+                lang = ts_config(lang),
+                parameter = bind_index + bindings.len() - 1
+            );
+            ranks.push(format!(
+                "ts_rank(setweight(to_tsvector({lang},coalesce(object->>'{key}','')),'{weight:?}'),{query},{normalization})",
+                lang = ts_config(lang),
+                key = target.as_ref().unwrap_or(&name),
+                weight = weight,
+                query = with_synonyms(query, lang, synonyms, v),
+                normalization = normalization
+            ));
+        }
+    }
+    let rank = format!("({})", ranks.join(" + "));
+
+    let half_life = match fields.get("recency_half_life") {
+        Some(h) => parse_bucket(h).map_err(|_| {
+            CompassError::ConversionError(format!(
+                "recency_half_life '{}' should look like 12h or 7d",
+                h
+            ))
+        })?,
+        None => return Ok(rank),
+    };
+
+    let path = match fields.get("recency_field") {
+        Some(name) => field_path(schema, name).map_err(CompassError::ConversionError)?,
+        None => match schema.default_sort() {
+            SortKey::Path(path) => path,
+            _ => {
+                return Err(CompassError::ConversionError(
+                    "recency_half_life needs a recency_field".to_owned(),
+                ))
+            }
+        },
+    };
+    let seconds = match schema.fields.get(&path[0]).and_then(|f| f.converter) {
+        Some(conv) if path.len() == 1 && matches!(conv.to, ConvertTo::TimestampMillis) => 1000,
+        _ => 1,
+    };
+
+    bindings.push(Binding::TextArray(path));
+    let time = numeric_expr(&format!("(object #> ${})", bind_index + bindings.len() - 1));
+
+    Ok(format!(
+        "({rank} * coalesce(power(0.5, greatest(0, extract(epoch from now()) - {time} / {seconds}) / {half_life}), 0))",
+        rank = rank,
+        time = time,
+        seconds = seconds,
+        half_life = half_life
+    ))
+}
+
+// rewrites each synonym the query uses into an OR of its whole set. this works on the parsed tsquery,
+// so it doesn't matter which syntax the query came in; only words actually in `v` get a rewrite
+fn with_synonyms(query: String, lang: &str, synonyms: &[Vec<String>], v: &str) -> String {
+    let words: Vec<String> = v
+        .split(|c: char| !c.is_alphanumeric())
+        .map(str::to_lowercase)
+        .collect();
+
+    let lang = ts_config(lang);
+    let mut query = query;
+    for set in synonyms.iter() {
+        let alternatives = set.join(" | ");
+        for word in set.iter().filter(|w| words.contains(&w.to_lowercase())) {
+            query = format!(
+                "ts_rewrite({query}, to_tsquery({lang},'{word}'), to_tsquery({lang},'{alternatives}'))",
+                query = query,
+                lang = lang,
+                word = word,
+                alternatives = alternatives
+            );
+        }
+    }
+
+    query
+}
+
+// splits a value the same way parse_query_list does: terms, and the and/or operators between them
+pub(crate) fn split_terms(q: &str) -> (Vec<String>, Vec<&'static str>) {
+    let mut terms = Vec::new();
+    let mut ops = Vec::new();
+    let mut curr = String::new();
+
+    for val in q.split_inclusive('_') {
+        if val == "and_" || val == "or_" {
+            terms.push(curr.strip_suffix('_').unwrap_or(&curr).to_owned());
+            ops.push(if val == "and_" { "and" } else { "or" });
+            curr = String::new();
+        } else {
+            curr += val;
+        }
+    }
+
+    if !curr.is_empty() {
+        terms.push(curr);
+    }
+
+    (terms, ops)
+}
+
+fn check_nested<'a, I>(schema: &Schema, fields: I) -> Result<(), CompassError>
+where
+    I: Iterator<Item = &'a (String, FieldQuery)>,
+{
+    let mut paths: Vec<&str> = Vec::new();
+
+    for (path, query) in fields {
+        let query = match query {
+            FieldQuery::Not(inner) => inner,
+            q => q,
+        };
+        if let FieldQuery::Nested = query {
+            if !path.contains('.') {
+                continue;
+            }
+
+            let depth = path.split('.').count();
+            if depth > schema.nested.max_depth {
+                return Err(CompassError::NestedPathTooDeep {
+                    path: path.clone(),
+                    depth,
+                    max: schema.nested.max_depth,
+                });
+            }
+
+            if !paths.contains(&path.as_str()) {
+                paths.push(path);
+            }
+        }
+    }
+
+    if paths.len() > schema.nested.max_fields {
+        return Err(CompassError::TooManyNestedFields {
+            count: paths.len(),
+            max: schema.nested.max_fields,
+        });
+    }
+
+    Ok(())
+}
+
+fn check_terms(
+    schema: &Schema,
+    k: &str,
+    v: &str,
+    total_terms: &mut usize,
+) -> Result<(), CompassError> {
+    let terms = count_terms(v);
+    if terms > schema.limits.max_terms {
+        return Err(CompassError::QueryTooComplex(format!(
+            "'{}' has {} terms, the limit is {}",
+            k, terms, schema.limits.max_terms
+        )));
+    }
+
+    *total_terms += terms;
+    if *total_terms > schema.limits.max_total_terms {
+        return Err(CompassError::QueryTooComplex(format!(
+            "query has more than {} terms in total",
+            schema.limits.max_total_terms
+        )));
+    }
+    Ok(())
+}
+
+// how many filters parse_query_list would generate for this value
+fn count_terms(q: &str) -> usize {
+    1 + q
+        .split_inclusive('_')
+        .filter(|val| *val == "and_" || *val == "or_")
+        .count()
+}
+
+// deepest parenthesis nesting in a generated jsonpath, not counting anything inside string literals
+fn jsonpath_depth(q: &str) -> usize {
+    let mut depth = 0;
+    let mut max_depth = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+
+    for c in q.chars() {
+        if in_string {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '"' {
+                in_string = false;
+            }
+            continue;
+        }
+
+        match c {
+            '"' => in_string = true,
+            '(' => {
+                depth += 1;
+                max_depth = max_depth.max(depth);
+            }
+            ')' => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+
+    max_depth
+}
+
+fn is_fulltext(query: &FieldQuery) -> bool {
+    match query {
+        FieldQuery::Fulltext { .. } => true,
+        FieldQuery::Not(inner) => is_fulltext(inner),
+        _ => false,
+    }
+}
+
+// integer filter values. i128 covers u64 ids and anything else that doesn't fit an i64;
+// jsonpath numeric literals are arbitrary precision so nothing gets truncated on the postgres side
+fn parse_number(x: &str) -> Result<i128, CompassError> {
+    x.parse::<i128>().map_err(|e| match e.kind() {
+        IntErrorKind::PosOverflow | IntErrorKind::NegOverflow => {
+            CompassError::NumberOutOfRange(x.to_owned())
+        }
+        _ => CompassError::InvalidNumberError(e),
+    })
+}
+
+// to_tsquery throws a syntax error (a 500 by the time it reaches the client) on input like `cats &` or `!`,
+// so walk the query first and point at the token that doesn't fit
+fn check_tsquery(q: &str) -> Result<(), CompassError> {
+    let chars: Vec<char> = q.chars().collect();
+    let mut i = 0;
+    let mut depth = 0;
+    let mut expect_operand = true;
+
+    let bad = |token: String| Err(CompassError::InvalidFulltextQuery(token));
+
+    while i < chars.len() {
+        let c = chars[i];
+
+        if c.is_whitespace() {
+            i += 1;
+            continue;
+        }
+
+        // phrase operators: <-> and <N>
+        if c == '<' {
+            let end = match chars[i..].iter().position(|&c| c == '>') {
+                Some(end) => i + end,
+                None => return bad(chars[i..].iter().collect()),
+            };
+            let token: String = chars[i..=end].iter().collect();
+            let inner = &token[1..token.len() - 1];
+            if expect_operand || !(inner == "-" || inner.parse::<u16>().is_ok()) {
+                return bad(token);
+            }
+            expect_operand = true;
+            i = end + 1;
+            continue;
+        }
+
+        if expect_operand {
+            match c {
+                '!' => i += 1,
+                '(' => {
+                    depth += 1;
+                    i += 1;
+                }
+                '\'' => {
+                    // quoted operand, '' is an escaped quote
+                    let mut j = i + 1;
+                    loop {
+                        match chars.get(j) {
+                            Some('\'') if chars.get(j + 1) == Some(&'\'') => j += 2,
+                            Some('\'') => break,
+                            Some(_) => j += 1,
+                            None => return bad(chars[i..].iter().collect()),
+                        }
+                    }
+                    i = j + 1;
+                    expect_operand = false;
+                }
+                c if is_tsquery_word_char(c) => {
+                    while i < chars.len() && is_tsquery_word_char(chars[i]) {
+                        i += 1;
+                    }
+                    expect_operand = false;
+                }
+                c => return bad(c.to_string()),
+            }
+
+            // weight / prefix suffix like foo:* or foo:AB
+            if !expect_operand && chars.get(i) == Some(&':') {
+                let start = i;
+                i += 1;
+                while i < chars.len() && "*ABCDabcd".contains(chars[i]) {
+                    i += 1;
+                }
+                if i == start + 1 {
+                    return bad(chars[start..].iter().collect());
+                }
+            }
+        } else {
+            match c {
+                '&' | '|' => {
+                    expect_operand = true;
+                    i += 1;
+                }
+                ')' if depth > 0 => {
+                    depth -= 1;
+                    i += 1;
+                }
+                _ => {
+                    let token: String = chars[i..]
+                        .iter()
+                        .take_while(|c| !c.is_whitespace())
+                        .collect();
+                    return bad(token);
+                }
+            }
+        }
+    }
+
+    if expect_operand {
+        bad("end of query (expected a search term)".to_owned())
+    } else if depth > 0 {
+        bad("end of query (unclosed parenthesis)".to_owned())
+    } else {
+        Ok(())
+    }
+}
+
+fn is_tsquery_word_char(c: char) -> bool {
+    !c.is_whitespace() && !"()&|!<>:'".contains(c)
+}
+
+fn normalized(x: &str, normalize: Option<Normalization>) -> String {
+    match normalize {
+        Some(n) => n.apply(x),
+        None => x.to_owned(),
+    }
+}
+
+// nested keys like `metadata.player` take their options from the top-level field
+fn field_normalization(schema: &Schema, key: &str) -> Option<Normalization> {
+    let key = key.trim_end_matches('!');
+    schema
+        .fields
+        .get(key)
+        .or_else(|| key.split('.').next().and_then(|k| schema.fields.get(k)))
+        .and_then(|f| f.normalize)
+}
+
 fn parse_query_list<F>(q: &str, filter_gen: F) -> Result<String, CompassError>
 where
     F: Fn(&str) -> Result<String, CompassError>,
@@ -53,9 +556,10 @@ where
 pub fn generate_one_field(
     v: &str,
     field: (&String, FieldQuery),
+    normalize: Option<Normalization>,
     jsonb_filters: &mut Vec<String>,
     other_filters: &mut Vec<String>,
-    other_bindings: &mut Vec<String>,
+    other_bindings: &mut Vec<Binding>,
     bind_index: usize,
 ) -> Result<(), CompassError> {
     match field.1 {
@@ -74,9 +578,9 @@ pub fn generate_one_field(
                     Ok(format!("($.{} == {})", field.0, n))
                 } else {
                     Ok(format!(
-                        "($.{} == {})",
-                        field.0,
-                        x.parse::<i64>().map_err(CompassError::InvalidNumberError)?
+                        "(($.{field} == {value}) || ($.{field} == \"{value}\"))",
+                        field = field.0,
+                        value = parse_number(x)?
                     ))
                 }
             })?;
@@ -85,9 +589,9 @@ pub fn generate_one_field(
         FieldQuery::Min => {
             let filters = parse_query_list(v, |x| {
                 Ok(format!(
-                    "($.{} > {})",
-                    field.0,
-                    x.parse::<i64>().map_err(CompassError::InvalidNumberError)?
+                    "(($.{field} > {value}) || ($.{field}.type() == \"string\" && $.{field}.double() > {value}))",
+                    field = field.0,
+                    value = parse_number(x)?
                 ))
             })?;
             jsonb_filters.push(filters);
@@ -95,9 +599,9 @@ pub fn generate_one_field(
         FieldQuery::Max => {
             let filters = parse_query_list(v, |x| {
                 Ok(format!(
-                    "($.{} < {})",
-                    field.0,
-                    x.parse::<i64>().map_err(CompassError::InvalidNumberError)?
+                    "(($.{field} < {value}) || ($.{field}.type() == \"string\" && $.{field}.double() < {value}))",
+                    field = field.0,
+                    value = parse_number(x)?
                 ))
             })?;
             jsonb_filters.push(filters);
@@ -122,7 +626,7 @@ pub fn generate_one_field(
             let filters = parse_query_list(v, |x| {
                 let mut filter: Vec<String> = Vec::new();
 
-                if let Ok(n) = x.parse::<i64>() {
+                if let Ok(n) = x.parse::<i128>() {
                     filter.push(format!("($.{} == {})", field.0, n)); // if it looks like an int, make it an int! because we can't specificy all the metadata fields in the schema. yeah i don't like this either
                 } else if let Ok(n) = x.parse::<bool>() {
                     filter.push(format!("($.{} == {})", field.0, n));
@@ -132,7 +636,11 @@ pub fn generate_one_field(
                     filter.push(format!("(!exists($.{}))", field.0))
                 }
 
-                filter.push(format!("($.{} == \"{}\")", field.0, x));
+                filter.push(format!(
+                    "($.{} == {})",
+                    field.0,
+                    jsonpath_string(&normalized(x, normalize))
+                ));
 
                 Ok(format!("({})", filter.join(" || ")))
             })?;
@@ -154,21 +662,27 @@ pub fn generate_one_field(
                     Ok(format!(
                         "(($.{field} == {value}) || ($.{field} == \"{value}\"))",
                         field = field.0,
-                        value = x.parse::<i64>().map_err(CompassError::InvalidNumberError)?
+                        value = parse_number(x)?
                     ))
                 }
             })?;
             jsonb_filters.push(filters);
         }
         FieldQuery::StringTag => {
-            let filters = parse_query_list(v, |x| Ok(format!("($.{} == \"{}\")", field.0, x)))?;
+            let filters = parse_query_list(v, |x| {
+                Ok(format!(
+                    "($.{} == {})",
+                    field.0,
+                    jsonpath_string(&normalized(x, normalize))
+                ))
+            })?;
             jsonb_filters.push(filters);
         }
         FieldQuery::Nested => {
             let filters = parse_query_list(v, |x| {
                 let mut filter: Vec<String> = Vec::new();
 
-                if let Ok(n) = x.parse::<i64>() {
+                if let Ok(n) = x.parse::<i128>() {
                     filter.push(format!("($.{} == {})", field.0, n)); // if it looks like an int, make it an int! because we can't specificy all the metadata fields in the schema. yeah i don't like this either
                 } else if let Ok(n) = x.parse::<bool>() {
                     filter.push(format!("($.{} == {})", field.0, n));
@@ -178,7 +692,11 @@ pub fn generate_one_field(
                     filter.push(format!("(!exists($.{}))", field.0))
                 }
 
-                filter.push(format!("($.{} == \"{}\")", field.0, x));
+                filter.push(format!(
+                    "($.{} == {})",
+                    field.0,
+                    jsonpath_string(&normalized(x, normalize))
+                ));
 
                 Ok(format!("({})", filter.join(" || ")))
             })?;
@@ -188,124 +706,245 @@ pub fn generate_one_field(
             ref lang,
             ref syntax,
             ref target,
+            ref synonyms,
+            ..
         } => {
+            if let FulltextSyntax::TsQuery = syntax {
+                check_tsquery(v)?;
+            }
+
+            let query = format!(
+                "{function}({lang},${parameter})",
+                lang = ts_config(lang),
+                function = syntax,
+                parameter = other_bindings.len() + bind_index
+            );
+
             other_filters.push(format!(
-                "to_tsvector('{lang}',object->>'{key}') @@ {function}('{lang}',${parameter})",
-                lang = lang,
+                "to_tsvector({lang},object->>'{key}') @@ {query}",
+                lang = ts_config(lang),
                 key = target.as_ref().unwrap_or(field.0),
-                function = syntax,
-                parameter = other_filters.len() + bind_index
+                query = with_synonyms(query, lang, synonyms, v)
             ));
-            other_bindings.push(v.to_string());
+            other_bindings.push(Binding::Text(v.to_string()));
         }
+        // ordering, not filtering; generate_where takes care of it
+        FieldQuery::Vector { .. } => {}
         FieldQuery::Not(inner) => {
             // i hate myself
             let mut not_jsonb_filters = Vec::new();
-            let mut not_other_bindings = Vec::new();
             let mut not_other_filters = Vec::new();
             generate_one_field(
                 v,
                 (field.0, *inner),
+                normalize,
                 &mut not_jsonb_filters,
-                &mut not_other_bindings,
                 &mut not_other_filters,
+                other_bindings,
                 bind_index,
             )?;
 
             jsonb_filters.extend(not_jsonb_filters.into_iter().map(|v| format!("!({})", v)));
+            // a document without the field doesn't mention anything, so it's kept, the same as a
+            // negated jsonpath filter keeps it
+            other_filters.extend(
+                not_other_filters
+                    .into_iter()
+                    .map(|f| format!("NOT coalesce({}, false)", f)),
+            );
         }
     };
     Ok(())
 }
 
-pub fn generate_where(
+// a date-converted field's value as it's stored: epoch seconds or millis
+fn stored_epoch(conv: &ConverterSchema, x: &str) -> Option<i128> {
+    let dt = conv.parse_datetime(x)?;
+    match conv.to {
+        ConvertTo::Timestamp => Some(dt.timestamp() as i128),
+        ConvertTo::TimestampMillis => Some(dt.timestamp_millis() as i128),
+        ConvertTo::TagArray => None,
+    }
+}
+
+// the stored values a date given as a filter covers, down to the precision it was written in:
+// 2021-07-01 is that whole day (in the converter's timezone), 2021-07-01T03:00:00Z that whole second,
+// and anything with fractions of a second just that instant
+fn stored_span(conv: &ConverterSchema, x: &str) -> Option<(i128, i128)> {
+    let start = stored_epoch(conv, x)?;
+    let end = if let Ok(day) = NaiveDate::parse_from_str(x, "%Y-%m-%d") {
+        let next = day.succ_opt()?.format("%Y-%m-%d").to_string();
+        stored_epoch(conv, &next)? - 1
+    } else if x.contains('.') {
+        start
+    } else {
+        match conv.to {
+            ConvertTo::TimestampMillis => start + 999,
+            _ => start,
+        }
+    };
+    Some((start, end))
+}
+
+// a span of stored values as millis. a number in a filter on a mixed_units field is read the same way
+// stored ones are, by size; a whole second covers its 1000 millis
+fn millis_span(conv: &ConverterSchema, start: i128, end: i128, by_size: bool) -> (i128, i128) {
+    let seconds = if by_size {
+        start < MIXED_UNITS_THRESHOLD as i128
+    } else {
+        matches!(conv.to, ConvertTo::Timestamp)
+    };
+    if seconds {
+        (start * 1000, end * 1000 + 999)
+    } else {
+        (start, end)
+    }
+}
+
+// the filter for a mixed_units field, over a span of millis: compared as it is with values stored as
+// millis, and rounded to whole seconds for values stored as seconds
+fn mixed_units_filter(name: &str, query: &FieldQuery, (start, end): (i128, i128)) -> String {
+    let ceil = |ms: i128| -(-ms).div_euclid(1000);
+    let (millis, seconds) = match query {
+        FieldQuery::Min => (
+            format!("$.{} > {}", name, start),
+            format!("$.{} > {}", name, start.div_euclid(1000)),
+        ),
+        FieldQuery::Max => (
+            format!("$.{} < {}", name, start),
+            format!("$.{} < {}", name, ceil(start)),
+        ),
+        _ => (
+            format!("$.{f} >= {a} && $.{f} <= {b}", f = name, a = start, b = end),
+            format!(
+                "$.{f} >= {a} && $.{f} <= {b}",
+                f = name,
+                a = ceil(start),
+                b = end.div_euclid(1000)
+            ),
+        ),
+    };
+    format!(
+        "(($.{f} >= {t} && {millis}) || ($.{f} < {t} && {seconds}))",
+        f = name,
+        t = MIXED_UNITS_THRESHOLD,
+        millis = millis,
+        seconds = seconds
+    )
+}
+
+// filters on a field whose converter stores dates as epoch numbers, so queries can use dates the same
+// way documents were ingested with them: `created=2021-07-01T03:00:00Z`, `created_min=2021-07-01`.
+// numbers are still taken as stored values. None when the field isn't one of those, or the query
+// isn't a match or a bound
+fn date_filter(
     schema: &Schema,
-    fields: &HashMap<String, String>,
-    bind_index: usize,
-    force_json_query: bool,
-) -> Result<(String, String, String, Vec<String>), CompassError> {
-    let mut jsonb_filters = Vec::<String>::new();
-    let mut other_filters = Vec::<String>::new();
+    name: &str,
+    query: &FieldQuery,
+    v: &str,
+) -> Result<Option<String>, CompassError> {
+    let conv = match schema.fields.get(name).and_then(|f| f.converter) {
+        Some(conv)
+            if matches!(
+                conv.from,
+                ConvertFrom::DateTimeString | ConvertFrom::DateString
+            ) && !matches!(conv.to, ConvertTo::TagArray) =>
+        {
+            conv
+        }
+        _ => return Ok(None),
+    };
 
-    let mut other_bindings = Vec::<String>::new();
-
-    for (k, v) in fields {
-        let field_maybe = match schema.fields.get(k) {
-            // find field from URL query in schema
-            Some(field) => {
-                Some((k.clone(), field.query.clone())) // oh, we found it by name. cool, return that
-            }
-            None => {
-                let find_nested = |k: &str| {
-                    schema.fields.iter().find_map(|f| {
-                        match f.1.query {
-                            // oops we couldn't find it; let's see if it's a field that can have multiple names like range or metadata
-                            FieldQuery::Range {
-                                ref min, ref max, ..
-                            } => {
-                                if k == min {
-                                    Some((f.0.to_owned(), FieldQuery::Min))
-                                } else if k == max {
-                                    Some((f.0.to_owned(), FieldQuery::Max))
-                                } else {
-                                    None
-                                }
-                            }
-                            FieldQuery::Nested => {
-                                if k.split('.').next().unwrap() == f.0 {
-                                    Some((k.to_owned(), FieldQuery::Nested))
-                                } else {
-                                    None
-                                }
-                            }
-                            _ => None,
-                        }
-                    })
-                };
+    let filter = |x: &str| -> Result<String, CompassError> {
+        if x == "exists" {
+            return Ok(format!("(exists($.{}))", name));
+        } else if x == "notexists" {
+            return Ok(format!("(!exists($.{}))", name));
+        }
 
-                if let Some(f) = k.strip_suffix('!') {
-                    println!("{}", k);
-                    // THE GOOD CODE DETECTED (JK IT'S VERY BAD THIS IS THE WORST THING I'VE EVER WRITTEN AND I'M DYING INSIDE)
-                    schema
-                        .fields
-                        .get(f)
-                        .map(|field| (k.clone(), FieldQuery::Not(Box::new(field.query.clone()))))
-                        .or(find_nested(f).map(|(a, b)| (a, FieldQuery::Not(Box::new(b)))))
-                } else {
-                    find_nested(k)
-                }
+        let (start, end) = match x.parse::<i128>() {
+            Ok(n) if conv.mixed_units => {
+                return Ok(mixed_units_filter(
+                    name,
+                    query,
+                    millis_span(&conv, n, n, true),
+                ))
             }
+            Ok(n) => (n, n),
+            Err(_) => stored_span(&conv, x).ok_or_else(|| {
+                CompassError::ConversionError(format!("can't parse '{}' as a date", x))
+            })?,
         };
+        if conv.mixed_units {
+            return Ok(mixed_units_filter(
+                name,
+                query,
+                millis_span(&conv, start, end, false),
+            ));
+        }
+        Ok(match query {
+            FieldQuery::Min => format!("($.{} > {})", name, start),
+            FieldQuery::Max => format!("($.{} < {})", name, start),
+            _ if start == end => format!("($.{} == {})", name, start),
+            _ => format!(
+                "(($.{field} >= {start}) && ($.{field} <= {end}))",
+                field = name,
+                start = start,
+                end = end
+            ),
+        })
+    };
 
-        if let Some(field) = field_maybe {
-            generate_one_field(
-                v,
-                (&field.0, field.1),
-                &mut jsonb_filters,
-                &mut other_filters,
-                &mut other_bindings,
-                bind_index,
-            )?;
+    match query {
+        FieldQuery::Not(inner) => {
+            Ok(date_filter(schema, name, inner, v)?.map(|f| format!("!({})", f)))
         }
+        FieldQuery::Range { .. }
+        | FieldQuery::NumericTag { .. }
+        | FieldQuery::AmbiguousTag
+        | FieldQuery::Min
+        | FieldQuery::Max => Ok(Some(parse_query_list(v, filter)?)),
+        _ => Ok(None),
     }
+}
 
-    let json_query = format!("({})", jsonb_filters.join(" && "));
+pub(crate) fn page_bounds(
+    schema: &Schema,
+    fields: &HashMap<String, String>,
+) -> Result<(i64, i64), CompassError> {
+    let limit = match fields.get("limit") {
+        Some(l) => l.parse::<i64>().map_err(CompassError::InvalidNumberError)?,
+        // `k` reads better next to a vector query, but it's the same thing
+        None => match fields.get("k") {
+            Some(k) => k.parse::<i64>().map_err(CompassError::InvalidNumberError)?,
+            None => schema.limits.default_limit,
+        },
+    };
 
-    // build out full query
-    let query = if (!jsonb_filters.is_empty() || force_json_query) && other_filters.is_empty() {
-        "WHERE object @@ CAST($1 AS JSONPATH)".to_owned()
-    } else if (!jsonb_filters.is_empty() || force_json_query) && !other_filters.is_empty() {
-        format!(
-            "WHERE object @@ CAST($1 AS JSONPATH) AND {}",
-            other_filters.join(" AND ")
-        )
-    } else if !other_filters.is_empty() {
-        format!("WHERE {}", other_filters.join(" AND "))
-    } else {
-        String::new()
+    let offset = match fields.get("offset") {
+        Some(l) => l.parse::<i64>().map_err(CompassError::InvalidNumberError)?,
+        None => 0,
     };
 
-    let order = match fields.get("sortorder") {
+    if limit < 0 || limit > schema.limits.max_limit {
+        return Err(CompassError::LimitOutOfRange {
+            value: limit,
+            max: schema.limits.max_limit,
+        });
+    }
+
+    if offset < 0 || offset > schema.limits.max_offset {
+        return Err(CompassError::OffsetOutOfRange {
+            value: offset,
+            max: schema.limits.max_offset,
+        });
+    }
+
+    Ok((limit, offset))
+}
+
+pub(crate) fn sort_order(fields: &HashMap<String, String>) -> String {
+    match fields.get("sortorder") {
         Some(l) => {
             let ord = l.as_str().to_uppercase();
             if ord == "ASC" || ord == "DESC" {
@@ -315,179 +954,2422 @@ pub fn generate_where(
             }
         }
         None => "DESC".to_owned(),
-    };
+    }
+}
 
-    let order_string = format!(
-        " ORDER BY (object #> ($2)::text[]) {}, doc_id NULLS LAST LIMIT $3 OFFSET $4",
-        order
-    );
+// where documents without the sort field go: `nulls=first|last`, else the schema's `nulls`, else first
+// when descending and last when ascending, which is what postgres does by itself
+pub(crate) fn sort_nulls(schema: &Schema, fields: &HashMap<String, String>) -> Nulls {
+    match fields.get("nulls").map(|n| n.to_lowercase()).as_deref() {
+        Some("first") => Nulls::First,
+        Some("last") => Nulls::Last,
+        _ => match schema.nulls {
+            Some(nulls) => nulls,
+            None if sort_order(fields) == "DESC" => Nulls::First,
+            None => Nulls::Last,
+        },
+    }
+}
 
-    Ok((query, order_string, json_query, other_bindings))
+// the schema's secondary_sort, for sorts on some other field
+pub(crate) fn secondary_sort(schema: &Schema, sorted: &[String]) -> Option<Vec<String>> {
+    match schema.resolve_sort(schema.secondary_sort.as_deref()?) {
+        Ok(SortKey::Path(path)) if path != sorted => Some(path),
+        _ => None,
+    }
 }
 
-pub fn json_search(
-    client: &mut Client,
+// the collation a field sort compares its values as text in: `collation=name`, else the field's
+// `collation`. `collation=none` (or empty) keeps jsonb order, which for strings is byte order
+pub(crate) fn sort_collation(
     schema: &Schema,
     fields: &HashMap<String, String>,
-    raw_query: Option<String>,
-) -> Result<Vec<Value>, CompassError> {
-    let converters: HashMap<String, ConverterSchema> = schema
-        .fields
-        .iter()
-        .filter_map(|(k, v)| {
-            v.converter.map(|converter| (k.to_owned(), converter))
-        })
-        .collect();
-
-    let (query, sort_string, json_query, other_bindings) =
-        generate_where(schema, fields, 5, raw_query.is_some())?;
+    sorted: &[String],
+) -> Result<Option<String>, CompassError> {
+    match fields.get("collation").map(|c| c.trim()) {
+        Some("") | Some("none") => Ok(None),
+        Some(name) if is_collation_name(name) => Ok(Some(name.to_owned())),
+        Some(name) => Err(CompassError::InvalidCollation(format!(
+            "'{}' isn't a collation name",
+            name
+        ))),
+        None => Ok(schema
+            .fields
+            .get(&sorted.join("."))
+            .and_then(|field| field.collation.clone())),
+    }
+}
 
-    let json_query = if let Some(q) = raw_query {
-        q
-    } else {
-        json_query
-    };
+// the expression a field sort orders by, $2 being the field's path. collated sorts compare the value's
+// text, so numbers in a collated field sort as strings
+pub(crate) fn sort_expression(collation: Option<&str>) -> String {
+    match collation {
+        Some(collation) => format!("((object #>> $2) COLLATE \"{}\")", collation),
+        None => "(object #> $2)".to_owned(),
+    }
+}
 
-    let query = format!(
-        "SELECT object FROM {} {} {}",
-        schema.table, query, sort_string
-    );
+// the ORDER BY for a field sort: the field, then the secondary sort (`secondary` is its path's
+// placeholder) with documents missing it last, then doc_id so ties always come out the same way
+fn path_order_by(
+    order: &str,
+    nulls: Nulls,
+    secondary: Option<&str>,
+    collation: Option<&str>,
+) -> String {
+    let sorted = sort_expression(collation);
+    match secondary {
+        Some(secondary) => format!(
+            "{sorted} {order} {nulls}, (object #> {secondary}) {order} NULLS LAST, doc_id",
+            sorted = sorted,
+            order = order,
+            nulls = nulls.sql(),
+            secondary = secondary
+        ),
+        None => format!("{} {} {}, doc_id", sorted, order, nulls.sql()),
+    }
+}
+
+pub(crate) fn sort_key(
+    schema: &Schema,
+    fields: &HashMap<String, String>,
+) -> Result<SortKey, CompassError> {
+    match fields.get("sortby") {
+        Some(name) => schema.resolve_sort(name),
+        None => Ok(schema.default_sort()),
+    }
+}
+
+// turns a stored converter value back into what the document originally held. values that aren't
+// the stored representation (already strings, out of range, ...) are left alone
+pub(crate) fn convert_field(conv: &ConverterSchema, field: &mut Value) {
+    let stored = match field.as_i64() {
+        Some(n) => n,
+        None => return,
+    };
+
+    let dt = match conv.to {
+        ConvertTo::TagArray => None,
+        _ => Utc
+            .timestamp_millis_opt(conv.stored_millis(stored))
+            .single(),
+    };
+    let dt = match dt {
+        Some(dt) => dt,
+        None => return,
+    };
+
+    *field = match (conv.from, conv.timezone) {
+        (ConvertFrom::DateTimeString, None) => {
+            json!(dt.to_rfc3339_opts(SecondsFormat::Millis, true))
+        }
+        (ConvertFrom::DateTimeString, Some(tz)) => json!(dt
+            .with_timezone(&tz)
+            .to_rfc3339_opts(SecondsFormat::Millis, false)),
+        (ConvertFrom::DateString, None) => json!(dt.format("%Y-%m-%d").to_string()),
+        (ConvertFrom::DateString, Some(tz)) => {
+            json!(dt.with_timezone(&tz).format("%Y-%m-%d").to_string())
+        }
+        _ => return,
+    };
+}
+
+// converters, lookups and result transformers for one result document. every path returning documents goes through here
+pub(crate) fn convert_document(schema: &Schema, doc: &mut Value) {
+    for (key, conv) in schema.converter_plan() {
+        if let Some(value) = doc.get_mut(key) {
+            convert_field(conv, value);
+        }
+    }
+    for (output, lookup) in schema.lookups.iter() {
+        lookup.apply(output, doc);
+    }
+    schema.hooks.transform(doc);
+}
+
+// how search_response reads documents out of its rows
+pub(crate) trait ResultDocument: Sized {
+    fn select(schema: &Schema, select: String) -> String;
+    fn from_row(schema: &Schema, row: &Row) -> Result<Self, CompassError>;
+    fn json_len(&self) -> usize; // bytes it'll take up in the response
+}
+
+impl ResultDocument for Value {
+    fn select(_: &Schema, select: String) -> String {
+        select
+    }
+
+    fn from_row(schema: &Schema, row: &Row) -> Result<Value, CompassError> {
+        let mut val = row.get::<usize, Value>(0);
+        convert_document(schema, &mut val);
+        Ok(val)
+    }
+
+    fn json_len(&self) -> usize {
+        json_len(self)
+    }
+}
+
+// the document's text goes into the response as it is, without building a Value for it. only for
+// schemas with nothing to convert or look up afterwards
+impl ResultDocument for Box<RawValue> {
+    fn select(_: &Schema, select: String) -> String {
+        format!("({})::text", select)
+    }
+
+    fn from_row(_: &Schema, row: &Row) -> Result<Box<RawValue>, CompassError> {
+        Ok(RawValue::from_string(row.get::<usize, String>(0))?)
+    }
+
+    fn json_len(&self) -> usize {
+        self.get().len()
+    }
+}
+
+// a document along with its sort key as it's stored, before converters turn it into something else
+// (timestamps into strings in the schema's timezone, say), so json_search_multi can merge on it
+pub(crate) struct SortedDocument {
+    key: Option<Value>,
+    doc: Value,
+}
+
+impl ResultDocument for SortedDocument {
+    // doc_id and relevance sorts bind an empty $2, which would select the whole document a second time
+    fn select(schema: &Schema, select: String) -> String {
+        format!(
+            "jsonb_build_array({}, CASE WHEN cardinality($2) > 0 THEN {}.object #> $2 END)",
+            select, schema.table
+        )
+    }
+
+    fn from_row(schema: &Schema, row: &Row) -> Result<SortedDocument, CompassError> {
+        let (mut doc, key): (Value, Option<Value>) = serde_json::from_value(row.get(0))?;
+        convert_document(schema, &mut doc);
+        Ok(SortedDocument { key, doc })
+    }
+
+    fn json_len(&self) -> usize {
+        json_len(&self.doc)
+    }
+}
+
+// what every schema table has to look like
+fn table_ddl(table: &str) -> String {
+    format!(
+        "CREATE TABLE IF NOT EXISTS {table} (doc_id UUID PRIMARY KEY, object JSONB NOT NULL)",
+        table = table
+    )
+}
+
+// the words in each did_you_mean field, for spelling suggestions; filled by refresh_terms
+fn terms_ddl(table: &str) -> String {
+    format!(
+        "CREATE TABLE IF NOT EXISTS {table}_terms (field TEXT NOT NULL, term TEXT NOT NULL, ndoc INT NOT NULL, PRIMARY KEY (field, term))",
+        table = table
+    )
+}
+
+// creates the schema's table (and the index jsonpath queries use) if it isn't there yet
+pub fn migrate(client: &mut Client, schema: &Schema) -> Result<(), CompassError> {
+    client.batch_execute(&format!(
+        "{ddl}; CREATE INDEX IF NOT EXISTS {table}_object_idx ON {table} USING GIN (object jsonb_path_ops);",
+        ddl = table_ddl(&schema.table),
+        table = schema.table
+    ))?;
+
+    provision_text_search(client, schema)?;
+    provision_tenancy(client, schema)?;
+    provision_rollups(client, schema)?;
+    provision_audit(client)?;
+    provision_idempotency(client)?;
+
+    for (name, field) in schema.fields.iter().filter(|(_, f)| f.suggest) {
+        client.batch_execute(&format!(
+            "CREATE INDEX IF NOT EXISTS {index}_suggest_idx ON {table} (lower(object #>> {path}) text_pattern_ops)",
+            index = format!("{}_{}", schema.table, name).replace('.', "_"),
+            table = schema.table,
+            path = path_literal(name)
+        ))?;
+    }
+
+    if !schema.spelling_fields().is_empty() {
+        client.batch_execute(&format!(
+            "CREATE EXTENSION IF NOT EXISTS pg_trgm; {ddl}; \
+             CREATE INDEX IF NOT EXISTS {index}_terms_trgm_idx ON {table}_terms USING GIN (term gin_trgm_ops);",
+            ddl = terms_ddl(&schema.table),
+            index = schema.table.replace('.', "_"),
+            table = schema.table
+        ))?;
+    }
+
+    if schema.detects_language() {
+        client.batch_execute(&format!(
+            "ALTER TABLE {} ADD COLUMN IF NOT EXISTS {} regconfig NOT NULL DEFAULT 'simple'",
+            schema.table, LANGUAGE_COLUMN
+        ))?;
+    }
+
+    let vectors = schema.vector_columns();
+    if !vectors.is_empty() {
+        client.batch_execute("CREATE EXTENSION IF NOT EXISTS vector")?;
+    }
+    for (_, column, dimensions) in vectors {
+        client.batch_execute(&format!(
+            "ALTER TABLE {table} ADD COLUMN IF NOT EXISTS {column} vector({dimensions})",
+            table = schema.table,
+            column = column,
+            dimensions = dimensions
+        ))?;
+    }
+
+    Ok(())
+}
+
+// creates (or updates) the schema's text search configurations. postgres has no IF NOT EXISTS for these,
+// so existing ones are looked up first; their mappings are redone every time, since dropping them would
+// drop the indexes built on them too
+pub fn provision_text_search(client: &mut Client, schema: &Schema) -> Result<(), CompassError> {
+    let mut transaction = client.transaction()?;
+
+    for (name, config) in schema.text_search.iter() {
+        let mut dictionaries = Vec::new();
+
+        if let Some(ref synonyms) = config.synonyms {
+            let dict = format!("{}_synonyms", name);
+            let options = vec![format!("SYNONYMS = {}", synonyms)];
+            ensure_dictionary(&mut transaction, &dict, "synonym", &options)?;
+            dictionaries.push(dict);
+        }
+
+        let dict = format!("{}_words", name);
+        let template = match config.language {
+            Some(_) => "snowball",
+            None => "simple",
+        };
+        let options: Vec<String> = config
+            .language
+            .iter()
+            .map(|l| format!("LANGUAGE = {}", l))
+            .chain(
+                config
+                    .stopwords
+                    .iter()
+                    .map(|s| format!("STOPWORDS = {}", s)),
+            )
+            .collect();
+        ensure_dictionary(&mut transaction, &dict, template, &options)?;
+        dictionaries.push(dict);
+
+        let exists = transaction
+            .query_opt("SELECT 1 FROM pg_ts_config WHERE cfgname = $1", &[name])?
+            .is_some();
+        if !exists {
+            transaction.batch_execute(&format!(
+                "CREATE TEXT SEARCH CONFIGURATION {} (COPY = {})",
+                name, config.copy
+            ))?;
+        }
+
+        transaction.batch_execute(&format!(
+            "ALTER TEXT SEARCH CONFIGURATION {} ALTER MAPPING FOR asciiword, word, asciihword, hword, hword_asciipart, hword_part WITH {}",
+            name,
+            dictionaries.join(", ")
+        ))?;
+    }
+
+    transaction.commit()?;
+    Ok(())
+}
+
+// the template of an existing dictionary can't be changed, only its options
+fn ensure_dictionary(
+    transaction: &mut postgres::Transaction,
+    name: &str,
+    template: &str,
+    options: &[String],
+) -> Result<(), CompassError> {
+    let exists = transaction
+        .query_opt("SELECT 1 FROM pg_ts_dict WHERE dictname = $1", &[&name])?
+        .is_some();
+
+    if !exists {
+        let mut all = vec![format!("TEMPLATE = {}", template)];
+        all.extend(options.iter().cloned());
+        transaction.batch_execute(&format!(
+            "CREATE TEXT SEARCH DICTIONARY {} ({})",
+            name,
+            all.join(", ")
+        ))?;
+    } else if !options.is_empty() {
+        transaction.batch_execute(&format!(
+            "ALTER TEXT SEARCH DICTIONARY {} ({})",
+            name,
+            options.join(", ")
+        ))?;
+    }
+
+    Ok(())
+}
+
+// base64 of little-endian f32s (standard or url-safe alphabet) -> pgvector's text form, `[1,0.5,...]`
+pub(crate) fn parse_vector(v: &str, dimensions: usize) -> Result<String, CompassError> {
+    let bytes = base64::decode(v)
+        .or_else(|_| base64::decode_config(v, base64::URL_SAFE))
+        .map_err(|_| CompassError::ConversionError(format!("'{}' isn't valid base64", v)))?;
+
+    if bytes.len() != dimensions * 4 {
+        return Err(CompassError::ConversionError(format!(
+            "vector has {} bytes, expected {} f32s ({} bytes)",
+            bytes.len(),
+            dimensions,
+            dimensions * 4
+        )));
+    }
+
+    let floats: Vec<String> = bytes
+        .chunks_exact(4)
+        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .map(|f| {
+            if f.is_finite() {
+                Ok(f.to_string())
+            } else {
+                Err(CompassError::ConversionError(
+                    "vector contains NaN or infinity".to_owned(),
+                ))
+            }
+        })
+        .collect::<Result<_, _>>()?;
+
+    Ok(format!("[{}]", floats.join(",")))
+}
+
+// anything the search functions can run queries on: a plain Client, or a ManagedClient that reconnects on its own
+pub trait Connection {
+    fn client(&mut self) -> Result<&mut Client, postgres::Error>;
+
+    fn prepare_typed(
+        &mut self,
+        query: &str,
+        types: &[PostgresType],
+    ) -> Result<Statement, postgres::Error>;
+
+    // sets the statement_timeout a schema's quota asks for, and says what it was before so
+    // restore_statement_timeout can put it back once the schema's queries are done
+    fn set_statement_timeout(&mut self, ms: u64) -> Result<String, postgres::Error> {
+        Ok(self
+            .client()?
+            .query_one(SWAP_STATEMENT_TIMEOUT, &[&ms.to_string()])?
+            .get(0))
+    }
+
+    fn restore_statement_timeout(&mut self, previous: &str) -> Result<(), postgres::Error> {
+        self.client()?
+            .query_one(RESTORE_STATEMENT_TIMEOUT, &[&previous])?;
+        Ok(())
+    }
+}
+
+// the connection's statement_timeout, swapped for a quota's in one round trip. OFFSET 0 keeps the
+// subquery from being folded into the outer query, so the old value is read before set_config runs
+pub(crate) const SWAP_STATEMENT_TIMEOUT: &str =
+    "SELECT previous, set_config('statement_timeout', $1, false) \
+     FROM (SELECT current_setting('statement_timeout') AS previous OFFSET 0) p";
+pub(crate) const RESTORE_STATEMENT_TIMEOUT: &str =
+    "SELECT set_config('statement_timeout', $1, false)";
+
+impl Connection for Client {
+    fn client(&mut self) -> Result<&mut Client, postgres::Error> {
+        Ok(self)
+    }
+
+    fn prepare_typed(
+        &mut self,
+        query: &str,
+        types: &[PostgresType],
+    ) -> Result<Statement, postgres::Error> {
+        Client::prepare_typed(self, query, types)
+    }
+}
+
+// a client for embedders that hold one connection for hours. when postgres or something in between
+// closes it, the next query reconnects (backing off between attempts) and statements are prepared again
+// on the new connection; prepared statements are cached per connection either way
+pub struct ManagedClient {
+    config: DatabaseConfig,
+    client: Client,
+    statements: HashMap<(String, Vec<PostgresType>), Statement>,
+    shapes: Option<Arc<StatementShapes>>,
+    pub warm_shapes: usize, // how many of the shared shapes to prepare on (re)connecting
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl ManagedClient {
+    pub fn connect(config: DatabaseConfig) -> Result<ManagedClient, CompassError> {
+        let client = config.connect()?;
+        Ok(ManagedClient {
+            config,
+            client,
+            statements: HashMap::new(),
+            shapes: None,
+            warm_shapes: 32,
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
+        })
+    }
+
+    // shares statement shapes with the other clients holding `shapes`, and prepares the most used ones
+    // on this connection now
+    pub fn with_shapes(mut self, shapes: Arc<StatementShapes>) -> ManagedClient {
+        self.shapes = Some(shapes);
+        self.warm();
+        self
+    }
+
+    fn warm(&mut self) {
+        let shapes = match self.shapes {
+            Some(ref shapes) => shapes.hottest(self.warm_shapes),
+            None => return,
+        };
+        for key in shapes {
+            // a shape that doesn't prepare anymore (say, its table was dropped) just waits for a request
+            if let Ok(statement) = self.client.prepare_typed(&key.0, &key.1) {
+                self.statements.insert(key, statement);
+            }
+        }
+    }
+
+    fn reconnect(&mut self) -> Result<(), postgres::Error> {
+        // the old connection's statements don't exist on the new one
+        self.statements.clear();
+
+        let mut backoff = self.initial_backoff;
+        let mut attempt = 1;
+        loop {
+            match self.config.connect_search() {
+                Ok(client) => {
+                    self.client = client;
+                    self.warm();
+                    return Ok(());
+                }
+                Err(e) if attempt < self.max_attempts => {
+                    eprintln!(
+                        "compass: reconnect attempt {} failed ({}), retrying in {:?}",
+                        attempt, e, backoff
+                    );
+                    thread::sleep(backoff);
+                    backoff = (backoff * 2).min(self.max_backoff);
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.client.is_closed()
+    }
+}
+
+impl Connection for ManagedClient {
+    fn client(&mut self) -> Result<&mut Client, postgres::Error> {
+        if self.client.is_closed() {
+            self.reconnect()?;
+        }
+        Ok(&mut self.client)
+    }
+
+    fn prepare_typed(
+        &mut self,
+        query: &str,
+        types: &[PostgresType],
+    ) -> Result<Statement, postgres::Error> {
+        if let Some(ref shapes) = self.shapes {
+            shapes.record(query, types);
+        }
+
+        let key = (query.to_owned(), types.to_vec());
+        if !self.client.is_closed() {
+            if let Some(statement) = self.statements.get(&key) {
+                return Ok(statement.clone());
+            }
+        }
+
+        // is_closed only notices once a query has failed, so a dead connection usually shows up here first
+        let prepared = self.client()?.prepare_typed(query, types);
+        let statement = match prepared {
+            Err(e) if e.is_closed() => {
+                self.reconnect()?;
+                self.client.prepare_typed(query, types)?
+            }
+            res => res?,
+        };
+
+        self.statements.insert(key, statement.clone());
+        Ok(statement)
+    }
+}
+
+// postgres only says `relation "x" does not exist`; point at what the table is supposed to be instead
+pub(crate) fn pg_error(schema: &Schema) -> impl Fn(postgres::Error) -> CompassError + '_ {
+    move |err| {
+        if err.code() == Some(&SqlState::UNDEFINED_OBJECT) {
+            if let Some(db) = err
+                .as_db_error()
+                .filter(|e| e.message().contains("collation"))
+            {
+                return CompassError::InvalidCollation(db.message().to_owned());
+            }
+        }
+
+        let problem = if err.code() == Some(&SqlState::UNDEFINED_TABLE) {
+            format!("table '{}' doesn't exist", schema.table)
+        } else if err.code() == Some(&SqlState::UNDEFINED_COLUMN) {
+            format!(
+                "table '{}' doesn't have the expected columns ({})",
+                schema.table,
+                err.as_db_error().map_or("", |e| e.message())
+            )
+        } else {
+            return CompassError::PGError(err);
+        };
+
+        CompassError::SchemaMismatch(format!(
+            "{}. it should look like `{}`; run compass::migrate to create it",
+            problem,
+            table_ddl(&schema.table)
+        ))
+    }
+}
+
+// slots in the schema's throttle and quota, for the ones it has
+pub(crate) struct QueryPermit<'a> {
+    _throttle: Option<ThrottlePermit<'a>>,
+    _quota: Option<QuotaPermit<'a>>,
+}
+
+// holds a slot in the schema's throttle and quota, if it has them, for as long as the query runs
+pub(crate) fn schema_permit(schema: &Schema) -> Result<QueryPermit<'_>, CompassError> {
+    let quota = match schema.quota {
+        Some(ref quota) => Some(quota.acquire()?),
+        None => None,
+    };
+    let throttle = match schema.throttle {
+        Some(ref throttle) => Some(throttle.acquire()?),
+        None => None,
+    };
+    Ok(QueryPermit {
+        _throttle: throttle,
+        _quota: quota,
+    })
+}
+
+// a connection with a schema's permit held and its quota's statement timeout set, for as long as the
+// schema's queries run on it. dropping it puts the connection's own timeout back, then frees the permit
+pub(crate) struct PermittedClient<'c, 's, C: Connection> {
+    client: &'c mut C,
+    previous_timeout: Option<String>,
+    _permit: QueryPermit<'s>,
+}
+
+impl<C: Connection> Drop for PermittedClient<'_, '_, C> {
+    fn drop(&mut self) {
+        if let Some(ref previous) = self.previous_timeout {
+            // a connection that broke mid-query has no timeout left to put back
+            let _ = self.client.restore_statement_timeout(previous);
+        }
+    }
+}
+
+impl<C: Connection> Deref for PermittedClient<'_, '_, C> {
+    type Target = C;
+
+    fn deref(&self) -> &C {
+        &*self.client
+    }
+}
+
+impl<C: Connection> DerefMut for PermittedClient<'_, '_, C> {
+    fn deref_mut(&mut self) -> &mut C {
+        &mut *self.client
+    }
+}
+
+impl<C: Connection> Connection for PermittedClient<'_, '_, C> {
+    fn client(&mut self) -> Result<&mut Client, postgres::Error> {
+        self.client.client()
+    }
+
+    fn prepare_typed(
+        &mut self,
+        query: &str,
+        types: &[PostgresType],
+    ) -> Result<Statement, postgres::Error> {
+        self.client.prepare_typed(query, types)
+    }
+
+    fn set_statement_timeout(&mut self, ms: u64) -> Result<String, postgres::Error> {
+        self.client.set_statement_timeout(ms)
+    }
+
+    fn restore_statement_timeout(&mut self, previous: &str) -> Result<(), postgres::Error> {
+        self.client.restore_statement_timeout(previous)
+    }
+}
+
+// schema_permit, on the connection the schema's queries are about to run on. the timeout is only
+// touched for schemas whose quota sets one, so other queries cost no extra round trip and keep
+// whatever statement_timeout the embedder set
+pub(crate) fn throttle_permit<'c, 's, C: Connection>(
+    client: &'c mut C,
+    schema: &'s Schema,
+) -> Result<PermittedClient<'c, 's, C>, CompassError> {
+    let permit = schema_permit(schema)?;
+    let previous_timeout = match schema.quota.as_ref().and_then(|q| q.statement_timeout_ms()) {
+        Some(ms) => Some(client.set_statement_timeout(ms).map_err(pg_error(schema))?),
+        None => None,
+    };
+    Ok(PermittedClient {
+        client,
+        previous_timeout,
+        _permit: permit,
+    })
+}
+
+// a value bound after the jsonpath, with the type it's declared as when the statement is prepared.
+// postgres can't always infer these (e.g. a parameter that's only ever passed to a function), so every
+// binding carries its own
+#[derive(Debug, Clone, PartialEq)]
+pub enum Binding {
+    Text(String),
+    Int(i64),
+    TextArray(Vec<String>),
+    Json(Value),
+}
+
+impl Binding {
+    pub fn pg_type(&self) -> PostgresType {
+        match self {
+            Binding::Text(_) => PostgresType::TEXT,
+            Binding::Int(_) => PostgresType::INT8,
+            Binding::TextArray(_) => PostgresType::TEXT_ARRAY,
+            Binding::Json(_) => PostgresType::JSONB,
+        }
+    }
+
+    pub fn as_sql(&self) -> &dyn ToSql {
+        self.as_sync_sql()
+    }
+
+    // for clients whose futures have to be Send, like tokio_postgres'
+    pub fn as_sync_sql(&self) -> &(dyn ToSql + Sync) {
+        match self {
+            Binding::Text(s) => s,
+            Binding::Int(n) => n,
+            Binding::TextArray(a) => a,
+            Binding::Json(v) => v,
+        }
+    }
+}
+
+// everything generate_where works out from a query: the sql fragments, the jsonpath bound to $1,
+// the extra bindings that follow it, and the parameters that didn't match anything
+#[derive(Debug, Clone)]
+pub struct QueryPlan {
+    pub where_clause: String,
+    pub order_clause: String, // ORDER BY, LIMIT and OFFSET, for searches
+    pub order_by: String,     // just the ORDER BY expressions
+    pub json_query: String,
+    pub bindings: Vec<Binding>,
+    pub ignored_params: Vec<String>,
+    pub secondary: Option<String>, // placeholder of the secondary sort's path, when the ORDER BY has one
+    pub collation: Option<String>, // what the sort field is compared in, see sort_collation
+    bind_index: usize,             // parameter number of bindings[0]
+}
+
+impl QueryPlan {
+    // the types to prepare with: the leading parameters the caller binds itself, then one per binding
+    pub fn param_types(&self, leading: &[PostgresType]) -> Vec<PostgresType> {
+        leading
+            .iter()
+            .cloned()
+            .chain(self.bindings.iter().map(Binding::pg_type))
+            .collect()
+    }
+
+    // adds a binding after the plan's own and returns its placeholder
+    pub fn bind(&mut self, binding: Binding) -> String {
+        self.bindings.push(binding);
+        format!("${}", self.bind_index + self.bindings.len() - 1)
+    }
+
+    // ANDs a condition that can't be expressed as query parameters onto the WHERE clause
+    pub fn and_where(&mut self, condition: &str) {
+        if self.where_clause.is_empty() {
+            self.where_clause = format!("WHERE {}", condition);
+        } else {
+            self.where_clause = format!("{} AND {}", self.where_clause, condition);
+        }
+    }
+}
+
+// query groups let filters on different fields be alternatives: `g1.type=54&g1.season=3&g1.or.weather=7`
+// is (type 54 and season 3) or weather 7. a group's plain filters are ANDed, then ORed with each of its
+// `or.` filters, and the group as a whole is ANDed with everything else. groups compile to one jsonpath
+// clause each, so fields that aren't queried with jsonpath (fulltext, vectors) can't be in them
+fn generate_groups(
+    schema: &Schema,
+    mut grouped: Vec<(&str, bool, &String, &String, (String, FieldQuery))>,
+    total_terms: &mut usize,
+) -> Result<Vec<String>, CompassError> {
+    // by group, then plain filters before `or.` ones, then by key, so the sql is always the same
+    grouped.sort_by(|a, b| (a.0, a.1, a.2).cmp(&(b.0, b.1, b.2)));
+
+    let mut clauses = Vec::new();
+    let mut i = 0;
+    while i < grouped.len() {
+        let group = grouped[i].0;
+        let mut all = Vec::new();
+        let mut any = Vec::new();
+
+        while i < grouped.len() && grouped[i].0 == group {
+            let (_, or, k, v, (ref name, ref query)) = grouped[i];
+            i += 1;
+
+            if is_fulltext(query) {
+                return Err(CompassError::InvalidQuerySyntax(format!(
+                    "{}: only fields queried with jsonpath can go in a group",
+                    k
+                )));
+            }
+            check_terms(schema, k, v, total_terms)?;
+
+            let mut jsonb = Vec::new();
+            let mut other = Vec::new();
+            let mut bindings = Vec::new();
+            match date_filter(schema, name, query, v)? {
+                Some(filter) => jsonb.push(filter),
+                None => generate_one_field(
+                    v,
+                    (name, query.clone()),
+                    field_normalization(schema, name),
+                    &mut jsonb,
+                    &mut other,
+                    &mut bindings,
+                    0,
+                )?,
+            }
+            if !other.is_empty() || jsonb.is_empty() {
+                return Err(CompassError::InvalidQuerySyntax(format!(
+                    "{}: only fields queried with jsonpath can go in a group",
+                    k
+                )));
+            }
+
+            if or {
+                any.push(jsonb.join(" && "));
+            } else {
+                all.push(jsonb.join(" && "));
+            }
+        }
+
+        let mut alternatives = Vec::new();
+        if !all.is_empty() {
+            alternatives.push(format!("({})", all.join(" && ")));
+        }
+        alternatives.extend(any.into_iter().map(|clause| format!("({})", clause)));
+        clauses.push(format!("({})", alternatives.join(" || ")));
+    }
+
+    Ok(clauses)
+}
+
+pub fn generate_where(
+    schema: &Schema,
+    fields: &HashMap<String, String>,
+    bind_index: usize,
+    force_json_query: bool,
+) -> Result<QueryPlan, CompassError> {
+    plan_where(
+        schema,
+        &prepare_params(schema, fields)?,
+        bind_index,
+        force_json_query,
+    )
+}
+
+// a query's parameters with presets and `q` expanded and the schema's middleware run over them. a
+// search reads its filters, sorting, paging and cursor from these, so it calls this once, up front
+pub(crate) fn prepare_params<'a>(
+    schema: &Schema,
+    fields: &'a HashMap<String, String>,
+) -> Result<Cow<'a, HashMap<String, String>>, CompassError> {
+    schema.hooks.before_query(expand_params(schema, fields)?)
+}
+
+// generate_where, for `fields` that went through prepare_params already
+pub(crate) fn plan_where(
+    schema: &Schema,
+    fields: &HashMap<String, String>,
+    bind_index: usize,
+    force_json_query: bool,
+) -> Result<QueryPlan, CompassError> {
+    let mut jsonb_filters = Vec::<String>::new();
+    let mut other_filters = Vec::<String>::new();
+
+    let mut other_bindings = Vec::<Binding>::new();
+
+    // HashMap order changes from run to run; go by name, then by where the field sits in the schema,
+    // so the same query always compiles to the same sql
+    let mut params: Vec<(&String, &String)> = fields.iter().collect();
+    params.sort();
+
+    let mut resolved = Vec::new();
+    let mut grouped = Vec::new();
+    let mut ignored_params = Vec::new();
+    for (k, v) in params {
+        validate_key(k)?;
+
+        let field_maybe = schema.resolve_field(k);
+
+        // a real field by that name wins over a group
+        if field_maybe.is_none() {
+            if let Some((group, or, key)) = split_group_key(k) {
+                match schema.resolve_field(key) {
+                    Some(field) => grouped.push((group, or, k, v, field)),
+                    None if schema.strict => return Err(CompassError::UnknownField(k.clone())),
+                    None => ignored_params.push(k.clone()),
+                }
+                continue;
+            }
+        }
+
+        if field_maybe.is_none() && schema.strict && !RESERVED_PARAMS.contains(&k.as_str()) {
+            return Err(CompassError::UnknownField(k.clone()));
+        }
+
+        match field_maybe {
+            Some(field) => resolved.push((k, v, field)),
+            None if !RESERVED_PARAMS.contains(&k.as_str()) => ignored_params.push(k.clone()),
+            None => {}
+        }
+    }
+
+    resolved.sort_by_key(|(_, _, field)| schema.field_position(&field.0));
+
+    check_nested(
+        schema,
+        resolved
+            .iter()
+            .map(|(_, _, field)| field)
+            .chain(grouped.iter().map(|(_, _, _, _, field)| field)),
+    )?;
+
+    let mut total_terms = 0;
+    let mut nearest = None;
+    let mut ranked = Vec::new();
+
+    for (k, v, field) in resolved {
+        // vector queries don't filter, they pick the order
+        if let FieldQuery::Vector {
+            ref column,
+            dimensions,
+        } = field.1
+        {
+            nearest = Some((
+                column.clone().unwrap_or_else(|| field.0.clone()),
+                parse_vector(v, dimensions)?,
+            ));
+            continue;
+        }
+
+        if !is_fulltext(&field.1) {
+            check_terms(schema, k, v, &mut total_terms)?;
+        }
+
+        if let FieldQuery::Fulltext { .. } = field.1 {
+            ranked.push((field.0.clone(), field.1.clone(), v));
+        }
+
+        if let Some(filter) = date_filter(schema, &field.0, &field.1, v)? {
+            jsonb_filters.push(filter);
+            continue;
+        }
+
+        generate_one_field(
+            v,
+            (&field.0, field.1),
+            field_normalization(schema, &field.0),
+            &mut jsonb_filters,
+            &mut other_filters,
+            &mut other_bindings,
+            bind_index,
+        )?;
+    }
+
+    jsonb_filters.extend(generate_groups(schema, grouped, &mut total_terms)?);
+
+    // not up to the query: with tenancy, every plan is scoped to the schema's tenant
+    if let Some(ref tenancy) = schema.tenancy {
+        let tenant = schema.tenant.clone().ok_or(CompassError::TenantRequired)?;
+        other_bindings.push(Binding::Text(tenant));
+        other_filters.push(format!(
+            "{} = ${}",
+            tenancy.sql(None),
+            bind_index + other_bindings.len() - 1
+        ));
+    }
+
+    let json_query = format!("({})", jsonb_filters.join(" && "));
+
+    let depth = jsonpath_depth(&json_query);
+    if depth > schema.limits.max_depth {
+        return Err(CompassError::QueryTooComplex(format!(
+            "filter nests {} levels deep, the limit is {}",
+            depth, schema.limits.max_depth
+        )));
+    }
+
+    // build out full query
+    let query = if (!jsonb_filters.is_empty() || force_json_query) && other_filters.is_empty() {
+        "WHERE object @@ CAST($1 AS JSONPATH)".to_owned()
+    } else if (!jsonb_filters.is_empty() || force_json_query) && !other_filters.is_empty() {
+        format!(
+            "WHERE object @@ CAST($1 AS JSONPATH) AND {}",
+            other_filters.join(" AND ")
+        )
+    } else if !other_filters.is_empty() {
+        format!("WHERE {}", other_filters.join(" AND "))
+    } else {
+        String::new()
+    };
+
+    let order = sort_order(fields);
+    let mut secondary = None;
+    let mut collation = None;
+
+    let order_by = match nearest {
+        Some((column, vector)) => {
+            other_bindings.push(Binding::Text(vector));
+            format!(
+                "{} <-> ${}::vector, doc_id",
+                column,
+                bind_index + other_bindings.len() - 1
+            )
+        }
+        None => match sort_key(schema, fields)? {
+            // without fulltext parameters there's nothing to rank
+            SortKey::Relevance if !ranked.is_empty() => format!(
+                "{} {}, doc_id",
+                relevance(schema, fields, ranked, &mut other_bindings, bind_index)?,
+                order
+            ),
+            SortKey::DocId | SortKey::Relevance => format!("doc_id {}", order),
+            SortKey::Path(path) => {
+                if let Some(path) = secondary_sort(schema, &path) {
+                    other_bindings.push(Binding::TextArray(path));
+                    secondary = Some(format!("${}", bind_index + other_bindings.len() - 1));
+                }
+                collation = sort_collation(schema, fields, &path)?;
+                path_order_by(
+                    &order,
+                    sort_nulls(schema, fields),
+                    secondary.as_deref(),
+                    collation.as_deref(),
+                )
+            }
+        },
+    };
+
+    Ok(QueryPlan {
+        where_clause: query,
+        order_clause: format!(" ORDER BY {} LIMIT $3 OFFSET $4", order_by),
+        order_by,
+        json_query,
+        bindings: other_bindings,
+        ignored_params,
+        secondary,
+        collation,
+        bind_index,
+    })
+}
+
+// `a.b` -> `'{a,b}'`, for paths that schema validation already limited to identifiers
+pub(crate) fn path_literal(path: &str) -> String {
+    format!("'{{{}}}'", path.split('.').collect::<Vec<_>>().join(","))
+}
+
+// jsonb_build_object takes at most 100 arguments
+const MAX_PROJECTED_FIELDS: usize = 50;
+
+// the paths `fields=` asked for, as a tree so `player.id,player.name` come out under one `player`
+#[derive(Default)]
+struct Projection {
+    whole: bool, // this path itself was asked for, so everything under it comes along
+    children: IndexMap<String, Projection>,
+}
+
+impl Projection {
+    fn insert<'a, I: Iterator<Item = &'a str>>(&mut self, mut path: I) {
+        match path.next() {
+            None => self.whole = true,
+            Some(segment) => self
+                .children
+                .entry(segment.to_owned())
+                .or_default()
+                .insert(path),
+        }
+    }
+
+    fn sql(&self, table: &str, path: &[&str]) -> String {
+        if self.whole {
+            return format!("{}.object #> {}", table, path_literal(&path.join(".")));
+        }
+
+        let pairs: Vec<String> = self
+            .children
+            .iter()
+            .map(|(key, child)| {
+                let mut child_path = path.to_vec();
+                child_path.push(key);
+                format!("'{}', {}", key, child.sql(table, &child_path))
+            })
+            .collect();
+        format!("jsonb_build_object({})", pairs.join(", "))
+    }
+}
+
+// `fields=name,player.id`: builds each result out of just those paths in sql, so the rest of the
+// document never leaves postgres. paths a document doesn't have come back as null
+fn projection(
+    schema: &Schema,
+    fields: &HashMap<String, String>,
+) -> Result<Option<String>, CompassError> {
+    let paths: Vec<&str> = match fields.get("fields") {
+        Some(spec) => spec.split(',').filter(|p| !p.is_empty()).collect(),
+        None => return Ok(None),
+    };
+    if paths.is_empty() {
+        return Ok(None);
+    }
+    if paths.len() > MAX_PROJECTED_FIELDS {
+        return Err(CompassError::QueryTooComplex(format!(
+            "fields= asks for {} paths, the limit is {}",
+            paths.len(),
+            MAX_PROJECTED_FIELDS
+        )));
+    }
+
+    let mut tree = Projection::default();
+    for path in paths {
+        // validate_key would let the negation suffix through
+        if path.ends_with('!') {
+            return Err(CompassError::InvalidKey(path.to_owned()));
+        }
+        validate_key(path)?;
+        tree.insert(path.split('.'));
+    }
+
+    Ok(Some(tree.sql(&schema.table, &[])))
+}
+
+// the select list and LATERAL joins for `join=a,b`. each join adds its match (or matches) to the
+// document under the join's output key
+fn join_clause(
+    schema: &Schema,
+    fields: &HashMap<String, String>,
+) -> Result<(String, String), CompassError> {
+    let document = match projection(schema, fields)? {
+        Some(projected) => projected,
+        None => format!("{}.object", schema.table),
+    };
+    let names = match fields.get("join") {
+        Some(names) => names,
+        None => return Ok((document, String::new())),
+    };
+
+    let mut outputs = Vec::new();
+    let mut laterals = String::new();
+
+    for (i, name) in names.split(',').filter(|n| !n.is_empty()).enumerate() {
+        let join = schema
+            .joins
+            .get(name)
+            .ok_or_else(|| CompassError::UnknownJoin(name.to_owned()))?;
+        let table = join.table.as_ref().ok_or_else(|| {
+            CompassError::ConfigError(format!(
+                "join '{}' isn't resolved; load schemas through Config or call Schema::resolve_joins",
+                name
+            ))
+        })?;
+
+        let mut matches = format!(
+            "o.object #> {} = {}.object #> {}",
+            path_literal(&join.foreign),
+            schema.table,
+            path_literal(&join.local)
+        );
+        // resolve_joins only lets tenant scoped schemas be joined from other tenant scoped ones
+        if let (Some(ref theirs), Some(ref ours)) = (&join.tenancy, &schema.tenancy) {
+            matches += &format!(
+                " AND {} = {}",
+                theirs.sql(Some("o")),
+                ours.sql(Some(&schema.table))
+            );
+        }
+        laterals += &if join.many {
+            format!(
+                " LEFT JOIN LATERAL (SELECT jsonb_agg(o.object) AS doc FROM {} o WHERE {}) j{} ON true",
+                table, matches, i
+            )
+        } else {
+            format!(
+                " LEFT JOIN LATERAL (SELECT o.object AS doc FROM {} o WHERE {} LIMIT 1) j{} ON true",
+                table, matches, i
+            )
+        };
+        let output = join.output.as_deref().unwrap_or(name);
+        outputs.push(format!("'{}', j{}.doc", output, i));
+    }
+
+    if outputs.is_empty() {
+        return Ok((document, String::new()));
+    }
+
+    Ok((
+        format!("{} || jsonb_build_object({})", document, outputs.join(", ")),
+        laterals,
+    ))
+}
+
+// computed columns for `window=rank:runs:team,running_count,lag:runs`, worked out over every matching
+// document before limit/offset apply:
+//   rank:<field>[:<partition>]  -> _rank_<field>, highest first
+//   running_count               -> _running_count, in the search's sort order
+//   lag:<field>, lead:<field>   -> _lag_<field>/_lead_<field>, the field on the previous/next document in
+//                                  the search's sort order
+fn window_columns(
+    schema: &Schema,
+    fields: &HashMap<String, String>,
+    plan: &mut QueryPlan,
+) -> Result<Option<String>, CompassError> {
+    let spec = match fields.get("window") {
+        Some(spec) => spec,
+        None => return Ok(None),
+    };
+
+    let order = sort_order(fields);
+    let sort_order_by = format!(
+        "ORDER BY {}",
+        match sort_key(schema, fields)? {
+            SortKey::DocId | SortKey::Relevance => format!("doc_id {}", order),
+            SortKey::Path(_) => path_order_by(
+                &order,
+                sort_nulls(schema, fields),
+                plan.secondary.as_deref(),
+                plan.collation.as_deref()
+            ),
+        }
+    );
+
+    let mut columns = Vec::new();
+    for window in spec.split(',').filter(|w| !w.is_empty()) {
+        let parts: Vec<&str> = window.split(':').collect();
+        let mut path_of = |name: &str| -> Result<String, CompassError> {
+            let path = field_path(schema, name).map_err(CompassError::InvalidAggregate)?;
+            Ok(format!(
+                "(object #> {})",
+                plan.bind(Binding::TextArray(path))
+            ))
+        };
+
+        let (key, expr) = match parts.as_slice() {
+            ["rank", field] | ["rank", field, _] => {
+                let partition = match parts.get(2) {
+                    Some(p) => format!("PARTITION BY {} ", path_of(p)?),
+                    None => String::new(),
+                };
+                let metric = numeric_expr(&path_of(field)?);
+                (
+                    format!("_rank_{}", field),
+                    format!(
+                        "RANK() OVER ({}ORDER BY {} DESC NULLS LAST)",
+                        partition, metric
+                    ),
+                )
+            }
+            ["running_count"] => (
+                "_running_count".to_owned(),
+                format!("COUNT(*) OVER ({} ROWS UNBOUNDED PRECEDING)", sort_order_by),
+            ),
+            [op @ "lag", field] | [op @ "lead", field] => (
+                format!("_{}_{}", op, field),
+                format!(
+                    "{}({}) OVER ({})",
+                    op.to_uppercase(),
+                    path_of(field)?,
+                    sort_order_by
+                ),
+            ),
+            _ => {
+                return Err(CompassError::InvalidAggregate(format!(
+                    "unknown window '{}'",
+                    window
+                )))
+            }
+        };
+
+        // the key goes into the sql as a literal; field names are identifiers by now, but check anyway
+        if !key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+        {
+            return Err(CompassError::InvalidAggregate(format!(
+                "unknown window '{}'",
+                window
+            )));
+        }
+        columns.push(format!("'{}', {}", key, expr));
+    }
+
+    if columns.is_empty() {
+        Ok(None)
+    } else {
+        Ok(Some(format!("jsonb_build_object({})", columns.join(", "))))
+    }
+}
+
+// `snippets=true` adds `_snippets`: for each fulltext field in the query, the best-matching excerpt of it
+// with matches in <b></b>, via ts_headline. snippet_words (default 35) is the most words per fragment,
+// snippet_fragments (default 0, meaning one excerpt cut around the best match) how many fragments to
+// pick, and snippet_delimiter what goes between them
+fn snippet_columns(
+    schema: &Schema,
+    fields: &HashMap<String, String>,
+    plan: &mut QueryPlan,
+) -> Result<Option<String>, CompassError> {
+    if fields.get("snippets").map_or(true, |s| s == "false") {
+        return Ok(None);
+    }
+
+    let parse = |name: &str, default: i64, min: i64, max: i64| -> Result<i64, CompassError> {
+        let n = match fields.get(name) {
+            Some(n) => n.parse::<i64>().map_err(CompassError::InvalidNumberError)?,
+            None => return Ok(default),
+        };
+        if n < min || n > max {
+            return Err(CompassError::ConversionError(format!(
+                "{} has to be between {} and {}",
+                name, min, max
+            )));
+        }
+        Ok(n)
+    };
+    let words = parse("snippet_words", 35, 2, 100)?;
+    let fragments = parse("snippet_fragments", 0, 0, 10)?;
+
+    let mut options = format!(
+        "MaxWords={}, MinWords={}, MaxFragments={}",
+        words,
+        words / 2,
+        fragments
+    );
+    if let Some(delimiter) = fields.get("snippet_delimiter") {
+        if delimiter.contains('"') || delimiter.len() > 16 {
+            return Err(CompassError::ConversionError(
+                "snippet_delimiter can't contain \" or be longer than 16 bytes".to_owned(),
+            ));
+        }
+        options += &format!(", FragmentDelimiter=\"{}\"", delimiter);
+    }
+
+    let mut params: Vec<(&String, &String)> = fields.iter().collect();
+    params.sort();
+
+    let mut columns = Vec::new();
+    for (k, v) in params {
+        let (name, lang, syntax, target) = match schema.resolve_field(k) {
+            Some((
+                name,
+                FieldQuery::Fulltext {
+                    lang,
+                    syntax,
+                    target,
+                    ..
+                },
+            )) => (name, lang, syntax, target),
+            _ => continue,
+        };
+
+        let key = plan.bind(Binding::Text(name.clone()));
+        let query = plan.bind(Binding::Text(v.clone()));
+        let options = plan.bind(Binding::Text(options.clone()));
+        columns.push(format!(
+            "{key}::text, ts_headline({lang}, object->>'{target}', {syntax}({lang}, {query}), {options})",
+            key = key,
+            lang = ts_config(&lang),
+            target = target.as_ref().unwrap_or(&name),
+            syntax = syntax,
+            query = query,
+            options = options
+        ));
+    }
+
+    if columns.is_empty() {
+        Ok(None)
+    } else {
+        Ok(Some(format!(
+            "jsonb_build_object('_snippets', jsonb_build_object({}))",
+            columns.join(", ")
+        )))
+    }
+}
+
+// `collapse=gameId`: of the documents sharing a value of that field, only the first in sort order is
+// returned, with `collapsed_count` saying how many there were. documents without the field count as one
+// group. this goes last, so the subqueries see every other condition on the plan
+fn collapse_column(
+    schema: &Schema,
+    fields: &HashMap<String, String>,
+    plan: &mut QueryPlan,
+) -> Result<Option<String>, CompassError> {
+    let name = match fields.get("collapse") {
+        Some(name) => name,
+        None => return Ok(None),
+    };
+    let path = field_path(schema, name).map_err(CompassError::ConversionError)?;
+    let key = plan.bind(Binding::TextArray(path));
+
+    let where_clause = plan.where_clause.clone();
+    let and = if where_clause.is_empty() {
+        "WHERE"
+    } else {
+        "AND"
+    };
+
+    plan.and_where(&format!(
+        "doc_id IN (SELECT DISTINCT ON (object #> {key}) doc_id FROM {table} {where_clause} ORDER BY object #> {key}, {order_by})",
+        key = key,
+        table = schema.table,
+        where_clause = where_clause,
+        order_by = plan.order_by
+    ));
+
+    // unqualified columns inside resolve to the subquery's own table, so the filters apply to it as-is
+    Ok(Some(format!(
+        "jsonb_build_object('collapsed_count', (SELECT COUNT(*) FROM {table} c {where_clause} {and} c.object #> {key} IS NOT DISTINCT FROM {table}.object #> {key}))",
+        table = schema.table,
+        where_clause = where_clause,
+        and = and,
+        key = key
+    )))
+}
+
+pub fn json_search<C: Connection>(
+    client: &mut C,
+    schema: &Schema,
+    fields: &HashMap<String, String>,
+    raw_query: Option<RawQuery>,
+) -> Result<Vec<Value>, CompassError> {
+    Ok(json_search_response(client, schema, fields, raw_query)?.data)
+}
+
+// same as json_search, but wrapped in a SearchResponse so it can carry metadata like `debug=stats` timings
+pub fn json_search_response<C: Connection>(
+    client: &mut C,
+    schema: &Schema,
+    fields: &HashMap<String, String>,
+    raw_query: Option<RawQuery>,
+) -> Result<SearchResponse, CompassError> {
+    if fields.contains_key("similar_to") {
+        return json_similar(client, schema, fields);
+    }
+
+    search_response(client, schema, fields, raw_query, &|_| Ok(()))
+}
+
+// json_search_response for handlers that only serialize the result. when the schema has no converters,
+// lookups or transformers, documents are passed through as postgres' text and never parsed into a Value
+pub fn json_search_raw<C: Connection>(
+    client: &mut C,
+    schema: &Schema,
+    fields: &HashMap<String, String>,
+    raw_query: Option<RawQuery>,
+) -> Result<SearchResponse<Box<RawValue>>, CompassError> {
+    if !fields.contains_key("similar_to")
+        && schema.converter_plan().is_empty()
+        && schema.lookups.is_empty()
+        && !schema.hooks.has_transformers()
+    {
+        return search_response(client, schema, fields, raw_query, &|_| Ok(()));
+    }
+
+    let response = json_search_response(client, schema, fields, raw_query)?;
+    Ok(SearchResponse {
+        data: response
+            .data
+            .iter()
+            .map(to_raw_value)
+            .collect::<Result<_, _>>()?,
+        meta: response.meta,
+        stats: response.stats,
+    })
+}
+
+// conditions that don't come from query parameters get added to the plan by `extra`, which runs for
+// the count query too
+type ExtraConditions<'a> = &'a dyn Fn(&mut QueryPlan) -> Result<(), CompassError>;
+
+// a search's statement and what it takes to read its page back. search_response runs it on a sync
+// client, and the async module on a tokio one
+pub(crate) struct SearchStatement {
+    pub(crate) query: String,
+    pub(crate) param_types: Vec<PostgresType>,
+    pub(crate) json_query: String,
+    pub(crate) sort_by: Vec<String>, // doc_id sorts don't read $2, but it's still bound so the statement shape stays the same
+    pub(crate) limit: i64,
+    pub(crate) offset: i64,
+    pub(crate) bindings: Vec<Binding>,
+    pub(crate) ignored_params: Vec<String>,
+    pub(crate) by_cursor: bool,
+    pub(crate) with_total: bool,
+}
+
+impl SearchStatement {
+    // $1 to $4, then the plan's bindings
+    pub(crate) fn params(&self) -> Vec<&(dyn ToSql + Sync)> {
+        let leading: [&(dyn ToSql + Sync); 4] =
+            [&self.json_query, &self.sort_by, &self.limit, &self.offset];
+        leading
+            .iter()
+            .copied()
+            .chain(self.bindings.iter().map(Binding::as_sync_sql))
+            .collect()
+    }
+
+    pub(crate) fn record(&self, schema: &Schema, rows: usize, elapsed: Duration) {
+        if let Some(ref log) = schema.slow_log {
+            log.record(
+                &schema.table,
+                &self.query,
+                || self.params().iter().map(|p| format!("{:?}", p)).collect(),
+                elapsed,
+            );
+        }
+        record_usage(schema, rows, elapsed);
+    }
+}
+
+// `fields` have to be prepared already (see prepare_params), and `raw_query` checked
+pub(crate) fn search_statement<D: ResultDocument>(
+    schema: &Schema,
+    fields: &HashMap<String, String>,
+    raw_query: Option<&str>,
+    extra: ExtraConditions,
+) -> Result<SearchStatement, CompassError> {
+    let mut plan = plan_where(schema, fields, 5, raw_query.is_some())?;
+    extra(&mut plan)?;
+    let windows = window_columns(schema, fields, &mut plan)?;
+    let snippets = snippet_columns(schema, fields, &mut plan)?;
+    let collapsed = collapse_column(schema, fields, &mut plan)?;
+    // keyset paging goes after collapse, whose subqueries have to see every document and not just
+    // the ones past the cursor
+    let by_cursor = fields.contains_key("cursor");
+    if by_cursor {
+        if let Some(cursor) = resume_cursor(schema, fields, raw_query)? {
+            let condition = after_cursor(
+                &cursor,
+                &sort_key(schema, fields)?,
+                &sort_order(fields),
+                sort_nulls(schema, fields),
+                &mut plan,
+            )?;
+            plan.and_where(&condition);
+        }
+    }
+    let param_types = plan.param_types(&[
+        PostgresType::TEXT,
+        PostgresType::TEXT_ARRAY,
+        PostgresType::INT8,
+        PostgresType::INT8,
+    ]);
+    let secondary = plan.secondary.clone();
+    let QueryPlan {
+        where_clause: query,
+        order_clause: sort_string,
+        json_query,
+        bindings,
+        ignored_params,
+        ..
+    } = plan;
+
+    let json_query = match raw_query {
+        Some(q) => q.to_owned(),
+        None => json_query,
+    };
+
+    let (select, joins) = join_clause(schema, fields)?;
+    let select = match windows {
+        Some(w) => format!("{} || {}", select, w),
+        None => select,
+    };
+    let select = [snippets, collapsed]
+        .iter()
+        .flatten()
+        .fold(select, |select, column| format!("{} || {}", select, column));
+    let select = D::select(schema, select);
+    // where the page ended, for next_cursor. doc_id sorts only need the id
+    let select = if !by_cursor {
+        select
+    } else if let SortKey::Path(_) = sort_key(schema, fields)? {
+        format!(
+            "{}, {table}.doc_id, {table}.object #> $2, {secondary}",
+            select,
+            table = schema.table,
+            secondary = match secondary {
+                Some(ref p) => format!("{}.object #> {}", schema.table, p),
+                None => "NULL::jsonb".to_owned(),
+            }
+        )
+    } else {
+        format!(
+            "{}, {}.doc_id, NULL::jsonb, NULL::jsonb",
+            select, schema.table
+        )
+    };
+    // the total in the same statement, for callers that always want it. COUNT(*) OVER () still has
+    // to look at every match, so it's opt-in
+    let with_total = fields.get("with_total").map_or(false, |t| t == "true");
+    let select = if with_total {
+        format!("{}, COUNT(*) OVER ()", select)
+    } else {
+        select
+    };
+    let query = format!(
+        "SELECT {} FROM {}{} {} {}",
+        select, schema.table, joins, query, sort_string
+    );
+
+    let sort_by: Vec<String> = match sort_key(schema, fields)? {
+        SortKey::DocId | SortKey::Relevance => Vec::new(),
+        SortKey::Path(path) => path,
+    };
+
+    let (limit, offset) = page_bounds(schema, fields)?;
+
+    Ok(SearchStatement {
+        query,
+        param_types,
+        json_query,
+        sort_by,
+        limit,
+        offset,
+        bindings,
+        ignored_params,
+        by_cursor,
+        with_total,
+    })
+}
+
+// a search page as its rows come in. documents are converted as they arrive, so the page can stop at
+// the response budget instead of holding all of it first. the first document always goes in, however
+// big, so paging moves on
+pub(crate) struct PageReader<D> {
+    budget: usize,
+    rows: Vec<Row>,
+    pub(crate) data: Vec<D>,
+    bytes: usize,
+    pub(crate) truncated: bool,
+    pub(crate) converting: Duration,
+}
+
+impl<D: ResultDocument> PageReader<D> {
+    pub(crate) fn new(schema: &Schema) -> PageReader<D> {
+        PageReader {
+            budget: schema.limits.max_response_bytes,
+            rows: Vec::new(),
+            data: Vec::new(),
+            bytes: 0,
+            truncated: false,
+            converting: Duration::default(),
+        }
+    }
+
+    // false once the budget is used up, and the rest of the rows should be left unread
+    pub(crate) fn push(&mut self, schema: &Schema, row: Row) -> Result<bool, CompassError> {
+        let converting_from = Instant::now();
+        let doc = D::from_row(schema, &row)?;
+        if self.budget > 0 {
+            self.bytes += doc.json_len();
+            if self.bytes > self.budget && !self.data.is_empty() {
+                self.truncated = true;
+                return Ok(false);
+            }
+        }
+        self.converting += converting_from.elapsed();
+        self.rows.push(row);
+        self.data.push(doc);
+        Ok(true)
+    }
+
+    // a short page means there's nothing after it, unless the budget cut it short
+    pub(crate) fn next_cursor(
+        &self,
+        schema: &Schema,
+        fields: &HashMap<String, String>,
+        raw_query: Option<&str>,
+        statement: &SearchStatement,
+    ) -> Result<Option<String>, CompassError> {
+        match self.rows.last() {
+            Some(row)
+                if statement.by_cursor
+                    && (self.truncated || self.rows.len() as i64 == statement.limit) =>
+            {
+                Ok(Some(next_cursor(
+                    schema,
+                    fields,
+                    raw_query,
+                    row.get::<usize, Option<Value>>(2),
+                    row.get::<usize, Option<Value>>(3),
+                    row.get::<usize, Uuid>(1),
+                )?))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    // with_total's count, when the page tells it. None when it was paged past the end, so there was no
+    // row to read it from and it has to be counted separately
+    pub(crate) fn total(&self, statement: &SearchStatement) -> Option<i64> {
+        let total_column = if statement.by_cursor { 4 } else { 1 };
+        match self.rows.first() {
+            Some(row) => Some(row.get::<usize, i64>(total_column)),
+            None if statement.offset == 0 => Some(0),
+            None => None,
+        }
+    }
+
+    pub(crate) fn next_offset(&self, statement: &SearchStatement) -> Option<i64> {
+        if self.truncated && !statement.by_cursor {
+            Some(statement.offset + self.data.len() as i64)
+        } else {
+            None
+        }
+    }
+}
+
+fn search_response<C: Connection, D: ResultDocument>(
+    client: &mut C,
+    schema: &Schema,
+    fields: &HashMap<String, String>,
+    raw_query: Option<RawQuery>,
+    extra: ExtraConditions,
+) -> Result<SearchResponse<D>, CompassError> {
+    // everything below reads the filters presets and `q=` stand for, not the shorthands themselves, and
+    // whatever the middleware made of them
+    search_prepared(
+        client,
+        schema,
+        &prepare_params(schema, fields)?,
+        raw_query,
+        extra,
+    )
+}
+
+// search_response, for `fields` that went through prepare_params already
+fn search_prepared<C: Connection, D: ResultDocument>(
+    client: &mut C,
+    schema: &Schema,
+    fields: &HashMap<String, String>,
+    raw_query: Option<RawQuery>,
+    extra: ExtraConditions,
+) -> Result<SearchResponse<D>, CompassError> {
+    let client = &mut throttle_permit(client, schema)?;
+
+    let collect_stats = fields
+        .get("debug")
+        .map_or(false, |d| d.split(',').any(|x| x == "stats"));
+    let started = Instant::now();
+
+    let raw_query = match raw_query {
+        Some(q) => Some(q.checked(&schema.raw_query)?),
+        None => None,
+    };
+
+    let search = search_statement::<D>(schema, fields, raw_query.as_deref(), extra)?;
+
+    // limit=0 means the caller only wants the total, so don't bother selecting any documents
+    if search.limit == 0 {
+        let total = count_matching(client, schema, fields, raw_query, extra)?;
+        let stats = if collect_stats {
+            Some(QueryStats {
+                total_ms: millis(started.elapsed()),
+                ..QueryStats::default()
+            })
+        } else {
+            None
+        };
+
+        return Ok(SearchResponse {
+            data: Vec::new(),
+            meta: SearchMeta {
+                ignored_params: search.ignored_params,
+                total: Some(total),
+                did_you_mean: None,
+                next_cursor: None,
+                truncated: false,
+                next_offset: None,
+            },
+            stats,
+        });
+    }
+
+    let planned = Instant::now();
 
     let statement: Statement = client
-        .prepare_typed(query.as_str(), &[PostgresType::TEXT, PostgresType::TEXT])
-        .map_err(CompassError::PGError)?;
+        .prepare_typed(search.query.as_str(), &search.param_types)
+        .map_err(pg_error(schema))?;
+
+    let prepared = Instant::now();
+
+    let mut row_iter = client
+        .client()
+        .map_err(pg_error(schema))?
+        .query_raw(&statement, search.params())
+        .map_err(pg_error(schema))?;
+
+    let executed = Instant::now();
+
+    let mut page = PageReader::<D>::new(schema);
+    while let Some(row) = row_iter.next().map_err(pg_error(schema))? {
+        if !page.push(schema, row)? {
+            break;
+        }
+    }
 
-    let sort_by = match fields.get("sortby") {
-        Some(l) => l.as_str(),
-        None => schema.default_order_by.as_str(),
+    let fetched = Instant::now();
+    search.record(schema, page.data.len(), fetched - planned);
+
+    let next_cursor = page.next_cursor(schema, fields, raw_query.as_deref(), &search)?;
+    let total = if !search.with_total {
+        None
+    } else {
+        match page.total(&search) {
+            Some(total) => Some(total),
+            None => Some(count_matching(client, schema, fields, raw_query, extra)?),
+        }
     };
+    let next_offset = page.next_offset(&search);
 
-    let limit = match fields.get("limit") {
-        Some(l) => l.parse::<i64>().map_err(CompassError::InvalidNumberError)?,
-        None => 100,
+    let did_you_mean = if page.data.is_empty() && search.offset == 0 {
+        spelling_suggestions(client, schema, fields)?
+    } else {
+        None
     };
 
-    let offset = match fields.get("offset") {
-        Some(l) => l.parse::<i64>().map_err(CompassError::InvalidNumberError)?,
-        None => 0,
+    let stats = if collect_stats {
+        let finished = Instant::now();
+        Some(QueryStats {
+            plan_ms: millis(planned - started),
+            prepare_ms: millis(prepared - planned),
+            execute_ms: millis(executed - prepared),
+            fetch_ms: millis((fetched - executed).saturating_sub(page.converting)),
+            convert_ms: millis(page.converting),
+            total_ms: millis(finished - started),
+            rows: page.data.len(),
+        })
+    } else {
+        None
+    };
+
+    Ok(SearchResponse {
+        data: page.data,
+        meta: SearchMeta {
+            ignored_params: search.ignored_params,
+            total,
+            did_you_mean,
+            next_cursor,
+            truncated: page.truncated,
+            next_offset,
+        },
+        stats,
+    })
+}
+
+pub fn json_count<C: Connection>(
+    client: &mut C,
+    schema: &Schema,
+    fields: &HashMap<String, String>,
+) -> Result<i64, CompassError> {
+    let client = &mut throttle_permit(client, schema)?;
+    count_matching(
+        client,
+        schema,
+        &prepare_params(schema, fields)?,
+        None,
+        &|_| Ok(()),
+    )
+}
+
+// the doc_ids of the page a search would return, in its order, without reading the documents. joins,
+// windows, snippets and cursors don't change which documents are on a page, so they're not looked at
+pub fn json_search_ids<C: Connection>(
+    client: &mut C,
+    schema: &Schema,
+    fields: &HashMap<String, String>,
+) -> Result<Vec<Uuid>, CompassError> {
+    let client = &mut throttle_permit(client, schema)?;
+    let fields = &*prepare_params(schema, fields)?;
+
+    let mut plan = plan_where(schema, fields, 5, false)?;
+    collapse_column(schema, fields, &mut plan)?;
+    let param_types = plan.param_types(&[
+        PostgresType::TEXT,
+        PostgresType::TEXT_ARRAY,
+        PostgresType::INT8,
+        PostgresType::INT8,
+    ]);
+    let query = format!(
+        "SELECT doc_id FROM {} {} {}",
+        schema.table, plan.where_clause, plan.order_clause
+    );
+
+    let sort_by: Vec<String> = match sort_key(schema, fields)? {
+        SortKey::DocId | SortKey::Relevance => Vec::new(),
+        SortKey::Path(path) => path,
     };
+    let (limit, offset) = page_bounds(schema, fields)?;
 
-    let params: Vec<&dyn ToSql> = vec![&json_query, &sort_by, &limit, &offset];
+    let statement: Statement = client
+        .prepare_typed(query.as_str(), &param_types)
+        .map_err(pg_error(schema))?;
+    let params: Vec<&dyn ToSql> = vec![&plan.json_query, &sort_by, &limit, &offset];
 
     let rows: Vec<Row> = client
+        .client()
+        .map_err(pg_error(schema))?
         .query_raw(
             &statement,
             params
                 .iter()
                 .copied()
-                .chain(other_bindings.iter().map(|x| &*x as &dyn ToSql))
+                .chain(plan.bindings.iter().map(Binding::as_sql))
                 .collect::<Vec<&dyn ToSql>>(),
         )
-        .map_err(CompassError::PGError)?
+        .map_err(pg_error(schema))?
         .collect()
-        .map_err(CompassError::PGError)?;
+        .map_err(pg_error(schema))?;
 
-    Ok(rows
-        .into_iter()
-        .map(|x| {
-            let mut val = x.get::<usize, Value>(0);
-            for (key, conv) in converters.iter() {
-                if let Some(field) = val.get_mut(key) {
-                    match (conv.from, conv.to) {
-                        (ConvertFrom::DateTimeString, ConvertTo::Timestamp) => {
-                            // convert timestamps back into date-strings
-                            let timest = field.as_i64().unwrap();
-                            let dt = DateTime::<Utc>::from_utc(
-                                NaiveDateTime::from_timestamp(timest, 0),
-                                Utc,
-                            );
-                            *field = json!(dt.to_rfc3339_opts(chrono::SecondsFormat::Millis, true));
-                        }
-                        (ConvertFrom::DateTimeString, ConvertTo::TimestampMillis) => {
-                            let dt = Utc.timestamp_millis(field.as_i64().unwrap());
-                            *field = json!(dt.to_rfc3339_opts(chrono::SecondsFormat::Millis, true));
-                        }
-                        _ => {}
-                    }
-                }
-            }
-            val
-        })
-        .collect())
+    Ok(rows.iter().map(|row| row.get(0)).collect())
 }
 
-pub fn json_count(
-    client: &mut Client,
+// a count's statement: $1 is the jsonpath, then the plan's bindings. `fields` have to be prepared already
+pub(crate) fn count_statement(
     schema: &Schema,
     fields: &HashMap<String, String>,
+    raw_query: Option<String>,
+    extra: ExtraConditions,
+) -> Result<(String, QueryPlan), CompassError> {
+    let mut plan = plan_where(schema, fields, 2, raw_query.is_some())?;
+    extra(&mut plan)?;
+    if let Some(q) = raw_query {
+        plan.json_query = q;
+    }
+    let query = format!(
+        "SELECT COUNT(*) FROM {} {}",
+        schema.table, plan.where_clause
+    );
+    Ok((query, plan))
+}
+
+// count_statement, run. `fields` have to be prepared already
+fn count_matching<C: Connection>(
+    client: &mut C,
+    schema: &Schema,
+    fields: &HashMap<String, String>,
+    raw_query: Option<String>,
+    extra: ExtraConditions,
 ) -> Result<i64, CompassError> {
-    let (query, _, json_query, other_bindings) = generate_where(schema, fields, 2, false)?;
-    let query = format!("SELECT COUNT(*) FROM {} {}", schema.table, query);
+    let (query, plan) = count_statement(schema, fields, raw_query, extra)?;
+    let param_types = plan.param_types(&[PostgresType::TEXT]);
+    let QueryPlan {
+        json_query,
+        bindings: other_bindings,
+        ..
+    } = plan;
+
+    let started = Instant::now();
 
     let statement: Statement = client
-        .prepare_typed(query.as_str(), &[PostgresType::TEXT])
-        .map_err(CompassError::PGError)?;
+        .prepare_typed(query.as_str(), &param_types)
+        .map_err(pg_error(schema))?;
 
     let params: Vec<&dyn ToSql> = vec![&json_query];
 
     let res: Row = client
+        .client()
+        .map_err(pg_error(schema))?
         .query_raw(
             &statement,
             params
                 .iter()
                 .copied()
-                .chain(other_bindings.iter().map(|x| &*x as &dyn ToSql))
+                .chain(other_bindings.iter().map(Binding::as_sql))
                 .collect::<Vec<&dyn ToSql>>(),
         )
-        .map_err(CompassError::PGError)?
-        .next()?
+        .map_err(pg_error(schema))?
+        .next()
+        .map_err(pg_error(schema))?
         .unwrap();
-    res.try_get::<usize, i64>(0).map_err(CompassError::PGError)
+
+    if let Some(ref log) = schema.slow_log {
+        log.record(
+            &schema.table,
+            &query,
+            || {
+                params
+                    .iter()
+                    .map(|p| format!("{:?}", p))
+                    .chain(other_bindings.iter().map(|b| format!("{:?}", b.as_sql())))
+                    .collect()
+            },
+            started.elapsed(),
+        );
+    }
+    record_usage(schema, 0, started.elapsed());
+
+    res.try_get::<usize, i64>(0).map_err(pg_error(schema))
+}
+
+// jsonb's ordering across types: null < string < number < bool < array < object
+fn json_type_rank(v: &Value) -> u8 {
+    match v {
+        Value::Null => 0,
+        Value::String(_) => 1,
+        Value::Number(_) => 2,
+        Value::Bool(_) => 3,
+        Value::Array(_) => 4,
+        Value::Object(_) => 5,
+    }
+}
+
+fn compare_json(a: &Value, b: &Value) -> Ordering {
+    match (a, b) {
+        (Value::String(a), Value::String(b)) => a.cmp(b),
+        (Value::Number(a), Value::Number(b)) => a
+            .as_f64()
+            .partial_cmp(&b.as_f64())
+            .unwrap_or(Ordering::Equal),
+        (Value::Bool(a), Value::Bool(b)) => a.cmp(b),
+        (a, b) => json_type_rank(a).cmp(&json_type_rank(b)),
+    }
+}
+
+// runs the same query against several schemas and merges the results by the sort key, tagging each
+// document with the name of the schema it came from under `_schema`. the filters and `sortby` have to
+// make sense for every schema; documents missing the sort key go where `nulls` (or the first schema) says
+pub fn json_search_multi<C: Connection>(
+    client: &mut C,
+    schemas: &[(&str, &Schema)],
+    fields: &HashMap<String, String>,
+) -> Result<SearchResponse, CompassError> {
+    let first = match schemas.first() {
+        Some((_, schema)) => schema,
+        None => return Ok(SearchResponse::default()),
+    };
+
+    // paging and sorting are read the way a single schema search reads them, after the first schema's
+    // presets and middleware, and every schema gets that same page and sort so the results can be merged
+    let prepared = schemas
+        .iter()
+        .map(|(_, schema)| prepare_params(schema, fields))
+        .collect::<Result<Vec<_>, _>>()?;
+    let (limit, offset) = page_bounds(first, &prepared[0])?;
+    let descending = sort_order(&prepared[0]) == "DESC";
+    let nulls_first = sort_nulls(first, &prepared[0]) == Nulls::First;
+    let sortby = prepared[0].get("sortby").cloned();
+
+    for (_, schema) in schemas.iter() {
+        if limit > schema.limits.max_limit {
+            return Err(CompassError::LimitOutOfRange {
+                value: limit,
+                max: schema.limits.max_limit,
+            });
+        }
+        if limit + offset > schema.limits.max_limit {
+            return Err(CompassError::OffsetOutOfRange {
+                value: offset,
+                max: (schema.limits.max_limit - limit).max(0),
+            });
+        }
+    }
+
+    let mut tagged: Vec<(Option<Value>, Value)> = Vec::new();
+    let mut ignored: Option<Vec<String>> = None;
+    let mut total = 0;
+
+    for ((name, schema), per_schema) in schemas.iter().zip(prepared) {
+        // each schema keeps the filters its own presets and middleware left; the page has to cover the
+        // requested one once merged
+        let mut per_schema = per_schema.into_owned();
+        per_schema.insert("limit".to_owned(), (limit + offset).to_string());
+        per_schema.insert("offset".to_owned(), "0".to_owned());
+        match sortby {
+            Some(ref sortby) => per_schema.insert("sortby".to_owned(), sortby.clone()),
+            None => per_schema.remove("sortby"),
+        };
+        per_schema.insert(
+            "sortorder".to_owned(),
+            if descending { "DESC" } else { "ASC" }.to_owned(),
+        );
+        per_schema.insert(
+            "nulls".to_owned(),
+            if nulls_first { "first" } else { "last" }.to_owned(),
+        );
+
+        // relevance isn't comparable across schemas, and has no key; those results just keep schema order
+        let response =
+            search_prepared::<_, SortedDocument>(client, schema, &per_schema, None, &|_| Ok(()))?;
+        total += response.meta.total.unwrap_or(0);
+
+        // a parameter only counts as ignored if no schema used it
+        ignored = Some(match ignored {
+            None => response.meta.ignored_params,
+            Some(prev) => prev
+                .into_iter()
+                .filter(|p| response.meta.ignored_params.contains(p))
+                .collect(),
+        });
+
+        for SortedDocument { key, mut doc } in response.data {
+            if let Some(obj) = doc.as_object_mut() {
+                obj.insert("_schema".to_owned(), json!(name));
+            }
+            tagged.push((key, doc));
+        }
+    }
+
+    // stable, so ties keep schema order
+    tagged.sort_by(|(a, _), (b, _)| match (a, b) {
+        (Some(a), Some(b)) if descending => compare_json(b, a),
+        (Some(a), Some(b)) => compare_json(a, b),
+        (Some(_), None) if nulls_first => Ordering::Greater,
+        (None, Some(_)) if nulls_first => Ordering::Less,
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => Ordering::Equal,
+    });
+
+    Ok(SearchResponse {
+        data: tagged
+            .into_iter()
+            .skip(offset as usize)
+            .take(limit as usize)
+            .map(|(_, doc)| doc)
+            .collect(),
+        meta: SearchMeta {
+            ignored_params: ignored.unwrap_or_default(),
+            total: if limit == 0 { Some(total) } else { None },
+            did_you_mean: None,
+            next_cursor: None,
+            truncated: false,
+            next_offset: None,
+        },
+        stats: None,
+    })
+}
+
+// documents for a path `a.b` holding `value`, as a containment filter
+pub(crate) fn containing(path: &str, value: Value) -> Value {
+    path.rsplit('.')
+        .fold(value, |inner, key| json!({ key: inner }))
+}
+
+// WHERE (object @> {"playerId": "x"}) OR (object @> {"lineup": ["x"]}) OR ... across the schema's
+// `mentions` fields. a GIN index on object (see migrate) covers every branch
+fn mention_condition(schema: &Schema, entity: &str, plan: &mut QueryPlan) {
+    let mut values = vec![json!(entity)];
+    if let Ok(n) = entity.parse::<i64>() {
+        values.push(json!(n));
+    }
+
+    let mut branches = Vec::new();
+    for path in schema.mentions.iter() {
+        for value in values.iter() {
+            for doc in [
+                containing(path, value.clone()),
+                containing(path, json!([value])),
+            ]
+            .iter()
+            {
+                let param = plan.bind(Binding::Json(doc.clone()));
+                branches.push(format!("object @> {}", param));
+            }
+        }
+    }
+
+    plan.and_where(&format!("({})", branches.join(" OR ")));
+}
+
+// every document that mentions `entity` in any of the schema's `mentions` fields, scalar or array.
+// `fields` can narrow it down further and takes the usual sort/limit/offset
+pub fn json_mentions<C: Connection>(
+    client: &mut C,
+    schema: &Schema,
+    entity: &str,
+    fields: &HashMap<String, String>,
+) -> Result<SearchResponse, CompassError> {
+    if schema.mentions.is_empty() {
+        return Err(CompassError::ConfigError(
+            "schema doesn't declare any `mentions` fields".to_owned(),
+        ));
+    }
+
+    search_response(client, schema, fields, None, &|plan| {
+        mention_condition(schema, entity, plan);
+        Ok(())
+    })
 }
 
-pub fn get_by_ids(
-    client: &mut Client,
+pub fn get_by_ids<C: Connection>(
+    client: &mut C,
     schema: &Schema,
     ids: &Vec<Uuid>,
 ) -> Result<Vec<Value>, CompassError> {
-    // make a table of field -> converter, to see if we need to do any conversions on the results
-    let converters: HashMap<String, ConverterSchema> = schema
-        .fields
-        .iter()
-        .filter_map(|(k, v)| {
-            v.converter.map(|converter| (k.to_owned(), converter))
-        })
-        .collect();
+    let client = &mut throttle_permit(client, schema)?;
+
+    let (scope, tenant) = schema.tenant_scope(2)?;
+    let mut params: Vec<&(dyn ToSql + Sync)> = vec![ids];
+    params.extend(tenant.iter().map(|t| t as &(dyn ToSql + Sync)));
 
     Ok(client
+        .client()
+        .map_err(pg_error(schema))?
         .query(
-            format!("SELECT object FROM {} WHERE doc_id = ANY($1)", schema.table).as_str(),
-            &[ids],
-        )?
+            format!(
+                "SELECT object FROM {} WHERE doc_id = ANY($1){}",
+                schema.table, scope
+            )
+            .as_str(),
+            &params,
+        )
+        .map_err(pg_error(schema))?
         .into_iter()
         .map(|x| {
             let mut val = x.get::<usize, Value>(0);
-            for (key, conv) in converters.iter() {
-                if let Some(field) = val.get_mut(key) {
-                    match (conv.from, conv.to) {
-                        (ConvertFrom::DateTimeString, ConvertTo::Timestamp) => {
-                            // convert timestamps back into date-strings
-                            let timest = field.as_i64().unwrap();
-                            let dt = DateTime::<Utc>::from_utc(
-                                NaiveDateTime::from_timestamp(timest, 0),
-                                Utc,
-                            );
-                            *field = json!(dt.to_rfc3339_opts(chrono::SecondsFormat::Millis, true));
-                        }
-                        (ConvertFrom::DateTimeString, ConvertTo::TimestampMillis) => {
-                            let dt = Utc.timestamp_millis(field.as_i64().unwrap());
-                            *field = json!(dt.to_rfc3339_opts(chrono::SecondsFormat::Millis, true));
-                        }
-                        _ => {}
-                    }
-                }
-            }
+            convert_document(schema, &mut val);
             val
         })
         .collect())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+
+    fn one_field(name: &str, query: FieldQuery, v: &str) -> Result<String, CompassError> {
+        let mut jsonb_filters = Vec::new();
+        let mut other_filters = Vec::new();
+        let mut bindings = Vec::new();
+        generate_one_field(
+            v,
+            (&name.to_owned(), query),
+            None,
+            &mut jsonb_filters,
+            &mut other_filters,
+            &mut bindings,
+            2,
+        )?;
+        assert!(other_filters.is_empty() && bindings.is_empty());
+        Ok(jsonb_filters.remove(0))
+    }
+
+    #[test]
+    fn jsonpath_string_escapes() {
+        assert_eq!(jsonpath_string(r#"a"b"#), r#""a\"b""#);
+        assert_eq!(jsonpath_string(r"a\b"), r#""a\\b""#);
+        assert_eq!(jsonpath_string(r#"\""#), r#""\\\"""#);
+        assert_eq!(
+            jsonpath_string(r#"x" || $.secret == "y"#),
+            r#""x\" || $.secret == \"y""#
+        );
+        assert_eq!(
+            jsonpath_string("line\nfeed\r\ttab\u{0}\u{7}\u{1b}"),
+            r#""line\nfeed\r\ttab\u0000\u0007\u001b""#
+        );
+        assert_eq!(jsonpath_string("ünïcödé ⚾ 野球"), "\"ünïcödé ⚾ 野球\"");
+    }
+
+    #[test]
+    fn hostile_string_values_stay_strings() {
+        assert_eq!(
+            one_field("name", FieldQuery::StringTag, r#"x" || $.secret == "y"#).unwrap(),
+            r#"(($.name == "x\" || $.secret == \"y"))"#
+        );
+        assert_eq!(
+            one_field("name", FieldQuery::StringTag, r#"back\slash\"#).unwrap(),
+            r#"(($.name == "back\\slash\\"))"#
+        );
+        assert_eq!(
+            one_field("name", FieldQuery::StringTag, "bell\u{7}\nnewline").unwrap(),
+            r#"(($.name == "bell\u0007\nnewline"))"#
+        );
+        assert_eq!(
+            one_field("name", FieldQuery::StringTag, "ünïcödé ⚾").unwrap(),
+            "(($.name == \"ünïcödé ⚾\"))"
+        );
+        assert_eq!(
+            one_field("tag", FieldQuery::AmbiguousTag, r#"1" || true || ""#).unwrap(),
+            r#"((($.tag == "1\" || true || \"")))"#
+        );
+    }
+
+    #[test]
+    fn hostile_values_on_typed_fields_are_rejected() {
+        let numeric = FieldQuery::NumericTag {
+            aliases: HashMap::new(),
+        };
+        assert!(one_field("type", numeric, "1 || true").is_err());
+        assert!(one_field("season", FieldQuery::Min, "12) || (true").is_err());
+        assert!(one_field("flag", FieldQuery::Bool, "true || $.secret").is_err());
+    }
+
+    #[test]
+    fn quotes_backslashes_and_parentheses_in_values() {
+        let schema = test_schema();
+        assert_eq!(
+            json_query(
+                &schema,
+                &params(&[("name", r#"O'Brien "the (great)" \ fan"#)])
+            ),
+            r#"((($.name == "O'Brien \"the (great)\" \\ fan")))"#
+        );
+        assert_eq!(
+            json_query(&schema, &params(&[("name", r#"\") || (true"#)])),
+            r#"((($.name == "\\\") || (true")))"#
+        );
+
+        // parentheses inside a string don't count toward limits.max_depth, balanced or not
+        let deep = "(".repeat(schema.limits.max_depth + 8);
+        assert_eq!(
+            json_query(&schema, &params(&[("name", deep.as_str())])),
+            format!("((($.name == \"{}\")))", deep)
+        );
+        assert_eq!(
+            json_query(&schema, &params(&[("name", ")))")])),
+            r#"((($.name == ")))")))"#
+        );
+    }
+
+    #[test]
+    fn jsonpath_depth_skips_strings() {
+        assert_eq!(jsonpath_depth(r#"(($.a == "((((") && ($.b == "\"(("))"#), 2);
+        assert_eq!(jsonpath_depth(r#"($.a == "\\") && ((($.b == 1)))"#), 3);
+    }
+
+    #[test]
+    fn keys_have_to_be_plain_dotted_names() {
+        let schema = test_schema();
+        for key in &[
+            r#"name" || true || "x"#,
+            "name)",
+            "player.na me",
+            "player..id",
+            "player.1st",
+            "",
+        ] {
+            assert!(
+                matches!(
+                    generate_where(&schema, &params(&[(key, "x")]), 2, false),
+                    Err(CompassError::InvalidKey(_))
+                ),
+                "{:?} should be rejected",
+                key
+            );
+        }
+        assert!(
+            generate_where(&schema, &params(&[("player.stats_2.hits", "x")]), 2, false).is_ok()
+        );
+    }
+
+    struct SortBySeason;
+
+    impl QueryMiddleware for SortBySeason {
+        fn before_query(&self, fields: &mut HashMap<String, String>) -> Result<(), CompassError> {
+            fields.insert("sortby".to_owned(), "season".to_owned());
+            Ok(())
+        }
+    }
+
+    struct CapLimit(i64);
+
+    impl QueryMiddleware for CapLimit {
+        fn before_query(&self, fields: &mut HashMap<String, String>) -> Result<(), CompassError> {
+            let limit = fields.get("limit").and_then(|l| l.parse::<i64>().ok());
+            if limit.map_or(true, |l| l > self.0) {
+                fields.insert("limit".to_owned(), self.0.to_string());
+            }
+            Ok(())
+        }
+    }
+
+    #[derive(Default)]
+    struct CountCalls(AtomicUsize);
+
+    impl QueryMiddleware for CountCalls {
+        fn before_query(&self, _: &mut HashMap<String, String>) -> Result<(), CompassError> {
+            self.0.fetch_add(1, AtomicOrdering::SeqCst);
+            Ok(())
+        }
+    }
+
+    // the statement search_response runs for these parameters
+    fn statement(schema: &Schema, fields: &HashMap<String, String>) -> SearchStatement {
+        search_statement::<Value>(
+            schema,
+            &prepare_params(schema, fields).unwrap(),
+            None,
+            &|_| Ok(()),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn middleware_sortby_is_the_one_bound() {
+        let schema = test_schema();
+        assert!(statement(&schema, &params(&[])).sort_by.is_empty());
+
+        schema.hooks.add_middleware(Arc::new(SortBySeason));
+        let search = statement(&schema, &params(&[("sortby", "doc_id")]));
+        assert_eq!(search.sort_by, vec!["season".to_owned()]);
+        assert!(search.query.contains("ORDER BY (object #> $2)"));
+    }
+
+    #[test]
+    fn middleware_limit_is_the_one_used() {
+        let schema = test_schema();
+        schema.hooks.add_middleware(Arc::new(CapLimit(10)));
+        assert_eq!(statement(&schema, &params(&[("limit", "500")])).limit, 10);
+        assert_eq!(statement(&schema, &params(&[("limit", "5")])).limit, 5);
+        assert_eq!(statement(&schema, &params(&[])).limit, 10);
+    }
+
+    #[test]
+    fn middleware_runs_once_per_statement() {
+        let schema = test_schema();
+        let calls = Arc::new(CountCalls::default());
+        schema.hooks.add_middleware(calls.clone());
+
+        statement(&schema, &params(&[("type", "1")]));
+        assert_eq!(calls.0.load(AtomicOrdering::SeqCst), 1);
+
+        let fields = prepare_params(&schema, &params(&[("type", "1")])).unwrap();
+        count_statement(&schema, &fields, None, &|_| Ok(())).unwrap();
+        assert_eq!(calls.0.load(AtomicOrdering::SeqCst), 2);
+    }
+}
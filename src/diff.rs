@@ -0,0 +1,152 @@
+use super::*;
+
+use postgres::types::ToSql;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use uuid::Uuid;
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DiffOp {
+    Added,
+    Removed,
+    Changed,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct DiffEntry {
+    pub path: String, // json pointer, e.g. /lineup/3
+    pub op: DiffOp,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub from: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub to: Option<Value>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct DocumentDiff {
+    pub a: Uuid,
+    pub b: Uuid,
+    pub changes: Vec<DiffEntry>,
+}
+
+fn escape_pointer(key: &str) -> String {
+    key.replace('~', "~0").replace('/', "~1")
+}
+
+fn diff_objects(
+    path: &str,
+    a: &Map<String, Value>,
+    b: &Map<String, Value>,
+    out: &mut Vec<DiffEntry>,
+) {
+    for (key, av) in a.iter() {
+        let child = format!("{}/{}", path, escape_pointer(key));
+        match b.get(key) {
+            Some(bv) => diff_values(&child, av, bv, out),
+            None => out.push(DiffEntry {
+                path: child,
+                op: DiffOp::Removed,
+                from: Some(av.clone()),
+                to: None,
+            }),
+        }
+    }
+
+    for (key, bv) in b.iter() {
+        if !a.contains_key(key) {
+            out.push(DiffEntry {
+                path: format!("{}/{}", path, escape_pointer(key)),
+                op: DiffOp::Added,
+                from: None,
+                to: Some(bv.clone()),
+            });
+        }
+    }
+}
+
+fn diff_values(path: &str, a: &Value, b: &Value, out: &mut Vec<DiffEntry>) {
+    match (a, b) {
+        (Value::Object(a), Value::Object(b)) => diff_objects(path, a, b, out),
+        // element by element; anything past the shorter array shows up as added/removed
+        (Value::Array(a), Value::Array(b)) => {
+            for i in 0..a.len().max(b.len()) {
+                let child = format!("{}/{}", path, i);
+                match (a.get(i), b.get(i)) {
+                    (Some(av), Some(bv)) => diff_values(&child, av, bv, out),
+                    (Some(av), None) => out.push(DiffEntry {
+                        path: child,
+                        op: DiffOp::Removed,
+                        from: Some(av.clone()),
+                        to: None,
+                    }),
+                    (None, Some(bv)) => out.push(DiffEntry {
+                        path: child,
+                        op: DiffOp::Added,
+                        from: None,
+                        to: Some(bv.clone()),
+                    }),
+                    (None, None) => {}
+                }
+            }
+        }
+        (a, b) if a != b => out.push(DiffEntry {
+            path: path.to_owned(),
+            op: DiffOp::Changed,
+            from: Some(a.clone()),
+            to: Some(b.clone()),
+        }),
+        _ => {}
+    }
+}
+
+// every difference between two documents, as json pointer paths. keys are visited in document order
+pub fn diff_documents(a: &Value, b: &Value) -> Vec<DiffEntry> {
+    let mut out = Vec::new();
+    diff_values("", a, b, &mut out);
+    out
+}
+
+// fetches two documents of a schema (converted the same way search results are) and diffs them
+pub fn json_diff<C: Connection>(
+    client: &mut C,
+    schema: &Schema,
+    a: Uuid,
+    b: Uuid,
+) -> Result<DocumentDiff, CompassError> {
+    let client = &mut throttle_permit(client, schema)?;
+
+    let ids = vec![a, b];
+    let (scope, tenant) = schema.tenant_scope(2)?;
+    let mut params: Vec<&(dyn ToSql + Sync)> = vec![&ids];
+    params.extend(tenant.iter().map(|t| t as &(dyn ToSql + Sync)));
+
+    let rows = client
+        .client()
+        .map_err(pg_error(schema))?
+        .query(
+            format!(
+                "SELECT doc_id, object FROM {} WHERE doc_id = ANY($1){}",
+                schema.table, scope
+            )
+            .as_str(),
+            &params,
+        )
+        .map_err(pg_error(schema))?;
+
+    let find = |id: Uuid| -> Result<Value, CompassError> {
+        let row = rows
+            .iter()
+            .find(|r| r.get::<usize, Uuid>(0) == id)
+            .ok_or(CompassError::DocumentNotFound(id))?;
+        let mut doc = row.get::<usize, Value>(1);
+        convert_document(schema, &mut doc);
+        Ok(doc)
+    };
+
+    Ok(DocumentDiff {
+        a,
+        b,
+        changes: diff_documents(&find(a)?, &find(b)?),
+    })
+}
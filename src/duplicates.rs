@@ -0,0 +1,235 @@
+use super::*;
+
+use postgres::fallible_iterator::FallibleIterator;
+use postgres::types::ToSql;
+use postgres::types::Type as PostgresType;
+use postgres::{Client, Row};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use uuid::Uuid;
+
+use std::collections::HashMap;
+
+fn invalid(msg: String) -> CompassError {
+    CompassError::InvalidAggregate(msg)
+}
+
+// what makes two documents the same document, for find_duplicates and resolve_duplicates
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DuplicateQuery {
+    pub keys: Vec<String>, // fields that together identify a document; documents missing any of them are skipped
+    #[serde(default)]
+    pub keep_by: Option<String>, // of each group, the document with the greatest value of this is kept. the smallest doc_id without one
+    #[serde(default = "default_samples")]
+    pub samples: usize, // doc_ids reported per group
+}
+
+fn default_samples() -> usize {
+    3
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct DuplicateGroup {
+    pub key: Vec<Value>, // the keys' values, in the order they were given
+    pub count: i64,
+    pub sample: Vec<Uuid>, // the first is the one resolve_duplicates would keep
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct DuplicateReport {
+    pub groups: Vec<DuplicateGroup>, // the biggest first, up to max_limit of them
+    pub total_groups: i64,
+    pub extra_documents: i64, // how many documents resolving every group would remove
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DuplicatePolicy {
+    Delete, // keep one document of each group, delete the rest
+    Merge, // the same, but top-level fields only the deleted documents had are copied onto the kept one first
+}
+
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct ResolvedDuplicates {
+    pub groups: usize,
+    pub deleted: usize,
+    pub merged: usize, // kept documents that got fields from the ones deleted
+}
+
+// the plan for the documents a duplicate query looks at, the expression for their key, and the order
+// that puts the one to keep first
+fn duplicate_plan(
+    schema: &Schema,
+    query: &DuplicateQuery,
+    fields: &HashMap<String, String>,
+) -> Result<(QueryPlan, String, String), CompassError> {
+    if query.keys.is_empty() {
+        return Err(invalid(
+            "finding duplicates needs at least one key".to_owned(),
+        ));
+    }
+
+    let mut plan = generate_where(schema, fields, 2, false)?;
+
+    let mut keys = Vec::new();
+    for key in query.keys.iter() {
+        let path = field_path(schema, key).map_err(invalid)?;
+        let value = format!("(object #> {})", plan.bind(Binding::TextArray(path)));
+        plan.and_where(&format!("{} IS NOT NULL", value));
+        keys.push(value);
+    }
+    let key = format!("jsonb_build_array({})", keys.join(", "));
+
+    let keep_order = match query.keep_by {
+        Some(ref keep_by) => {
+            let path = field_path(schema, keep_by).map_err(invalid)?;
+            format!(
+                "(object #> {}) DESC NULLS LAST, doc_id",
+                plan.bind(Binding::TextArray(path))
+            )
+        }
+        None => "doc_id".to_owned(),
+    };
+
+    Ok((plan, key, keep_order))
+}
+
+// groups of documents that agree on every key, for cleaning up after an import that ran twice.
+// parameters filter which documents are looked at, as in a search. nothing is changed
+pub fn find_duplicates<C: Connection>(
+    client: &mut C,
+    schema: &Schema,
+    query: &DuplicateQuery,
+    fields: &HashMap<String, String>,
+) -> Result<DuplicateReport, CompassError> {
+    let client = &mut throttle_permit(client, schema)?;
+
+    let (plan, key, keep_order) = duplicate_plan(schema, query, fields)?;
+    let sql = format!(
+        "SELECT jsonb_build_object('key', k, 'count', n, 'sample', to_jsonb(ids[1:{samples}]), \
+         'total_groups', COUNT(*) OVER (), 'extra_documents', SUM(n - 1) OVER ()) FROM (\
+         SELECT {key} AS k, COUNT(*) AS n, array_agg(doc_id ORDER BY {keep_order}) AS ids \
+         FROM {table} {where_clause} GROUP BY 1 HAVING COUNT(*) > 1) groups \
+         ORDER BY n DESC, k LIMIT {limit}",
+        samples = query.samples.max(1),
+        key = key,
+        keep_order = keep_order,
+        table = schema.table,
+        where_clause = plan.where_clause,
+        limit = schema.limits.max_limit
+    );
+
+    let rows = run_plan(client, schema, &sql, &plan)?;
+    let (total_groups, extra_documents) = match rows.first() {
+        Some(row) => (
+            row["total_groups"].as_i64().unwrap_or(0),
+            row["extra_documents"].as_i64().unwrap_or(0),
+        ),
+        None => (0, 0),
+    };
+
+    let groups = rows
+        .into_iter()
+        .map(|row| {
+            Ok(DuplicateGroup {
+                key: serde_json::from_value(row["key"].clone())?,
+                count: row["count"].as_i64().unwrap_or(0),
+                sample: serde_json::from_value(row["sample"].clone())?,
+            })
+        })
+        .collect::<Result<Vec<_>, CompassError>>()?;
+
+    Ok(DuplicateReport {
+        groups,
+        total_groups,
+        extra_documents,
+    })
+}
+
+// keeps one document of every duplicate group (see DuplicateQuery::keep_by) and deletes the others,
+// merging their fields into it first with DuplicatePolicy::Merge. every group among the documents the
+// parameters pick, not just the ones find_duplicates lists, in one transaction; run that first to see
+// what this will do
+pub fn resolve_duplicates(
+    client: &mut Client,
+    schema: &Schema,
+    query: &DuplicateQuery,
+    fields: &HashMap<String, String>,
+    policy: DuplicatePolicy,
+) -> Result<ResolvedDuplicates, CompassError> {
+    let (plan, key, keep_order) = duplicate_plan(schema, query, fields)?;
+    let sql = format!(
+        "SELECT k, doc_id, object FROM (\
+         SELECT {key} AS k, doc_id, object, COUNT(*) OVER (PARTITION BY {key}) AS n, \
+         ROW_NUMBER() OVER (PARTITION BY {key} ORDER BY {keep_order}) AS rn \
+         FROM {table} {where_clause}) ranked WHERE n > 1 ORDER BY k, rn",
+        key = key,
+        keep_order = keep_order,
+        table = schema.table,
+        where_clause = plan.where_clause
+    );
+
+    scope_session(client, schema)?;
+    let mut transaction = client.transaction().map_err(pg_error(schema))?;
+
+    let statement = transaction
+        .prepare_typed(&sql, &plan.param_types(&[PostgresType::TEXT]))
+        .map_err(pg_error(schema))?;
+    let params: Vec<&dyn ToSql> = std::iter::once(&plan.json_query as &dyn ToSql)
+        .chain(plan.bindings.iter().map(Binding::as_sql))
+        .collect();
+    let rows: Vec<Row> = transaction
+        .query_raw(&statement, params.iter().copied())
+        .map_err(pg_error(schema))?
+        .collect()
+        .map_err(pg_error(schema))?;
+
+    let mut resolved = ResolvedDuplicates::default();
+    let mut deleted: Vec<Uuid> = Vec::new();
+    let mut i = 0;
+    while i < rows.len() {
+        let group: Value = rows[i].get(0);
+        let kept: Uuid = rows[i].get(1);
+        let mut object: Value = rows[i].get(2);
+        let mut merged = false;
+        i += 1;
+
+        while i < rows.len() && rows[i].get::<usize, Value>(0) == group {
+            deleted.push(rows[i].get(1));
+            if policy == DuplicatePolicy::Merge {
+                if let (Some(into), Value::Object(other)) =
+                    (object.as_object_mut(), rows[i].get::<usize, Value>(2))
+                {
+                    for (field, value) in other {
+                        if !into.contains_key(&field) {
+                            into.insert(field, value);
+                            merged = true;
+                        }
+                    }
+                }
+            }
+            i += 1;
+        }
+
+        if merged {
+            transaction
+                .execute(
+                    format!("UPDATE {} SET object = $1 WHERE doc_id = $2", schema.table).as_str(),
+                    &[&object, &kept],
+                )
+                .map_err(pg_error(schema))?;
+            resolved.merged += 1;
+        }
+        resolved.groups += 1;
+    }
+
+    resolved.deleted = transaction
+        .execute(
+            format!("DELETE FROM {} WHERE doc_id = ANY($1)", schema.table).as_str(),
+            &[&deleted],
+        )
+        .map_err(pg_error(schema))? as usize;
+
+    transaction.commit().map_err(pg_error(schema))?;
+    Ok(resolved)
+}
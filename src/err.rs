@@ -1,6 +1,8 @@
 use postgres::error::Error as PGError;
 use serde_json::error::Error as SerdeError;
+use serde_yaml::Error as YAMLSerdeError;
 use std::fmt;
+use std::io::Error as IOErr;
 use std::num::ParseIntError;
 use std::str::ParseBoolError;
 
@@ -11,6 +13,62 @@ pub enum CompassError {
     JSONError(SerdeError),
     InvalidNumberError(ParseIntError),
     InvalidBoolError(ParseBoolError),
+    ConfigError(String),
+    IOError(IOErr),
+    YAMLError(YAMLSerdeError),
+    TOMLError(toml::de::Error),
+    ShuttingDown,
+    InvalidKey(String),
+    InvalidSortField(String),
+    InvalidCollation(String),
+    QueryTooComplex(String),
+    UnknownField(String),
+    NumberOutOfRange(String),
+    ConversionError(String),
+    LimitOutOfRange {
+        value: i64,
+        max: i64,
+    },
+    OffsetOutOfRange {
+        value: i64,
+        max: i64,
+    },
+    InvalidFulltextQuery(String),
+    SchemaMismatch(String),
+    NestedPathTooDeep {
+        path: String,
+        depth: usize,
+        max: usize,
+    },
+    TooManyNestedFields {
+        count: usize,
+        max: usize,
+    },
+    RawQueryRejected(String),
+    Overloaded {
+        retry_after_secs: u64,
+    },
+    PoolTimeout(String),
+    InvalidPipeline(String),
+    UnknownJoin(String),
+    InvalidAggregate(String),
+    DocumentNotFound(uuid::Uuid),
+    InvalidCursor(String),
+    InvalidQuerySyntax(String),
+    UnsupportedEsQuery(String),
+    InvalidFilterDocument(String),
+    InvalidODataQuery(String),
+    SqlRejected(String),
+    EncodingError(String),
+    WebhookNotFound(uuid::Uuid),
+    InvalidWebhook(String),
+    TenantRequired,
+    InvalidSnapshot(String),
+    InvalidPreset(String),
+    InvalidAnnotation(String),
+    InvalidIdempotencyKey(String),
+    IdempotencyKeyReused(String),
+    QueryRejected(String), // for QueryMiddleware to turn a query down with
 }
 
 impl std::error::Error for CompassError {}
@@ -39,6 +97,24 @@ impl From<ParseBoolError> for CompassError {
     }
 }
 
+impl From<IOErr> for CompassError {
+    fn from(err: IOErr) -> CompassError {
+        CompassError::IOError(err)
+    }
+}
+
+impl From<YAMLSerdeError> for CompassError {
+    fn from(err: YAMLSerdeError) -> CompassError {
+        CompassError::YAMLError(err)
+    }
+}
+
+impl From<toml::de::Error> for CompassError {
+    fn from(err: toml::de::Error) -> CompassError {
+        CompassError::TOMLError(err)
+    }
+}
+
 impl fmt::Display for CompassError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{:?}", self)
@@ -94,6 +170,327 @@ impl<'r> Responder<'r, 'static> for CompassError {
                     .sized_body(r_text.len(), Cursor::new(r_text))
                     .ok()
             }
+            ConfigError(ref msg) => {
+                let r_text = msg.clone();
+                Response::build()
+                    .status(Status::InternalServerError)
+                    .sized_body(r_text.len(), Cursor::new(r_text))
+                    .ok()
+            }
+            IOError(ref err) => {
+                let r_text = err.to_string();
+                Response::build()
+                    .status(Status::InternalServerError)
+                    .sized_body(r_text.len(), Cursor::new(r_text))
+                    .ok()
+            }
+            YAMLError(ref err) => {
+                let r_text = err.to_string();
+                Response::build()
+                    .status(Status::InternalServerError)
+                    .sized_body(r_text.len(), Cursor::new(r_text))
+                    .ok()
+            }
+            TOMLError(ref err) => {
+                let r_text = err.to_string();
+                Response::build()
+                    .status(Status::InternalServerError)
+                    .sized_body(r_text.len(), Cursor::new(r_text))
+                    .ok()
+            }
+            InvalidKey(ref key) => {
+                let r_text = format!(
+                    "invalid query parameter name '{}': expected dot-separated identifiers",
+                    key
+                );
+                Response::build()
+                    .status(Status::BadRequest)
+                    .sized_body(r_text.len(), Cursor::new(r_text))
+                    .ok()
+            }
+            InvalidSortField(ref name) => {
+                let r_text = format!("can't sort by '{}': not a sortable field", name);
+                Response::build()
+                    .status(Status::BadRequest)
+                    .sized_body(r_text.len(), Cursor::new(r_text))
+                    .ok()
+            }
+            InvalidCollation(ref msg) => {
+                let r_text = format!("invalid collation: {}", msg);
+                Response::build()
+                    .status(Status::BadRequest)
+                    .sized_body(r_text.len(), Cursor::new(r_text))
+                    .ok()
+            }
+            QueryTooComplex(ref msg) => {
+                let r_text = format!("query too complex: {}", msg);
+                Response::build()
+                    .status(Status::BadRequest)
+                    .sized_body(r_text.len(), Cursor::new(r_text))
+                    .ok()
+            }
+            UnknownField(ref name) => {
+                let r_text = format!("unknown query parameter '{}'", name);
+                Response::build()
+                    .status(Status::BadRequest)
+                    .sized_body(r_text.len(), Cursor::new(r_text))
+                    .ok()
+            }
+            NumberOutOfRange(ref value) => {
+                let r_text = format!(
+                    "number '{}' is out of range; accepted values are {} to {}",
+                    value,
+                    i128::MIN,
+                    i128::MAX
+                );
+                Response::build()
+                    .status(Status::BadRequest)
+                    .sized_body(r_text.len(), Cursor::new(r_text))
+                    .ok()
+            }
+            ConversionError(ref msg) => {
+                let r_text = msg.clone();
+                Response::build()
+                    .status(Status::BadRequest)
+                    .sized_body(r_text.len(), Cursor::new(r_text))
+                    .ok()
+            }
+            LimitOutOfRange { value, max } => {
+                let r_text = format!(
+                    "limit {} is out of range; it has to be between 0 and {}",
+                    value, max
+                );
+                Response::build()
+                    .status(Status::BadRequest)
+                    .sized_body(r_text.len(), Cursor::new(r_text))
+                    .ok()
+            }
+            OffsetOutOfRange { value, max } => {
+                let r_text = if value < 0 {
+                    format!("offset {} is out of range; it can't be negative", value)
+                } else {
+                    format!(
+                        "offset {} is past the maximum of {}; to page further, use cursor pagination (cursor=) or filter on the sort field instead (e.g. created_min=<last value seen>)",
+                        value, max
+                    )
+                };
+                Response::build()
+                    .status(Status::BadRequest)
+                    .sized_body(r_text.len(), Cursor::new(r_text))
+                    .ok()
+            }
+            InvalidFulltextQuery(ref token) => {
+                let r_text = format!("couldn't parse fulltext query near: {}", token);
+                Response::build()
+                    .status(Status::BadRequest)
+                    .sized_body(r_text.len(), Cursor::new(r_text))
+                    .ok()
+            }
+            SchemaMismatch(ref msg) => {
+                let r_text = format!("schema doesn't match the database: {}", msg);
+                Response::build()
+                    .status(Status::InternalServerError)
+                    .sized_body(r_text.len(), Cursor::new(r_text))
+                    .ok()
+            }
+            NestedPathTooDeep {
+                ref path,
+                depth,
+                max,
+            } => {
+                let r_text = format!(
+                    "'{}' is {} levels deep; nested keys can go at most {} levels",
+                    path, depth, max
+                );
+                Response::build()
+                    .status(Status::BadRequest)
+                    .sized_body(r_text.len(), Cursor::new(r_text))
+                    .ok()
+            }
+            TooManyNestedFields { count, max } => {
+                let r_text = format!(
+                    "query filters on {} nested paths, the limit is {}",
+                    count, max
+                );
+                Response::build()
+                    .status(Status::BadRequest)
+                    .sized_body(r_text.len(), Cursor::new(r_text))
+                    .ok()
+            }
+            RawQueryRejected(ref msg) => {
+                let r_text = format!("raw query rejected: {}", msg);
+                Response::build()
+                    .status(Status::BadRequest)
+                    .sized_body(r_text.len(), Cursor::new(r_text))
+                    .ok()
+            }
+            Overloaded { retry_after_secs } => {
+                let r_text = "too many queries against this schema right now, try again later";
+                Response::build()
+                    .status(Status::ServiceUnavailable)
+                    .raw_header("Retry-After", retry_after_secs.to_string())
+                    .sized_body(r_text.len(), Cursor::new(r_text))
+                    .ok()
+            }
+            PoolTimeout(ref msg) => {
+                let r_text = format!("no database connection free: {}", msg);
+                Response::build()
+                    .status(Status::ServiceUnavailable)
+                    .raw_header("Retry-After", "1")
+                    .sized_body(r_text.len(), Cursor::new(r_text))
+                    .ok()
+            }
+            InvalidPipeline(ref msg) => {
+                let r_text = format!("invalid pipeline: {}", msg);
+                Response::build()
+                    .status(Status::BadRequest)
+                    .sized_body(r_text.len(), Cursor::new(r_text))
+                    .ok()
+            }
+            UnknownJoin(ref name) => {
+                let r_text = format!("unknown join '{}'", name);
+                Response::build()
+                    .status(Status::BadRequest)
+                    .sized_body(r_text.len(), Cursor::new(r_text))
+                    .ok()
+            }
+            InvalidAggregate(ref msg) => {
+                let r_text = format!("invalid aggregate query: {}", msg);
+                Response::build()
+                    .status(Status::BadRequest)
+                    .sized_body(r_text.len(), Cursor::new(r_text))
+                    .ok()
+            }
+            DocumentNotFound(id) => {
+                let r_text = format!("no document with id {}", id);
+                Response::build()
+                    .status(Status::NotFound)
+                    .sized_body(r_text.len(), Cursor::new(r_text))
+                    .ok()
+            }
+            InvalidCursor(ref msg) => {
+                let r_text = format!("invalid cursor: {}", msg);
+                Response::build()
+                    .status(Status::BadRequest)
+                    .sized_body(r_text.len(), Cursor::new(r_text))
+                    .ok()
+            }
+            InvalidQuerySyntax(ref msg) => {
+                let r_text = format!("couldn't parse q=: {}", msg);
+                Response::build()
+                    .status(Status::BadRequest)
+                    .sized_body(r_text.len(), Cursor::new(r_text))
+                    .ok()
+            }
+            UnsupportedEsQuery(ref msg) => {
+                let r_text = format!("unsupported elasticsearch query: {}", msg);
+                Response::build()
+                    .status(Status::BadRequest)
+                    .sized_body(r_text.len(), Cursor::new(r_text))
+                    .ok()
+            }
+            InvalidFilterDocument(ref msg) => {
+                let r_text = format!("unsupported filter document: {}", msg);
+                Response::build()
+                    .status(Status::BadRequest)
+                    .sized_body(r_text.len(), Cursor::new(r_text))
+                    .ok()
+            }
+            InvalidODataQuery(ref msg) => {
+                let r_text = format!("unsupported odata query: {}", msg);
+                Response::build()
+                    .status(Status::BadRequest)
+                    .sized_body(r_text.len(), Cursor::new(r_text))
+                    .ok()
+            }
+            SqlRejected(ref msg) => {
+                let r_text = format!("sql rejected: {}", msg);
+                Response::build()
+                    .status(Status::BadRequest)
+                    .sized_body(r_text.len(), Cursor::new(r_text))
+                    .ok()
+            }
+            EncodingError(ref msg) => {
+                let r_text = format!("couldn't encode the response: {}", msg);
+                Response::build()
+                    .status(Status::InternalServerError)
+                    .sized_body(r_text.len(), Cursor::new(r_text))
+                    .ok()
+            }
+            WebhookNotFound(id) => {
+                let r_text = format!("no webhook with id {}", id);
+                Response::build()
+                    .status(Status::NotFound)
+                    .sized_body(r_text.len(), Cursor::new(r_text))
+                    .ok()
+            }
+            InvalidWebhook(ref msg) => {
+                let r_text = format!("invalid webhook: {}", msg);
+                Response::build()
+                    .status(Status::BadRequest)
+                    .sized_body(r_text.len(), Cursor::new(r_text))
+                    .ok()
+            }
+            TenantRequired => {
+                let r_text =
+                    "this schema is scoped by tenant; the request needs a key that belongs to one";
+                Response::build()
+                    .status(Status::Unauthorized)
+                    .sized_body(r_text.len(), Cursor::new(r_text))
+                    .ok()
+            }
+            InvalidSnapshot(ref msg) => {
+                let r_text = format!("invalid snapshot: {}", msg);
+                Response::build()
+                    .status(Status::BadRequest)
+                    .sized_body(r_text.len(), Cursor::new(r_text))
+                    .ok()
+            }
+            InvalidPreset(ref msg) => {
+                let r_text = format!("invalid preset: {}", msg);
+                Response::build()
+                    .status(Status::BadRequest)
+                    .sized_body(r_text.len(), Cursor::new(r_text))
+                    .ok()
+            }
+            InvalidAnnotation(ref msg) => {
+                let r_text = format!("invalid annotation: {}", msg);
+                Response::build()
+                    .status(Status::BadRequest)
+                    .sized_body(r_text.len(), Cursor::new(r_text))
+                    .ok()
+            }
+            InvalidIdempotencyKey(ref msg) => {
+                let r_text = format!("invalid idempotency key: {}", msg);
+                Response::build()
+                    .status(Status::BadRequest)
+                    .sized_body(r_text.len(), Cursor::new(r_text))
+                    .ok()
+            }
+            IdempotencyKeyReused(ref key) => {
+                let r_text = format!(
+                    "idempotency key '{}' was already used for a different request",
+                    key
+                );
+                Response::build()
+                    .status(Status::UnprocessableEntity)
+                    .sized_body(r_text.len(), Cursor::new(r_text))
+                    .ok()
+            }
+            QueryRejected(ref msg) => {
+                let r_text = format!("query rejected: {}", msg);
+                Response::build()
+                    .status(Status::BadRequest)
+                    .sized_body(r_text.len(), Cursor::new(r_text))
+                    .ok()
+            }
+            ShuttingDown => {
+                let r_text = "server is shutting down";
+                Response::build()
+                    .status(Status::ServiceUnavailable)
+                    .sized_body(r_text.len(), Cursor::new(r_text))
+                    .ok()
+            }
         }
     }
 }
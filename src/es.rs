@@ -0,0 +1,349 @@
+use super::*;
+
+use serde_json::{json, Map, Value};
+
+use std::collections::HashMap;
+use std::time::Instant;
+
+// enough of elasticsearch's `_search` body for existing clients and dashboards to point at compass:
+//   query: match_all, term, terms, match, match_phrase, range, and bool (must/filter/must_not, plus
+//          should when every should clause is on the same field)
+//   from, size, sort (one field, with `missing` as _first or _last), _source (a list of fields)
+// it's translated into the usual query parameters, so whatever those can't say isn't supported here
+fn unsupported(msg: &str) -> CompassError {
+    CompassError::UnsupportedEsQuery(msg.to_owned())
+}
+
+// term values are json; parameters are text
+pub(crate) fn param_value(v: &Value) -> Result<String, CompassError> {
+    match v {
+        Value::String(s) => Ok(s.clone()),
+        Value::Number(n) => Ok(n.to_string()),
+        Value::Bool(b) => Ok(b.to_string()),
+        _ => Err(unsupported(
+            "term values have to be strings, numbers or booleans",
+        )),
+    }
+}
+
+// `{"field": ...}` queries name exactly one field
+fn single_field<'a>(kind: &str, body: &'a Value) -> Result<(&'a String, &'a Value), CompassError> {
+    match body.as_object() {
+        Some(map) if map.len() == 1 => Ok(map.iter().next().unwrap()),
+        _ => Err(unsupported(&format!("{} takes exactly one field", kind))),
+    }
+}
+
+// `{"field": "x"}` or `{"field": {"value": "x"}}` (`query` for match queries)
+fn leaf_value<'a>(v: &'a Value, key: &str) -> &'a Value {
+    v.get(key).unwrap_or(v)
+}
+
+#[derive(Debug)]
+struct Translated {
+    key: String,
+    value: String,
+}
+
+// one leaf query as the parameters it stands for. `should`/`must_not` need to know the field, so this
+// doesn't add them to the parameter map itself
+fn translate_leaf(
+    schema: &Schema,
+    kind: &str,
+    body: &Value,
+) -> Result<Vec<Translated>, CompassError> {
+    let (field, v) = single_field(kind, body)?;
+    let one = |value: String| -> Result<Vec<Translated>, CompassError> {
+        Ok(vec![Translated {
+            key: field.clone(),
+            value,
+        }])
+    };
+
+    match kind {
+        "term" => one(param_value(leaf_value(v, "value"))?),
+        "terms" => {
+            let values = v
+                .as_array()
+                .ok_or_else(|| unsupported("terms takes a list of values"))?
+                .iter()
+                .map(param_value)
+                .collect::<Result<Vec<_>, _>>()?;
+            if values.is_empty() {
+                return Err(unsupported("terms needs at least one value"));
+            }
+            one(values.join("_or_"))
+        }
+        "match" => one(param_value(leaf_value(v, "query"))?),
+        "match_phrase" => {
+            let phrase = param_value(leaf_value(v, "query"))?;
+            match schema.resolve_field(field) {
+                Some((
+                    _,
+                    FieldQuery::Fulltext {
+                        syntax: FulltextSyntax::WebSearch,
+                        ..
+                    },
+                )) => one(format!("\"{}\"", phrase)),
+                _ => one(phrase),
+            }
+        }
+        "range" => {
+            let (min, max) = match schema.resolve_field(field) {
+                Some((_, FieldQuery::Range { min, max, .. })) => (min, max),
+                _ => return Err(unsupported(&format!("{} isn't a range field", field))),
+            };
+            // min and max are strict, so gte and lte step one past their value
+            let bound =
+                |key: &str, inclusive: bool, lower: bool| -> Result<Option<String>, CompassError> {
+                    let value = match v.get(key) {
+                        Some(value) => param_value(value)?,
+                        None => return Ok(None),
+                    };
+                    strict_bound(&value, inclusive, lower)
+                        .map(Some)
+                        .ok_or_else(|| {
+                            unsupported("gte and lte only work on whole numbers, use gt or lt")
+                        })
+                };
+
+            let mut translated = Vec::new();
+            for (from, inclusive, lower) in [
+                ("gte", true, true),
+                ("gt", false, true),
+                ("lte", true, false),
+                ("lt", false, false),
+            ]
+            .iter()
+            {
+                if let Some(value) = bound(*from, *inclusive, *lower)? {
+                    translated.push(Translated {
+                        key: if *lower { min.clone() } else { max.clone() },
+                        value,
+                    });
+                }
+            }
+            Ok(translated)
+        }
+        _ => Err(unsupported(&format!("{} queries", kind))),
+    }
+}
+
+fn translate(
+    schema: &Schema,
+    query: &Value,
+    negated: bool,
+    params: &mut HashMap<String, String>,
+) -> Result<(), CompassError> {
+    let (kind, body) = single_field("a query", query)?;
+
+    match kind.as_str() {
+        "match_all" if !negated => Ok(()),
+        "bool" => {
+            let clauses = |key: &str| -> Vec<&Value> {
+                match body.get(key) {
+                    Some(Value::Array(clauses)) => clauses.iter().collect(),
+                    Some(clause) => vec![clause],
+                    None => Vec::new(),
+                }
+            };
+            let nested = ["must", "filter", "must_not"]
+                .iter()
+                .any(|key| !clauses(key).is_empty());
+            if negated && nested {
+                return Err(unsupported(
+                    "a bool inside must_not can only hold should clauses",
+                ));
+            }
+
+            // scoring doesn't exist here, so must and filter are the same thing
+            for clause in clauses("must").into_iter().chain(clauses("filter")) {
+                translate(schema, clause, false, params)?;
+            }
+            for clause in clauses("must_not") {
+                translate(schema, clause, true, params)?;
+            }
+
+            let should = clauses("should");
+            if should.is_empty() {
+                return Ok(());
+            }
+            // one of several values of one field is a single `_or_` parameter; anything else isn't
+            let mut alternatives: Vec<Translated> = Vec::new();
+            for clause in should {
+                let (kind, body) = single_field("a query", clause)?;
+                let mut leaf = translate_leaf(schema, kind, body)?;
+                if leaf.len() != 1 || kind == "range" {
+                    return Err(unsupported(
+                        "should clauses have to be term, terms or match queries",
+                    ));
+                }
+                alternatives.push(leaf.remove(0));
+            }
+            if alternatives.iter().any(|t| t.key != alternatives[0].key) {
+                return Err(unsupported("should clauses have to be on the same field"));
+            }
+            let key = alternatives[0].key.clone();
+            let value = alternatives
+                .into_iter()
+                .map(|t| t.value)
+                .collect::<Vec<_>>()
+                .join("_or_");
+            add(params, key, value, negated);
+            Ok(())
+        }
+        "range" if negated => Err(unsupported("must_not can't hold a range")),
+        _ => {
+            for t in translate_leaf(schema, kind, body)? {
+                add(params, t.key, t.value, negated);
+            }
+            Ok(())
+        }
+    }
+}
+
+fn add(params: &mut HashMap<String, String>, key: String, value: String, negated: bool) {
+    let key = if negated { format!("{}!", key) } else { key };
+    and_param(params, key, value);
+}
+
+// the query parameters an elasticsearch `_search` body stands for
+pub fn es_params(schema: &Schema, body: &Value) -> Result<HashMap<String, String>, CompassError> {
+    let mut params = HashMap::new();
+
+    if let Some(query) = body.get("query") {
+        translate(schema, query, false, &mut params)?;
+    }
+
+    if let Some(from) = body.get("from") {
+        params.insert("offset".to_owned(), param_value(from)?);
+    }
+    if let Some(size) = body.get("size") {
+        params.insert("limit".to_owned(), param_value(size)?);
+    }
+
+    // ["field"], [{"field": "desc"}] or [{"field": {"order": "desc", "missing": "_first"}}]
+    let sort: Vec<&Value> = match body.get("sort") {
+        Some(Value::Array(sorts)) => sorts.iter().collect(),
+        Some(sort) => vec![sort],
+        None => Vec::new(),
+    };
+    match sort.as_slice() {
+        [] => {}
+        [Value::String(field)] => {
+            params.insert("sortby".to_owned(), field.clone());
+        }
+        [sort] => {
+            let (field, order) = single_field("a sort", sort)?;
+            let order = match leaf_value(order, "order") {
+                Value::String(order) => order.clone(),
+                _ => return Err(unsupported("sort orders are \"asc\" or \"desc\"")),
+            };
+            params.insert("sortby".to_owned(), field.clone());
+            params.insert("sortorder".to_owned(), order);
+            match sort.get(field).and_then(|s| s.get("missing")) {
+                None => {}
+                Some(Value::String(m)) if m == "_first" || m == "_last" => {
+                    params.insert("nulls".to_owned(), m[1..].to_owned());
+                }
+                Some(_) => return Err(unsupported("sort missing is \"_first\" or \"_last\"")),
+            }
+        }
+        _ => return Err(unsupported("sorting by more than one field")),
+    }
+
+    match body.get("_source") {
+        None | Some(Value::Bool(true)) => {}
+        Some(Value::Array(fields)) => {
+            let fields = fields
+                .iter()
+                .map(param_value)
+                .collect::<Result<Vec<_>, _>>()?;
+            params.insert("fields".to_owned(), fields.join(","));
+        }
+        Some(_) => return Err(unsupported("_source has to be a list of fields")),
+    }
+
+    Ok(params)
+}
+
+// runs an elasticsearch `_search` body and answers the way elasticsearch would, with the documents
+// under hits.hits[]._source. `name` goes in each hit's _index
+pub fn json_es_search<C: Connection>(
+    client: &mut C,
+    schema: &Schema,
+    name: &str,
+    body: &Value,
+) -> Result<Value, CompassError> {
+    let started = Instant::now();
+    let mut params = es_params(schema, body)?;
+    params.insert("with_total".to_owned(), "true".to_owned());
+
+    let response = json_search_response(client, schema, &params, None)?;
+    let hits: Vec<Value> = response
+        .data
+        .into_iter()
+        .map(|doc| {
+            let mut hit = Map::new();
+            hit.insert("_index".to_owned(), json!(name));
+            hit.insert("_score".to_owned(), Value::Null);
+            hit.insert("_source".to_owned(), doc);
+            Value::Object(hit)
+        })
+        .collect();
+
+    Ok(json!({
+        "took": started.elapsed().as_millis() as u64,
+        "timed_out": false,
+        "hits": {
+            "total": { "value": response.meta.total.unwrap_or(0), "relation": "eq" },
+            "max_score": null,
+            "hits": hits,
+        },
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    fn compiled(body: Value) -> String {
+        let schema = test_schema();
+        json_query(&schema, &es_params(&schema, &body).unwrap())
+    }
+
+    fn range(bounds: Value) -> Value {
+        json!({"query": {"range": {"season": bounds}}})
+    }
+
+    #[test]
+    fn range_operators() {
+        assert_eq!(
+            compiled(range(json!({"gte": 12}))),
+            format!("(({}))", season_above(11))
+        );
+        assert_eq!(
+            compiled(range(json!({"gt": 12}))),
+            format!("(({}))", season_above(12))
+        );
+        assert_eq!(
+            compiled(range(json!({"lte": 15}))),
+            format!("(({}))", season_below(16))
+        );
+        assert_eq!(
+            compiled(range(json!({"lt": 15}))),
+            format!("(({}))", season_below(15))
+        );
+        assert_eq!(
+            compiled(range(json!({"gte": 12, "lte": 15}))),
+            format!("(({}) && ({}))", season_below(16), season_above(11))
+        );
+    }
+
+    #[test]
+    fn inclusive_bounds_need_whole_numbers() {
+        let schema = test_schema();
+        assert!(es_params(&schema, &range(json!({"gte": "2021-07-01"}))).is_err());
+    }
+}
@@ -0,0 +1,151 @@
+use super::*;
+
+use postgres::fallible_iterator::FallibleIterator;
+use postgres::types::ToSql;
+use postgres::types::Type as PostgresType;
+use serde_json::Value;
+
+#[cfg(feature = "parquet_support")]
+use super::columnar::{arrow_schema, columns, record_batch};
+#[cfg(feature = "parquet_support")]
+use parquet::arrow::ArrowWriter;
+
+use std::collections::HashMap;
+use std::io::Write;
+
+// an export is for building datasets, so it can go well past a search's max_limit
+const MAX_PER_VALUE: i64 = 100_000;
+
+// an export is written out this many documents at a time as a parquet row group
+#[cfg(feature = "parquet_support")]
+const PARQUET_BATCH: usize = 8192;
+
+// writes a balanced sample as NDJSON, one document per line: up to `per_value` documents for each
+// value of `stratify`, e.g. `stratify=eventType&per_value=1000`. which documents get picked depends on
+// `seed` (any string, default empty), so the same seed gives the same dataset. other parameters filter
+// as in a search. returns how many documents were written
+pub fn export_stratified<C: Connection, W: Write>(
+    client: &mut C,
+    schema: &Schema,
+    fields: &HashMap<String, String>,
+    out: &mut W,
+) -> Result<usize, CompassError> {
+    let written = stratified_sample(client, schema, fields, |doc| {
+        serde_json::to_writer(&mut *out, &doc)?;
+        out.write_all(b"\n")?;
+        Ok(())
+    })?;
+    out.flush()?;
+
+    Ok(written)
+}
+
+// the same sample as export_stratified, as a parquet file with the columns the flight service sends
+#[cfg(feature = "parquet_support")]
+pub fn export_stratified_parquet<C: Connection, W: Write + Send>(
+    client: &mut C,
+    schema: &Schema,
+    fields: &HashMap<String, String>,
+    out: W,
+) -> Result<usize, CompassError> {
+    let columns = columns(schema);
+    let arrow_schema = arrow_schema(&columns);
+    let mut writer =
+        ArrowWriter::try_new(out, arrow_schema.clone(), None).map_err(parquet_error)?;
+
+    let mut docs = Vec::with_capacity(PARQUET_BATCH);
+    let write_batch = |docs: &mut Vec<Value>, writer: &mut ArrowWriter<W>| {
+        let batch = record_batch(&arrow_schema, &columns, docs).map_err(parquet_error)?;
+        docs.clear();
+        writer.write(&batch).map_err(parquet_error)
+    };
+
+    let written = stratified_sample(client, schema, fields, |doc| {
+        docs.push(doc);
+        if docs.len() == PARQUET_BATCH {
+            write_batch(&mut docs, &mut writer)?;
+        }
+        Ok(())
+    })?;
+    if !docs.is_empty() {
+        write_batch(&mut docs, &mut writer)?;
+    }
+    writer.close().map_err(parquet_error)?;
+
+    Ok(written)
+}
+
+#[cfg(feature = "parquet_support")]
+fn parquet_error<E: std::fmt::Display>(e: E) -> CompassError {
+    CompassError::EncodingError(format!("couldn't write parquet: {}", e))
+}
+
+// runs the sample's query and hands each converted document to `each`, in doc_id order. returns how
+// many there were
+fn stratified_sample<C: Connection, F>(
+    client: &mut C,
+    schema: &Schema,
+    fields: &HashMap<String, String>,
+    mut each: F,
+) -> Result<usize, CompassError>
+where
+    F: FnMut(Value) -> Result<(), CompassError>,
+{
+    let client = &mut throttle_permit(client, schema)?;
+
+    let by = fields.get("stratify").ok_or_else(|| {
+        CompassError::ConversionError("export needs a field to stratify by".to_owned())
+    })?;
+    let path = field_path(schema, by).map_err(CompassError::ConversionError)?;
+    let per_value = match fields.get("per_value") {
+        Some(n) => n.parse::<i64>().map_err(CompassError::InvalidNumberError)?,
+        None => 1000,
+    };
+    if per_value < 1 || per_value > MAX_PER_VALUE {
+        return Err(CompassError::LimitOutOfRange {
+            value: per_value,
+            max: MAX_PER_VALUE,
+        });
+    }
+    let seed = fields.get("seed").cloned().unwrap_or_default();
+
+    let filters = without(fields, &["stratify", "per_value", "seed"]);
+    let mut plan = generate_where(schema, &filters, 2, false)?;
+    let stratum = plan.bind(Binding::TextArray(path));
+    let seed = plan.bind(Binding::Text(seed));
+    let per_value = plan.bind(Binding::Int(per_value));
+
+    let sql = format!(
+        "SELECT object FROM (SELECT object, ROW_NUMBER() OVER (PARTITION BY object #> {stratum} ORDER BY md5(doc_id::text || {seed}), doc_id) AS rn \
+         FROM {table} {where_clause}) sampled WHERE rn <= {per_value} ORDER BY doc_id",
+        stratum = stratum,
+        seed = seed,
+        table = schema.table,
+        where_clause = plan.where_clause,
+        per_value = per_value
+    );
+
+    let statement = client
+        .prepare_typed(&sql, &plan.param_types(&[PostgresType::TEXT]))
+        .map_err(pg_error(schema))?;
+    let params: Vec<&dyn ToSql> = std::iter::once(&plan.json_query as &dyn ToSql)
+        .chain(plan.bindings.iter().map(Binding::as_sql))
+        .collect();
+
+    // streamed, so a big export doesn't have to fit in memory
+    let mut rows = client
+        .client()
+        .map_err(pg_error(schema))?
+        .query_raw(&statement, params.iter().copied())
+        .map_err(pg_error(schema))?;
+
+    let mut written = 0;
+    while let Some(row) = rows.next().map_err(pg_error(schema))? {
+        let mut doc: Value = row.get(0);
+        convert_document(schema, &mut doc);
+        each(doc)?;
+        written += 1;
+    }
+
+    Ok(written)
+}
@@ -0,0 +1,208 @@
+use super::columnar::{arrow_schema, columns, record_batch};
+use super::*;
+
+use arrow_flight::encode::FlightDataEncoderBuilder;
+use arrow_flight::error::FlightError;
+use arrow_flight::flight_service_server::{FlightService, FlightServiceServer};
+use arrow_flight::{
+    Action, ActionType, Criteria, Empty, FlightData, FlightDescriptor, FlightEndpoint, FlightInfo,
+    HandshakeRequest, HandshakeResponse, IpcMessage, PutResult, SchemaAsIpc, SchemaResult, Ticket,
+};
+use arrow_ipc::writer::IpcWriteOptions;
+use futures::stream::{self, BoxStream, StreamExt, TryStreamExt};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Request, Response, Status, Streaming};
+
+use std::convert::TryInto;
+
+// record batches queued ahead of a slow reader
+const BATCH_BUFFER: usize = 2;
+
+// tickets and descriptor commands are a BatchQuery as json: {"schema": "feed", "params": {"type": "54"}}
+fn decode_query(bytes: &[u8]) -> Result<BatchQuery, Status> {
+    serde_json::from_slice(bytes)
+        .map_err(|e| Status::invalid_argument(format!("couldn't read the compass query: {}", e)))
+}
+
+// Arrow Flight for bulk reads: a ticket is a compass query, and DoGet streams every document it matches
+// as record batches, one per page, with a typed column per schema field. GetFlightInfo and GetSchema take
+// the same query as a descriptor command
+#[derive(Debug, Clone)]
+pub struct CompassFlightService {
+    config: ConfigHandle,
+}
+
+impl CompassFlightService {
+    pub fn new(config: ConfigHandle) -> CompassFlightService {
+        CompassFlightService { config }
+    }
+
+    // ready to hand to tonic::transport::Server::add_service
+    pub fn server(self) -> FlightServiceServer<CompassFlightService> {
+        FlightServiceServer::new(self)
+    }
+
+    fn schema(&self, name: &str, key: Option<&str>) -> Result<(Schema, DatabaseConfig), Status> {
+        let loaded = self.config.current();
+        match loaded.schemas.get(name) {
+            Some(schema) => Ok((
+                schema.for_key(key).map_err(status)?,
+                loaded.config.database.clone(),
+            )),
+            None => Err(Status::not_found(format!("unknown schema '{}'", name))),
+        }
+    }
+
+    fn descriptor_query(descriptor: &FlightDescriptor) -> Result<BatchQuery, Status> {
+        if descriptor.cmd.is_empty() {
+            return Err(Status::invalid_argument(
+                "the descriptor's cmd has to be a compass query",
+            ));
+        }
+        decode_query(&descriptor.cmd)
+    }
+}
+
+fn unimplemented<T>(what: &str) -> Result<T, Status> {
+    Err(Status::unimplemented(format!(
+        "compass only serves reads; {} isn't supported",
+        what
+    )))
+}
+
+#[tonic::async_trait]
+impl FlightService for CompassFlightService {
+    type HandshakeStream = BoxStream<'static, Result<HandshakeResponse, Status>>;
+    type ListFlightsStream = BoxStream<'static, Result<FlightInfo, Status>>;
+    type DoGetStream = BoxStream<'static, Result<FlightData, Status>>;
+    type DoPutStream = BoxStream<'static, Result<PutResult, Status>>;
+    type DoActionStream = BoxStream<'static, Result<arrow_flight::Result, Status>>;
+    type ListActionsStream = BoxStream<'static, Result<ActionType, Status>>;
+    type DoExchangeStream = BoxStream<'static, Result<FlightData, Status>>;
+
+    async fn handshake(
+        &self,
+        _request: Request<Streaming<HandshakeRequest>>,
+    ) -> Result<Response<Self::HandshakeStream>, Status> {
+        unimplemented("Handshake")
+    }
+
+    async fn list_flights(
+        &self,
+        _request: Request<Criteria>,
+    ) -> Result<Response<Self::ListFlightsStream>, Status> {
+        unimplemented("ListFlights")
+    }
+
+    async fn get_flight_info(
+        &self,
+        request: Request<FlightDescriptor>,
+    ) -> Result<Response<FlightInfo>, Status> {
+        let key = bearer(&request);
+        let descriptor = request.into_inner();
+        let query = Self::descriptor_query(&descriptor)?;
+        let (schema, database) = self.schema(&query.schema, key.as_deref())?;
+        let arrow_schema = arrow_schema(&columns(&schema));
+
+        let ticket = Ticket {
+            ticket: descriptor.cmd.clone(),
+        };
+        let total = blocking(move || {
+            let mut client = database.connect()?;
+            scope_session(&mut client, &schema)?;
+            json_count(&mut client, &schema, &query.params)
+        })
+        .await?;
+
+        let message: IpcMessage = SchemaAsIpc::new(&arrow_schema, &IpcWriteOptions::default())
+            .try_into()
+            .map_err(|e: arrow_schema::ArrowError| Status::internal(e.to_string()))?;
+        let endpoint = FlightEndpoint {
+            ticket: Some(ticket),
+            location: Vec::new(),
+        };
+        Ok(Response::new(FlightInfo::new(
+            message,
+            Some(descriptor),
+            vec![endpoint],
+            total,
+            -1,
+        )))
+    }
+
+    async fn get_schema(
+        &self,
+        request: Request<FlightDescriptor>,
+    ) -> Result<Response<SchemaResult>, Status> {
+        let query = Self::descriptor_query(request.get_ref())?;
+        let (schema, _) = self.schema(&query.schema, bearer(&request).as_deref())?;
+        let arrow_schema = arrow_schema(&columns(&schema));
+
+        let result: SchemaResult = SchemaAsIpc::new(&arrow_schema, &IpcWriteOptions::default())
+            .try_into()
+            .map_err(|e: arrow_schema::ArrowError| Status::internal(e.to_string()))?;
+        Ok(Response::new(result))
+    }
+
+    async fn do_get(
+        &self,
+        request: Request<Ticket>,
+    ) -> Result<Response<Self::DoGetStream>, Status> {
+        let query = decode_query(&request.get_ref().ticket)?;
+        let (schema, database) = self.schema(&query.schema, bearer(&request).as_deref())?;
+        let columns = columns(&schema);
+        let arrow_schema = arrow_schema(&columns);
+        let mut client = blocking(move || database.connect()).await?;
+
+        let (tx, rx) = mpsc::channel(BATCH_BUFFER);
+        let batch_schema = arrow_schema.clone();
+        tokio::task::spawn_blocking(move || {
+            let sent = scope_session(&mut client, &schema).and_then(|_| {
+                page_through(&schema, &mut client, query.params, |docs| {
+                    // stops once the client has gone away
+                    let batch =
+                        record_batch(&batch_schema, &columns, &docs).map_err(FlightError::from);
+                    tx.blocking_send(batch).is_ok()
+                })
+            });
+            if let Err(e) = sent {
+                let _ = tx.blocking_send(Err(FlightError::Tonic(status(e))));
+            }
+        });
+
+        let stream = FlightDataEncoderBuilder::new()
+            .with_schema(arrow_schema)
+            .build(ReceiverStream::new(rx))
+            .map_err(Status::from);
+        Ok(Response::new(stream.boxed()))
+    }
+
+    async fn do_put(
+        &self,
+        _request: Request<Streaming<FlightData>>,
+    ) -> Result<Response<Self::DoPutStream>, Status> {
+        unimplemented("DoPut")
+    }
+
+    async fn do_action(
+        &self,
+        _request: Request<Action>,
+    ) -> Result<Response<Self::DoActionStream>, Status> {
+        unimplemented("DoAction")
+    }
+
+    async fn list_actions(
+        &self,
+        _request: Request<Empty>,
+    ) -> Result<Response<Self::ListActionsStream>, Status> {
+        Ok(Response::new(stream::empty().boxed()))
+    }
+
+    async fn do_exchange(
+        &self,
+        _request: Request<Streaming<FlightData>>,
+    ) -> Result<Response<Self::DoExchangeStream>, Status> {
+        unimplemented("DoExchange")
+    }
+}
@@ -0,0 +1,116 @@
+use super::*;
+
+use serde::Serialize;
+
+use std::collections::HashMap;
+
+// the wire formats a response can go out in, picked from the Accept header
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Json,
+    MessagePack,
+    Cbor,
+}
+
+impl Format {
+    // the best format an Accept header allows: the highest q, then the earliest listed. json when
+    // nothing we know is listed, since that's what every client can read
+    pub fn negotiate(accept: Option<&str>) -> Format {
+        let mut best = (Format::Json, 0.0f32);
+        for part in accept.unwrap_or("").split(',') {
+            let mut pieces = part.split(';').map(str::trim);
+            let format = match pieces.next().map(|m| m.to_ascii_lowercase()) {
+                Some(ref m) => match Format::from_media_type(m) {
+                    Some(format) => format,
+                    None => continue,
+                },
+                None => continue,
+            };
+            let q = pieces
+                .find_map(|p| p.strip_prefix("q="))
+                .and_then(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            if q > best.1 {
+                best = (format, q);
+            }
+        }
+        best.0
+    }
+
+    fn from_media_type(media_type: &str) -> Option<Format> {
+        match media_type {
+            "application/json" => Some(Format::Json),
+            "application/msgpack" | "application/x-msgpack" | "application/vnd.msgpack" => {
+                Some(Format::MessagePack)
+            }
+            "application/cbor" => Some(Format::Cbor),
+            _ => None,
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Json => "application/json",
+            Format::MessagePack => "application/msgpack",
+            Format::Cbor => "application/cbor",
+        }
+    }
+
+    pub fn serialize<T: Serialize>(self, value: &T) -> Result<Vec<u8>, CompassError> {
+        match self {
+            Format::Json => Ok(serde_json::to_vec(value)?),
+            // with field names, so documents and meta stay maps like in json
+            Format::MessagePack => rmp_serde::to_vec_named(value)
+                .map_err(|e| CompassError::EncodingError(e.to_string())),
+            Format::Cbor => {
+                let mut body = Vec::new();
+                ciborium::ser::into_writer(value, &mut body)
+                    .map_err(|e| CompassError::EncodingError(e.to_string()))?;
+                Ok(body)
+            }
+        }
+    }
+}
+
+// a search, serialized in `format`. json goes through json_search_raw; other formats need documents
+// as values, since a RawValue would come out as one long string
+pub fn search_formatted<C: Connection>(
+    client: &mut C,
+    schema: &Schema,
+    fields: &HashMap<String, String>,
+    raw_query: Option<RawQuery>,
+    format: Format,
+) -> Result<FormattedBody, CompassError> {
+    let body = match format {
+        Format::Json => format.serialize(&json_search_raw(client, schema, fields, raw_query)?)?,
+        _ => format.serialize(&json_search_response(client, schema, fields, raw_query)?)?,
+    };
+    Ok(FormattedBody { body, format })
+}
+
+// a serialized response and the format it's in
+#[derive(Debug)]
+pub struct FormattedBody {
+    pub body: Vec<u8>,
+    pub format: Format,
+}
+
+#[cfg(feature = "rocket_support")]
+use rocket::{
+    http::{ContentType, Status},
+    response::{self, Responder, Response},
+    Request,
+};
+#[cfg(feature = "rocket_support")]
+impl<'r> Responder<'r, 'static> for FormattedBody {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        let content_type =
+            ContentType::parse_flexible(self.format.content_type()).unwrap_or(ContentType::JSON);
+        Response::build()
+            .status(Status::Ok)
+            .header(content_type)
+            .raw_header("Vary", "Accept")
+            .sized_body(self.body.len(), std::io::Cursor::new(self.body))
+            .ok()
+    }
+}
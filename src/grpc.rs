@@ -0,0 +1,310 @@
+use super::*;
+
+use serde_json::Value;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Request, Response, Status};
+
+use std::collections::HashMap;
+
+// generated from proto/compass.proto by build.rs
+pub mod proto {
+    tonic::include_proto!("compass");
+}
+
+use proto::compass_server::{Compass, CompassServer};
+
+// documents a Stream reply sends before the receiver has to catch up
+const STREAM_BUFFER: usize = 256;
+
+// the gRPC api: Search, Count, GetByIds and Stream, answered from whatever config is current. queries
+// run on tokio's blocking threads, each on its own connection
+#[derive(Debug, Clone)]
+pub struct CompassService {
+    config: ConfigHandle,
+}
+
+impl CompassService {
+    pub fn new(config: ConfigHandle) -> CompassService {
+        CompassService { config }
+    }
+
+    // ready to hand to tonic::transport::Server::add_service
+    pub fn server(self) -> CompassServer<CompassService> {
+        CompassServer::new(self)
+    }
+
+    // the schema, scoped to the key's tenant if it has tenancy, and the database settings to connect with
+    fn schema(&self, name: &str, key: Option<&str>) -> Result<(Schema, DatabaseConfig), Status> {
+        let loaded = self.config.current();
+        match loaded.schemas.get(name) {
+            Some(schema) => Ok((
+                schema.for_key(key).map_err(status)?,
+                loaded.config.database.clone(),
+            )),
+            None => Err(Status::not_found(format!("unknown schema '{}'", name))),
+        }
+    }
+}
+
+// the api key from `authorization: Bearer <key>` metadata
+pub(crate) fn bearer<T>(request: &Request<T>) -> Option<String> {
+    request
+        .metadata()
+        .get("authorization")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(str::to_owned)
+}
+
+pub(crate) fn status(err: CompassError) -> Status {
+    match err {
+        CompassError::PGError(_)
+        | CompassError::IOError(_)
+        | CompassError::JSONError(_)
+        | CompassError::ConfigError(_) => Status::internal(err.to_string()),
+        CompassError::ShuttingDown => Status::unavailable("server is shutting down"),
+        CompassError::Overloaded { retry_after_secs } => {
+            Status::resource_exhausted(format!("overloaded, retry after {}s", retry_after_secs))
+        }
+        CompassError::DocumentNotFound(id) => Status::not_found(format!("no document {}", id)),
+        CompassError::TenantRequired => Status::unauthenticated(err.to_string()),
+        err => Status::invalid_argument(err.to_string()),
+    }
+}
+
+pub(crate) async fn blocking<T, F>(f: F) -> Result<T, Status>
+where
+    F: FnOnce() -> Result<T, CompassError> + Send + 'static,
+    T: Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|e| Status::internal(e.to_string()))?
+        .map_err(status)
+}
+
+// the query parameters a SearchRequest stands for
+fn search_params(request: &proto::SearchRequest) -> HashMap<String, String> {
+    let mut params = request.filters.clone();
+    if let Some(ref sort_by) = request.sort_by {
+        params.insert("sortby".to_owned(), sort_by.clone());
+    }
+    match request.sort_order() {
+        proto::SortOrder::Asc => {
+            params.insert("sortorder".to_owned(), "asc".to_owned());
+        }
+        proto::SortOrder::Desc => {
+            params.insert("sortorder".to_owned(), "desc".to_owned());
+        }
+        proto::SortOrder::Unspecified => {}
+    }
+    if let Some(limit) = request.limit {
+        params.insert("limit".to_owned(), limit.to_string());
+    }
+    if let Some(offset) = request.offset {
+        params.insert("offset".to_owned(), offset.to_string());
+    }
+    if request.with_total {
+        params.insert("with_total".to_owned(), "true".to_owned());
+    }
+    if let Some(ref cursor) = request.cursor {
+        params.insert("cursor".to_owned(), cursor.clone());
+    }
+    if !request.fields.is_empty() {
+        params.insert("fields".to_owned(), request.fields.join(","));
+    }
+    params
+}
+
+fn to_value(v: Value) -> prost_types::Value {
+    use prost_types::value::Kind;
+
+    let kind = match v {
+        Value::Null => Kind::NullValue(0),
+        Value::Bool(b) => Kind::BoolValue(b),
+        Value::Number(n) => Kind::NumberValue(n.as_f64().unwrap_or_default()),
+        Value::String(s) => Kind::StringValue(s),
+        Value::Array(values) => Kind::ListValue(prost_types::ListValue {
+            values: values.into_iter().map(to_value).collect(),
+        }),
+        object => Kind::StructValue(to_struct(object)),
+    };
+    prost_types::Value { kind: Some(kind) }
+}
+
+// documents are objects; anything else comes out as {"value": ...}
+fn to_struct(doc: Value) -> prost_types::Struct {
+    let fields = match doc {
+        Value::Object(map) => map.into_iter().map(|(k, v)| (k, to_value(v))).collect(),
+        other => std::iter::once(("value".to_owned(), to_value(other))).collect(),
+    };
+    prost_types::Struct { fields }
+}
+
+fn to_meta(meta: SearchMeta) -> proto::SearchMeta {
+    proto::SearchMeta {
+        ignored_params: meta.ignored_params,
+        total: meta.total,
+        did_you_mean: meta
+            .did_you_mean
+            .map(|d| d.into_iter().collect())
+            .unwrap_or_default(),
+        next_cursor: meta.next_cursor,
+        truncated: meta.truncated,
+        next_offset: meta.next_offset,
+    }
+}
+
+// hands `each` every document `params` matches, a page at a time, until it returns false. pages follow
+// cursors when the schema has a cursor secret and by offset otherwise, which stops at limits.max_offset.
+// `limit` caps the whole run
+pub(crate) fn page_through<F>(
+    schema: &Schema,
+    client: &mut postgres::Client,
+    mut params: HashMap<String, String>,
+    mut each: F,
+) -> Result<(), CompassError>
+where
+    F: FnMut(Vec<Value>) -> bool,
+{
+    let mut remaining = match params.remove("limit") {
+        Some(l) => Some(l.parse::<i64>().map_err(CompassError::InvalidNumberError)?),
+        None => None,
+    };
+    let by_cursor = schema.cursor_secret.is_some() && !params.contains_key("offset");
+    if by_cursor {
+        params.entry("cursor".to_owned()).or_default();
+    }
+    let mut offset = match params.get("offset") {
+        Some(o) => o.parse::<i64>().map_err(CompassError::InvalidNumberError)?,
+        None => 0,
+    };
+
+    loop {
+        let page = remaining.map_or(schema.limits.max_limit, |r| r.min(schema.limits.max_limit));
+        if page <= 0 {
+            return Ok(());
+        }
+        params.insert("limit".to_owned(), page.to_string());
+        if !by_cursor {
+            params.insert("offset".to_owned(), offset.to_string());
+        }
+
+        let response = json_search_response(client, schema, &params, None)?;
+        let returned = response.data.len() as i64;
+        if returned > 0 && !each(response.data) {
+            return Ok(());
+        }
+        remaining = remaining.map(|r| r - returned);
+
+        if by_cursor {
+            match response.meta.next_cursor {
+                Some(cursor) => params.insert("cursor".to_owned(), cursor),
+                None => return Ok(()),
+            };
+        } else {
+            match response.meta.next_offset {
+                Some(next) => offset = next,
+                None if returned < page => return Ok(()),
+                None => offset += returned,
+            }
+            if offset > schema.limits.max_offset {
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl Compass for CompassService {
+    async fn search(
+        &self,
+        request: Request<proto::SearchRequest>,
+    ) -> Result<Response<proto::SearchReply>, Status> {
+        let key = bearer(&request);
+        let request = request.into_inner();
+        let (schema, database) = self.schema(&request.schema, key.as_deref())?;
+        let response = blocking(move || {
+            let mut client = database.connect()?;
+            scope_session(&mut client, &schema)?;
+            json_search_response(&mut client, &schema, &search_params(&request), None)
+        })
+        .await?;
+
+        Ok(Response::new(proto::SearchReply {
+            data: response.data.into_iter().map(to_struct).collect(),
+            meta: Some(to_meta(response.meta)),
+        }))
+    }
+
+    async fn count(
+        &self,
+        request: Request<proto::SearchRequest>,
+    ) -> Result<Response<proto::CountReply>, Status> {
+        let key = bearer(&request);
+        let request = request.into_inner();
+        let (schema, database) = self.schema(&request.schema, key.as_deref())?;
+        let count = blocking(move || {
+            let mut client = database.connect()?;
+            scope_session(&mut client, &schema)?;
+            json_count(&mut client, &schema, &search_params(&request))
+        })
+        .await?;
+
+        Ok(Response::new(proto::CountReply { count }))
+    }
+
+    async fn get_by_ids(
+        &self,
+        request: Request<proto::GetByIdsRequest>,
+    ) -> Result<Response<proto::DocumentsReply>, Status> {
+        let key = bearer(&request);
+        let request = request.into_inner();
+        let ids = request
+            .ids
+            .iter()
+            .map(|id| uuid::Uuid::parse_str(id))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| Status::invalid_argument(format!("invalid id: {}", e)))?;
+        let (schema, database) = self.schema(&request.schema, key.as_deref())?;
+        let docs = blocking(move || {
+            let mut client = database.connect()?;
+            scope_session(&mut client, &schema)?;
+            get_by_ids(&mut client, &schema, &ids)
+        })
+        .await?;
+
+        Ok(Response::new(proto::DocumentsReply {
+            data: docs.into_iter().map(to_struct).collect(),
+        }))
+    }
+
+    type StreamStream = ReceiverStream<Result<prost_types::Struct, Status>>;
+
+    async fn stream(
+        &self,
+        request: Request<proto::SearchRequest>,
+    ) -> Result<Response<Self::StreamStream>, Status> {
+        let key = bearer(&request);
+        let request = request.into_inner();
+        let (schema, database) = self.schema(&request.schema, key.as_deref())?;
+        let mut client = blocking(move || database.connect()).await?;
+
+        let (tx, rx) = mpsc::channel(STREAM_BUFFER);
+        tokio::task::spawn_blocking(move || {
+            let sent = scope_session(&mut client, &schema).and_then(|_| {
+                page_through(&schema, &mut client, search_params(&request), |docs| {
+                    // stops once the client has gone away
+                    docs.into_iter()
+                        .all(|doc| tx.blocking_send(Ok(to_struct(doc))).is_ok())
+                })
+            });
+            if let Err(e) = sent {
+                let _ = tx.blocking_send(Err(status(e)));
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+}
@@ -0,0 +1,519 @@
+use super::*;
+
+use serde::Serialize;
+use serde_json::Value;
+
+use std::fmt::Write;
+
+// one query parameter a schema accepts
+#[derive(Serialize, Debug, Clone)]
+pub struct ParamHelp {
+    pub name: String,  // as written in the schema; matched case-insensitively
+    pub field: String, // the schema field it filters on
+    pub kind: &'static str,
+    pub description: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub values: Vec<String>, // named values it takes besides the usual ones, e.g. a range's aliases
+    pub examples: Vec<String>,
+    pub negatable: bool,  // takes a trailing `!`
+    pub combinable: bool, // takes `_or_`/`_and_` between values
+    pub groupable: bool,  // can go in a `g1.` query group
+    pub sortable: bool,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct OperatorHelp {
+    pub syntax: &'static str,
+    pub description: &'static str,
+    pub example: String,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ReservedHelp {
+    pub name: &'static str,
+    pub description: &'static str,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct PresetHelp {
+    pub name: String,
+    pub arguments: Vec<String>, // request parameters its `{name}` placeholders are filled from
+    pub expands_to: Vec<(String, String)>,
+}
+
+// everything a client can put in a query string for one schema, worked out from the schema itself so it
+// can't fall behind it. see schema_help
+#[derive(Serialize, Debug, Clone)]
+pub struct SchemaHelp {
+    pub schema: String,
+    pub strict: bool, // unknown parameters are a 400 rather than ignored
+    pub params: Vec<ParamHelp>,
+    pub operators: Vec<OperatorHelp>,
+    pub sortable: Vec<String>,
+    pub default_order_by: String,
+    pub default_limit: i64,
+    pub max_limit: i64,
+    pub reserved: Vec<ReservedHelp>,
+    pub presets: Vec<PresetHelp>,
+}
+
+// what the reserved parameters do; the list itself is RESERVED_PARAMS
+fn reserved_description(name: &str) -> &'static str {
+    match name {
+        "sortby" => "field to sort by: doc_id, a sortable field, or relevance",
+        "sortorder" => "asc or desc",
+        "nulls" => "first or last: where documents without the sort field go",
+        "collation" => "e.g. en-u-kn-true: sorts the sort field as text in that collation",
+        "limit" => "documents per page",
+        "offset" => "documents to skip",
+        "debug" => "adds the generated sql to the response",
+        "join" => "comma separated joins to attach to each document",
+        "window" => "computed columns, e.g. rank:<field>, running_count, lag:<field>",
+        "similar_to" => "a doc_id; orders results by how much they share with it",
+        "k" => "same as limit, for nearest neighbour searches",
+        "rank_normalization" => "ts_rank normalization bitmask for sortby=relevance",
+        "recency_half_life" => "e.g. 7d; halves relevance for every period of age",
+        "recency_field" => "the date recency_half_life counts from",
+        "snippets" => "true to add _snippets for the fulltext parameters",
+        "snippet_words" => "words per snippet, 2 to 100",
+        "snippet_fragments" => "fragments per snippet, up to 10",
+        "snippet_delimiter" => "goes between snippet fragments",
+        "collapse" => "a field; returns only the first document of each value",
+        "with_total" => "true to add the number of matches to meta.total",
+        "cursor" => "empty for the first page, then meta.next_cursor",
+        "fields" => "comma separated paths to return instead of whole documents",
+        "q" => "a lucene-style query, e.g. type:54 AND season:[12 TO 15]",
+        "preset" => "comma separated presets, see presets",
+        _ => "",
+    }
+}
+
+fn operators(schema: &Schema) -> Vec<OperatorHelp> {
+    // examples use the schema's own first filterable field where there is one
+    let field = schema
+        .fields
+        .iter()
+        .find(|(_, f)| {
+            !matches!(
+                f.query,
+                FieldQuery::Fulltext { .. } | FieldQuery::Vector { .. }
+            )
+        })
+        .map(|(name, _)| name.as_str())
+        .unwrap_or("field");
+
+    vec![
+        OperatorHelp {
+            syntax: "<param>!=<value>",
+            description: "negates the parameter",
+            example: format!("{}!=1", field),
+        },
+        OperatorHelp {
+            syntax: "<value>_or_<value>",
+            description: "either value",
+            example: format!("{}=1_or_2", field),
+        },
+        OperatorHelp {
+            syntax: "<value>_and_<value>",
+            description: "both values, for fields holding arrays",
+            example: format!("{}=1_and_2", field),
+        },
+        OperatorHelp {
+            syntax: "exists, notexists",
+            description: "whether the document has the field at all",
+            example: format!("{}=exists", field),
+        },
+        OperatorHelp {
+            syntax: "g<n>.<param>, g<n>.or.<param>",
+            description:
+                "query groups: plain filters in a group are ANDed, then ORed with its or. filters",
+            example: format!("g1.{}=1&g1.or.{}=2", field, field),
+        },
+    ]
+}
+
+fn date_description(conv: &ConverterSchema) -> Option<String> {
+    match conv.from {
+        ConvertFrom::DateTimeString | ConvertFrom::DateString => Some(format!(
+            "takes rfc3339 dates or YYYY-MM-DD days, read in {}, as well as epoch {}",
+            conv.timezone.map(|tz| tz.name()).unwrap_or("UTC"),
+            match conv.to {
+                ConvertTo::TimestampMillis => "millis",
+                _ => "seconds",
+            }
+        )),
+        _ => None,
+    }
+}
+
+fn field_help(name: &str, field: &Field, query: &FieldQuery) -> Vec<ParamHelp> {
+    let param = |name: &str, kind, description: String, examples: Vec<String>| ParamHelp {
+        name: name.to_owned(),
+        field: name.to_owned(),
+        kind,
+        description,
+        values: Vec::new(),
+        examples,
+        negatable: true,
+        combinable: true,
+        groupable: true,
+        sortable: field.sortable,
+    };
+    let dates = field.converter.as_ref().and_then(date_description);
+    let with_dates = |description: &str| match dates {
+        Some(ref dates) => format!("{}; {}", description, dates),
+        None => description.to_owned(),
+    };
+
+    match *query {
+        FieldQuery::Range {
+            ref min,
+            ref max,
+            ref aliases,
+        } => {
+            let mut values: Vec<String> = aliases.keys().map(|a| a.to_lowercase()).collect();
+            values.sort();
+
+            let mut exact = param(
+                name,
+                "range",
+                with_dates("equal to this number"),
+                vec![format!("{}=12", name)],
+            );
+            if let Some(alias) = values.first() {
+                exact.examples.push(format!("{}={}", name, alias));
+            }
+            exact.values = values;
+
+            let mut lower = param(
+                min,
+                "min",
+                with_dates(&format!("greater than this; the lower end of {}", name)),
+                vec![format!("{}=12", min)],
+            );
+            lower.field = name.to_owned();
+            lower.sortable = false;
+            let mut upper = param(
+                max,
+                "max",
+                with_dates(&format!("less than this; the upper end of {}", name)),
+                vec![format!("{}=15", max)],
+            );
+            upper.field = name.to_owned();
+            upper.sortable = false;
+
+            vec![exact, lower, upper]
+        }
+        FieldQuery::Fulltext { ref syntax, .. } => {
+            let (description, example) = match syntax {
+                FulltextSyntax::WebSearch => (
+                    "fulltext search: words, \"quoted phrases\", or, -excluded",
+                    "\"home run\" -foul",
+                ),
+                FulltextSyntax::Plain => ("fulltext search: every word has to match", "home run"),
+                FulltextSyntax::Phrase => ("fulltext search: the words in this order", "home run"),
+                FulltextSyntax::TsQuery => (
+                    "fulltext search in postgres tsquery syntax: &, |, !, <->",
+                    "home & !foul",
+                ),
+            };
+            let mut help = param(
+                name,
+                "fulltext",
+                description.to_owned(),
+                vec![format!("{}={}", name, example)],
+            );
+            help.combinable = false;
+            help.groupable = false;
+            vec![help]
+        }
+        FieldQuery::AmbiguousTag => vec![param(
+            name,
+            "tag",
+            with_dates("equal to this number, boolean or string"),
+            vec![format!("{}=54", name)],
+        )],
+        FieldQuery::NumericTag { ref aliases } => {
+            let mut values: Vec<String> = aliases.keys().map(|a| a.to_lowercase()).collect();
+            values.sort();
+            let mut help = param(
+                name,
+                "numeric_tag",
+                with_dates("equal to this number"),
+                vec![format!("{}=54", name)],
+            );
+            if let Some(alias) = values.first() {
+                help.examples.push(format!("{}={}", name, alias));
+            }
+            help.values = values;
+            vec![help]
+        }
+        FieldQuery::StringTag => vec![param(
+            name,
+            "string_tag",
+            "equal to this string".to_owned(),
+            vec![format!("{}=abc", name)],
+        )],
+        FieldQuery::Nested => {
+            let mut help = param(
+                &format!("{}.<path>", name),
+                "nested",
+                format!(
+                    "a key inside {}, equal to this number, boolean or string",
+                    name
+                ),
+                vec![format!("{}.player=abc", name)],
+            );
+            help.field = name.to_owned();
+            vec![
+                param(
+                    name,
+                    "tag",
+                    "equal to this number, boolean or string".to_owned(),
+                    vec![format!("{}=exists", name)],
+                ),
+                help,
+            ]
+        }
+        FieldQuery::Min => vec![param(
+            name,
+            "min",
+            with_dates("greater than this"),
+            vec![format!("{}=12", name)],
+        )],
+        FieldQuery::Max => vec![param(
+            name,
+            "max",
+            with_dates("less than this"),
+            vec![format!("{}=15", name)],
+        )],
+        FieldQuery::Bool => {
+            let mut help = param(
+                name,
+                "bool",
+                "true or false".to_owned(),
+                vec![format!("{}=true", name)],
+            );
+            help.values = vec!["true".to_owned(), "false".to_owned()];
+            vec![help]
+        }
+        FieldQuery::Vector { dimensions, .. } => {
+            let near = format!("{}_near", name);
+            let mut help = param(
+                &near,
+                "vector",
+                format!(
+                    "{} little-endian f32s as base64; orders results by distance to them, nearest first",
+                    dimensions
+                ),
+                vec![format!("{}=<base64>", near)],
+            );
+            help.field = name.to_owned();
+            help.negatable = false;
+            help.combinable = false;
+            help.groupable = false;
+            vec![help]
+        }
+        FieldQuery::Not(ref inner) => field_help(name, field, inner),
+    }
+}
+
+// the query parameters, operators, reserved parameters and presets `schema` accepts, for serving as
+// `GET /<schema>/help`. see schema_help_html for a page a person can read
+pub fn schema_help(name: &str, schema: &Schema) -> SchemaHelp {
+    let params = schema
+        .fields
+        .iter()
+        .flat_map(|(key, field)| field_help(key, field, &field.query))
+        .collect();
+
+    let mut sortable = vec!["doc_id".to_owned()];
+    sortable.extend(
+        schema
+            .fields
+            .iter()
+            .filter(|(_, f)| f.sortable)
+            .map(|(k, _)| k.clone()),
+    );
+    if schema
+        .fields
+        .values()
+        .any(|f| matches!(f.query, FieldQuery::Fulltext { .. }))
+    {
+        sortable.push("relevance".to_owned());
+    }
+
+    let presets = schema
+        .presets
+        .iter()
+        .map(|(name, params)| {
+            let expands_to: Vec<(String, String)> = params
+                .iter()
+                .map(|(k, v)| {
+                    let v = match v {
+                        Value::String(s) => s.clone(),
+                        v => v.to_string(),
+                    };
+                    (k.clone(), v)
+                })
+                .collect();
+            let mut arguments: Vec<String> = Vec::new();
+            for (_, v) in expands_to.iter() {
+                for p in placeholders(v) {
+                    if !arguments.iter().any(|a| a == p) {
+                        arguments.push(p.to_owned());
+                    }
+                }
+            }
+            PresetHelp {
+                name: name.clone(),
+                arguments,
+                expands_to,
+            }
+        })
+        .collect();
+
+    SchemaHelp {
+        schema: name.to_owned(),
+        strict: schema.strict,
+        params,
+        operators: operators(schema),
+        sortable,
+        default_order_by: schema.default_order_by.clone(),
+        default_limit: schema.limits.default_limit,
+        max_limit: schema.limits.max_limit,
+        reserved: RESERVED_PARAMS
+            .iter()
+            .map(|&name| ReservedHelp {
+                name,
+                description: reserved_description(name),
+            })
+            .collect(),
+        presets,
+    }
+}
+
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn code_list(items: &[String]) -> String {
+    items
+        .iter()
+        .map(|i| format!("<code>{}</code>", escape(i)))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+// schema_help as a plain html page, for `GET /<schema>/help` with `Accept: text/html`
+pub fn schema_help_html(help: &SchemaHelp) -> String {
+    let mut html = String::new();
+    let _ = write!(
+        html,
+        "<!doctype html>\n<html><head><meta charset=\"utf-8\"><title>{0} query parameters</title></head><body>\n\
+         <h1>{0}</h1>\n<p>sorted by <code>{1}</code> by default; {2} documents per page, up to {3}.{4}</p>\n",
+        escape(&help.schema),
+        escape(&help.default_order_by),
+        help.default_limit,
+        help.max_limit,
+        if help.strict {
+            " parameters that aren't listed here are rejected."
+        } else {
+            " parameters that aren't listed here are ignored."
+        }
+    );
+
+    html.push_str("<h2>parameters</h2>\n<table>\n<tr><th>parameter</th><th>type</th><th>description</th><th>values</th><th>examples</th></tr>\n");
+    for p in help.params.iter() {
+        let mut notes = Vec::new();
+        if !p.negatable {
+            notes.push("no <code>!</code>");
+        }
+        if !p.combinable {
+            notes.push("no <code>_or_</code>/<code>_and_</code>");
+        }
+        if !p.groupable {
+            notes.push("not in groups");
+        }
+        if p.sortable {
+            notes.push("sortable");
+        }
+        let _ = writeln!(
+            html,
+            "<tr><td><code>{}</code></td><td>{}</td><td>{}{}</td><td>{}</td><td>{}</td></tr>",
+            escape(&p.name),
+            p.kind,
+            escape(&p.description),
+            if notes.is_empty() {
+                String::new()
+            } else {
+                format!(" ({})", notes.join(", "))
+            },
+            code_list(&p.values),
+            code_list(&p.examples)
+        );
+    }
+    html.push_str("</table>\n");
+
+    html.push_str("<h2>operators</h2>\n<table>\n<tr><th>syntax</th><th>description</th><th>example</th></tr>\n");
+    for o in help.operators.iter() {
+        let _ = writeln!(
+            html,
+            "<tr><td><code>{}</code></td><td>{}</td><td><code>{}</code></td></tr>",
+            escape(o.syntax),
+            escape(o.description),
+            escape(&o.example)
+        );
+    }
+    html.push_str("</table>\n");
+
+    let _ = writeln!(
+        html,
+        "<h2>sorting</h2>\n<p><code>sortby</code> takes {}.</p>",
+        code_list(&help.sortable)
+    );
+
+    html.push_str("<h2>other parameters</h2>\n<table>\n");
+    for r in help.reserved.iter() {
+        let _ = writeln!(
+            html,
+            "<tr><td><code>{}</code></td><td>{}</td></tr>",
+            r.name,
+            escape(r.description)
+        );
+    }
+    html.push_str("</table>\n");
+
+    if !help.presets.is_empty() {
+        html.push_str("<h2>presets</h2>\n<table>\n<tr><th>preset</th><th>needs</th><th>stands for</th></tr>\n");
+        for p in help.presets.iter() {
+            let expands_to: Vec<String> = p
+                .expands_to
+                .iter()
+                .map(|(k, v)| format!("{}={}", k, v))
+                .collect();
+            let _ = writeln!(
+                html,
+                "<tr><td><code>preset={}</code></td><td>{}</td><td>{}</td></tr>",
+                escape(&p.name),
+                code_list(&p.arguments),
+                code_list(&expands_to)
+            );
+        }
+        html.push_str("</table>\n");
+    }
+
+    html.push_str("</body></html>\n");
+    html
+}
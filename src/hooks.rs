@@ -0,0 +1,82 @@
+use super::*;
+
+use serde_json::Value;
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, RwLock};
+
+// embedder code that gets every result document of a schema after its converters and lookups ran, to
+// redact, enrich or reshape it. register one with ConfigHandle::register_transformer (or straight on a
+// schema's hooks) instead of forking compass for it
+pub trait ResultTransformer: Send + Sync {
+    fn transform(&self, doc: &mut Value);
+}
+
+// embedder code that sees a query's parameters before they're compiled, after presets and `q` are
+// expanded, and can add to them, rewrite them, or turn the query down with an error (QueryRejected is
+// there for that). e.g. enforcing a date range on every search. sorting, paging and cursors read what it
+// leaves too (see prepare_params). it has to give the same result for the same parameters
+pub trait QueryMiddleware: Send + Sync {
+    fn before_query(&self, fields: &mut HashMap<String, String>) -> Result<(), CompassError>;
+}
+
+// a schema's registered hooks, shared by its clones and carried over when the config is reloaded
+#[derive(Default)]
+pub struct Hooks {
+    transformers: RwLock<Vec<Arc<dyn ResultTransformer>>>,
+    middleware: RwLock<Vec<Arc<dyn QueryMiddleware>>>,
+}
+
+impl Hooks {
+    // transformers run in the order they were added
+    pub fn add_transformer(&self, transformer: Arc<dyn ResultTransformer>) {
+        self.transformers.write().unwrap().push(transformer);
+    }
+
+    pub fn has_transformers(&self) -> bool {
+        !self.transformers.read().unwrap().is_empty()
+    }
+
+    pub(crate) fn transform(&self, doc: &mut Value) {
+        for transformer in self.transformers.read().unwrap().iter() {
+            transformer.transform(doc);
+        }
+    }
+
+    // middleware runs in the order it was added, each seeing what the one before it left
+    pub fn add_middleware(&self, middleware: Arc<dyn QueryMiddleware>) {
+        self.middleware.write().unwrap().push(middleware);
+    }
+
+    pub fn has_middleware(&self) -> bool {
+        !self.middleware.read().unwrap().is_empty()
+    }
+
+    // `fields` as the middleware leaves them; only copied when there is some
+    pub(crate) fn before_query<'a>(
+        &self,
+        fields: Cow<'a, HashMap<String, String>>,
+    ) -> Result<Cow<'a, HashMap<String, String>>, CompassError> {
+        let middleware = self.middleware.read().unwrap();
+        if middleware.is_empty() {
+            return Ok(fields);
+        }
+
+        let mut fields = fields.into_owned();
+        for m in middleware.iter() {
+            m.before_query(&mut fields)?;
+        }
+        Ok(Cow::Owned(fields))
+    }
+}
+
+impl fmt::Debug for Hooks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Hooks")
+            .field("transformers", &self.transformers.read().unwrap().len())
+            .field("middleware", &self.middleware.read().unwrap().len())
+            .finish()
+    }
+}
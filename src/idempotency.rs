@@ -0,0 +1,182 @@
+use super::*;
+
+use postgres::{Client, Transaction};
+use serde::Serialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+// what each write run through `idempotent` answered, by key, created by migrate
+pub const IDEMPOTENCY_TABLE: &str = "compass_idempotency";
+
+// the `Idempotency-Key` header clients send with writes they might retry
+pub const IDEMPOTENCY_HEADER: &str = "Idempotency-Key";
+
+const MAX_KEY_LENGTH: usize = 255;
+
+#[derive(Serialize, Debug, Clone)]
+pub struct IdempotentResponse {
+    pub response: Value,
+    pub replayed: bool, // the write had already been done under this key, and this is what it answered then
+}
+
+pub fn provision_idempotency(client: &mut Client) -> Result<(), CompassError> {
+    client.batch_execute(&format!(
+        "CREATE TABLE IF NOT EXISTS {table} (scope TEXT NOT NULL, key TEXT NOT NULL, request_hash TEXT NOT NULL, \
+         response JSONB NOT NULL, created_at TIMESTAMPTZ NOT NULL DEFAULT now(), PRIMARY KEY (scope, key)); \
+         CREATE INDEX IF NOT EXISTS {table}_created_idx ON {table} (created_at)",
+        table = IDEMPOTENCY_TABLE
+    ))?;
+    Ok(())
+}
+
+// keys are only unique per table and caller, so two api keys (or tenants) can't see each other's answers
+fn idempotency_scope(schema: &Schema) -> String {
+    format!(
+        "{}/{}/{}",
+        schema.table,
+        schema.tenant.as_deref().unwrap_or(""),
+        schema.key.as_deref().unwrap_or(ANONYMOUS)
+    )
+}
+
+fn request_hash(request: &Value) -> String {
+    Sha256::digest(request.to_string().as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+// runs `write` at most once per key. `write` gets a transaction that the key is recorded in too, so
+// either both commit or neither does: a retry after the write failed (or after the connection dropped
+// before commit) runs it again, and a retry after it succeeded gets the response it returned the first
+// time without running anything. a retry that arrives while the first attempt is still running waits
+// for it. `request` is what the write was asked to do (the body, say); reusing a key for a different
+// request is an error rather than a replay
+pub fn idempotent<F>(
+    client: &mut Client,
+    schema: &Schema,
+    key: &str,
+    request: &Value,
+    write: F,
+) -> Result<IdempotentResponse, CompassError>
+where
+    F: FnOnce(&mut Transaction<'_>) -> Result<Value, CompassError>,
+{
+    if key.is_empty() || key.len() > MAX_KEY_LENGTH || !key.chars().all(|c| c.is_ascii_graphic()) {
+        return Err(CompassError::InvalidIdempotencyKey(format!(
+            "{} has to be 1 to {} printable ascii characters",
+            IDEMPOTENCY_HEADER, MAX_KEY_LENGTH
+        )));
+    }
+
+    let scope = idempotency_scope(schema);
+    let hash = request_hash(request);
+
+    scope_session(client, schema)?;
+    let mut transaction = client.transaction()?;
+
+    // a concurrent attempt with the same key holds this row until it's done, so this waits for it
+    let claimed = transaction.execute(
+        format!(
+            "INSERT INTO {} (scope, key, request_hash, response) VALUES ($1, $2, $3, 'null') \
+             ON CONFLICT (scope, key) DO NOTHING",
+            IDEMPOTENCY_TABLE
+        )
+        .as_str(),
+        &[&scope, &key, &hash],
+    )? == 1;
+
+    if !claimed {
+        let row = transaction.query_one(
+            format!(
+                "SELECT request_hash, response FROM {} WHERE scope = $1 AND key = $2",
+                IDEMPOTENCY_TABLE
+            )
+            .as_str(),
+            &[&scope, &key],
+        )?;
+        transaction.commit()?;
+
+        if row.get::<usize, String>(0) != hash {
+            return Err(CompassError::IdempotencyKeyReused(key.to_owned()));
+        }
+        return Ok(IdempotentResponse {
+            response: row.get(1),
+            replayed: true,
+        });
+    }
+
+    let response = write(&mut transaction)?;
+    transaction.execute(
+        format!(
+            "UPDATE {} SET response = $3 WHERE scope = $1 AND key = $2",
+            IDEMPOTENCY_TABLE
+        )
+        .as_str(),
+        &[&scope, &key, &response],
+    )?;
+    transaction.commit()?;
+
+    Ok(IdempotentResponse {
+        response,
+        replayed: false,
+    })
+}
+
+// forgets keys recorded more than `max_age_secs` ago, returning how many. retries after that run the
+// write again, so keep them for longer than any client retries for
+pub fn expire_idempotency_keys(
+    client: &mut Client,
+    max_age_secs: i64,
+) -> Result<u64, CompassError> {
+    Ok(client.execute(
+        format!(
+            "DELETE FROM {} WHERE created_at < now() - make_interval(secs => $1)",
+            IDEMPOTENCY_TABLE
+        )
+        .as_str(),
+        &[&(max_age_secs as f64)],
+    )?)
+}
+
+#[cfg(feature = "rocket_support")]
+use rocket::{
+    http::{ContentType, Status},
+    request::{FromRequest, Outcome},
+    response::{self, Responder, Response},
+    Request,
+};
+
+// the request's Idempotency-Key header, if it sent one
+#[cfg(feature = "rocket_support")]
+pub struct IdempotencyKey(pub Option<String>);
+
+#[cfg(feature = "rocket_support")]
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for IdempotencyKey {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(IdempotencyKey(
+            request
+                .headers()
+                .get_one(IDEMPOTENCY_HEADER)
+                .map(str::to_owned),
+        ))
+    }
+}
+
+#[cfg(feature = "rocket_support")]
+impl<'r> Responder<'r, 'static> for IdempotentResponse {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        let body = self.response.to_string();
+        let mut response = Response::build();
+        response.status(Status::Ok).header(ContentType::JSON);
+        if self.replayed {
+            response.raw_header("Idempotent-Replayed", "true");
+        }
+        response
+            .sized_body(body.len(), std::io::Cursor::new(body))
+            .ok()
+    }
+}
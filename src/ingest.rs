@@ -0,0 +1,319 @@
+use super::*;
+
+use postgres::types::ToSql;
+use serde::Serialize;
+use serde_json::{json, Value};
+
+fn normalize_strings(val: &mut Value, normalize: Normalization) {
+    match val {
+        Value::String(s) => *s = normalize.apply(s),
+        Value::Array(items) => {
+            for item in items.iter_mut() {
+                normalize_strings(item, normalize);
+            }
+        }
+        _ => {}
+    }
+}
+
+// run this on documents before inserting them, so stored values are in the same form query values get compiled to
+pub fn prepare_document(schema: &Schema, doc: &mut Value) -> Result<(), CompassError> {
+    for (key, field) in schema.fields.iter() {
+        let pointer = format!("/{}", key.replace('.', "/"));
+        if let Some(val) = doc.pointer_mut(&pointer) {
+            if let Some(normalize) = field.normalize {
+                normalize_strings(val, normalize);
+            }
+
+            if let Some(ref conv) = field.converter {
+                *val = conv.to_stored(val).map_err(|e| match e {
+                    CompassError::ConversionError(msg) => {
+                        CompassError::ConversionError(format!("{}: {}", key, msg))
+                    }
+                    e => e,
+                })?;
+            }
+        }
+    }
+
+    // documents are stored under the tenant writing them, whatever they say themselves
+    if let Some(Tenancy::Field(ref field)) = schema.tenancy {
+        let tenant = schema.tenant.clone().ok_or(CompassError::TenantRequired)?;
+        let no_object = || {
+            CompassError::ConversionError(format!(
+                "{}: the document has no object to put the tenant in",
+                field
+            ))
+        };
+        let mut parts: Vec<&str> = field.split('.').collect();
+        let last = parts.pop().unwrap_or_default();
+        let mut target = doc;
+        for part in parts {
+            target = match target {
+                Value::Object(map) => map.entry(part).or_insert_with(|| json!({})),
+                _ => return Err(no_object()),
+            };
+        }
+        match target {
+            Value::Object(map) => map.insert(last.to_owned(), Value::String(tenant)),
+            _ => return Err(no_object()),
+        };
+    }
+
+    Ok(())
+}
+
+// vector fields live in their own column rather than in `object`: this takes them out of a document
+// (before it's inserted) as (column, pgvector text) pairs, to hand to store_embedding once the row exists
+pub fn take_embeddings(
+    schema: &Schema,
+    doc: &mut Value,
+) -> Result<Vec<(String, String)>, CompassError> {
+    let mut out = Vec::new();
+
+    for (name, column, dimensions) in schema.vector_columns() {
+        let val = match doc.as_object_mut().and_then(|o| o.remove(name)) {
+            Some(Value::Null) | None => continue,
+            Some(v) => v,
+        };
+
+        let floats = val
+            .as_array()
+            .and_then(|a| a.iter().map(Value::as_f64).collect::<Option<Vec<f64>>>())
+            .filter(|f| f.len() == dimensions)
+            .ok_or_else(|| {
+                CompassError::ConversionError(format!(
+                    "{}: expected an array of {} numbers",
+                    name, dimensions
+                ))
+            })?;
+
+        let text: Vec<String> = floats.iter().map(|f| (*f as f32).to_string()).collect();
+        out.push((column.to_owned(), format!("[{}]", text.join(","))));
+    }
+
+    Ok(out)
+}
+
+// something wrong with one field of a submitted document
+#[derive(Serialize, Debug, Clone)]
+pub struct FieldProblem {
+    pub field: String, // empty when it's the document itself
+    pub problem: String,
+    #[serde(skip_serializing_if = "Value::is_null")]
+    pub value: Value, // as submitted
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct DocumentDiagnostics {
+    pub index: usize, // position in the submitted list
+    pub valid: bool,
+    pub problems: Vec<FieldProblem>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stored: Option<Value>, // what would go in `object`, when it's valid: converted, normalized, vectors taken out
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ValidationReport {
+    pub valid: usize,
+    pub invalid: usize,
+    pub documents: Vec<DocumentDiagnostics>,
+}
+
+fn document_problems(schema: &Schema, doc: &Value) -> (Vec<FieldProblem>, Value) {
+    let mut stored = doc.clone();
+    let mut problems = Vec::new();
+    if !stored.is_object() {
+        problems.push(FieldProblem {
+            field: String::new(),
+            problem: "a document has to be a json object".to_owned(),
+            value: Value::Null,
+        });
+        return (problems, stored);
+    }
+
+    for (key, field) in schema.fields.iter() {
+        let pointer = format!("/{}", key.replace('.', "/"));
+        let val = match stored.pointer_mut(&pointer) {
+            Some(Value::Null) | None => continue,
+            Some(val) => val,
+        };
+        let problem = |problem: String| FieldProblem {
+            field: key.clone(),
+            problem,
+            value: doc.pointer(&pointer).cloned().unwrap_or(Value::Null),
+        };
+
+        if let FieldQuery::Vector { dimensions, .. } = field.query {
+            let fits = val.as_array().map_or(false, |a| {
+                a.len() == dimensions && a.iter().all(Value::is_number)
+            });
+            if !fits {
+                problems.push(problem(format!(
+                    "expected an array of {} numbers",
+                    dimensions
+                )));
+            }
+            continue;
+        }
+
+        if let Some(normalize) = field.normalize {
+            normalize_strings(val, normalize);
+        }
+        if let Some(ref conv) = field.converter {
+            match conv.to_stored(val) {
+                Ok(converted) => *val = converted,
+                Err(CompassError::ConversionError(msg)) => {
+                    problems.push(problem(msg));
+                    continue;
+                }
+                Err(e) => {
+                    problems.push(problem(e.to_string()));
+                    continue;
+                }
+            }
+        }
+        if let Some(expected) = type_problem(&field.query, val) {
+            problems.push(problem(format!("expected {}", expected)));
+        }
+    }
+
+    if let Some(object) = stored.as_object_mut() {
+        for (name, _, _) in schema.vector_columns() {
+            object.remove(name);
+        }
+    }
+    (problems, stored)
+}
+
+// a dry run of ingesting `docs` into the schema: each one goes through the converters, normalization and
+// the type checks its fields' queries expect, and every problem is reported rather than just the first.
+// nothing is written and no connection is needed, so producers can test what they emit against the live
+// schema; serve it as `POST /<schema>/validate`. tenancy fields are filled in at ingest, not checked here
+pub fn validate_documents(schema: &Schema, docs: &[Value]) -> ValidationReport {
+    let documents: Vec<DocumentDiagnostics> = docs
+        .iter()
+        .enumerate()
+        .map(|(index, doc)| {
+            let (problems, stored) = document_problems(schema, doc);
+            let valid = problems.is_empty();
+            DocumentDiagnostics {
+                index,
+                valid,
+                problems,
+                stored: if valid { Some(stored) } else { None },
+            }
+        })
+        .collect();
+
+    let valid = documents.iter().filter(|d| d.valid).count();
+    ValidationReport {
+        valid,
+        invalid: documents.len() - valid,
+        documents,
+    }
+}
+
+pub fn store_embedding<C: Connection>(
+    client: &mut C,
+    schema: &Schema,
+    column: &str,
+    doc_id: uuid::Uuid,
+    vector: &str,
+) -> Result<(), CompassError> {
+    if !schema.vector_columns().iter().any(|(_, c, _)| *c == column) {
+        return Err(CompassError::FieldNotFound);
+    }
+
+    let (scope, tenant) = schema.tenant_scope(3)?;
+    let mut params: Vec<&(dyn ToSql + Sync)> = vec![&vector, &doc_id];
+    params.extend(tenant.iter().map(|t| t as &(dyn ToSql + Sync)));
+
+    client
+        .client()
+        .map_err(pg_error(schema))?
+        .execute(
+            format!(
+                "UPDATE {} SET {} = $1::text::vector WHERE doc_id = $2{}",
+                schema.table, column, scope
+            )
+            .as_str(),
+            &params,
+        )
+        .map_err(pg_error(schema))?;
+
+    Ok(())
+}
+
+// a fulltext `lang` of "auto" means "whatever language the document is in"
+pub const AUTO_LANGUAGE: &str = "auto";
+pub(crate) const LANGUAGE_COLUMN: &str = "doc_language";
+
+// whatlang -> the postgres text search configuration for it, for the ones postgres ships
+fn pg_language(lang: whatlang::Lang) -> Option<&'static str> {
+    use whatlang::Lang::*;
+    Some(match lang {
+        Dan => "danish",
+        Nld => "dutch",
+        Eng => "english",
+        Fin => "finnish",
+        Fra => "french",
+        Deu => "german",
+        Hun => "hungarian",
+        Ita => "italian",
+        Nob => "norwegian",
+        Por => "portuguese",
+        Ron => "romanian",
+        Rus => "russian",
+        Spa => "spanish",
+        Swe => "swedish",
+        Tur => "turkish",
+        _ => return None,
+    })
+}
+
+// the text search configuration for a document, from the text in its fulltext fields. falls back to
+// `simple` (no stemming) when the text is too short to tell or the language has no configuration
+pub fn detect_language(schema: &Schema, doc: &Value) -> &'static str {
+    let text: Vec<&str> = schema
+        .fields
+        .iter()
+        .filter_map(|(name, field)| match field.query {
+            FieldQuery::Fulltext { ref target, .. } => {
+                doc.get(target.as_ref().unwrap_or(name))?.as_str()
+            }
+            _ => None,
+        })
+        .collect();
+
+    whatlang::detect(&text.join("\n"))
+        .filter(|info| info.is_reliable())
+        .and_then(|info| pg_language(info.lang()))
+        .unwrap_or("simple")
+}
+
+pub fn store_language<C: Connection>(
+    client: &mut C,
+    schema: &Schema,
+    doc_id: uuid::Uuid,
+    language: &str,
+) -> Result<(), CompassError> {
+    let (scope, tenant) = schema.tenant_scope(3)?;
+    let mut params: Vec<&(dyn ToSql + Sync)> = vec![&language, &doc_id];
+    params.extend(tenant.iter().map(|t| t as &(dyn ToSql + Sync)));
+
+    client
+        .client()
+        .map_err(pg_error(schema))?
+        .execute(
+            format!(
+                "UPDATE {} SET {} = $1::text::regconfig WHERE doc_id = $2{}",
+                schema.table, LANGUAGE_COLUMN, scope
+            )
+            .as_str(),
+            &params,
+        )
+        .map_err(pg_error(schema))?;
+
+    Ok(())
+}
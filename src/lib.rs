@@ -1,6 +1,108 @@
+pub mod admin;
+pub mod aggregate;
+pub mod alerts;
+pub mod annotate;
+pub mod audit;
+pub mod batch;
+pub mod cache;
+pub mod canonical;
+#[cfg(any(feature = "flight_support", feature = "parquet_support"))]
+mod columnar;
+pub mod config;
+pub mod cursor;
 mod db;
+pub mod diff;
+pub mod duplicates;
 pub mod err;
+pub mod es;
+pub mod export;
+#[cfg(feature = "flight_support")]
+pub mod flight;
+pub mod format;
+#[cfg(feature = "grpc_support")]
+pub mod grpc;
+pub mod help;
+pub mod hooks;
+pub mod idempotency;
+pub mod ingest;
+pub mod lucene;
+pub mod mongo;
+#[cfg(feature = "async_support")]
+pub mod nonblocking; // not glob-exported, its functions share names with the sync ones
+pub mod odata;
+pub mod pipeline;
+#[cfg(feature = "pool_support")]
+pub mod pool;
+pub mod presets;
+pub mod quality;
+pub mod quota;
+pub mod raw;
+pub mod response;
+pub mod rollups;
+pub mod sandbox;
+pub mod scheduler;
 pub mod schema;
+pub mod shapes;
+pub mod shutdown;
+pub mod similar;
+pub mod slowlog;
+pub mod snapshot;
+pub mod spelling;
+pub mod suggest;
+pub mod tenancy;
+#[cfg(test)]
+mod testing;
+pub mod throttle;
+pub mod usage;
+pub mod webhooks;
+pub use admin::*;
+pub use aggregate::*;
+pub use alerts::*;
+pub use annotate::*;
+pub use audit::*;
+pub use batch::*;
+pub use cache::*;
+pub use canonical::*;
+pub use config::*;
+pub use cursor::*;
 pub use db::*;
+pub use diff::*;
+pub use duplicates::*;
 pub use err::*;
+pub use es::*;
+pub use export::*;
+#[cfg(feature = "flight_support")]
+pub use flight::*;
+pub use format::*;
+#[cfg(feature = "grpc_support")]
+pub use grpc::*;
+pub use help::*;
+pub use hooks::*;
+pub use idempotency::*;
+pub use ingest::*;
+pub use lucene::*;
+pub use mongo::*;
+pub use odata::*;
+pub use pipeline::*;
+#[cfg(feature = "pool_support")]
+pub use pool::*;
+pub use presets::*;
+pub use quality::*;
+pub use quota::*;
+pub use raw::*;
+pub use response::*;
+pub use rollups::*;
+pub use sandbox::*;
+pub use scheduler::*;
 pub use schema::*;
+pub use shapes::*;
+pub use shutdown::*;
+pub use similar::*;
+pub use slowlog::*;
+pub use snapshot::*;
+pub use spelling::*;
+pub use suggest::*;
+pub use tenancy::*;
+pub use throttle::*;
+pub use usage::*;
+pub use webhooks::*;
@@ -0,0 +1,542 @@
+use super::*;
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+// `q=type:54 AND season:[12 TO 15] AND description:"home run"`, for people used to elasticsearch's
+// query strings. it's rewritten into the ordinary query parameters, so it filters exactly like them:
+//   field:value              field=value
+//   field:(a OR b)           field=a_or_b (AND works too)
+//   field:[a TO b]           <min>=a-1, <max>=b+1 on a range field; * leaves that end open
+//   field:>a, field:<b       <min>=a or <max>=b; >= and <= work for whole numbers
+//   NOT field:value, -field  field!=value
+// clauses are ANDed, with or without the AND. OR between clauses only works on the same field
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Field(String),
+    Word(String),
+    Phrase(String),
+    Open,
+    Close,
+    OpenRange,
+    CloseRange,
+    And,
+    Or,
+    Not,
+}
+
+fn syntax_error(msg: &str) -> CompassError {
+    CompassError::InvalidQuerySyntax(msg.to_owned())
+}
+
+fn is_field_name(s: &str) -> bool {
+    s.split('.').all(|segment| {
+        segment
+            .chars()
+            .next()
+            .map_or(false, |c| c.is_ascii_alphabetic() || c == '_')
+            && segment
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_')
+    })
+}
+
+// whether the next word is `field:...`
+fn starts_clause<I: Iterator<Item = char>>(chars: I) -> bool {
+    let word: String = chars
+        .take_while(|c| !c.is_whitespace() && !"()[]{}\"".contains(*c))
+        .collect();
+    match word.split_once(':') {
+        Some((field, _)) => is_field_name(field),
+        None => false,
+    }
+}
+
+fn tokenize(q: &str) -> Result<Vec<Token>, CompassError> {
+    let mut tokens = Vec::new();
+    let mut chars = q.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '(' | ')' | '[' | ']' => {
+                chars.next();
+                tokens.push(match c {
+                    '(' => Token::Open,
+                    ')' => Token::Close,
+                    '[' => Token::OpenRange,
+                    _ => Token::CloseRange,
+                });
+            }
+            '{' | '}' => {
+                return Err(syntax_error(
+                    "exclusive ranges aren't supported, use [a TO b] or > and <",
+                ))
+            }
+            '"' => {
+                chars.next();
+                let mut phrase = String::new();
+                loop {
+                    match chars.next() {
+                        Some('\\') => phrase.extend(chars.next()),
+                        Some('"') => break,
+                        Some(c) => phrase.push(c),
+                        None => return Err(syntax_error("unterminated quote")),
+                    }
+                }
+                tokens.push(Token::Phrase(phrase));
+            }
+            // -field:value negates, but -5 is just a negative number
+            '-' | '!' if starts_clause(chars.clone().skip(1)) => {
+                chars.next();
+                tokens.push(Token::Not);
+            }
+            _ => {
+                let mut word = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || "()[]{}\"".contains(c) {
+                        break;
+                    }
+                    chars.next();
+                    // the first colon after a field name ends the field; later ones are part of the value
+                    if c == ':' && is_field_name(&word) {
+                        tokens.push(Token::Field(word));
+                        word = String::new();
+                        continue;
+                    }
+                    word.push(c);
+                }
+                if word.is_empty() {
+                    continue;
+                }
+                tokens.push(match word.as_str() {
+                    "AND" | "&&" => Token::And,
+                    "OR" | "||" => Token::Or,
+                    "NOT" => Token::Not,
+                    _ => Token::Word(word),
+                });
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+#[derive(Debug)]
+enum Filter {
+    Terms(String), // already in the underscore syntax
+    Range(Option<String>, Option<String>),
+}
+
+#[derive(Debug)]
+struct Clause {
+    negated: bool,
+    field: String,
+    value: Filter,
+}
+
+struct Parser<'a> {
+    schema: &'a Schema,
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    // clauses, each with whether an OR joins it to the one before
+    fn clauses(&mut self) -> Result<Vec<(bool, Clause)>, CompassError> {
+        let mut clauses = Vec::new();
+        let mut or = false;
+
+        while let Some(token) = self.next() {
+            match token {
+                Token::And if !clauses.is_empty() => continue,
+                Token::Or if !clauses.is_empty() => or = true,
+                Token::Not => {
+                    let mut clause = self.clause()?;
+                    clause.negated = !clause.negated;
+                    clauses.push((or, clause));
+                    or = false;
+                }
+                Token::Field(_) => {
+                    self.pos -= 1;
+                    let clause = self.clause()?;
+                    clauses.push((or, clause));
+                    or = false;
+                }
+                Token::Word(w) | Token::Phrase(w) => {
+                    return Err(syntax_error(&format!(
+                        "'{}' needs a field, like field:{}",
+                        w, w
+                    )))
+                }
+                Token::Open => {
+                    return Err(syntax_error(
+                        "parentheses only group the values of one field, like field:(a OR b)",
+                    ))
+                }
+                t => return Err(syntax_error(&format!("unexpected {:?}", t))),
+            }
+        }
+
+        if or {
+            return Err(syntax_error("OR at the end of the query"));
+        }
+        Ok(clauses)
+    }
+
+    fn clause(&mut self) -> Result<Clause, CompassError> {
+        let field = match self.next() {
+            Some(Token::Field(f)) => f,
+            _ => return Err(syntax_error("NOT has to come before field:value")),
+        };
+
+        let value = match self.next() {
+            Some(Token::Word(w)) => self.word(&field, w)?,
+            Some(Token::Phrase(p)) => Filter::Terms(self.phrase(&field, p)),
+            Some(Token::OpenRange) => self.range(&field)?,
+            Some(Token::Open) => Filter::Terms(self.group(&field)?),
+            _ => return Err(syntax_error(&format!("{}: needs a value", field))),
+        };
+
+        Ok(Clause {
+            negated: false,
+            field,
+            value,
+        })
+    }
+
+    // a bare word, which might be one end of a range
+    fn word(&self, field: &str, w: String) -> Result<Filter, CompassError> {
+        Ok(if let Some(v) = w.strip_prefix(">=") {
+            Filter::Range(Some(self.bound(field, v, true, true)?), None)
+        } else if let Some(v) = w.strip_prefix("<=") {
+            Filter::Range(None, Some(self.bound(field, v, true, false)?))
+        } else if let Some(v) = w.strip_prefix('>') {
+            Filter::Range(Some(self.bound(field, v, false, true)?), None)
+        } else if let Some(v) = w.strip_prefix('<') {
+            Filter::Range(None, Some(self.bound(field, v, false, false)?))
+        } else {
+            Filter::Terms(w)
+        })
+    }
+
+    fn bound(
+        &self,
+        field: &str,
+        v: &str,
+        inclusive: bool,
+        lower: bool,
+    ) -> Result<String, CompassError> {
+        strict_bound(v, inclusive, lower).ok_or_else(|| {
+            syntax_error(&format!(
+                "{}: >=, <= and [a TO b] only work on whole numbers, use > and <",
+                field
+            ))
+        })
+    }
+
+    // quotes mean something to websearch fulltext fields, so they keep them
+    fn phrase(&self, field: &str, p: String) -> String {
+        match self.schema.resolve_field(field) {
+            Some((
+                _,
+                FieldQuery::Fulltext {
+                    syntax: FulltextSyntax::WebSearch,
+                    ..
+                },
+            )) => format!("\"{}\"", p),
+            _ => p,
+        }
+    }
+
+    fn range_end(&mut self) -> Result<Option<String>, CompassError> {
+        match self.next() {
+            Some(Token::Word(w)) if w == "*" => Ok(None),
+            Some(Token::Word(w)) | Some(Token::Phrase(w)) => Ok(Some(w)),
+            _ => Err(syntax_error("ranges look like [a TO b]")),
+        }
+    }
+
+    fn range(&mut self, field: &str) -> Result<Filter, CompassError> {
+        let from = self.range_end()?;
+        match self.next() {
+            Some(Token::Word(w)) if w == "TO" => {}
+            _ => return Err(syntax_error("ranges look like [a TO b]")),
+        }
+        let to = self.range_end()?;
+        match self.next() {
+            Some(Token::CloseRange) => Ok(Filter::Range(
+                from.map(|v| self.bound(field, &v, true, true))
+                    .transpose()?,
+                to.map(|v| self.bound(field, &v, true, false)).transpose()?,
+            )),
+            _ => Err(syntax_error("ranges look like [a TO b]")),
+        }
+    }
+
+    // field:(a OR b AND c) -> a_or_b_and_c
+    fn group(&mut self, field: &str) -> Result<String, CompassError> {
+        let mut terms = String::new();
+        let mut op = None;
+        loop {
+            let term = match self.next() {
+                Some(Token::Close) if !terms.is_empty() && op.is_none() => return Ok(terms),
+                Some(Token::And) if !terms.is_empty() && op.is_none() => {
+                    op = Some("_and_");
+                    continue;
+                }
+                Some(Token::Or) if !terms.is_empty() && op.is_none() => {
+                    op = Some("_or_");
+                    continue;
+                }
+                Some(Token::Word(w)) => w,
+                Some(Token::Phrase(p)) => self.phrase(field, p),
+                _ => return Err(syntax_error(&format!("{}: unfinished (group)", field))),
+            };
+            if !terms.is_empty() {
+                terms += op.take().unwrap_or("_and_");
+            }
+            terms += &term;
+        }
+    }
+}
+
+// `<min>`/`<max>` parameters are strict bounds (season_min=12 is season > 12), so an inclusive bound
+// is stepped one past its value, which only works for whole numbers. None for anything else
+pub(crate) fn strict_bound(value: &str, inclusive: bool, lower: bool) -> Option<String> {
+    if !inclusive {
+        return Some(value.to_owned());
+    }
+    let n = value.parse::<i64>().ok()?;
+    let stepped = if lower {
+        n.checked_sub(1)?
+    } else {
+        n.checked_add(1)?
+    };
+    Some(stepped.to_string())
+}
+
+// a value's `_or_`ed groups of `_and_`ed terms. _and_ binds tighter, the way parse_query_list reads it
+fn or_groups(value: &str) -> Vec<Vec<String>> {
+    let (terms, ops) = split_terms(value);
+    let mut groups: Vec<Vec<String>> = vec![Vec::new()];
+    for (i, term) in terms.into_iter().enumerate() {
+        if i > 0 && ops[i - 1] == "or" {
+            groups.push(Vec::new());
+        }
+        if let Some(group) = groups.last_mut() {
+            group.push(term);
+        }
+    }
+    groups
+}
+
+// a AND b for two values. there's no grouping inside a value, so gluing them together with _and_
+// would read `54_or_55` AND `12` as 54 OR (55 AND 12); every group of one is paired with every group
+// of the other instead: 54_and_12_or_55_and_12
+fn and_values(a: &str, b: &str) -> String {
+    let right = or_groups(b);
+    let mut groups = Vec::new();
+    for left in or_groups(a) {
+        for other in right.iter() {
+            groups.push(
+                left.iter()
+                    .chain(other.iter())
+                    .cloned()
+                    .collect::<Vec<_>>()
+                    .join("_and_"),
+            );
+        }
+    }
+    groups.join("_or_")
+}
+
+// the value for `key` that filters on both `existing` and `value`. a negated key stands for NOT (its
+// value), and NOT a AND NOT b is NOT (a OR b), so those are joined with _or_
+pub(crate) fn and_key_values(key: &str, existing: &str, value: &str) -> String {
+    if key.ends_with('!') {
+        format!("{}_or_{}", existing, value)
+    } else {
+        and_values(existing, value)
+    }
+}
+
+// adds a filter to the parameters, ANDed with any already there for that key
+pub(crate) fn and_param(params: &mut HashMap<String, String>, key: String, value: String) {
+    let joined = match params.get(&key) {
+        Some(existing) => and_key_values(&key, existing, &value),
+        None => value,
+    };
+    params.insert(key, joined);
+}
+
+// the query parameters `q` stands for
+pub fn parse_lucene(schema: &Schema, q: &str) -> Result<HashMap<String, String>, CompassError> {
+    let mut parser = Parser {
+        schema,
+        tokens: tokenize(q)?,
+        pos: 0,
+    };
+    let clauses = parser.clauses()?;
+
+    // OR'd clauses on one field fold into one value
+    let mut folded: Vec<Clause> = Vec::new();
+    for (or, clause) in clauses {
+        if !or {
+            folded.push(clause);
+            continue;
+        }
+        let previous = folded.last_mut().expect("an OR always follows a clause");
+        match (&mut previous.value, clause.value) {
+            (Filter::Terms(terms), Filter::Terms(more))
+                if previous.field == clause.field && previous.negated == clause.negated =>
+            {
+                *terms = format!("{}_or_{}", terms, more);
+            }
+            _ => {
+                return Err(syntax_error(
+                    "OR only works between values of the same field, like a:1 OR a:2",
+                ))
+            }
+        }
+    }
+
+    let mut params: HashMap<String, String> = HashMap::new();
+    for clause in folded {
+        match clause.value {
+            Filter::Terms(terms) => {
+                let key = if clause.negated {
+                    format!("{}!", clause.field)
+                } else {
+                    clause.field
+                };
+                and_param(&mut params, key, terms);
+            }
+            Filter::Range(from, to) => {
+                let (min, max) = match schema.resolve_field(&clause.field) {
+                    Some((_, FieldQuery::Range { min, max, .. })) if !clause.negated => (min, max),
+                    Some((_, FieldQuery::Range { .. })) => {
+                        return Err(syntax_error("ranges can't be negated"))
+                    }
+                    _ => {
+                        return Err(syntax_error(&format!(
+                            "{} isn't a range field",
+                            clause.field
+                        )))
+                    }
+                };
+                if let Some(from) = from {
+                    and_param(&mut params, min, from);
+                }
+                if let Some(to) = to {
+                    and_param(&mut params, max, to);
+                }
+            }
+        }
+    }
+
+    Ok(params)
+}
+
+// `fields` with `q` swapped for the parameters it stands for, joined with any the request already had
+pub(crate) fn expand_lucene<'a>(
+    schema: &Schema,
+    fields: &'a HashMap<String, String>,
+) -> Result<Cow<'a, HashMap<String, String>>, CompassError> {
+    let q = match fields.get("q") {
+        Some(q) => q,
+        None => return Ok(Cow::Borrowed(fields)),
+    };
+
+    let mut expanded = fields.clone();
+    expanded.remove("q");
+    for (key, value) in parse_lucene(schema, q)? {
+        and_param(&mut expanded, key, value);
+    }
+    Ok(Cow::Owned(expanded))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    fn compiled(q: &str) -> String {
+        let schema = test_schema();
+        json_query(&schema, &parse_lucene(&schema, q).unwrap())
+    }
+
+    #[test]
+    fn inclusive_range_keeps_both_ends() {
+        assert_eq!(
+            compiled("season:[12 TO 15]"),
+            format!("(({}) && ({}))", season_below(16), season_above(11))
+        );
+        assert_eq!(
+            compiled("season:[12 TO *]"),
+            format!("(({}))", season_above(11))
+        );
+    }
+
+    #[test]
+    fn one_sided_bounds() {
+        assert_eq!(compiled("season:>=12"), format!("(({}))", season_above(11)));
+        assert_eq!(compiled("season:>12"), format!("(({}))", season_above(12)));
+        assert_eq!(compiled("season:<=15"), format!("(({}))", season_below(16)));
+        assert_eq!(compiled("season:<15"), format!("(({}))", season_below(15)));
+    }
+
+    #[test]
+    fn repeated_negations_exclude_each_value() {
+        // every dialect ANDs its filters together through and_param
+        let schema = test_schema();
+        let dialects = vec![
+            ("lucene", parse_lucene(&schema, "NOT type:1 NOT type:2")),
+            (
+                "es",
+                es_params(
+                    &schema,
+                    &serde_json::json!({"query": {"bool": {"must_not": [
+                        {"term": {"type": 1}},
+                        {"term": {"type": 2}}
+                    ]}}}),
+                ),
+            ),
+            (
+                "mongo",
+                mongo_params(
+                    &schema,
+                    &serde_json::json!({"type": {"$ne": 1, "$nin": [2]}}),
+                ),
+            ),
+            (
+                "odata",
+                odata_params(&schema, &params(&[("$filter", "type ne 1 and type ne 2")])),
+            ),
+        ];
+
+        for (dialect, fields) in dialects {
+            assert_eq!(
+                json_query(&schema, &fields.unwrap()),
+                format!("(!(({} || {})))", type_is(1), type_is(2)),
+                "{}",
+                dialect
+            );
+        }
+    }
+
+    #[test]
+    fn inclusive_bounds_need_whole_numbers() {
+        let schema = test_schema();
+        assert!(parse_lucene(&schema, "season:>=twelve").is_err());
+        assert!(parse_lucene(&schema, "season:[1.5 TO 3]").is_err());
+    }
+}
@@ -0,0 +1,141 @@
+use compass::*;
+
+use std::collections::HashMap;
+use std::env;
+use std::path::PathBuf;
+use std::process;
+
+const USAGE: &str = "usage: compass [--config compass.toml] <command> [options]
+
+commands:
+  snapshot --schema <name> --out <file>   export every document of a schema (.zst and .gz are compressed)
+  restore --schema <name> --in <file> (--replace | --merge)
+                                          load a snapshot through the schema's current converters. --replace
+                                          empties the table first, --merge overwrites documents by id.
+                                          documents that don't fit the schema anymore are printed as ndjson";
+
+// flags that don't take a value
+const SWITCHES: &[&str] = &["replace", "merge"];
+
+struct Args {
+    command: String,
+    options: HashMap<String, String>,
+}
+
+impl Args {
+    fn parse(args: Vec<String>) -> Result<Args, String> {
+        let mut command = None;
+        let mut options = HashMap::new();
+        let mut args = args.into_iter();
+
+        while let Some(arg) = args.next() {
+            match arg.strip_prefix("--") {
+                Some(name) if SWITCHES.contains(&name) => {
+                    options.insert(name.to_owned(), String::new());
+                }
+                Some(name) => {
+                    let value = args
+                        .next()
+                        .ok_or_else(|| format!("--{} needs a value", name))?;
+                    options.insert(name.to_owned(), value);
+                }
+                None if command.is_none() => command = Some(arg),
+                None => return Err(format!("unexpected argument '{}'", arg)),
+            }
+        }
+
+        Ok(Args {
+            command: command.ok_or_else(|| "no command given".to_owned())?,
+            options,
+        })
+    }
+
+    fn required(&self, name: &str) -> Result<&str, String> {
+        self.options
+            .get(name)
+            .map(String::as_str)
+            .ok_or_else(|| format!("{} needs --{}", self.command, name))
+    }
+}
+
+fn run(args: &Args) -> Result<(), String> {
+    let config_path = args
+        .options
+        .get("config")
+        .cloned()
+        .or_else(|| env::var("COMPASS_CONFIG").ok())
+        .unwrap_or_else(|| "compass.toml".to_owned());
+    let config = Config::from_file(&config_path).map_err(|e| e.to_string())?;
+    config.validate().map_err(|e| e.to_string())?;
+    let schemas = config.load_schemas().map_err(|e| e.to_string())?;
+
+    let schema_name = args.required("schema")?;
+    let schema = schemas
+        .get(schema_name)
+        .ok_or_else(|| format!("no schema '{}' in {}", schema_name, config_path))?;
+
+    match args.command.as_str() {
+        "snapshot" => {
+            let out = PathBuf::from(args.required("out")?);
+            let mut client = config
+                .database
+                .connect_writable()
+                .map_err(|e| e.to_string())?;
+            let written = snapshot_to_file(&mut client, schema_name, schema, &out)
+                .map_err(|e| e.to_string())?;
+            eprintln!(
+                "compass: wrote {} documents of {} to {}",
+                written,
+                schema_name,
+                out.display()
+            );
+            Ok(())
+        }
+        "restore" => {
+            let input = PathBuf::from(args.required("in")?);
+            let mode = match (
+                args.options.contains_key("replace"),
+                args.options.contains_key("merge"),
+            ) {
+                (true, false) => RestoreMode::Replace,
+                (false, true) => RestoreMode::Merge,
+                _ => return Err("restore needs one of --replace or --merge".to_owned()),
+            };
+            let mut client = config
+                .database
+                .connect_writable()
+                .map_err(|e| e.to_string())?;
+            let report =
+                restore_from_file(&mut client, schema, &input, mode).map_err(|e| e.to_string())?;
+
+            for rejected in report.rejected.iter() {
+                println!(
+                    "{}",
+                    serde_json::to_string(rejected).map_err(|e| e.to_string())?
+                );
+            }
+            if report.snapshot_version != report.schema_version {
+                eprintln!(
+                    "compass: {} has changed since the snapshot was taken ({} -> {})",
+                    schema_name, report.snapshot_version, report.schema_version
+                );
+            }
+            eprintln!(
+                "compass: restored {} documents into {}, {} didn't fit the schema",
+                report.restored,
+                schema_name,
+                report.rejected.len()
+            );
+            Ok(())
+        }
+        other => Err(format!("unknown command '{}'", other)),
+    }
+}
+
+fn main() {
+    let result = Args::parse(env::args().skip(1).collect()).and_then(|args| run(&args));
+    if let Err(e) = result {
+        eprintln!("compass: {}\n\n{}", e, USAGE);
+        process::exit(1);
+    }
+}
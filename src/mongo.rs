@@ -0,0 +1,311 @@
+use super::*;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use std::collections::HashMap;
+
+// a POST search: the same parameters as a GET, plus optionally a mongodb find filter
+//   {"filter": {"type": {"$in": [54, 55]}, "season": {"$gte": 12}}, "params": {"sortby": "created"}}
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SearchBody {
+    #[serde(default)]
+    pub params: HashMap<String, String>,
+    pub filter: Option<Value>,
+}
+
+fn unsupported(msg: &str) -> CompassError {
+    CompassError::InvalidFilterDocument(msg.to_owned())
+}
+
+// one field's condition: a plain value for equality, or an operator document
+fn translate_field(
+    schema: &Schema,
+    field: &str,
+    condition: &Value,
+    negated: bool,
+    params: &mut HashMap<String, String>,
+) -> Result<(), CompassError> {
+    let operators = match condition.as_object() {
+        Some(ops) if ops.keys().all(|k| k.starts_with('$')) && !ops.is_empty() => ops,
+        _ => {
+            add(params, field, param_value(condition)?, negated);
+            return Ok(());
+        }
+    };
+
+    for (op, v) in operators {
+        match op.as_str() {
+            "$eq" => add(params, field, param_value(v)?, negated),
+            "$ne" => add(params, field, param_value(v)?, !negated),
+            "$in" | "$nin" => {
+                let values = v
+                    .as_array()
+                    .ok_or_else(|| unsupported(&format!("{} takes a list", op)))?
+                    .iter()
+                    .map(param_value)
+                    .collect::<Result<Vec<_>, _>>()?;
+                if values.is_empty() {
+                    return Err(unsupported(&format!("{} needs at least one value", op)));
+                }
+                add(params, field, values.join("_or_"), negated ^ (op == "$nin"));
+            }
+            "$gt" | "$gte" | "$lt" | "$lte" if !negated => {
+                let (min, max) = match schema.resolve_field(field) {
+                    Some((_, FieldQuery::Range { min, max, .. })) => (min, max),
+                    _ => return Err(unsupported(&format!("{} isn't a range field", field))),
+                };
+                let inclusive = op == "$gte" || op == "$lte";
+                let lower = op == "$gt" || op == "$gte";
+                // min and max are strict, so $gte and $lte step one past their value
+                let value = strict_bound(&param_value(v)?, inclusive, lower).ok_or_else(|| {
+                    unsupported("$gte and $lte only work on whole numbers, use $gt or $lt")
+                })?;
+                let key = if lower { min } else { max };
+                and_param(params, key, value);
+            }
+            "$not" => translate_field(schema, field, v, !negated, params)?,
+            op => return Err(unsupported(&format!("{} on a field", op))),
+        }
+    }
+    Ok(())
+}
+
+fn add(params: &mut HashMap<String, String>, field: &str, value: String, negated: bool) {
+    let key = if negated {
+        format!("{}!", field)
+    } else {
+        field.to_owned()
+    };
+    and_param(params, key, value);
+}
+
+// the query parameters a find filter stands for. fields are ANDed, like in mongo; $or only works when
+// every branch is one equality on the same field, since the parameters can't say "this field or that one"
+pub fn mongo_params(
+    schema: &Schema,
+    filter: &Value,
+) -> Result<HashMap<String, String>, CompassError> {
+    let filter = filter
+        .as_object()
+        .ok_or_else(|| unsupported("a filter has to be an object"))?;
+    let mut params = HashMap::new();
+
+    for (key, condition) in filter {
+        match key.as_str() {
+            "$and" => {
+                for branch in branches(condition)? {
+                    for (k, v) in mongo_params(schema, branch)? {
+                        and_param(&mut params, k, v);
+                    }
+                }
+            }
+            "$or" => {
+                let mut field = None;
+                let mut values = Vec::new();
+                for branch in branches(condition)? {
+                    let equality = match branch.as_object() {
+                        Some(b) if b.len() == 1 => b.iter().next().unwrap(),
+                        _ => return Err(unsupported("$or branches have to be single fields")),
+                    };
+                    let value = match equality.1 {
+                        Value::Object(ops) if ops.len() == 1 && ops.contains_key("$eq") => {
+                            param_value(&ops["$eq"])?
+                        }
+                        v => param_value(v)?,
+                    };
+                    if field.map_or(false, |f| f != equality.0) {
+                        return Err(unsupported("$or branches have to be on the same field"));
+                    }
+                    field = Some(equality.0);
+                    values.push(value);
+                }
+                if let Some(field) = field {
+                    add(&mut params, field, values.join("_or_"), false);
+                }
+            }
+            op if op.starts_with('$') => {
+                return Err(unsupported(&format!("{} at the top level", op)))
+            }
+            field => translate_field(schema, field, condition, false, &mut params)?,
+        }
+    }
+
+    Ok(params)
+}
+
+fn branches(v: &Value) -> Result<&Vec<Value>, CompassError> {
+    v.as_array()
+        .ok_or_else(|| unsupported("$and and $or take a list of filters"))
+}
+
+// a parameter value's term as json, as it would be written in a filter document: numbers and booleans
+// where the field takes them and the text says exactly that, strings otherwise
+fn term_value(query: &FieldQuery, term: &str) -> Value {
+    match query {
+        FieldQuery::Fulltext { .. } | FieldQuery::StringTag | FieldQuery::Vector { .. } => {}
+        FieldQuery::Bool => {
+            if let Ok(b) = term.parse::<bool>() {
+                return Value::Bool(b);
+            }
+        }
+        _ => {
+            if let Ok(n) = term.parse::<i64>() {
+                if n.to_string() == term {
+                    return Value::from(n);
+                }
+            }
+        }
+    }
+    Value::String(term.to_owned())
+}
+
+// the inverse of mongo_params, for showing or sharing a query as a filter document: the filter the
+// parameters stand for, and the parameters no filter document can say (query groups, negated bounds,
+// lists mixing `_and_` and `_or_`), to send alongside it as `params`
+pub fn filter_document<'a, I>(schema: &Schema, params: I) -> (Value, HashMap<String, String>)
+where
+    I: IntoIterator<Item = (&'a String, &'a String)>,
+{
+    let mut fields: Map<String, Value> = Map::new();
+    let mut and = Vec::new();
+    let mut rest = HashMap::new();
+
+    for (key, value) in params {
+        let (base, negated) = match key.strip_suffix('!') {
+            Some(base) => (base, true),
+            None => (key.as_str(), false),
+        };
+        let (name, query) = match schema.resolve_field(base) {
+            Some(resolved) => resolved,
+            None => {
+                rest.insert(key.clone(), value.clone());
+                continue;
+            }
+        };
+        let (terms, ops) = split_terms(value);
+        let mut operators = |field: &str, op: &str, v: Value| {
+            if let Value::Object(ops) = fields
+                .entry(field.to_owned())
+                .or_insert_with(|| Value::Object(Map::new()))
+            {
+                ops.insert(op.to_owned(), v);
+            }
+        };
+
+        match query {
+            FieldQuery::Min | FieldQuery::Max if !negated && terms.len() == 1 => {
+                // the parameters are strict bounds, so they read back through mongo_params unchanged
+                let op = if let FieldQuery::Min = query {
+                    "$gt"
+                } else {
+                    "$lt"
+                };
+                operators(&name, op, term_value(&query, &terms[0]));
+            }
+            FieldQuery::Min | FieldQuery::Max => {
+                rest.insert(key.clone(), value.clone());
+            }
+            _ if terms.len() == 1 => {
+                let op = if negated { "$ne" } else { "$eq" };
+                operators(base, op, term_value(&query, &terms[0]));
+            }
+            _ if !terms.is_empty() && ops.iter().all(|op| *op == "or") => {
+                let op = if negated { "$nin" } else { "$in" };
+                let values = terms.iter().map(|t| term_value(&query, t)).collect();
+                operators(base, op, Value::Array(values));
+            }
+            _ if !negated && !terms.is_empty() && ops.iter().all(|op| *op == "and") => {
+                for term in terms.iter() {
+                    let mut branch = Map::new();
+                    branch.insert(base.to_owned(), term_value(&query, term));
+                    and.push(Value::Object(branch));
+                }
+            }
+            _ => {
+                rest.insert(key.clone(), value.clone());
+            }
+        }
+    }
+
+    // a lone $eq reads better as the plain value
+    for condition in fields.values_mut() {
+        let plain = match condition {
+            Value::Object(ops) if ops.len() == 1 => ops.remove("$eq"),
+            _ => None,
+        };
+        if let Some(plain) = plain {
+            *condition = plain;
+        }
+    }
+    if !and.is_empty() {
+        fields.insert("$and".to_owned(), Value::Array(and));
+    }
+    (Value::Object(fields), rest)
+}
+
+// the parameters a POST body stands for: the filter's ANDed with `params`
+pub fn body_params(
+    schema: &Schema,
+    body: &SearchBody,
+) -> Result<HashMap<String, String>, CompassError> {
+    let mut params = body.params.clone();
+    if let Some(ref filter) = body.filter {
+        for (key, value) in mongo_params(schema, filter)? {
+            and_param(&mut params, key, value);
+        }
+    }
+    Ok(params)
+}
+
+// searches with a POST body
+pub fn json_search_body<C: Connection>(
+    client: &mut C,
+    schema: &Schema,
+    body: &SearchBody,
+) -> Result<SearchResponse, CompassError> {
+    json_search_response(client, schema, &body_params(schema, body)?, None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+    use serde_json::json;
+
+    fn compiled(filter: Value) -> String {
+        let schema = test_schema();
+        json_query(&schema, &mongo_params(&schema, &filter).unwrap())
+    }
+
+    #[test]
+    fn range_operators() {
+        assert_eq!(
+            compiled(json!({"season": {"$gte": 12}})),
+            format!("(({}))", season_above(11))
+        );
+        assert_eq!(
+            compiled(json!({"season": {"$gt": 12}})),
+            format!("(({}))", season_above(12))
+        );
+        assert_eq!(
+            compiled(json!({"season": {"$lte": 15}})),
+            format!("(({}))", season_below(16))
+        );
+        assert_eq!(
+            compiled(json!({"season": {"$lt": 15}})),
+            format!("(({}))", season_below(15))
+        );
+    }
+
+    #[test]
+    fn bounds_round_trip() {
+        let schema = test_schema();
+        let bounds = params(&[("season_min", "11"), ("season_max", "16")]);
+
+        let (filter, rest) = filter_document(&schema, bounds.iter());
+        assert_eq!(filter, json!({"season": {"$gt": 11, "$lt": 16}}));
+        assert!(rest.is_empty());
+        assert_eq!(mongo_params(&schema, &filter).unwrap(), bounds);
+    }
+}
@@ -0,0 +1,295 @@
+// json_search, json_count and get_by_ids over tokio_postgres, for async servers (axum, warp, ...) that
+// would otherwise have to run the sync ones on blocking threads. they build the same statements as the
+// sync functions and answer the same way, except as noted on json_search_response
+use super::*;
+
+use futures::{join, pin_mut, TryStreamExt};
+use postgres::types::ToSql;
+use postgres::types::Type as PostgresType;
+use serde_json::Value;
+use tokio_postgres::Client;
+use uuid::Uuid;
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::time::Instant;
+
+// runs `query` with schema_permit held and the quota's statement timeout set, then puts the
+// connection's own timeout back. schemas without a timeout in their quota leave it alone. tasks
+// sharing a Client interleave their statements, so another task's query can land while a quota's
+// timeout is set: give schemas with statement_timeout_ms a connection per task
+async fn permitted<T>(
+    client: &Client,
+    schema: &Schema,
+    query: impl Future<Output = Result<T, CompassError>>,
+) -> Result<T, CompassError> {
+    let _permit = schema_permit(schema)?;
+    let ms = match schema.quota.as_ref().and_then(|q| q.statement_timeout_ms()) {
+        Some(ms) => ms.to_string(),
+        None => return query.await,
+    };
+
+    // tokio_postgres sends requests in the order their futures are first polled, so the swap goes out
+    // first and the query's first statement right behind it, in the same round trip
+    let swap = async {
+        client
+            .query_one(SWAP_STATEMENT_TIMEOUT, &[&ms])
+            .await
+            .map_err(pg_error(schema))
+    };
+    let (swapped, result) = join!(swap, query);
+    let previous: String = swapped?.get(0);
+
+    let restored = client
+        .query_one(RESTORE_STATEMENT_TIMEOUT, &[&previous])
+        .await
+        .map_err(pg_error(schema));
+    let value = result?;
+    restored?;
+    Ok(value)
+}
+
+pub async fn json_search(
+    client: &Client,
+    schema: &Schema,
+    fields: &HashMap<String, String>,
+    raw_query: Option<RawQuery>,
+) -> Result<Vec<Value>, CompassError> {
+    Ok(json_search_response(client, schema, fields, raw_query)
+        .await?
+        .data)
+}
+
+// the sync json_search_response, except that `similar_to` isn't supported and meta.did_you_mean is
+// never filled in
+pub async fn json_search_response(
+    client: &Client,
+    schema: &Schema,
+    fields: &HashMap<String, String>,
+    raw_query: Option<RawQuery>,
+) -> Result<SearchResponse, CompassError> {
+    if fields.contains_key("similar_to") {
+        return Err(CompassError::ConversionError(
+            "similar_to needs the sync json_search".to_owned(),
+        ));
+    }
+
+    permitted(
+        client,
+        schema,
+        search_page(client, schema, fields, raw_query),
+    )
+    .await
+}
+
+async fn search_page(
+    client: &Client,
+    schema: &Schema,
+    fields: &HashMap<String, String>,
+    raw_query: Option<RawQuery>,
+) -> Result<SearchResponse, CompassError> {
+    let fields = &*prepare_params(schema, fields)?;
+
+    let collect_stats = fields
+        .get("debug")
+        .map_or(false, |d| d.split(',').any(|x| x == "stats"));
+    let started = Instant::now();
+
+    let raw_query = match raw_query {
+        Some(q) => Some(q.checked(&schema.raw_query)?),
+        None => None,
+    };
+
+    let search = search_statement::<Value>(schema, fields, raw_query.as_deref(), &|_| Ok(()))?;
+
+    if search.limit == 0 {
+        let total = count(client, schema, fields, raw_query).await?;
+        let stats = if collect_stats {
+            Some(QueryStats {
+                total_ms: millis(started.elapsed()),
+                ..QueryStats::default()
+            })
+        } else {
+            None
+        };
+
+        return Ok(SearchResponse {
+            data: Vec::new(),
+            meta: SearchMeta {
+                ignored_params: search.ignored_params,
+                total: Some(total),
+                did_you_mean: None,
+                next_cursor: None,
+                truncated: false,
+                next_offset: None,
+            },
+            stats,
+        });
+    }
+
+    let planned = Instant::now();
+
+    // query_typed_raw sends the parameter types along, so there's no separate round trip to prepare
+    let rows = client
+        .query_typed_raw(
+            search.query.as_str(),
+            search
+                .params()
+                .into_iter()
+                .zip(search.param_types.iter().cloned()),
+        )
+        .await
+        .map_err(pg_error(schema))?;
+    pin_mut!(rows);
+
+    let executed = Instant::now();
+
+    let mut page = PageReader::<Value>::new(schema);
+    while let Some(row) = rows.try_next().await.map_err(pg_error(schema))? {
+        if !page.push(schema, row)? {
+            break;
+        }
+    }
+
+    let fetched = Instant::now();
+    search.record(schema, page.data.len(), fetched - planned);
+
+    let next_cursor = page.next_cursor(schema, fields, raw_query.as_deref(), &search)?;
+    // every row carries with_total's COUNT(*) OVER (), so only an empty page past the first one has to
+    // be counted on its own
+    let total = if !search.with_total {
+        None
+    } else {
+        match page.total(&search) {
+            Some(total) => Some(total),
+            None => Some(count(client, schema, fields, raw_query).await?),
+        }
+    };
+    let next_offset = page.next_offset(&search);
+
+    let stats = if collect_stats {
+        let finished = Instant::now();
+        Some(QueryStats {
+            plan_ms: millis(planned - started),
+            prepare_ms: 0,
+            execute_ms: millis(executed - planned),
+            fetch_ms: millis((fetched - executed).saturating_sub(page.converting)),
+            convert_ms: millis(page.converting),
+            total_ms: millis(finished - started),
+            rows: page.data.len(),
+        })
+    } else {
+        None
+    };
+
+    Ok(SearchResponse {
+        data: page.data,
+        meta: SearchMeta {
+            ignored_params: search.ignored_params,
+            total,
+            did_you_mean: None,
+            next_cursor,
+            truncated: page.truncated,
+            next_offset,
+        },
+        stats,
+    })
+}
+
+pub async fn json_count(
+    client: &Client,
+    schema: &Schema,
+    fields: &HashMap<String, String>,
+) -> Result<i64, CompassError> {
+    permitted(client, schema, async {
+        count(client, schema, &prepare_params(schema, fields)?, None).await
+    })
+    .await
+}
+
+// `fields` have to be prepared already, see prepare_params
+async fn count(
+    client: &Client,
+    schema: &Schema,
+    fields: &HashMap<String, String>,
+    raw_query: Option<String>,
+) -> Result<i64, CompassError> {
+    let (query, plan) = count_statement(schema, fields, raw_query, &|_| Ok(()))?;
+
+    let started = Instant::now();
+
+    let params: Vec<&(dyn ToSql + Sync)> = std::iter::once(&plan.json_query as &(dyn ToSql + Sync))
+        .chain(plan.bindings.iter().map(Binding::as_sync_sql))
+        .collect();
+    let rows = client
+        .query_typed_raw(
+            query.as_str(),
+            params
+                .iter()
+                .copied()
+                .zip(plan.param_types(&[PostgresType::TEXT])),
+        )
+        .await
+        .map_err(pg_error(schema))?;
+    pin_mut!(rows);
+    let row = rows
+        .try_next()
+        .await
+        .map_err(pg_error(schema))?
+        .expect("COUNT(*) always returns a row");
+
+    if let Some(ref log) = schema.slow_log {
+        log.record(
+            &schema.table,
+            &query,
+            || params.iter().map(|p| format!("{:?}", p)).collect(),
+            started.elapsed(),
+        );
+    }
+    record_usage(schema, 0, started.elapsed());
+
+    row.try_get::<usize, i64>(0).map_err(pg_error(schema))
+}
+
+pub async fn get_by_ids(
+    client: &Client,
+    schema: &Schema,
+    ids: &Vec<Uuid>,
+) -> Result<Vec<Value>, CompassError> {
+    permitted(client, schema, documents(client, schema, ids)).await
+}
+
+async fn documents(
+    client: &Client,
+    schema: &Schema,
+    ids: &Vec<Uuid>,
+) -> Result<Vec<Value>, CompassError> {
+    let (scope, tenant) = schema.tenant_scope(2)?;
+    let mut params: Vec<(&(dyn ToSql + Sync), PostgresType)> =
+        vec![(ids as &(dyn ToSql + Sync), PostgresType::UUID_ARRAY)];
+    params.extend(
+        tenant
+            .iter()
+            .map(|t| (t as &(dyn ToSql + Sync), PostgresType::TEXT)),
+    );
+
+    let rows = client
+        .query_typed_raw(
+            format!(
+                "SELECT object FROM {} WHERE doc_id = ANY($1){}",
+                schema.table, scope
+            )
+            .as_str(),
+            params,
+        )
+        .await
+        .map_err(pg_error(schema))?;
+    rows.map_ok(|x| {
+        let mut val = x.get::<usize, Value>(0);
+        convert_document(schema, &mut val);
+        val
+    })
+    .try_collect()
+    .await
+    .map_err(pg_error(schema))
+}
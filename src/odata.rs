@@ -0,0 +1,405 @@
+use super::*;
+
+use serde_json::{json, Value};
+
+use std::collections::HashMap;
+
+// OData query options, for enterprise tooling (excel and power bi connectors) that speaks it:
+//   $filter=type eq 54 and season ge 12 and (weather eq 1 or weather eq 2)
+//   $orderby=created desc, $top=50, $skip=100, $select=name,player/id, $count=true
+// like q= and the elasticsearch body, $filter is rewritten into the usual query parameters. it handles
+// eq, ne, gt, ge, lt, le, in (...), not, and, contains(field, 'text') on fulltext fields, and or between
+// equalities on the same field
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Text(String),
+    Open,
+    Close,
+    Comma,
+}
+
+#[derive(Debug)]
+enum Expr {
+    Compare(String, String, String), // field, operator, value
+    In(String, Vec<String>),
+    Contains(String, String),
+    Not(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+}
+
+fn unsupported(msg: &str) -> CompassError {
+    CompassError::InvalidODataQuery(msg.to_owned())
+}
+
+fn tokenize(filter: &str) -> Result<Vec<Token>, CompassError> {
+    let mut tokens = Vec::new();
+    let mut chars = filter.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '(' | ')' | ',' => {
+                chars.next();
+                tokens.push(match c {
+                    '(' => Token::Open,
+                    ')' => Token::Close,
+                    _ => Token::Comma,
+                });
+            }
+            '\'' => {
+                chars.next();
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        // '' is a quote inside a string
+                        Some('\'') if chars.peek() == Some(&'\'') => {
+                            chars.next();
+                            text.push('\'');
+                        }
+                        Some('\'') => break,
+                        Some(c) => text.push(c),
+                        None => return Err(unsupported("unterminated string")),
+                    }
+                }
+                tokens.push(Token::Text(text));
+            }
+            _ => {
+                let mut word = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || "(),'".contains(c) {
+                        break;
+                    }
+                    word.push(c);
+                    chars.next();
+                }
+                tokens.push(Token::Word(word));
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn keyword(&mut self, keyword: &str) -> bool {
+        match self.tokens.get(self.pos) {
+            Some(Token::Word(w)) if w == keyword => {
+                self.pos += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn expect(&mut self, token: Token) -> Result<(), CompassError> {
+        match self.next() {
+            Some(ref t) if *t == token => Ok(()),
+            _ => Err(unsupported(&format!("expected {:?}", token))),
+        }
+    }
+
+    fn or(&mut self) -> Result<Expr, CompassError> {
+        let mut expr = self.and()?;
+        while self.keyword("or") {
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr, CompassError> {
+        let mut expr = self.unary()?;
+        while self.keyword("and") {
+            expr = Expr::And(Box::new(expr), Box::new(self.unary()?));
+        }
+        Ok(expr)
+    }
+
+    fn unary(&mut self) -> Result<Expr, CompassError> {
+        if self.keyword("not") {
+            return Ok(Expr::Not(Box::new(self.unary()?)));
+        }
+
+        match self.next() {
+            Some(Token::Open) => {
+                let expr = self.or()?;
+                self.expect(Token::Close)?;
+                Ok(expr)
+            }
+            Some(Token::Word(ref f)) if f == "contains" => {
+                self.expect(Token::Open)?;
+                let field = self.field()?;
+                self.expect(Token::Comma)?;
+                let text = self.literal()?;
+                self.expect(Token::Close)?;
+                Ok(Expr::Contains(field, text))
+            }
+            Some(Token::Word(field)) => {
+                let field = field_name(&field)?;
+                if self.keyword("in") {
+                    self.expect(Token::Open)?;
+                    let mut values = vec![self.literal()?];
+                    while let Some(Token::Comma) = self.tokens.get(self.pos) {
+                        self.pos += 1;
+                        values.push(self.literal()?);
+                    }
+                    self.expect(Token::Close)?;
+                    return Ok(Expr::In(field, values));
+                }
+
+                let op = match self.next() {
+                    Some(Token::Word(op)) => op,
+                    _ => return Err(unsupported(&format!("{} needs an operator", field))),
+                };
+                if !["eq", "ne", "gt", "ge", "lt", "le"].contains(&op.as_str()) {
+                    return Err(unsupported(&format!("the {} operator", op)));
+                }
+                Ok(Expr::Compare(field, op, self.literal()?))
+            }
+            _ => Err(unsupported("expected a comparison")),
+        }
+    }
+
+    fn field(&mut self) -> Result<String, CompassError> {
+        match self.next() {
+            Some(Token::Word(w)) => field_name(&w),
+            _ => Err(unsupported("expected a field")),
+        }
+    }
+
+    fn literal(&mut self) -> Result<String, CompassError> {
+        match self.next() {
+            Some(Token::Text(t)) => Ok(t),
+            Some(Token::Word(w)) if w != "null" => Ok(w),
+            _ => Err(unsupported("expected a value")),
+        }
+    }
+}
+
+// OData paths use slashes: player/id
+fn field_name(path: &str) -> Result<String, CompassError> {
+    let name = path.replace('/', ".");
+    let valid = name.split('.').all(|segment| {
+        segment
+            .chars()
+            .next()
+            .map_or(false, |c| c.is_ascii_alphabetic() || c == '_')
+            && segment
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_')
+    });
+    if valid {
+        Ok(name)
+    } else {
+        Err(unsupported(&format!("'{}' isn't a field", path)))
+    }
+}
+
+// an `or` of equalities on one field, as (field, values)
+fn alternatives(expr: &Expr) -> Option<(String, Vec<String>)> {
+    match expr {
+        Expr::Compare(field, op, value) if op == "eq" => Some((field.clone(), vec![value.clone()])),
+        Expr::In(field, values) => Some((field.clone(), values.clone())),
+        Expr::Or(a, b) => {
+            let (field, mut values) = alternatives(a)?;
+            let (other, more) = alternatives(b)?;
+            if field != other {
+                return None;
+            }
+            values.extend(more);
+            Some((field, values))
+        }
+        _ => None,
+    }
+}
+
+fn translate(
+    schema: &Schema,
+    expr: Expr,
+    negated: bool,
+    params: &mut HashMap<String, String>,
+) -> Result<(), CompassError> {
+    match expr {
+        Expr::And(a, b) if !negated => {
+            translate(schema, *a, false, params)?;
+            translate(schema, *b, false, params)
+        }
+        Expr::Not(inner) => translate(schema, *inner, !negated, params),
+        Expr::Compare(field, op, value) => match op.as_str() {
+            "eq" | "ne" => {
+                add(params, field, value, negated ^ (op == "ne"));
+                Ok(())
+            }
+            _ if negated => Err(unsupported("not can't hold a range comparison")),
+            op => {
+                let (min, max) = match schema.resolve_field(&field) {
+                    Some((_, FieldQuery::Range { min, max, .. })) => (min, max),
+                    _ => return Err(unsupported(&format!("{} isn't a range field", field))),
+                };
+                let lower = op == "gt" || op == "ge";
+                // min and max are strict, so ge and le step one past their value
+                let value =
+                    strict_bound(&value, op == "ge" || op == "le", lower).ok_or_else(|| {
+                        unsupported("ge and le only work on whole numbers, use gt or lt")
+                    })?;
+                let key = if lower { min } else { max };
+                and_param(params, key, value);
+                Ok(())
+            }
+        },
+        Expr::Contains(field, text) => match schema.resolve_field(&field) {
+            Some((_, FieldQuery::Fulltext { .. })) => {
+                add(params, field, text, negated);
+                Ok(())
+            }
+            _ => Err(unsupported(&format!(
+                "contains only works on fulltext fields, and {} isn't one",
+                field
+            ))),
+        },
+        expr => match alternatives(&expr) {
+            Some((field, values)) => {
+                add(params, field, values.join("_or_"), negated);
+                Ok(())
+            }
+            None => Err(unsupported(
+                "or only works between equalities on the same field",
+            )),
+        },
+    }
+}
+
+fn add(params: &mut HashMap<String, String>, field: String, value: String, negated: bool) {
+    let key = if negated {
+        format!("{}!", field)
+    } else {
+        field
+    };
+    and_param(params, key, value);
+}
+
+// the query parameters OData's $filter, $orderby, $top, $skip and $select stand for. parameters that
+// aren't OData options pass through unchanged
+pub fn odata_params(
+    schema: &Schema,
+    fields: &HashMap<String, String>,
+) -> Result<HashMap<String, String>, CompassError> {
+    let mut params: HashMap<String, String> = fields
+        .iter()
+        .filter(|(k, _)| !k.starts_with('$'))
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect();
+
+    if let Some(filter) = fields.get("$filter") {
+        let mut parser = Parser {
+            tokens: tokenize(filter)?,
+            pos: 0,
+        };
+        let expr = parser.or()?;
+        if parser.pos < parser.tokens.len() {
+            return Err(unsupported("unexpected text after the filter"));
+        }
+        translate(schema, expr, false, &mut params)?;
+    }
+
+    if let Some(orderby) = fields.get("$orderby") {
+        if orderby.contains(',') {
+            return Err(unsupported("ordering by more than one field"));
+        }
+        let mut parts = orderby.split_whitespace();
+        let field = parts
+            .next()
+            .ok_or_else(|| unsupported("$orderby needs a field"))?;
+        params.insert("sortby".to_owned(), field_name(field)?);
+        if let Some(order) = parts.next() {
+            params.insert("sortorder".to_owned(), order.to_owned());
+        }
+    }
+    if let Some(top) = fields.get("$top") {
+        params.insert("limit".to_owned(), top.clone());
+    }
+    if let Some(skip) = fields.get("$skip") {
+        params.insert("offset".to_owned(), skip.clone());
+    }
+    if let Some(select) = fields.get("$select") {
+        let paths = select
+            .split(',')
+            .map(|p| field_name(p.trim()))
+            .collect::<Result<Vec<_>, _>>()?;
+        params.insert("fields".to_owned(), paths.join(","));
+    }
+    if fields.get("$count").map_or(false, |c| c == "true") {
+        params.insert("with_total".to_owned(), "true".to_owned());
+    }
+
+    Ok(params)
+}
+
+// a search answered the way OData clients expect: the documents under `value`, and `@odata.count`
+// when $count=true asked for it
+pub fn json_odata_search<C: Connection>(
+    client: &mut C,
+    schema: &Schema,
+    fields: &HashMap<String, String>,
+) -> Result<Value, CompassError> {
+    let params = odata_params(schema, fields)?;
+    let response = json_search_response(client, schema, &params, None)?;
+
+    let mut body = json!({ "value": response.data });
+    if let Some(total) = response.meta.total {
+        body["@odata.count"] = json!(total);
+    }
+    Ok(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    fn compiled(filter: &str) -> String {
+        let schema = test_schema();
+        json_query(
+            &schema,
+            &odata_params(&schema, &params(&[("$filter", filter)])).unwrap(),
+        )
+    }
+
+    #[test]
+    fn range_operators() {
+        assert_eq!(
+            compiled("season ge 12"),
+            format!("(({}))", season_above(11))
+        );
+        assert_eq!(
+            compiled("season gt 12"),
+            format!("(({}))", season_above(12))
+        );
+        assert_eq!(
+            compiled("season le 15"),
+            format!("(({}))", season_below(16))
+        );
+        assert_eq!(
+            compiled("season lt 15"),
+            format!("(({}))", season_below(15))
+        );
+        assert_eq!(
+            compiled("season ge 12 and season le 15"),
+            format!("(({}) && ({}))", season_below(16), season_above(11))
+        );
+    }
+}
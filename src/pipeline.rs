@@ -0,0 +1,338 @@
+use super::*;
+
+use indexmap::IndexMap;
+use postgres::fallible_iterator::FallibleIterator;
+use postgres::types::ToSql;
+use postgres::types::Type as PostgresType;
+use postgres::Row;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::time::Instant;
+
+// an aggregation query, posted as json:
+//
+//   [{"filter": {"season": "12"}}, {"group": ["team"]},
+//    {"aggregate": {"games": {"op": "count"}, "runs": {"op": "avg", "field": "runs"}}},
+//    {"sort": [{"by": "games", "order": "desc"}]}, {"limit": 10}]
+//
+// stages are optional but have to come in that order, each at most once. the whole thing compiles to one
+// sql statement; the filter stage takes the same parameters as a search
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(transparent)]
+pub struct Pipeline {
+    pub stages: Vec<Stage>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "lowercase")]
+pub enum Stage {
+    Filter(HashMap<String, String>),
+    Group(Vec<String>),
+    Aggregate(IndexMap<String, Aggregate>),
+    Sort(Vec<PipelineSort>),
+    Limit(i64),
+}
+
+impl Stage {
+    fn rank(&self) -> usize {
+        match self {
+            Stage::Filter(_) => 0,
+            Stage::Group(_) => 1,
+            Stage::Aggregate(_) => 2,
+            Stage::Sort(_) => 3,
+            Stage::Limit(_) => 4,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Aggregate {
+    pub op: AggregateOp,
+    pub field: Option<String>, // everything but count needs one
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AggregateOp {
+    Count,
+    CountDistinct,
+    Sum,
+    Avg,
+    Min,
+    Max,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PipelineSort {
+    pub by: String, // a group field or an aggregate name
+    #[serde(default)]
+    pub order: PipelineOrder,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum PipelineOrder {
+    Asc,
+    Desc,
+}
+
+impl FromStr for AggregateOp {
+    type Err = CompassError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "count" => Ok(AggregateOp::Count),
+            "count_distinct" => Ok(AggregateOp::CountDistinct),
+            "sum" => Ok(AggregateOp::Sum),
+            "avg" => Ok(AggregateOp::Avg),
+            "min" => Ok(AggregateOp::Min),
+            "max" => Ok(AggregateOp::Max),
+            _ => Err(CompassError::InvalidAggregate(format!(
+                "unknown aggregate '{}'",
+                s
+            ))),
+        }
+    }
+}
+
+impl Default for PipelineOrder {
+    fn default() -> Self {
+        PipelineOrder::Desc
+    }
+}
+
+fn invalid(msg: String) -> CompassError {
+    CompassError::InvalidPipeline(msg)
+}
+
+// a declared field (or a path into a nested one) as a path array for `#>`
+pub(crate) fn field_path(schema: &Schema, name: &str) -> Result<Vec<String>, String> {
+    match schema.resolve_field(name) {
+        Some((path, FieldQuery::Min))
+        | Some((path, FieldQuery::Max))
+        | Some((path, FieldQuery::Not(_))) => Err(format!(
+            "'{}' is a filter, not a field (use '{}')",
+            name, path
+        )),
+        Some((path, FieldQuery::Vector { .. })) => Err(format!(
+            "'{}' is an embedding, it isn't stored in the document",
+            path
+        )),
+        Some((path, _)) => Ok(path.split('.').map(str::to_owned).collect()),
+        None => Err(format!("unknown field '{}'", name)),
+    }
+}
+
+// a jsonb value as numeric; non-numbers become NULL, so aggregates skip them instead of the whole
+// query failing on a cast
+pub(crate) fn numeric_expr(v: &str) -> String {
+    format!(
+        "CASE WHEN jsonb_typeof({v}) = 'number' THEN ({v} #>> '{{}}')::numeric END",
+        v = v
+    )
+}
+
+// numbered parameters after the ones the filter stage uses
+struct Params {
+    first: usize,
+    bindings: Vec<Binding>,
+}
+
+impl Params {
+    fn push(&mut self, binding: Binding) -> String {
+        self.bindings.push(binding);
+        format!("${}", self.first + self.bindings.len() - 1)
+    }
+}
+
+pub struct CompiledPipeline {
+    pub sql: String,
+    pub json_query: String,
+    pub bindings: Vec<Binding>, // everything after $1, in order
+}
+
+impl Pipeline {
+    pub fn compile(&self, schema: &Schema) -> Result<CompiledPipeline, CompassError> {
+        let mut rank = None;
+        for stage in self.stages.iter() {
+            if rank.map_or(false, |r| stage.rank() <= r) {
+                return Err(invalid(
+                    "stages have to go filter, group, aggregate, sort, limit, each at most once"
+                        .to_owned(),
+                ));
+            }
+            rank = Some(stage.rank());
+        }
+
+        let empty = HashMap::new();
+        let mut filter = &empty;
+        let mut group: &[String] = &[];
+        let mut aggregates = None;
+        let mut sort: &[PipelineSort] = &[];
+        let mut limit = schema.limits.default_limit;
+
+        for stage in self.stages.iter() {
+            match stage {
+                Stage::Filter(f) => filter = f,
+                Stage::Group(g) => group = g,
+                Stage::Aggregate(a) => aggregates = Some(a),
+                Stage::Sort(s) => sort = s,
+                Stage::Limit(l) => limit = *l,
+            }
+        }
+
+        if limit < 0 || limit > schema.limits.max_limit {
+            return Err(CompassError::LimitOutOfRange {
+                value: limit,
+                max: schema.limits.max_limit,
+            });
+        }
+
+        let plan = generate_where(schema, filter, 2, false)?;
+        let mut params = Params {
+            first: 2 + plan.bindings.len(),
+            bindings: Vec::new(),
+        };
+
+        // output name -> the sql expression that computes it
+        let mut outputs: IndexMap<String, String> = IndexMap::new();
+        let mut group_by = Vec::new();
+
+        for name in group.iter() {
+            let path = field_path(schema, name).map_err(invalid)?;
+            let expr = format!("(object #> {})", params.push(Binding::TextArray(path)));
+            group_by.push(expr.clone());
+            outputs.insert(name.clone(), expr);
+        }
+
+        let default_count = {
+            let mut m = IndexMap::new();
+            m.insert(
+                "count".to_owned(),
+                Aggregate {
+                    op: AggregateOp::Count,
+                    field: None,
+                },
+            );
+            m
+        };
+
+        for (name, agg) in aggregates.unwrap_or(&default_count).iter() {
+            if outputs.contains_key(name) {
+                return Err(invalid(format!("'{}' is used twice in the output", name)));
+            }
+
+            let value = match (agg.op, agg.field.as_ref()) {
+                (AggregateOp::Count, None) => None,
+                (_, Some(field)) => Some(format!(
+                    "(object #> {})",
+                    params.push(Binding::TextArray(
+                        field_path(schema, field).map_err(invalid)?
+                    ))
+                )),
+                (op, None) => {
+                    return Err(invalid(format!(
+                        "aggregate '{}' ({:?}) needs a field",
+                        name, op
+                    )))
+                }
+            };
+
+            let expr = match (agg.op, value) {
+                (AggregateOp::Count, None) => "COUNT(*)".to_owned(),
+                (AggregateOp::Count, Some(v)) => format!("COUNT({})", v),
+                (AggregateOp::CountDistinct, Some(v)) => format!("COUNT(DISTINCT {})", v),
+                (AggregateOp::Sum, Some(v)) => format!("SUM({})", numeric_expr(&v)),
+                (AggregateOp::Avg, Some(v)) => format!("AVG({})", numeric_expr(&v)),
+                (AggregateOp::Min, Some(v)) => format!("MIN({})", numeric_expr(&v)),
+                (AggregateOp::Max, Some(v)) => format!("MAX({})", numeric_expr(&v)),
+                (_, None) => unreachable!(),
+            };
+            outputs.insert(name.clone(), expr);
+        }
+
+        let mut order_by = Vec::new();
+        for s in sort.iter() {
+            let expr = outputs
+                .get(&s.by)
+                .ok_or_else(|| invalid(format!("can't sort by '{}': not in the output", s.by)))?;
+            let dir = match s.order {
+                PipelineOrder::Asc => "ASC",
+                PipelineOrder::Desc => "DESC",
+            };
+            order_by.push(format!("{} {} NULLS LAST", expr, dir));
+        }
+
+        let mut columns = Vec::new();
+        for (name, expr) in outputs.iter() {
+            let key = params.push(Binding::Text(name.clone()));
+            columns.push(format!("{}::text, {}", key, expr));
+        }
+
+        let mut sql = format!(
+            "SELECT jsonb_build_object({}) FROM {} {}",
+            columns.join(", "),
+            schema.table,
+            plan.where_clause
+        );
+        if !group_by.is_empty() {
+            sql += &format!(" GROUP BY {}", group_by.join(", "));
+        }
+        if !order_by.is_empty() {
+            sql += &format!(" ORDER BY {}", order_by.join(", "));
+        }
+        sql += &format!(" LIMIT {}", limit);
+
+        Ok(CompiledPipeline {
+            sql,
+            json_query: plan.json_query,
+            bindings: plan.bindings.into_iter().chain(params.bindings).collect(),
+        })
+    }
+}
+
+pub fn run_pipeline<C: Connection>(
+    client: &mut C,
+    schema: &Schema,
+    pipeline: &Pipeline,
+) -> Result<Vec<Value>, CompassError> {
+    let client = &mut throttle_permit(client, schema)?;
+
+    let compiled = pipeline.compile(schema)?;
+    let types: Vec<PostgresType> = std::iter::once(PostgresType::TEXT)
+        .chain(compiled.bindings.iter().map(Binding::pg_type))
+        .collect();
+
+    let started = Instant::now();
+
+    let statement = client
+        .prepare_typed(&compiled.sql, &types)
+        .map_err(pg_error(schema))?;
+
+    let params: Vec<&dyn ToSql> = std::iter::once(&compiled.json_query as &dyn ToSql)
+        .chain(compiled.bindings.iter().map(Binding::as_sql))
+        .collect();
+
+    let rows: Vec<Row> = client
+        .client()
+        .map_err(pg_error(schema))?
+        .query_raw(&statement, params.iter().copied())
+        .map_err(pg_error(schema))?
+        .collect()
+        .map_err(pg_error(schema))?;
+
+    if let Some(ref log) = schema.slow_log {
+        log.record(
+            &schema.table,
+            &compiled.sql,
+            || params.iter().map(|p| format!("{:?}", p)).collect(),
+            started.elapsed(),
+        );
+    }
+    record_usage(schema, rows.len(), started.elapsed());
+
+    Ok(rows.into_iter().map(|r| r.get::<usize, Value>(0)).collect())
+}
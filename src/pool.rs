@@ -0,0 +1,188 @@
+// a pool of ManagedClients for servers that search from several threads at once, so handlers don't have
+// to share one `&mut Client` or bring their own pool. sized and timed out by [database] in compass.toml
+use super::*;
+
+use postgres::types::Type as PostgresType;
+use postgres::{Client, Statement};
+use r2d2::{ManageConnection, Pool, PooledConnection};
+use serde::Serialize;
+use serde_json::Value;
+use uuid::Uuid;
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+// opens the pool's connections, the same way ManagedClient::connect does
+pub struct CompassConnectionManager {
+    config: DatabaseConfig,
+    shapes: Option<Arc<StatementShapes>>,
+}
+
+impl ManageConnection for CompassConnectionManager {
+    type Connection = ManagedClient;
+    type Error = CompassError;
+
+    fn connect(&self) -> Result<ManagedClient, CompassError> {
+        let mut client = ManagedClient::connect(self.config.clone())?;
+        // the pool replaces connections that die, so one that's checked out doesn't back off reconnecting
+        client.max_attempts = 1;
+        Ok(match self.shapes {
+            Some(ref shapes) => client.with_shapes(shapes.clone()),
+            None => client,
+        })
+    }
+
+    // the health check, on every checkout with database.pool_health_check
+    fn is_valid(&self, client: &mut ManagedClient) -> Result<(), CompassError> {
+        client.client()?.batch_execute("SELECT 1")?;
+        Ok(())
+    }
+
+    fn has_broken(&self, client: &mut ManagedClient) -> bool {
+        client.is_closed()
+    }
+}
+
+// a connection checked out of a CompassPool, back in the pool once it's dropped. it keeps the session
+// settings the last request left on it, so scope it to a tenant (CompassPool::get_scoped) before using it
+pub type PooledClient = PooledConnection<CompassConnectionManager>;
+
+impl Connection for PooledClient {
+    fn client(&mut self) -> Result<&mut Client, postgres::Error> {
+        (**self).client()
+    }
+
+    fn prepare_typed(
+        &mut self,
+        query: &str,
+        types: &[PostgresType],
+    ) -> Result<Statement, postgres::Error> {
+        (**self).prepare_typed(query, types)
+    }
+
+    fn set_statement_timeout(&mut self, ms: u64) -> Result<String, postgres::Error> {
+        (**self).set_statement_timeout(ms)
+    }
+
+    fn restore_statement_timeout(&mut self, previous: &str) -> Result<(), postgres::Error> {
+        (**self).restore_statement_timeout(previous)
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct PoolStatus {
+    pub max_size: u32,
+    pub connections: u32, // open right now, checked out or not
+    pub idle: u32,
+}
+
+#[derive(Clone)]
+pub struct CompassPool {
+    pool: Pool<CompassConnectionManager>,
+}
+
+// 0 for never
+fn seconds(secs: u64) -> Option<Duration> {
+    if secs == 0 {
+        None
+    } else {
+        Some(Duration::from_secs(secs))
+    }
+}
+
+fn pool_error(err: r2d2::Error) -> CompassError {
+    CompassError::PoolTimeout(err.to_string())
+}
+
+impl CompassPool {
+    // opens the pool, failing if postgres can't be reached within database.pool_timeout_secs
+    pub fn new(config: &DatabaseConfig) -> Result<CompassPool, CompassError> {
+        CompassPool::build(config, None)
+    }
+
+    // a pool whose connections share statement shapes, see ManagedClient::with_shapes
+    pub fn with_shapes(
+        config: &DatabaseConfig,
+        shapes: Arc<StatementShapes>,
+    ) -> Result<CompassPool, CompassError> {
+        CompassPool::build(config, Some(shapes))
+    }
+
+    fn build(
+        config: &DatabaseConfig,
+        shapes: Option<Arc<StatementShapes>>,
+    ) -> Result<CompassPool, CompassError> {
+        if config.pool_timeout_secs == 0 {
+            return Err(CompassError::ConfigError(
+                "database.pool_timeout_secs must be at least 1".to_owned(),
+            ));
+        }
+
+        let manager = CompassConnectionManager {
+            config: config.clone(),
+            shapes,
+        };
+        let pool = Pool::builder()
+            .max_size(config.pool_size.max(1))
+            .connection_timeout(Duration::from_secs(config.pool_timeout_secs))
+            .idle_timeout(seconds(config.pool_idle_timeout_secs))
+            .max_lifetime(seconds(config.pool_max_lifetime_secs))
+            .test_on_check_out(config.pool_health_check)
+            .build(manager)
+            .map_err(pool_error)?;
+
+        Ok(CompassPool { pool })
+    }
+
+    // waits up to database.pool_timeout_secs for a free connection, then gives up with PoolTimeout (a 503)
+    pub fn get(&self) -> Result<PooledClient, CompassError> {
+        self.pool.get().map_err(pool_error)
+    }
+
+    // a connection scoped to the schema's tenant, see scope_session
+    pub fn get_scoped(&self, schema: &Schema) -> Result<PooledClient, CompassError> {
+        let mut client = self.get()?;
+        scope_session(&mut client, schema)?;
+        Ok(client)
+    }
+
+    pub fn json_search(
+        &self,
+        schema: &Schema,
+        fields: &HashMap<String, String>,
+        raw_query: Option<RawQuery>,
+    ) -> Result<Vec<Value>, CompassError> {
+        json_search(&mut self.get_scoped(schema)?, schema, fields, raw_query)
+    }
+
+    pub fn json_search_response(
+        &self,
+        schema: &Schema,
+        fields: &HashMap<String, String>,
+        raw_query: Option<RawQuery>,
+    ) -> Result<SearchResponse, CompassError> {
+        json_search_response(&mut self.get_scoped(schema)?, schema, fields, raw_query)
+    }
+
+    pub fn json_count(
+        &self,
+        schema: &Schema,
+        fields: &HashMap<String, String>,
+    ) -> Result<i64, CompassError> {
+        json_count(&mut self.get_scoped(schema)?, schema, fields)
+    }
+
+    pub fn get_by_ids(&self, schema: &Schema, ids: &Vec<Uuid>) -> Result<Vec<Value>, CompassError> {
+        get_by_ids(&mut self.get_scoped(schema)?, schema, ids)
+    }
+
+    pub fn status(&self) -> PoolStatus {
+        let state = self.pool.state();
+        PoolStatus {
+            max_size: self.pool.max_size(),
+            connections: state.connections,
+            idle: state.idle_connections,
+        }
+    }
+}
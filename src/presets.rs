@@ -0,0 +1,141 @@
+use super::*;
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+fn invalid(msg: String) -> CompassError {
+    CompassError::InvalidPreset(msg)
+}
+
+// the `{name}`s in a preset value, in order
+pub(crate) fn placeholders(value: &str) -> Vec<&str> {
+    let mut names = Vec::new();
+    let mut rest = value;
+    while let Some(start) = rest.find('{') {
+        match rest[start..].find('}') {
+            Some(end) => {
+                names.push(&rest[start + 1..start + end]);
+                rest = &rest[start + end + 1..];
+            }
+            None => break,
+        }
+    }
+    names
+}
+
+pub(crate) fn validate_presets(schema: &Schema) -> Result<(), CompassError> {
+    for (name, params) in schema.presets.iter() {
+        for (key, value) in params.iter() {
+            if key == "preset" {
+                return Err(CompassError::ConfigError(format!(
+                    "preset '{}' can't use other presets",
+                    name
+                )));
+            }
+            let value = param_value(value).map_err(|_| {
+                CompassError::ConfigError(format!(
+                    "preset '{}' has to give '{}' a string, number or boolean",
+                    name, key
+                ))
+            })?;
+            if placeholders(&value).iter().any(|p| !is_sql_identifier(p)) {
+                return Err(CompassError::ConfigError(format!(
+                    "preset '{}' has a placeholder in '{}' that isn't a plain name",
+                    name, key
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+// `fields` with `preset=name,...` swapped for the parameters those presets stand for. `{name}` in a preset
+// is filled in from the request's `name` parameter, which is used up. filters are ANDed with any the
+// request already had; reserved parameters like sortby only apply when the request doesn't set them
+pub(crate) fn expand_presets<'a>(
+    schema: &Schema,
+    fields: &'a HashMap<String, String>,
+) -> Result<Cow<'a, HashMap<String, String>>, CompassError> {
+    let names = match fields.get("preset") {
+        Some(names) => names,
+        None => return Ok(Cow::Borrowed(fields)),
+    };
+
+    let mut expanded = fields.clone();
+    expanded.remove("preset");
+
+    let mut used = Vec::new();
+    let mut params = Vec::new();
+    for name in names.split(',').filter(|n| !n.is_empty()) {
+        let preset = schema
+            .presets
+            .get(name)
+            .ok_or_else(|| invalid(format!("there's no preset '{}'", name)))?;
+
+        for (key, value) in preset.iter() {
+            let template = param_value(value)?;
+            let mut value = template.clone();
+            for placeholder in placeholders(&template) {
+                let argument = fields.get(placeholder).ok_or_else(|| {
+                    invalid(format!("preset '{}' needs a '{}'", name, placeholder))
+                })?;
+                value = value.replace(&format!("{{{}}}", placeholder), argument);
+                used.push(placeholder.to_owned());
+            }
+            params.push((key.clone(), value));
+        }
+    }
+
+    for argument in used.iter() {
+        expanded.remove(argument);
+    }
+    for (key, value) in params {
+        if RESERVED_PARAMS.contains(&key.as_str()) {
+            expanded.entry(key).or_insert(value);
+        } else {
+            and_param(&mut expanded, key, value);
+        }
+    }
+    Ok(Cow::Owned(expanded))
+}
+
+// presets, then `q`: everything a search's parameters stand for
+pub(crate) fn expand_params<'a>(
+    schema: &Schema,
+    fields: &'a HashMap<String, String>,
+) -> Result<Cow<'a, HashMap<String, String>>, CompassError> {
+    match expand_presets(schema, fields)? {
+        Cow::Borrowed(fields) => expand_lucene(schema, fields),
+        Cow::Owned(fields) => Ok(Cow::Owned(expand_lucene(schema, &fields)?.into_owned())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+    use indexmap::IndexMap;
+    use serde_json::json;
+
+    #[test]
+    fn preset_filters_and_with_the_request() {
+        let mut schema = test_schema();
+        let mut regular = IndexMap::new();
+        regular.insert("type".to_owned(), json!("54_or_55"));
+        schema.presets.insert("regular".to_owned(), regular);
+
+        let request = params(&[("preset", "regular"), ("type", "12")]);
+        let expanded = expand_params(&schema, &request).unwrap();
+        assert_eq!(expanded["type"], "12_and_54_or_12_and_55");
+        assert_eq!(
+            json_query(&schema, &request),
+            format!(
+                "(({} && {} || {} && {}))",
+                type_is(12),
+                type_is(54),
+                type_is(12),
+                type_is(55)
+            )
+        );
+    }
+}
@@ -0,0 +1,178 @@
+use super::*;
+
+use indexmap::IndexMap;
+use serde::Serialize;
+use serde_json::Value;
+
+use std::collections::HashMap;
+
+#[derive(Serialize, Debug, Clone)]
+pub struct FieldQuality {
+    pub expected: &'static str,
+    pub missing_pct: f64,
+    pub wrong_type_pct: f64, // of all documents, not just the ones that have the field
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unconvertible_pct: Option<f64>, // fields with a converter: stored values that aren't whole numbers
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct QualityReport {
+    pub documents: i64,
+    pub sampled: bool,
+    pub fields: IndexMap<String, FieldQuality>,
+}
+
+// what a field's query expects to find in documents, and a condition that holds when `{v}` is that.
+// range queries also parse numeric strings, so those pass too
+fn expected_type(query: &FieldQuery) -> Option<(&'static str, &'static str)> {
+    Some(match query {
+        FieldQuery::Range { .. } | FieldQuery::Min | FieldQuery::Max => (
+            "number",
+            "(jsonb_typeof({v}) = 'number' OR (jsonb_typeof({v}) = 'string' AND ({v} #>> '{}') ~ '^\\s*-?[0-9]+(\\.[0-9]+)?\\s*$'))",
+        ),
+        FieldQuery::NumericTag { .. } => (
+            "number or array of numbers",
+            "(jsonb_typeof({v}) = 'number' OR (jsonb_typeof({v}) = 'array' AND NOT jsonb_path_exists({v}, '$[*] ? (@.type() != \"number\")')))",
+        ),
+        FieldQuery::StringTag => (
+            "string or array of strings",
+            "(jsonb_typeof({v}) = 'string' OR (jsonb_typeof({v}) = 'array' AND NOT jsonb_path_exists({v}, '$[*] ? (@.type() != \"string\")')))",
+        ),
+        FieldQuery::AmbiguousTag => (
+            "string, number or array",
+            "jsonb_typeof({v}) IN ('string', 'number', 'array')",
+        ),
+        FieldQuery::Bool => ("boolean", "jsonb_typeof({v}) = 'boolean'"),
+        FieldQuery::Fulltext { .. } => ("string", "jsonb_typeof({v}) = 'string'"),
+        FieldQuery::Nested => ("object or array", "jsonb_typeof({v}) IN ('object', 'array')"),
+        FieldQuery::Not(inner) => return expected_type(inner),
+        FieldQuery::Vector { .. } => return None, // not in the document
+    })
+}
+
+// the same checks as expected_type's conditions, for a document that isn't in the table yet: what the
+// value should have been, when it isn't
+pub(crate) fn type_problem(query: &FieldQuery, v: &Value) -> Option<&'static str> {
+    let numeric_string = |s: &str| {
+        let s = s.trim();
+        let s = s.strip_prefix('-').unwrap_or(s);
+        let digits = |p: &str| !p.is_empty() && p.bytes().all(|b| b.is_ascii_digit());
+        let mut parts = s.splitn(2, '.');
+        parts.next().map_or(false, digits) && parts.next().map_or(true, digits)
+    };
+    let all = |v: &Value, f: fn(&Value) -> bool| v.as_array().map_or(false, |a| a.iter().all(f));
+
+    let fits = match query {
+        FieldQuery::Range { .. } | FieldQuery::Min | FieldQuery::Max => {
+            v.is_number() || v.as_str().map_or(false, numeric_string)
+        }
+        FieldQuery::NumericTag { .. } => v.is_number() || all(v, Value::is_number),
+        FieldQuery::StringTag => v.is_string() || all(v, Value::is_string),
+        FieldQuery::AmbiguousTag => v.is_string() || v.is_number() || v.is_array(),
+        FieldQuery::Bool => v.is_boolean(),
+        FieldQuery::Fulltext { .. } => v.is_string(),
+        FieldQuery::Nested => v.is_object() || v.is_array(),
+        FieldQuery::Not(inner) => return type_problem(inner, v),
+        FieldQuery::Vector { .. } => true,
+    };
+    if fits {
+        None
+    } else {
+        expected_type(query).map(|(expected, _)| expected)
+    }
+}
+
+// scans the table (a sample of it, if it's big) and reports, per schema field, how many documents are
+// missing it, hold the wrong json type for its query, or hold something its converter can't read back.
+// these are the documents range and tag queries silently don't match. filters apply as in a search
+pub fn quality_report<C: Connection>(
+    client: &mut C,
+    schema: &Schema,
+    fields: &HashMap<String, String>,
+) -> Result<QualityReport, CompassError> {
+    let client = &mut throttle_permit(client, schema)?;
+
+    let (sample, sampled) = sample_clause(client, schema)?;
+    let mut plan = generate_where(schema, fields, 2, false)?;
+
+    let mut checked = Vec::new();
+    let mut columns = vec!["COUNT(*)".to_owned()];
+    for (name, field) in schema.fields.iter() {
+        let (expected, ok) = match expected_type(&field.query) {
+            Some(e) => e,
+            None => continue,
+        };
+
+        let key = match field.query {
+            FieldQuery::Fulltext {
+                target: Some(ref target),
+                ..
+            } => target,
+            _ => name,
+        };
+        let path = plan.bind(Binding::TextArray(
+            key.split('.').map(str::to_owned).collect(),
+        ));
+        let v = format!("(object #> {})", path);
+        let ok = ok.replace("{v}", &v);
+
+        columns.push(format!("COUNT(*) FILTER (WHERE {} IS NULL)", v));
+        columns.push(format!(
+            "COUNT(*) FILTER (WHERE {v} IS NOT NULL AND NOT {ok})",
+            v = v,
+            ok = ok
+        ));
+        if field.converter.is_some() {
+            columns.push(format!(
+                "COUNT(*) FILTER (WHERE {v} IS NOT NULL AND NOT CASE WHEN jsonb_typeof({v}) = 'number' THEN ({v} #>> '{{}}')::numeric % 1 = 0 ELSE false END)",
+                v = v
+            ));
+        }
+        checked.push((name.clone(), expected, field.converter.is_some()));
+    }
+
+    let sql = format!(
+        "SELECT jsonb_build_array({}) FROM {} {} {}",
+        columns.join(", "),
+        schema.table,
+        sample,
+        plan.where_clause
+    );
+
+    let counts: Vec<i64> = match run_plan(client, schema, &sql, &plan)?.pop() {
+        Some(Value::Array(counts)) => counts.iter().map(|c| c.as_i64().unwrap_or(0)).collect(),
+        _ => Vec::new(),
+    };
+    let mut counts = counts.into_iter();
+    let documents = counts.next().unwrap_or(0);
+    let pct = |n: Option<i64>| match documents {
+        0 => 0.0,
+        d => 100.0 * n.unwrap_or(0) as f64 / d as f64,
+    };
+
+    let mut report = IndexMap::new();
+    for (name, expected, converted) in checked {
+        let missing_pct = pct(counts.next());
+        let wrong_type_pct = pct(counts.next());
+        let unconvertible_pct = if converted {
+            Some(pct(counts.next()))
+        } else {
+            None
+        };
+        report.insert(
+            name,
+            FieldQuality {
+                expected,
+                missing_pct,
+                wrong_type_pct,
+                unconvertible_pct,
+            },
+        );
+    }
+
+    Ok(QualityReport {
+        documents,
+        sampled,
+        fields: report,
+    })
+}
@@ -0,0 +1,74 @@
+use serde::{Deserialize, Serialize};
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::RwLock;
+
+use super::CompassError;
+
+// a `[quotas.<schema>]` table in compass.toml, so one heavy schema can't take the whole pool from the
+// others sharing it. every limit is 0 for none
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct QuotaConfig {
+    pub max_concurrent: usize, // queries against the schema at once; more get a 503
+    pub max_rows: i64, // per request; lowers limits.max_limit (and default_limit) for this schema
+    pub statement_timeout_ms: u64, // postgres cancels the schema's queries after this long
+}
+
+// a schema's quota and the queries it has running, shared by the schema's clones
+#[derive(Debug)]
+pub struct Quota {
+    config: RwLock<QuotaConfig>,
+    in_flight: AtomicUsize,
+}
+
+pub struct QuotaPermit<'a> {
+    quota: &'a Quota,
+}
+
+impl Quota {
+    pub fn new(config: &QuotaConfig) -> Quota {
+        Quota {
+            config: RwLock::new(config.clone()),
+            in_flight: AtomicUsize::new(0),
+        }
+    }
+
+    // a reload resizes the quota in place, so the queries already running still count against it
+    pub fn reconfigure(&self, config: &QuotaConfig) {
+        *self.config.write().unwrap() = config.clone();
+    }
+
+    pub fn acquire(&self) -> Result<QuotaPermit<'_>, CompassError> {
+        let max = self.config.read().unwrap().max_concurrent;
+        self.in_flight
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+                if max > 0 && n >= max {
+                    None
+                } else {
+                    Some(n + 1)
+                }
+            })
+            .map_err(|_| CompassError::Overloaded {
+                retry_after_secs: 1,
+            })?;
+        Ok(QuotaPermit { quota: self })
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Acquire)
+    }
+
+    pub fn statement_timeout_ms(&self) -> Option<u64> {
+        match self.config.read().unwrap().statement_timeout_ms {
+            0 => None,
+            ms => Some(ms),
+        }
+    }
+}
+
+impl<'a> Drop for QuotaPermit<'a> {
+    fn drop(&mut self) {
+        self.quota.in_flight.fetch_sub(1, Ordering::AcqRel);
+    }
+}
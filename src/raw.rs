@@ -0,0 +1,201 @@
+use serde::{Deserialize, Serialize};
+
+use super::CompassError;
+
+// what an unprivileged raw jsonpath query is allowed to use. comparisons, boolean logic and plain
+// accessors are always fine; anything that looks like a function call has to be listed here
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct RawQueryConfig {
+    pub allowed_functions: Vec<String>, // `exists(...)` and item methods like `.size()`
+    pub allow_like_regex: bool,         // patterns are still checked for nested quantifiers
+    pub allow_recursive_wildcard: bool, // `.**`, which walks every level of every document
+    pub max_length: usize,
+}
+
+impl Default for RawQueryConfig {
+    fn default() -> Self {
+        RawQueryConfig {
+            allowed_functions: [
+                "exists", "size", "type", "double", "abs", "floor", "ceiling",
+            ]
+            .iter()
+            .map(|s| s.to_string())
+            .collect(),
+            allow_like_regex: true,
+            allow_recursive_wildcard: false,
+            max_length: 4096,
+        }
+    }
+}
+
+// a jsonpath query passed straight through to postgres. privileged ones (admin tooling, internal
+// callers) skip the allowlist entirely
+#[derive(Debug, Clone, PartialEq)]
+pub struct RawQuery {
+    pub query: String,
+    pub privileged: bool,
+}
+
+impl RawQuery {
+    pub fn new(query: String) -> RawQuery {
+        RawQuery {
+            query,
+            privileged: false,
+        }
+    }
+
+    pub fn privileged(query: String) -> RawQuery {
+        RawQuery {
+            query,
+            privileged: true,
+        }
+    }
+
+    // hands back the query text if `config` allows it
+    pub fn checked(self, config: &RawQueryConfig) -> Result<String, CompassError> {
+        if !self.privileged {
+            check_raw_query(&self.query, config)?;
+        }
+        Ok(self.query)
+    }
+}
+
+impl From<String> for RawQuery {
+    fn from(query: String) -> RawQuery {
+        RawQuery::new(query)
+    }
+}
+
+fn rejected(msg: String) -> Result<(), CompassError> {
+    Err(CompassError::RawQueryRejected(msg))
+}
+
+pub fn check_raw_query(q: &str, config: &RawQueryConfig) -> Result<(), CompassError> {
+    if q.len() > config.max_length {
+        return rejected(format!(
+            "query is {} bytes, the limit is {}",
+            q.len(),
+            config.max_length
+        ));
+    }
+
+    let chars: Vec<char> = q.chars().collect();
+    let mut i = 0;
+    let mut expect_pattern = false;
+
+    while i < chars.len() {
+        let c = chars[i];
+
+        if c == '"' {
+            let (literal, end) = string_literal(&chars, i);
+            if expect_pattern {
+                check_regex(&literal)?;
+                expect_pattern = false;
+            }
+            i = end;
+            continue;
+        }
+
+        if c == '.' && chars.get(i + 1) == Some(&'*') && chars.get(i + 2) == Some(&'*') {
+            if !config.allow_recursive_wildcard {
+                return rejected("recursive wildcards (.**) aren't allowed".to_owned());
+            }
+            i += 3;
+            continue;
+        }
+
+        if c.is_ascii_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            let word: String = chars[start..i].iter().collect();
+
+            if word == "like_regex" {
+                if !config.allow_like_regex {
+                    return rejected("like_regex isn't allowed".to_owned());
+                }
+                expect_pattern = true;
+                continue;
+            }
+
+            let next = chars[i..].iter().find(|c| !c.is_whitespace());
+            if next == Some(&'(')
+                && !config
+                    .allowed_functions
+                    .iter()
+                    .any(|f| f.eq_ignore_ascii_case(&word))
+            {
+                return rejected(format!("'{}' isn't an allowed function", word));
+            }
+            continue;
+        }
+
+        i += 1;
+    }
+
+    Ok(())
+}
+
+// reads the string literal starting at the quote at `start`; returns its contents and the index after the closing quote
+fn string_literal(chars: &[char], start: usize) -> (String, usize) {
+    let mut literal = String::new();
+    let mut i = start + 1;
+    while i < chars.len() {
+        match chars[i] {
+            '\\' => {
+                if let Some(c) = chars.get(i + 1) {
+                    literal.push('\\');
+                    literal.push(*c);
+                }
+                i += 2;
+            }
+            '"' => return (literal, i + 1),
+            c => {
+                literal.push(c);
+                i += 1;
+            }
+        }
+    }
+    (literal, i)
+}
+
+// rejects the classic catastrophic-backtracking shape: a quantified group that itself contains a quantifier, like `(a+)+` or `(.*)*`
+fn check_regex(pattern: &str) -> Result<(), CompassError> {
+    let chars: Vec<char> = pattern.chars().collect();
+    let mut groups: Vec<bool> = Vec::new(); // per open group: has it seen a quantifier yet
+    let mut i = 0;
+
+    while i < chars.len() {
+        match chars[i] {
+            '\\' => i += 1,
+            '(' => groups.push(false),
+            ')' => {
+                let quantified_inside = groups.pop().unwrap_or(false);
+                let quantified_after =
+                    matches!(chars.get(i + 1), Some('*') | Some('+') | Some('{'));
+                if quantified_inside && quantified_after {
+                    return rejected(format!(
+                        "like_regex pattern \"{}\" has a nested quantifier",
+                        pattern
+                    ));
+                }
+                if quantified_inside || quantified_after {
+                    if let Some(outer) = groups.last_mut() {
+                        *outer = true;
+                    }
+                }
+            }
+            '*' | '+' | '{' => {
+                if let Some(group) = groups.last_mut() {
+                    *group = true;
+                }
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+
+    Ok(())
+}
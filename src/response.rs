@@ -0,0 +1,73 @@
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::io;
+use std::time::Duration;
+
+// timings in milliseconds, only filled in when the request asks for `debug=stats`
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct QueryStats {
+    pub plan_ms: f64,
+    pub prepare_ms: f64,
+    pub execute_ms: f64,
+    pub fetch_ms: f64,
+    pub convert_ms: f64,
+    pub total_ms: f64,
+    pub rows: usize,
+}
+
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct SearchMeta {
+    // query parameters that didn't match any schema field, so typos don't go unnoticed
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub ignored_params: Vec<String>,
+    // number of matching documents, when it was worked out (e.g. for limit=0 queries)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total: Option<i64>,
+    // corrected fulltext queries, by parameter, when a search found nothing
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub did_you_mean: Option<BTreeMap<String, String>>,
+    // pass back as `cursor=` for the next page; only set when paging by cursor and there may be more
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_cursor: Option<String>,
+    // the page stopped early at limits.max_response_bytes. with offset paging, carry on from next_offset
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub truncated: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_offset: Option<i64>,
+}
+
+// `D` is Box<RawValue> for json_search_raw, where documents go out as postgres wrote them
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct SearchResponse<D = Value> {
+    pub data: Vec<D>,
+    pub meta: SearchMeta,
+    #[serde(rename = "_stats", skip_serializing_if = "Option::is_none")]
+    pub stats: Option<QueryStats>,
+}
+
+// how long `value` is as json, without building the string
+pub(crate) fn json_len<T: Serialize>(value: &T) -> usize {
+    struct Counter(usize);
+
+    impl io::Write for Counter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0 += buf.len();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let mut counter = Counter(0);
+    match serde_json::to_writer(&mut counter, value) {
+        Ok(()) => counter.0,
+        Err(_) => 0,
+    }
+}
+
+pub(crate) fn millis(d: Duration) -> f64 {
+    d.as_secs_f64() * 1000.0
+}
@@ -0,0 +1,372 @@
+use super::*;
+
+use chrono::{DateTime, TimeZone, Utc};
+use postgres::Client;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use std::collections::HashMap;
+
+// where migrate and refresh_rollups keep track of every rollup view: what it was created from, and when
+// it was last refreshed, which postgres doesn't remember for materialized views
+pub const ROLLUPS_TABLE: &str = "compass_rollups";
+
+fn config_error(name: &str, msg: &str) -> CompassError {
+    CompassError::ConfigError(format!("rollup '{}' {}", name, msg))
+}
+
+// counts precomputed per time bucket and group, kept in a materialized view:
+//
+//   rollups:
+//     daily_types:
+//       time: created
+//       bucket: 1d
+//       group_by: [type]
+//
+// downsampling with agg=count is answered from it while it's fresh, see downsample_response
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Rollup {
+    pub time: String, // a field holding epoch seconds (or millis, by its converter), like downsample's `time`
+    pub bucket: String, // 1h, 1d, ...; queries can use any multiple of it
+    #[serde(default)]
+    pub group_by: Vec<String>, // top-level fields counts are split by; the only ones queries can filter on
+    #[serde(default = "default_max_age")]
+    pub max_age_secs: i64, // how long after a refresh it's still served
+}
+
+fn default_max_age() -> i64 {
+    60 * 60
+}
+
+// how old the rollup a response was served from is
+#[derive(Serialize, Debug, Clone)]
+pub struct RollupFreshness {
+    pub rollup: String,
+    pub refreshed_at: DateTime<Utc>,
+    pub age_secs: i64,
+    pub max_age_secs: i64,
+}
+
+// how an aggregate was answered. without a rollup, it was computed from the table just now
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct AggregateMeta {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rollup: Option<RollupFreshness>,
+}
+
+// a rollup as refresh_rollups and rollup_status report it
+#[derive(Serialize, Debug, Clone)]
+pub struct RollupStatus {
+    pub rollup: String,
+    pub view: String,
+    pub refreshed_at: Option<DateTime<Utc>>, // never, since migrate (re)created it
+    pub age_secs: Option<i64>,
+    pub fresh: bool,
+}
+
+pub(crate) fn validate_rollups(schema: &Schema) -> Result<(), CompassError> {
+    if !schema.rollups.is_empty() && schema.tenancy.is_some() {
+        return Err(CompassError::ConfigError(
+            "rollups count every tenant's documents together, so they can't be used with tenancy"
+                .to_owned(),
+        ));
+    }
+
+    for (name, rollup) in schema.rollups.iter() {
+        if !is_sql_identifier(name) || name.contains('.') {
+            return Err(config_error(name, "needs a plain name"));
+        }
+        parse_bucket(&rollup.bucket).map_err(|e| config_error(name, &e.to_string()))?;
+        field_path(schema, &rollup.time).map_err(|e| config_error(name, &e))?;
+        for group in rollup.group_by.iter() {
+            match schema
+                .fields
+                .get(group)
+                .filter(|_| is_sql_identifier(group))
+                .map(|f| &f.query)
+            {
+                Some(FieldQuery::Fulltext { .. })
+                | Some(FieldQuery::Vector { .. })
+                | Some(FieldQuery::Nested)
+                | None => {
+                    return Err(config_error(
+                        name,
+                        &format!("can only group by plain top-level fields, not '{}'", group),
+                    ))
+                }
+                Some(_) => {}
+            }
+        }
+        if rollup.max_age_secs < 0 {
+            return Err(config_error(name, "can't have a negative max_age_secs"));
+        }
+    }
+    Ok(())
+}
+
+pub fn rollup_view(schema: &Schema, name: &str) -> String {
+    format!("{}_rollup_{}", schema.table, name)
+}
+
+// the bucket width in the time field's stored unit
+fn stored_width(schema: &Schema, rollup: &Rollup) -> Result<i64, CompassError> {
+    let secs = parse_bucket(&rollup.bucket)?;
+    let millis = schema
+        .fields
+        .get(&rollup.time)
+        .and_then(|f| f.converter)
+        .map_or(false, |c| matches!(c.to, ConvertTo::TimestampMillis));
+    Ok(if millis { secs * 1000 } else { secs })
+}
+
+// the view's query. its `object` column holds just the grouped fields, so the jsonpath generate_where
+// builds for them works on the view as it does on the table
+fn rollup_sql(schema: &Schema, rollup: &Rollup) -> Result<String, CompassError> {
+    let time = format!("(object #> {})", path_literal(&rollup.time));
+    let groups: Vec<String> = rollup
+        .group_by
+        .iter()
+        .map(|g| format!("'{}', object #> {}", g, path_literal(g)))
+        .collect();
+    Ok(format!(
+        "SELECT floor(({time} #>> '{{}}')::numeric / {width}) * {width} AS bucket, \
+         jsonb_strip_nulls(jsonb_build_object({groups})) AS object, COUNT(*) AS count \
+         FROM {table} WHERE jsonb_typeof({time}) = 'number' GROUP BY 1, 2",
+        time = time,
+        width = stored_width(schema, rollup)?,
+        groups = groups.join(", "),
+        table = schema.table
+    ))
+}
+
+// creates the schema's rollup views, empty until the first refresh_rollups. a view whose definition
+// changed is dropped and created again. called by migrate
+pub fn provision_rollups(client: &mut Client, schema: &Schema) -> Result<(), CompassError> {
+    if schema.rollups.is_empty() {
+        return Ok(());
+    }
+    client.batch_execute(&format!(
+        "CREATE TABLE IF NOT EXISTS {} (view TEXT PRIMARY KEY, definition TEXT NOT NULL, refreshed_at TIMESTAMPTZ)",
+        ROLLUPS_TABLE
+    ))?;
+
+    for (name, rollup) in schema.rollups.iter() {
+        let view = rollup_view(schema, name);
+        let sql = rollup_sql(schema, rollup)?;
+        let existing: Option<String> = client
+            .query_opt(
+                format!("SELECT definition FROM {} WHERE view = $1", ROLLUPS_TABLE).as_str(),
+                &[&view],
+            )?
+            .map(|row| row.get(0));
+        if existing.as_deref() == Some(sql.as_str()) {
+            continue;
+        }
+
+        let mut transaction = client.transaction()?;
+        transaction.batch_execute(&format!(
+            "DROP MATERIALIZED VIEW IF EXISTS {view}; \
+             CREATE MATERIALIZED VIEW {view} AS {sql} WITH NO DATA; \
+             CREATE UNIQUE INDEX {index}_key_idx ON {view} (bucket, object);",
+            view = view,
+            sql = sql,
+            index = view.replace('.', "_")
+        ))?;
+        transaction.execute(
+            format!(
+                "INSERT INTO {} (view, definition, refreshed_at) VALUES ($1, $2, NULL) \
+                 ON CONFLICT (view) DO UPDATE SET definition = EXCLUDED.definition, refreshed_at = NULL",
+                ROLLUPS_TABLE
+            )
+            .as_str(),
+            &[&view, &sql],
+        )?;
+        transaction.commit()?;
+    }
+    Ok(())
+}
+
+fn status(name: &str, view: String, rollup: &Rollup, row: Option<(i64, i64)>) -> RollupStatus {
+    RollupStatus {
+        rollup: name.to_owned(),
+        view,
+        refreshed_at: row.and_then(|(at, _)| Utc.timestamp_opt(at, 0).single()),
+        age_secs: row.map(|(_, age)| age),
+        fresh: row.map_or(false, |(_, age)| age <= rollup.max_age_secs),
+    }
+}
+
+// when each of the rollup views was last refreshed, unrefreshed ones included
+pub fn rollup_status<C: Connection>(
+    client: &mut C,
+    schema: &Schema,
+) -> Result<Vec<RollupStatus>, CompassError> {
+    let mut statuses = Vec::new();
+    for (name, rollup) in schema.rollups.iter() {
+        let view = rollup_view(schema, name);
+        let row = refreshed(client, &view)?;
+        statuses.push(status(name, view, rollup, row));
+    }
+    Ok(statuses)
+}
+
+// recomputes one rollup, or all of the schema's with None. after the first time, views are refreshed
+// concurrently, so aggregates keep being served from the old counts while it runs
+pub fn refresh_rollups(
+    client: &mut Client,
+    schema: &Schema,
+    name: Option<&str>,
+) -> Result<Vec<RollupStatus>, CompassError> {
+    let names: Vec<&String> = match name {
+        Some(name) => vec![schema
+            .rollups
+            .get_key_value(name)
+            .map(|(k, _)| k)
+            .ok_or_else(|| {
+                CompassError::InvalidAggregate(format!("there's no rollup '{}'", name))
+            })?],
+        None => schema.rollups.keys().collect(),
+    };
+
+    let mut statuses = Vec::new();
+    for name in names {
+        let view = rollup_view(schema, name);
+        let populated: bool = client
+            .query_opt(
+                "SELECT relispopulated FROM pg_class WHERE oid = to_regclass($1)",
+                &[&view],
+            )?
+            .map(|row| row.get(0))
+            .ok_or_else(|| {
+                CompassError::SchemaMismatch(format!(
+                    "rollup view '{}' doesn't exist; run compass::migrate to create it",
+                    view
+                ))
+            })?;
+
+        let concurrently = if populated { "CONCURRENTLY " } else { "" };
+        client.batch_execute(&format!(
+            "REFRESH MATERIALIZED VIEW {}{}",
+            concurrently, view
+        ))?;
+        client.execute(
+            format!(
+                "UPDATE {} SET refreshed_at = now() WHERE view = $1",
+                ROLLUPS_TABLE
+            )
+            .as_str(),
+            &[&view],
+        )?;
+
+        let row = refreshed(client, &view)?;
+        statuses.push(status(name, view, &schema.rollups[name.as_str()], row));
+    }
+    Ok(statuses)
+}
+
+// (refreshed_at, age), both in seconds by postgres' clock, for a view that's been refreshed
+fn refreshed<C: Connection>(
+    client: &mut C,
+    view: &str,
+) -> Result<Option<(i64, i64)>, CompassError> {
+    let row = client.client()?.query_opt(
+        format!(
+            "SELECT extract(epoch FROM refreshed_at)::int8, extract(epoch FROM now() - refreshed_at)::int8 \
+             FROM {} WHERE view = $1 AND refreshed_at IS NOT NULL",
+            ROLLUPS_TABLE
+        )
+        .as_str(),
+        &[&view],
+    )?;
+    Ok(row.map(|row| (row.get(0), row.get(1))))
+}
+
+// a fresh rollup that can answer a count downsample of `time_path` into buckets `width` wide with
+// these filters: one bucketing the same field at a width that divides it, grouped by every field the
+// filters touch
+fn matching_rollup<'a>(
+    schema: &'a Schema,
+    filters: &HashMap<String, String>,
+    time_path: &[String],
+    width: i64,
+) -> Result<Option<(&'a String, &'a Rollup)>, CompassError> {
+    if schema.rollups.is_empty() || schema.hooks.has_middleware() {
+        return Ok(None);
+    }
+
+    let filters = expand_params(schema, filters)?;
+    let mut touched = Vec::new();
+    for key in filters.keys() {
+        if RESERVED_PARAMS.contains(&key.as_str()) {
+            continue;
+        }
+        let key = split_group_key(key).map_or(key.as_str(), |(_, _, key)| key);
+        match schema.resolve_field(key) {
+            Some((field, _)) => touched.push(field),
+            None => continue, // ignored, or rejected by generate_where the same way either way
+        }
+    }
+
+    for (name, rollup) in schema.rollups.iter() {
+        if field_path(schema, &rollup.time).ok().as_deref() != Some(time_path)
+            || width % stored_width(schema, rollup)? != 0
+            || !touched.iter().all(|f| rollup.group_by.contains(f))
+        {
+            continue;
+        }
+        return Ok(Some((name, rollup)));
+    }
+    Ok(None)
+}
+
+// a count downsample from a rollup, if there's a fresh one that can answer it. None means it has to be
+// computed from the table
+pub(crate) fn rollup_downsample<C: Connection>(
+    client: &mut C,
+    schema: &Schema,
+    filters: &HashMap<String, String>,
+    time_path: &[String],
+    width: i64,
+) -> Result<Option<(Vec<Value>, RollupFreshness)>, CompassError> {
+    let (name, rollup) = match matching_rollup(schema, filters, time_path, width)? {
+        Some(found) => found,
+        None => return Ok(None),
+    };
+    let view = rollup_view(schema, name);
+    let (refreshed_at, age_secs) = match refreshed(client, &view)? {
+        Some((at, age)) if age <= rollup.max_age_secs => match Utc.timestamp_opt(at, 0).single() {
+            Some(at) => (at, age),
+            None => return Ok(None),
+        },
+        _ => return Ok(None),
+    };
+
+    let mut plan = generate_where(schema, filters, 2, false)?;
+    // anything beyond a jsonpath filter needs the table's own columns
+    if !plan.bindings.is_empty() {
+        return Ok(None);
+    }
+    let width = plan.bind(Binding::Int(width));
+
+    let max_buckets = schema.limits.max_limit;
+    let sql = format!(
+        "SELECT jsonb_build_object('bucket', bucket, 'value', count, 'count', count) FROM (\
+         SELECT floor(bucket / {width}) * {width} AS bucket, SUM(count)::int8 AS count \
+         FROM {view} {where_clause} GROUP BY 1) buckets ORDER BY bucket LIMIT {limit}",
+        width = width,
+        view = view,
+        where_clause = plan.where_clause,
+        limit = max_buckets + 1
+    );
+
+    let points = run_plan(client, schema, &sql, &plan)?;
+    Ok(Some((
+        points,
+        RollupFreshness {
+            rollup: name.clone(),
+            refreshed_at,
+            age_secs,
+            max_age_secs: rollup.max_age_secs,
+        },
+    )))
+}
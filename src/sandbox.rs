@@ -0,0 +1,291 @@
+use super::*;
+
+use postgres::fallible_iterator::FallibleIterator;
+use postgres::types::ToSql;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlparser::ast::{Expr, ObjectName, Query, Statement, Visit, Visitor};
+use sqlparser::dialect::PostgreSqlDialect;
+use sqlparser::parser::Parser;
+
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::ops::ControlFlow;
+
+// plain sql for the questions the query parameters will never cover. it's off unless enabled, and
+// meant to be served to admins only
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct SqlConfig {
+    pub enabled: bool,
+    pub role: Option<String>, // SET ROLE for the statement, required when enabled; should only be able to SELECT the schema tables
+    pub timeout_ms: u64,
+    pub max_rows: i64,
+}
+
+impl Default for SqlConfig {
+    fn default() -> Self {
+        SqlConfig {
+            enabled: false,
+            role: None,
+            timeout_ms: 30_000,
+            max_rows: 100_000,
+        }
+    }
+}
+
+fn rejected(msg: String) -> CompassError {
+    CompassError::SqlRejected(msg)
+}
+
+// functions that reach past the table: run sql from a string, read files, change settings (including
+// the role), or talk to other servers. the role is what really stops these; this makes the error clearer
+fn denied_function(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    name.starts_with("pg_")
+        || name.starts_with("lo_")
+        || name.starts_with("dblink")
+        || name.contains("_to_xml")
+        || ["set_config", "current_setting", "txid_current"].contains(&name.as_str())
+}
+
+fn plain_name(name: &ObjectName) -> String {
+    name.0
+        .iter()
+        .map(|ident| ident.value.as_str())
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+// one level of CTE names. every query pushes two: the names it sees because it's a CTE body (the
+// CTEs before it in the same WITH, or all of them WITH RECURSIVE), which hides that WITH's own level
+// from it, and then the names its own WITH defines
+struct Scope {
+    names: HashSet<String>,
+    hides_parent: bool,
+}
+
+// collects every table a statement reads and every function it calls. a name is only a CTE where the
+// WITH defining it is in scope, so `users` is still the real table next to a subquery that has a
+// `users` CTE of its own
+#[derive(Default)]
+struct References {
+    scopes: Vec<Scope>,
+    cte_bodies: HashMap<*const Query, HashSet<String>>, // CTE body -> the names in its WITH it can see
+    tables: Vec<String>,
+    functions: Vec<String>,
+}
+
+impl References {
+    fn is_cte(&self, name: &str) -> bool {
+        let mut hidden = false;
+        for scope in self.scopes.iter().rev() {
+            if hidden {
+                hidden = false;
+                continue;
+            }
+            if scope.names.contains(name) {
+                return true;
+            }
+            hidden = scope.hides_parent;
+        }
+        false
+    }
+}
+
+impl Visitor for References {
+    type Break = ();
+
+    fn pre_visit_query(&mut self, query: &Query) -> ControlFlow<()> {
+        let visible = self.cte_bodies.remove(&(query as *const Query));
+        self.scopes.push(Scope {
+            hides_parent: visible.is_some(),
+            names: visible.unwrap_or_default(),
+        });
+
+        let mut names = HashSet::new();
+        if let Some(ref with) = query.with {
+            for cte in with.cte_tables.iter() {
+                if !with.recursive {
+                    self.cte_bodies
+                        .insert(&*cte.query as *const Query, names.clone());
+                }
+                names.insert(cte.alias.name.value.clone());
+            }
+            if with.recursive {
+                for cte in with.cte_tables.iter() {
+                    self.cte_bodies
+                        .insert(&*cte.query as *const Query, names.clone());
+                }
+            }
+        }
+        self.scopes.push(Scope {
+            names,
+            hides_parent: false,
+        });
+        ControlFlow::Continue(())
+    }
+
+    fn post_visit_query(&mut self, _: &Query) -> ControlFlow<()> {
+        self.scopes.pop();
+        self.scopes.pop();
+        ControlFlow::Continue(())
+    }
+
+    fn pre_visit_relation(&mut self, relation: &ObjectName) -> ControlFlow<()> {
+        let name = plain_name(relation);
+        if !self.is_cte(&name) {
+            self.tables.push(name);
+        }
+        ControlFlow::Continue(())
+    }
+
+    fn pre_visit_expr(&mut self, expr: &Expr) -> ControlFlow<()> {
+        if let Expr::Function(ref f) = expr {
+            if let Some(name) = f.name.0.last() {
+                self.functions.push(name.value.clone());
+            }
+        }
+        ControlFlow::Continue(())
+    }
+}
+
+// parses `sql` and hands it back, re-printed from the parse, if it's one SELECT that only reads the
+// schema's table. the re-printed text is what runs, so nothing the parser skipped can sneak through
+pub fn check_sql(schema: &Schema, sql: &str) -> Result<String, CompassError> {
+    let mut statements = Parser::parse_sql(&PostgreSqlDialect {}, sql)
+        .map_err(|e| rejected(format!("couldn't parse: {}", e)))?;
+    if statements.len() != 1 {
+        return Err(rejected(format!(
+            "expected one statement, got {}",
+            statements.len()
+        )));
+    }
+    let query = match statements.remove(0) {
+        Statement::Query(query) => query,
+        _ => return Err(rejected("only SELECT statements are allowed".to_owned())),
+    };
+    if !query.locks.is_empty() {
+        return Err(rejected("FOR UPDATE/SHARE isn't allowed".to_owned()));
+    }
+
+    let mut references = References::default();
+    let _ = query.visit(&mut references);
+
+    for table in references.tables.iter() {
+        if *table != schema.table {
+            return Err(rejected(format!(
+                "only {} can be queried, not {}",
+                schema.table, table
+            )));
+        }
+    }
+    if let Some(f) = references.functions.iter().find(|f| denied_function(f)) {
+        return Err(rejected(format!("{}() isn't allowed", f)));
+    }
+
+    Ok(query.to_string())
+}
+
+fn quote_ident(ident: &str) -> String {
+    format!("\"{}\"", ident.replace('"', "\"\""))
+}
+
+// runs a checked SELECT in a read-only transaction, as `config.role` and under `config.timeout_ms`, and
+// writes its rows as NDJSON objects keyed by column name. returns how many rows were written
+pub fn sql_query_ndjson<C: Connection, W: Write>(
+    client: &mut C,
+    schema: &Schema,
+    config: &SqlConfig,
+    sql: &str,
+    out: &mut W,
+) -> Result<usize, CompassError> {
+    if !config.enabled {
+        return Err(rejected("the sql endpoint is disabled".to_owned()));
+    }
+    // the application's own role can read every table, which would leave the parser as the only barrier
+    let role = match config.role.as_deref() {
+        Some(role) if !role.is_empty() => role,
+        _ => return Err(rejected("the sql endpoint needs sql.role set".to_owned())),
+    };
+    let query = check_sql(schema, sql)?;
+    let client = &mut throttle_permit(client, schema)?;
+
+    let mut transaction = client
+        .client()
+        .map_err(pg_error(schema))?
+        .transaction()
+        .map_err(pg_error(schema))?;
+    let setup = format!(
+        "SET TRANSACTION READ ONLY; SET LOCAL statement_timeout = {}; SET LOCAL ROLE {};",
+        config.timeout_ms,
+        quote_ident(role)
+    );
+    transaction
+        .batch_execute(&setup)
+        .map_err(pg_error(schema))?;
+
+    let wrapped = format!(
+        "SELECT to_jsonb(sandboxed) FROM ({}) sandboxed LIMIT {}",
+        query, config.max_rows
+    );
+    let params: Vec<&dyn ToSql> = Vec::new();
+
+    let mut written = 0;
+    {
+        // streamed, like exports
+        let mut rows = transaction
+            .query_raw(wrapped.as_str(), params.iter().copied())
+            .map_err(pg_error(schema))?;
+        while let Some(row) = rows.next().map_err(pg_error(schema))? {
+            let row: Value = row.get(0);
+            serde_json::to_writer(&mut *out, &row)?;
+            out.write_all(b"\n")?;
+            written += 1;
+        }
+    }
+    out.flush()?;
+
+    // nothing to keep; it was read only
+    transaction.rollback().map_err(pg_error(schema))?;
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    fn allowed(sql: &str) -> bool {
+        check_sql(&test_schema(), sql).is_ok()
+    }
+
+    #[test]
+    fn ctes_only_cover_their_own_query() {
+        assert!(!allowed(
+            "SELECT * FROM users, (WITH users AS (SELECT 1) SELECT * FROM users) s"
+        ));
+        assert!(!allowed(
+            "SELECT * FROM (WITH users AS (SELECT 1) SELECT * FROM users) s, users"
+        ));
+        assert!(allowed(
+            "SELECT * FROM documents, (WITH users AS (SELECT 1) SELECT * FROM users) s"
+        ));
+    }
+
+    #[test]
+    fn cte_bodies_see_earlier_ctes_only() {
+        assert!(allowed(
+            "WITH a AS (SELECT * FROM documents), b AS (SELECT * FROM a) SELECT * FROM b"
+        ));
+        assert!(!allowed(
+            "WITH users AS (SELECT * FROM users) SELECT * FROM users"
+        ));
+        assert!(!allowed(
+            "WITH a AS (SELECT * FROM b), b AS (SELECT * FROM documents) SELECT * FROM a"
+        ));
+        assert!(allowed(
+            "WITH RECURSIVE a AS (SELECT 1 UNION ALL SELECT * FROM a) SELECT * FROM a, documents"
+        ));
+    }
+}
@@ -0,0 +1,245 @@
+use super::*;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use uuid::Uuid;
+
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+// a search run on a schedule, with the results POSTed to a webhook:
+//
+//   [[scheduled]]
+//   name = "new-incinerations"
+//   schema = "feed"
+//   schedule = "*/15 * * * *"
+//   params = { description = "incinerated", sortby = "created" }
+//   webhook = "https://example.com/hooks/incinerations"
+//   count_only = true
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ScheduledQuery {
+    pub name: String,
+    pub schema: String,
+    pub schedule: String, // cron: minute hour day month weekday, with an optional leading seconds field
+    #[serde(default)]
+    pub params: HashMap<String, String>,
+    pub webhook: String,
+    #[serde(default)]
+    pub count_only: bool, // post the match count and how it changed since the last run, not the documents
+    #[serde(default)]
+    pub changes_only: bool, // post the doc_ids that joined and left the results since the last run, and only when some did
+}
+
+impl ScheduledQuery {
+    pub(crate) fn parse_schedule(&self) -> Result<cron::Schedule, CompassError> {
+        // the cron crate wants seconds first
+        let expr = match self.schedule.split_whitespace().count() {
+            5 => format!("0 {}", self.schedule),
+            _ => self.schedule.clone(),
+        };
+        cron::Schedule::from_str(&expr).map_err(|e| {
+            CompassError::ConfigError(format!(
+                "scheduled query '{}' has an invalid schedule '{}': {}",
+                self.name, self.schedule, e
+            ))
+        })
+    }
+}
+
+struct JobState {
+    job: ScheduledQuery,
+    next: Option<DateTime<Utc>>,
+    last_count: Option<i64>,
+    last_ids: Option<HashSet<Uuid>>,
+}
+
+// runs the config's scheduled queries on one thread, one at a time. it rereads the config every tick, so
+// jobs added, changed or removed by a reload take effect without a restart. stops once `drain` closes
+pub struct Scheduler {
+    config: ConfigHandle,
+    drain: Arc<Drain>,
+    jobs: HashMap<String, JobState>,
+    webhooks: Option<Arc<Webhooks>>,
+}
+
+impl Scheduler {
+    pub fn new(config: ConfigHandle, drain: Arc<Drain>) -> Scheduler {
+        Scheduler {
+            config,
+            drain,
+            jobs: HashMap::new(),
+            webhooks: None,
+        }
+    }
+
+    // also sends every run's results to the registered webhooks that want them, as a "scheduled" event
+    pub fn with_webhooks(mut self, webhooks: Arc<Webhooks>) -> Scheduler {
+        self.webhooks = Some(webhooks);
+        self
+    }
+
+    pub fn spawn(mut self) -> thread::JoinHandle<()> {
+        thread::spawn(move || {
+            let mut client = None;
+            while !self.drain.is_closing() {
+                self.tick(&mut client);
+                thread::sleep(Duration::from_secs(1));
+            }
+        })
+    }
+
+    fn tick(&mut self, client: &mut Option<ManagedClient>) {
+        let loaded = self.config.current();
+        let now = Utc::now();
+
+        self.jobs.retain(|name, state| {
+            loaded
+                .config
+                .scheduled
+                .iter()
+                .any(|j| &j.name == name && *j == state.job)
+        });
+        for job in loaded.config.scheduled.iter() {
+            if self.jobs.contains_key(&job.name) {
+                continue;
+            }
+            let next = job.parse_schedule().ok().and_then(|s| s.after(&now).next());
+            self.jobs.insert(
+                job.name.clone(),
+                JobState {
+                    job: job.clone(),
+                    next,
+                    last_count: None,
+                    last_ids: None,
+                },
+            );
+        }
+
+        for state in self.jobs.values_mut() {
+            match state.next {
+                Some(next) if next <= now => {}
+                _ => continue,
+            }
+            state.next = state
+                .job
+                .parse_schedule()
+                .ok()
+                .and_then(|s| s.after(&now).next());
+
+            let schema = match loaded.schemas.get(&state.job.schema) {
+                Some(schema) => schema,
+                None => continue,
+            };
+
+            if client.is_none() {
+                match ManagedClient::connect(loaded.config.database.clone()) {
+                    Ok(c) => *client = Some(c),
+                    Err(e) => {
+                        eprintln!(
+                            "compass: scheduled query '{}' couldn't connect: {}",
+                            state.job.name, e
+                        );
+                        continue;
+                    }
+                }
+            }
+
+            let _guard = match self.drain.enter() {
+                Ok(guard) => guard,
+                Err(_) => return,
+            };
+            let webhooks = self.webhooks.as_ref();
+            if let Err(e) = run_job(client.as_mut().unwrap(), schema, state, now, webhooks) {
+                eprintln!(
+                    "compass: scheduled query '{}' failed: {}",
+                    state.job.name, e
+                );
+            }
+        }
+    }
+}
+
+fn run_job(
+    client: &mut ManagedClient,
+    schema: &Schema,
+    state: &mut JobState,
+    ran_at: DateTime<Utc>,
+    webhooks: Option<&Arc<Webhooks>>,
+) -> Result<(), CompassError> {
+    let job = &state.job;
+
+    let body = if job.count_only {
+        let count = json_count(client, schema, &job.params)?;
+        let delta = state.last_count.map(|last| count - last);
+        state.last_count = Some(count);
+        json!({
+            "name": job.name,
+            "schema": job.schema,
+            "ran_at": ran_at.to_rfc3339(),
+            "count": count,
+            "delta": delta,
+        })
+    } else if job.changes_only {
+        let ids = json_search_ids(client, schema, &job.params)?;
+        let current: HashSet<Uuid> = ids.iter().copied().collect();
+        // the first run has nothing to compare with, so everything on the page is new
+        let added: Vec<Uuid> = ids
+            .iter()
+            .filter(|id| {
+                state
+                    .last_ids
+                    .as_ref()
+                    .map_or(true, |last| !last.contains(id))
+            })
+            .copied()
+            .collect();
+        let mut removed: Vec<Uuid> = state
+            .last_ids
+            .as_ref()
+            .map(|last| last.difference(&current).copied().collect())
+            .unwrap_or_default();
+        removed.sort();
+        state.last_ids = Some(current);
+
+        if added.is_empty() && removed.is_empty() {
+            return Ok(());
+        }
+        json!({
+            "name": job.name,
+            "schema": job.schema,
+            "ran_at": ran_at.to_rfc3339(),
+            "added": added,
+            "removed": removed,
+            "count": ids.len(),
+        })
+    } else {
+        let response = json_search_response(client, schema, &job.params, None)?;
+        json!({
+            "name": job.name,
+            "schema": job.schema,
+            "ran_at": ran_at.to_rfc3339(),
+            "data": response.data,
+            "meta": response.meta,
+        })
+    };
+
+    if let Some(webhooks) = webhooks {
+        webhooks.dispatch("scheduled", &job.name, &body);
+    }
+    ureq::post(&job.webhook)
+        .timeout(Duration::from_secs(30))
+        .send_json(body)
+        .map_err(|e| {
+            CompassError::IOError(io::Error::new(
+                io::ErrorKind::Other,
+                format!("webhook {}: {}", job.webhook, e),
+            ))
+        })?;
+
+    Ok(())
+}
@@ -1,13 +1,595 @@
+use super::{
+    validate_presets, validate_rollups, CompassError, Hooks, Limits, Quota, RawQueryConfig, Rollup,
+    SlowQueryLog, Tenancy, Throttle, AUTO_LANGUAGE,
+};
+use chrono::{DateTime, LocalResult, NaiveDate, NaiveDateTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use indexmap::IndexMap;
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
 use std::collections::HashMap;
 use std::default;
 use std::fmt;
+use std::sync::{Arc, OnceLock};
+use unicode_normalization::UnicodeNormalization;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Schema {
-    pub fields: HashMap<String, Field>,
+    pub fields: IndexMap<String, Field>, // keeps the order fields are written in
     pub default_order_by: String,
+    #[serde(default)]
+    pub nulls: Option<Nulls>, // where documents without the sort field go, when the query doesn't say
+    #[serde(default)]
+    pub secondary_sort: Option<String>, // a sortable field that orders documents tied on the sort field
     pub table: String,
+    #[serde(skip)]
+    pub limits: Limits, // filled in from the server config, see Config::load_schemas
+    #[serde(skip)]
+    pub slow_log: Option<Arc<SlowQueryLog>>,
+    #[serde(skip)]
+    pub raw_query: RawQueryConfig, // also from the server config
+    #[serde(skip)]
+    pub throttle: Option<Arc<Throttle>>, // one per schema, shared by its clones
+    #[serde(skip)]
+    pub quota: Option<Arc<Quota>>, // from `[quotas.<name>]`, shared the same way
+    #[serde(skip)]
+    pub cursor_secret: Option<String>, // from the server config too
+    #[serde(default)]
+    pub strict: bool, // reject query parameters that don't resolve to any field
+    #[serde(default)]
+    pub nested: NestedLimits,
+    #[serde(default)]
+    pub joins: IndexMap<String, Join>, // requested with `join=name,...`
+    #[serde(default)]
+    pub lookups: IndexMap<String, Lookup>, // output key -> lookup table, applied to every result
+    #[serde(default)]
+    pub mentions: Vec<String>, // fields (or arrays) that hold entity ids, for json_mentions
+    #[serde(default)]
+    pub text_search: IndexMap<String, TextSearchConfig>, // created by provision_text_search, usable as a fulltext `lang`
+    #[serde(default)]
+    pub presets: IndexMap<String, IndexMap<String, Value>>, // `preset=name` -> the parameters it stands for, see expand_presets
+    #[serde(default)]
+    pub rollups: IndexMap<String, Rollup>, // materialized counts downsampling can be served from, see provision_rollups
+    #[serde(default)]
+    pub tenancy: Option<Tenancy>, // scopes every query to the caller's tenant, see for_key
+    #[serde(skip)]
+    pub tenant_keys: Arc<HashMap<String, String>>, // api key -> tenant, from the server config
+    #[serde(skip)]
+    pub tenant: Option<String>, // the tenant this copy of the schema is scoped to
+    #[serde(skip)]
+    pub usage: Option<Arc<Usage>>, // shared by every schema, when usage.enabled
+    #[serde(skip)]
+    pub key: Option<String>, // fingerprint of the api key this copy is being queried with
+    #[serde(skip)]
+    pub hooks: Arc<Hooks>, // embedder hooks, shared by clones and kept across reloads
+    #[serde(skip)]
+    index: OnceLock<SchemaIndex>,
+}
+
+// enriches results with documents from another schema whose `foreign` field equals this document's `local` field
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Join {
+    pub schema: String,
+    pub local: String,
+    pub foreign: String,
+    #[serde(default)]
+    pub many: bool, // attach every match as an array instead of the first one
+    #[serde(rename = "as")]
+    pub output: Option<String>, // key to put the match under; defaults to the join's name
+    #[serde(skip)]
+    pub table: Option<String>, // the other schema's table, see Schema::resolve_joins
+    #[serde(skip)]
+    pub tenancy: Option<Tenancy>, // and its tenancy, so matches stay within the document's tenant
+}
+
+// a postgres text search configuration of our own. dictionary files (`<name>.stop`, `<name>.syn`) have
+// to already be in the server's tsearch_data directory; postgres won't read them from anywhere else
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TextSearchConfig {
+    #[serde(default = "default_copy")]
+    pub copy: String, // the configuration to start from
+    pub language: Option<String>, // snowball stemmer; without one, words are only lowercased
+    pub stopwords: Option<String>,
+    pub synonyms: Option<String>,
+}
+
+fn default_copy() -> String {
+    "simple".to_owned()
+}
+
+// a small id -> value table for filling in things like `playerName` from `playerId`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Lookup {
+    pub from: String, // field holding the id, or an array of ids
+    pub values: HashMap<String, Value>,
+}
+
+impl Lookup {
+    fn resolve(&self, id: &Value) -> Value {
+        let key = match id {
+            Value::String(s) => s.clone(),
+            Value::Number(n) => n.to_string(),
+            Value::Array(ids) => {
+                return Value::Array(ids.iter().map(|id| self.resolve(id)).collect())
+            }
+            _ => return Value::Null,
+        };
+        self.values.get(&key).cloned().unwrap_or(Value::Null)
+    }
+
+    // adds `output` to a document. documents without the `from` field are left alone
+    pub fn apply(&self, output: &str, doc: &mut Value) {
+        let pointer = format!("/{}", self.from.replace('.', "/"));
+        let resolved = match doc.pointer(&pointer) {
+            Some(id) => self.resolve(id),
+            None => return,
+        };
+        if let Some(obj) = doc.as_object_mut() {
+            obj.insert(output.to_owned(), resolved);
+        }
+    }
+}
+
+// caps on dotted keys into `Nested` fields, so a query can't make the planner walk arbitrarily deep paths
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct NestedLimits {
+    pub max_depth: usize, // segments in one key, counting the field itself: `player.stats.hits` is 3
+    pub max_fields: usize, // distinct nested paths in one query
+}
+
+impl Default for NestedLimits {
+    fn default() -> Self {
+        NestedLimits {
+            max_depth: 4,
+            max_fields: 8,
+        }
+    }
+}
+
+// lookup tables derived from `fields`, built on first use. don't mutate `fields` after querying with a schema
+#[derive(Debug, Clone, Default)]
+struct SchemaIndex {
+    names: HashMap<String, String>, // lowercased field name -> field name as written in the schema
+    ranges: HashMap<String, (String, FieldQuery)>, // lowercased range min/max name -> (field, Min/Max)
+    range_names: HashMap<String, String>, // lowercased range min/max name -> as written in the schema
+    converters: Vec<(String, ConverterSchema)>, // fields with a converter, in schema order
+}
+
+impl SchemaIndex {
+    fn build(fields: &IndexMap<String, Field>) -> SchemaIndex {
+        let mut index = SchemaIndex::default();
+
+        for (name, field) in fields.iter() {
+            index.names.insert(name.to_lowercase(), name.clone());
+
+            if let Some(converter) = field.converter {
+                index.converters.push((name.clone(), converter));
+            }
+
+            if let FieldQuery::Range {
+                ref min, ref max, ..
+            } = field.query
+            {
+                index
+                    .ranges
+                    .insert(min.to_lowercase(), (name.clone(), FieldQuery::Min));
+                index
+                    .ranges
+                    .insert(max.to_lowercase(), (name.clone(), FieldQuery::Max));
+                index.range_names.insert(min.to_lowercase(), min.clone());
+                index.range_names.insert(max.to_lowercase(), max.clone());
+            }
+
+            if let FieldQuery::Vector { .. } = field.query {
+                let near = format!("{}_near", name);
+                index
+                    .ranges
+                    .insert(near.to_lowercase(), (name.clone(), field.query.clone()));
+                index.range_names.insert(near.to_lowercase(), near);
+            }
+        }
+
+        index
+    }
+}
+
+pub(crate) fn is_sql_identifier(s: &str) -> bool {
+    // allows schema-qualified names like public.documents
+    !s.is_empty()
+        && s.split('.').all(|part| {
+            part.chars()
+                .next()
+                .map_or(false, |c| c.is_ascii_alphabetic() || c == '_')
+                && part.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        })
+}
+
+// what can go between the double quotes of COLLATE "...": icu locales like en-u-kn-true or und-x-icu,
+// and libc ones like en_US.utf8 or C
+pub(crate) fn is_collation_name(s: &str) -> bool {
+    !s.is_empty()
+        && s.len() < 64
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.' || c == '@')
+}
+
+impl Schema {
+    // catches the mistakes that would otherwise only show up as broken sql at query time
+    pub fn validate(&self) -> Result<(), CompassError> {
+        if !is_sql_identifier(&self.table) {
+            return Err(CompassError::ConfigError(format!(
+                "table name '{}' isn't a plain sql identifier",
+                self.table
+            )));
+        }
+
+        if self.nested.max_depth == 0 || self.nested.max_fields == 0 {
+            return Err(CompassError::ConfigError(
+                "nested.max_depth and nested.max_fields have to be at least 1".to_owned(),
+            ));
+        }
+
+        for (name, join) in self.joins.iter() {
+            if !is_sql_identifier(&join.local)
+                || !is_sql_identifier(&join.foreign)
+                || !is_sql_identifier(join.output.as_ref().unwrap_or(name))
+            {
+                return Err(CompassError::ConfigError(format!(
+                    "join '{}' has to use plain dotted field names",
+                    name
+                )));
+            }
+        }
+
+        for path in self.mentions.iter() {
+            if !is_sql_identifier(path) {
+                return Err(CompassError::ConfigError(format!(
+                    "mentions field '{}' has to be a plain dotted field name",
+                    path
+                )));
+            }
+        }
+
+        for (name, config) in self.text_search.iter() {
+            let files = [&config.language, &config.stopwords, &config.synonyms];
+            if !is_sql_identifier(name)
+                || name.contains('.')
+                || !is_sql_identifier(&config.copy)
+                || files
+                    .iter()
+                    .filter_map(|f| f.as_ref())
+                    .any(|f| !is_sql_identifier(f))
+            {
+                return Err(CompassError::ConfigError(format!(
+                    "text search config '{}' can only use plain names",
+                    name
+                )));
+            }
+        }
+
+        validate_presets(self)?;
+        validate_rollups(self)?;
+
+        if let Some(ref tenancy) = self.tenancy {
+            tenancy.validate()?;
+        }
+
+        if let Some(ref secondary) = self.secondary_sort {
+            if let Ok(SortKey::DocId) | Ok(SortKey::Relevance) | Err(_) =
+                self.resolve_sort(secondary)
+            {
+                return Err(CompassError::ConfigError(format!(
+                    "secondary_sort '{}' has to be a sortable field",
+                    secondary
+                )));
+            }
+        }
+
+        if self.default_order_by.is_empty() {
+            return Err(CompassError::ConfigError(
+                "default_order_by can't be empty".to_owned(),
+            ));
+        }
+
+        for (name, field) in self.fields.iter() {
+            // field names end up in jsonpath accessors (`$.player.id`) the same way query keys do, so
+            // they're held to the same rule as validate_key; only values get quoted
+            if !is_sql_identifier(name) {
+                return Err(CompassError::ConfigError(format!(
+                    "field '{}' has to be a plain dotted name",
+                    name
+                )));
+            }
+
+            if let Some(ref collation) = field.collation {
+                if !field.sortable {
+                    return Err(CompassError::ConfigError(format!(
+                        "field '{}' has a collation but isn't sortable",
+                        name
+                    )));
+                }
+                if !is_collation_name(collation) {
+                    return Err(CompassError::ConfigError(format!(
+                        "field '{}' has collation '{}', which isn't a collation name",
+                        name, collation
+                    )));
+                }
+            }
+
+            match field.query {
+                FieldQuery::Range {
+                    ref min, ref max, ..
+                } => {
+                    for alias in [min, max].iter() {
+                        if self.fields.contains_key(alias.as_str()) {
+                            return Err(CompassError::ConfigError(format!(
+                                "range field '{}' uses '{}' which is already a field name",
+                                name, alias
+                            )));
+                        }
+                    }
+                }
+                FieldQuery::Vector {
+                    ref column,
+                    dimensions,
+                } => {
+                    if !is_sql_identifier(column.as_ref().unwrap_or(name)) || dimensions == 0 {
+                        return Err(CompassError::ConfigError(format!(
+                            "vector field '{}' needs a plain column name and at least 1 dimension",
+                            name
+                        )));
+                    }
+                }
+                FieldQuery::Fulltext {
+                    ref lang,
+                    ref synonyms,
+                    ref target,
+                    ..
+                } => {
+                    if !is_sql_identifier(lang) {
+                        return Err(CompassError::ConfigError(format!(
+                            "fulltext field '{}' has an invalid language '{}'",
+                            name, lang
+                        )));
+                    }
+                    if !target.as_deref().map_or(true, is_sql_identifier) {
+                        return Err(CompassError::ConfigError(format!(
+                            "fulltext field '{}' has to target a plain field name",
+                            name
+                        )));
+                    }
+
+                    // they go into the sql as literals
+                    for word in synonyms.iter().flatten() {
+                        if word.is_empty() || !word.chars().all(char::is_alphanumeric) {
+                            return Err(CompassError::ConfigError(format!(
+                                "fulltext field '{}' has a synonym '{}' that isn't a single word",
+                                name, word
+                            )));
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    fn index(&self) -> &SchemaIndex {
+        self.index.get_or_init(|| SchemaIndex::build(&self.fields))
+    }
+
+    // what convert_document runs over each result, worked out once instead of per request
+    pub(crate) fn converter_plan(&self) -> &[(String, ConverterSchema)] {
+        &self.index().converters
+    }
+
+    // where a resolved field name (or the top-level field of a nested key) appears in the schema
+    pub fn field_position(&self, name: &str) -> usize {
+        self.fields
+            .get_index_of(name)
+            .or_else(|| {
+                name.split('.')
+                    .next()
+                    .and_then(|head| self.fields.get_index_of(head))
+            })
+            .unwrap_or(usize::MAX)
+    }
+
+    // finds the field a query parameter refers to, ignoring case: a field by name, a range's min/max name,
+    // a key inside a nested field, or any of those with a trailing `!` for negation
+    pub fn resolve_field(&self, key: &str) -> Option<(String, FieldQuery)> {
+        if let Some(base) = key.strip_suffix('!') {
+            return self
+                .resolve_field(base)
+                .map(|(k, q)| (k, FieldQuery::Not(Box::new(q))));
+        }
+
+        let index = self.index();
+        let lower = key.to_lowercase();
+
+        if let Some(name) = index.names.get(&lower) {
+            return Some((name.clone(), self.fields[name].query.clone()));
+        }
+
+        if let Some(range) = index.ranges.get(&lower) {
+            return Some(range.clone());
+        }
+
+        // only the top-level name is case-insensitive; the rest of the path is document data
+        let (head, rest) = key.split_once('.')?;
+        let name = index.names.get(&head.to_lowercase())?;
+        match self.fields[name].query {
+            FieldQuery::Nested => Some((format!("{}.{}", name, rest), FieldQuery::Nested)),
+            _ => None,
+        }
+    }
+
+    // looks up the tables of the schemas joins point at
+    pub fn resolve_joins(&mut self, schemas: &HashMap<String, Schema>) -> Result<(), CompassError> {
+        for (name, join) in self.joins.iter_mut() {
+            match schemas.get(&join.schema) {
+                Some(other) if other.tenancy.is_some() && self.tenancy.is_none() => {
+                    return Err(CompassError::ConfigError(format!(
+                        "join '{}' reads tenant scoped schema '{}' from one without tenancy",
+                        name, join.schema
+                    )))
+                }
+                Some(other) => {
+                    join.table = Some(other.table.clone());
+                    join.tenancy = other.tenancy.clone();
+                }
+                None => {
+                    return Err(CompassError::ConfigError(format!(
+                        "join '{}' points at unknown schema '{}'",
+                        name, join.schema
+                    )))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // this schema, for a request made with an api key: usage is counted against the key, and with
+    // tenancy it's scoped to the key's tenant. schemas without tenancy don't need a key
+    pub fn for_key(&self, key: Option<&str>) -> Result<Schema, CompassError> {
+        let mut schema = match self.tenancy {
+            Some(_) => {
+                let tenant = key
+                    .and_then(|k| self.tenant_keys.get(k))
+                    .ok_or(CompassError::TenantRequired)?;
+                self.for_tenant(tenant)
+            }
+            None => self.clone(),
+        };
+        schema.key = key.map(key_fingerprint);
+        Ok(schema)
+    }
+
+    // this schema, scoped to a tenant the embedder has worked out itself
+    pub fn for_tenant(&self, tenant: &str) -> Schema {
+        let mut schema = self.clone();
+        schema.tenant = Some(tenant.to_owned());
+        schema
+    }
+
+    // ` AND <tenant> = $n` and the tenant to bind to $n, for the queries that don't go through
+    // generate_where. nothing for schemas without tenancy
+    pub(crate) fn tenant_scope(&self, n: usize) -> Result<(String, Option<&str>), CompassError> {
+        match self.tenancy {
+            Some(ref tenancy) => {
+                let tenant = self.tenant.as_deref().ok_or(CompassError::TenantRequired)?;
+                Ok((format!(" AND {} = ${}", tenancy.sql(None), n), Some(tenant)))
+            }
+            None => Ok((String::new(), None)),
+        }
+    }
+
+    // the spelling the schema uses for a query parameter name, e.g. `Season_Min!` -> `season_min!`
+    pub fn canonical_key(&self, key: &str) -> Option<String> {
+        if let Some(base) = key.strip_suffix('!') {
+            return self.canonical_key(base).map(|k| k + "!");
+        }
+
+        let index = self.index();
+        let lower = key.to_lowercase();
+
+        if let Some(name) = index
+            .names
+            .get(&lower)
+            .or_else(|| index.range_names.get(&lower))
+        {
+            return Some(name.clone());
+        }
+
+        let (head, rest) = key.split_once('.')?;
+        let name = index.names.get(&head.to_lowercase())?;
+        match self.fields[name].query {
+            FieldQuery::Nested => Some(format!("{}.{}", name, rest)),
+            _ => None,
+        }
+    }
+
+    // (field name, document key) for every fulltext field with did_you_mean on
+    pub fn spelling_fields(&self) -> Vec<(&str, &str)> {
+        self.fields
+            .iter()
+            .filter_map(|(name, field)| match field.query {
+                FieldQuery::Fulltext {
+                    ref target,
+                    did_you_mean: true,
+                    ..
+                } => Some((name.as_str(), target.as_deref().unwrap_or(name.as_str()))),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn detects_language(&self) -> bool {
+        self.fields.values().any(|f| match f.query {
+            FieldQuery::Fulltext { ref lang, .. } => lang == AUTO_LANGUAGE,
+            _ => false,
+        })
+    }
+
+    // (field name, column, dimensions) for every vector field
+    pub fn vector_columns(&self) -> Vec<(&str, &str, usize)> {
+        self.fields
+            .iter()
+            .filter_map(|(name, field)| match field.query {
+                FieldQuery::Vector {
+                    ref column,
+                    dimensions,
+                } => Some((
+                    name.as_str(),
+                    column.as_deref().unwrap_or(name.as_str()),
+                    dimensions,
+                )),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // resolves a user-supplied `sortby`. the old `{a,b}` path literal form is still accepted, but it has to name a declared field too
+    pub fn resolve_sort(&self, name: &str) -> Result<SortKey, CompassError> {
+        if name == "doc_id" {
+            return Ok(SortKey::DocId);
+        }
+        if name == "relevance" {
+            return Ok(SortKey::Relevance);
+        }
+
+        let name = match name.strip_prefix('{').and_then(|n| n.strip_suffix('}')) {
+            Some(path) => path
+                .split(',')
+                .map(str::trim)
+                .collect::<Vec<&str>>()
+                .join("."),
+            None => name.to_owned(),
+        };
+
+        match self.fields.get(&name) {
+            Some(field) if field.sortable => {
+                Ok(SortKey::Path(name.split('.').map(str::to_owned).collect()))
+            }
+            _ => Err(CompassError::InvalidSortField(name)),
+        }
+    }
+
+    // default_order_by is written by the schema author, so it's trusted as-is
+    pub fn default_sort(&self) -> SortKey {
+        let name = self.default_order_by.as_str();
+        if name == "doc_id" {
+            return SortKey::DocId;
+        }
+
+        match name.strip_prefix('{').and_then(|n| n.strip_suffix('}')) {
+            Some(path) => SortKey::Path(path.split(',').map(|s| s.trim().to_owned()).collect()),
+            None => SortKey::Path(name.split('.').map(str::to_owned).collect()),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -16,12 +598,158 @@ pub struct Field {
     pub converter: Option<ConverterSchema>,
     #[serde(default)]
     pub query: FieldQuery,
+    #[serde(default)]
+    pub sortable: bool,
+    #[serde(default)]
+    pub normalize: Option<Normalization>,
+    #[serde(default)]
+    pub suggest: bool, // gets a prefix index for typeahead (see suggest)
+    #[serde(default)]
+    pub collation: Option<String>, // sorts as text in this collation (e.g. `en-u-kn-true`) unless the query says `collation=`
+}
+
+// applied to stored strings at ingest (see prepare_document) and to query values, so both sides compare in the same form
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Normalization {
+    Nfc,
+    NfcCaseFold,
+}
+
+impl Normalization {
+    pub fn apply(&self, s: &str) -> String {
+        match self {
+            Normalization::Nfc => s.nfc().collect(),
+            Normalization::NfcCaseFold => s.to_lowercase().nfc().collect(),
+        }
+    }
+}
+
+// what a search is allowed to ORDER BY: doc_id, the jsonb path of a field the schema marks sortable, or
+// how well the document matches the query's fulltext parameters
+#[derive(Debug, Clone, PartialEq)]
+pub enum SortKey {
+    DocId,
+    Path(Vec<String>),
+    Relevance,
+}
+
+// `nulls: first` or `nulls: last`, in a schema or a query
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Nulls {
+    First,
+    Last,
+}
+
+impl Nulls {
+    pub fn sql(&self) -> &'static str {
+        match self {
+            Nulls::First => "NULLS FIRST",
+            Nulls::Last => "NULLS LAST",
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Copy)]
 pub struct ConverterSchema {
     pub from: ConvertFrom,
     pub to: ConvertTo,
+    // zone dates are rendered in, and that offset-less input is assumed to be in. utc when unset
+    #[serde(default)]
+    pub timezone: Option<Tz>,
+    // stored numbers may be seconds or millis whatever `to` says, told apart by size (see
+    // MIXED_UNITS_THRESHOLD), for data that's been written both ways
+    #[serde(default)]
+    pub mixed_units: bool,
+}
+
+// with mixed_units, stored timestamps at least this big are millis and smaller ones seconds. as millis
+// it's early 1973; as seconds it's the year 5138
+pub const MIXED_UNITS_THRESHOLD: i64 = 100_000_000_000;
+
+impl ConverterSchema {
+    // the ingest direction: turns an incoming value into what gets stored. values that already look
+    // converted (numbers, arrays) pass through untouched, unless mixed_units puts them in `to`'s unit
+    pub fn to_stored(&self, val: &Value) -> Result<Value, CompassError> {
+        let s = match val {
+            Value::String(s) => s,
+            Value::Number(n) if self.mixed_units => {
+                return Ok(match (n.as_i64(), self.to) {
+                    (Some(n), ConvertTo::Timestamp) => {
+                        json!(self.stored_millis(n).div_euclid(1000))
+                    }
+                    (Some(n), ConvertTo::TimestampMillis) => json!(self.stored_millis(n)),
+                    _ => val.clone(),
+                })
+            }
+            _ => return Ok(val.clone()),
+        };
+
+        match (self.from, self.to) {
+            (ConvertFrom::CommaSeparatedString, ConvertTo::TagArray) => Ok(split_tags(s, ',')),
+            (ConvertFrom::SemicolonSeparatedString, ConvertTo::TagArray) => Ok(split_tags(s, ';')),
+            (ConvertFrom::DateTimeString, to) | (ConvertFrom::DateString, to) => {
+                let dt = self.parse_datetime(s).ok_or_else(|| {
+                    CompassError::ConversionError(format!("can't parse '{}' as a date", s))
+                })?;
+                match to {
+                    ConvertTo::Timestamp => Ok(json!(dt.timestamp())),
+                    ConvertTo::TimestampMillis => Ok(json!(dt.timestamp_millis())),
+                    ConvertTo::TagArray => Err(CompassError::ConversionError(
+                        "dates can't be converted to a tag array".to_owned(),
+                    )),
+                }
+            }
+            (from, to) => Err(CompassError::ConversionError(format!(
+                "no converter from {:?} to {:?}",
+                from, to
+            ))),
+        }
+    }
+
+    // a stored timestamp in millis, whichever unit it was stored in
+    pub fn stored_millis(&self, n: i64) -> i64 {
+        match self.to {
+            _ if self.mixed_units && n >= MIXED_UNITS_THRESHOLD => n,
+            _ if self.mixed_units => n.saturating_mul(1000),
+            ConvertTo::Timestamp => n.saturating_mul(1000),
+            ConvertTo::TimestampMillis | ConvertTo::TagArray => n,
+        }
+    }
+
+    // rfc3339 with any offset, or a bare date/datetime taken to be in the converter's timezone
+    pub fn parse_datetime(&self, s: &str) -> Option<DateTime<Utc>> {
+        if let Ok(dt) = DateTime::parse_from_rfc3339(s) {
+            return Some(dt.with_timezone(&Utc));
+        }
+
+        let naive = NaiveDateTime::parse_from_str(s, "%Y-%m-%dT%H:%M:%S%.f")
+            .or_else(|_| NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S%.f"))
+            .ok()
+            .or_else(|| {
+                NaiveDate::parse_from_str(s, "%Y-%m-%d")
+                    .ok()
+                    .and_then(|d| d.and_hms_opt(0, 0, 0))
+            })?;
+
+        match self.timezone {
+            Some(tz) => match tz.from_local_datetime(&naive) {
+                LocalResult::Single(dt) | LocalResult::Ambiguous(dt, _) => {
+                    Some(dt.with_timezone(&Utc))
+                }
+                LocalResult::None => None,
+            },
+            None => Some(Utc.from_utc_datetime(&naive)),
+        }
+    }
+}
+
+fn split_tags(s: &str, sep: char) -> Value {
+    json!(s
+        .split(sep)
+        .map(str::trim)
+        .filter(|t| !t.is_empty())
+        .collect::<Vec<&str>>())
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -38,6 +766,12 @@ pub enum FieldQuery {
         #[serde(default)]
         syntax: FulltextSyntax,
         target: Option<String>,
+        #[serde(default)]
+        did_you_mean: bool, // suggest corrections when a search on this comes back empty (see refresh_terms)
+        #[serde(default)]
+        synonyms: Vec<Vec<String>>, // words in a set match each other, e.g. [incin, incineration]
+        #[serde(default)]
+        weight: RankWeight, // for sortby=relevance; a match in an A field counts most
     },
     AmbiguousTag,
     NumericTag {
@@ -49,6 +783,11 @@ pub enum FieldQuery {
     Min,
     Max,
     Bool,
+    // a pgvector column next to `object`; queried as `<field>_near=<base64 f32s>`, which orders results by distance
+    Vector {
+        column: Option<String>, // defaults to the field name
+        dimensions: usize,
+    },
     Not(Box<FieldQuery>),
 }
 
@@ -72,6 +811,21 @@ impl default::Default for FulltextSyntax {
     }
 }
 
+// postgres' setweight labels; ts_rank counts them as 1.0, 0.4, 0.2 and 0.1
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub enum RankWeight {
+    A,
+    B,
+    C,
+    D,
+}
+
+impl default::Default for RankWeight {
+    fn default() -> Self {
+        RankWeight::D
+    }
+}
+
 impl fmt::Display for FulltextSyntax {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -88,7 +842,7 @@ pub enum ConvertFrom {
     CommaSeparatedString,
     SemicolonSeparatedString,
     DateTimeString,
-    DateString,
+    DateString, // date-only fields: stored as midnight in the converter's timezone, rendered back as YYYY-MM-DD
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Copy)]
@@ -100,18 +854,101 @@ pub enum ConvertTo {
 
 #[cfg(feature = "rocket_support")]
 use rocket::{
-    request::{self, FromRequest, Request},
+    http::Status,
+    request::{self, FromRequest, Outcome, Request},
     State,
 };
 #[cfg(feature = "rocket_support")]
 #[rocket::async_trait]
 impl<'r> FromRequest<'r> for Schema {
-    type Error = ();
+    type Error = CompassError;
+
+    // with tenancy, the schema comes scoped to the tenant of the request's `Authorization: Bearer` key
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, CompassError> {
+        let schema = match request.guard::<&State<Schema>>().await {
+            Outcome::Success(s) => s.inner(),
+            Outcome::Failure((status, _)) => {
+                return Outcome::Failure((
+                    status,
+                    CompassError::ConfigError("no schema is managed".to_owned()),
+                ))
+            }
+            Outcome::Forward(f) => return Outcome::Forward(f),
+        };
+        let key = request
+            .headers()
+            .get_one("Authorization")
+            .and_then(|h| h.strip_prefix("Bearer "));
+        match schema.for_key(key) {
+            Ok(scoped) => Outcome::Success(scoped), // clone bad, i know
+            Err(e) => Outcome::Failure((Status::Unauthorized, e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    fn with_field(name: &str, yaml: &str) -> Schema {
+        let mut schema = test_schema();
+        let field: Field = serde_yaml::from_str(yaml).expect("the field parses");
+        schema.fields.insert(name.to_owned(), field);
+        schema
+    }
 
-    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, ()> {
-        request
-            .guard::<&State<Schema>>()
-            .await
-            .map(|s| s.inner().clone()) // clone bad, i know
+    #[test]
+    fn the_test_schema_is_valid() {
+        assert!(test_schema().validate().is_ok());
+        assert!(
+            with_field("stats.hits_2", "name: hits\nquery:\n  type: NumericTag\n")
+                .validate()
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn field_names_have_to_be_plain_dotted_names() {
+        for name in &["bad name", r#"a"b"#, "a(b)", "a)", "player..id", "1st", ""] {
+            assert!(
+                matches!(
+                    with_field(name, "name: x\nquery:\n  type: StringTag\n").validate(),
+                    Err(CompassError::ConfigError(_))
+                ),
+                "{:?} should be rejected",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn fulltext_targets_have_to_be_plain_names() {
+        for target in &["bad target", r#"a"b"#, "a(b)", "x') || true || ('"] {
+            let yaml = format!(
+                "name: text\nquery:\n  type: Fulltext\n  lang: english\n  target: '{}'\n",
+                target.replace('\'', "''")
+            );
+            assert!(
+                matches!(
+                    with_field("text", &yaml).validate(),
+                    Err(CompassError::ConfigError(_))
+                ),
+                "{:?} should be rejected",
+                target
+            );
+        }
+        assert!(with_field(
+            "text",
+            "name: text\nquery:\n  type: Fulltext\n  lang: english\n  target: description\n"
+        )
+        .validate()
+        .is_ok());
+        assert!(with_field(
+            "text",
+            "name: text\nquery:\n  type: Fulltext\n  lang: \"english') || ('\"\n"
+        )
+        .validate()
+        .is_err());
     }
 }
@@ -0,0 +1,71 @@
+use postgres::types::Type as PostgresType;
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+// a statement's sql and parameter types. filter values are bound (the jsonpath goes in as $1), so every
+// request with the same schema, fields, operators and options generates the same one
+type Shape = (String, Vec<PostgresType>);
+
+// the statement shapes every ManagedClient sharing this has prepared, and how often. statements
+// themselves belong to one connection, but with this a new or reconnected client prepares the most used
+// shapes straight away instead of paying for it on the first request of each
+#[derive(Debug)]
+pub struct StatementShapes {
+    capacity: usize,
+    uses: Mutex<HashMap<Shape, u64>>,
+}
+
+impl StatementShapes {
+    pub fn new(capacity: usize) -> StatementShapes {
+        StatementShapes {
+            capacity,
+            uses: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn record(&self, query: &str, types: &[PostgresType]) {
+        let mut uses = self.uses.lock().unwrap();
+        let key = (query.to_owned(), types.to_vec());
+        if let Some(count) = uses.get_mut(&key) {
+            *count += 1;
+            return;
+        }
+
+        // full: the least used shape makes room. one-off shapes churn through here without pushing
+        // out the ones that matter
+        if uses.len() >= self.capacity {
+            let least = uses
+                .iter()
+                .min_by_key(|(_, count)| **count)
+                .map(|(shape, _)| shape.clone());
+            match least {
+                Some(shape) => {
+                    uses.remove(&shape);
+                }
+                None => return, // capacity 0
+            }
+        }
+        uses.insert(key, 1);
+    }
+
+    // the `n` most used shapes, most used first
+    pub fn hottest(&self, n: usize) -> Vec<(String, Vec<PostgresType>)> {
+        let uses = self.uses.lock().unwrap();
+        let mut shapes: Vec<(&Shape, &u64)> = uses.iter().collect();
+        shapes.sort_by(|a, b| b.1.cmp(a.1));
+        shapes
+            .into_iter()
+            .take(n)
+            .map(|(shape, _)| shape.clone())
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.uses.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
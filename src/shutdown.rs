@@ -0,0 +1,111 @@
+use super::*;
+
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+#[derive(Debug, Default)]
+struct DrainState {
+    closing: bool,
+    in_flight: usize,
+}
+
+// tracks in-flight requests so a server can stop taking new ones and wait for the rest before closing its connections
+#[derive(Debug, Default)]
+pub struct Drain {
+    state: Mutex<DrainState>,
+    idle: Condvar,
+}
+
+pub struct DrainGuard<'a> {
+    drain: &'a Drain,
+}
+
+impl Drain {
+    pub fn new() -> Drain {
+        Drain::default()
+    }
+
+    // hold the returned guard for as long as the request is touching the database
+    pub fn enter(&self) -> Result<DrainGuard<'_>, CompassError> {
+        let mut state = self.state.lock().unwrap();
+        if state.closing {
+            return Err(CompassError::ShuttingDown);
+        }
+
+        state.in_flight += 1;
+        Ok(DrainGuard { drain: self })
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.state.lock().unwrap().in_flight
+    }
+
+    pub fn is_closing(&self) -> bool {
+        self.state.lock().unwrap().closing
+    }
+
+    // stops accepting new requests, then waits up to `timeout` for the in-flight ones. returns true if everything finished in time
+    pub fn shutdown(&self, timeout: Duration) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state.closing = true;
+
+        let (state, _) = self
+            .idle
+            .wait_timeout_while(state, timeout, |s| s.in_flight > 0)
+            .unwrap();
+        state.in_flight == 0
+    }
+
+    // spawns a thread that drains on SIGTERM and then calls `on_drained` (close your connections / exit there)
+    #[cfg(unix)]
+    pub fn shutdown_on_sigterm<F>(
+        self: Arc<Self>,
+        timeout: Duration,
+        on_drained: F,
+    ) -> Result<thread::JoinHandle<()>, CompassError>
+    where
+        F: FnOnce(bool) + Send + 'static,
+    {
+        let mut signals = signal_hook::iterator::Signals::new(&[signal_hook::consts::SIGTERM])?;
+        Ok(thread::spawn(move || {
+            if signals.forever().next().is_some() {
+                let clean = self.shutdown(timeout);
+                on_drained(clean);
+            }
+        }))
+    }
+}
+
+impl<'a> Drop for DrainGuard<'a> {
+    fn drop(&mut self) {
+        let mut state = self.drain.state.lock().unwrap();
+        state.in_flight -= 1;
+        if state.in_flight == 0 {
+            self.drain.idle.notify_all();
+        }
+    }
+}
+
+#[cfg(feature = "rocket_support")]
+use rocket::{
+    http::Status,
+    request::{self, FromRequest, Outcome, Request},
+    State,
+};
+#[cfg(feature = "rocket_support")]
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for DrainGuard<'r> {
+    type Error = CompassError;
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, CompassError> {
+        match request.guard::<&State<Arc<Drain>>>().await {
+            Outcome::Success(drain) => match drain.inner().enter() {
+                Ok(guard) => Outcome::Success(guard),
+                Err(e) => Outcome::Failure((Status::ServiceUnavailable, e)),
+            },
+            Outcome::Failure((status, _)) => Outcome::Failure((status, CompassError::ShuttingDown)),
+            Outcome::Forward(f) => Outcome::Forward(f),
+        }
+    }
+}
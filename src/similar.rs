@@ -0,0 +1,177 @@
+use super::*;
+
+use postgres::types::ToSql;
+use serde_json::Value;
+use uuid::Uuid;
+
+use std::collections::HashMap;
+
+// how much of the reference document goes into the query
+const MAX_TERMS: usize = 12;
+const MAX_TAG_VALUES: usize = 20;
+
+// the most frequent words of a text, ignoring short ones. only plain alphanumeric words come out, so they
+// can go into a tsquery as-is
+fn key_terms(text: &str) -> Vec<String> {
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for word in text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|w| w.chars().count() > 3)
+    {
+        *counts.entry(word.to_lowercase()).or_insert(0) += 1;
+    }
+
+    let mut terms: Vec<(String, usize)> = counts.into_iter().collect();
+    terms.sort_by(|(a, an), (b, bn)| bn.cmp(an).then_with(|| a.cmp(b)));
+    terms.into_iter().take(MAX_TERMS).map(|(t, _)| t).collect()
+}
+
+fn tag_values(value: &Value) -> Vec<Value> {
+    match value {
+        Value::Array(values) => values
+            .iter()
+            .filter(|v| v.is_string() || v.is_number())
+            .take(MAX_TAG_VALUES)
+            .cloned()
+            .collect(),
+        Value::String(_) | Value::Number(_) => vec![value.clone()],
+        _ => Vec::new(),
+    }
+}
+
+// `similar_to=<uuid>`: documents sharing the most with the reference one, best first. every tag value in
+// common adds 1 to `_similarity`, and each fulltext field adds its ts_rank against the reference's most
+// frequent words. the other parameters filter as usual; sortby/sortorder are ignored
+pub fn json_similar<C: Connection>(
+    client: &mut C,
+    schema: &Schema,
+    fields: &HashMap<String, String>,
+) -> Result<SearchResponse, CompassError> {
+    let client = &mut throttle_permit(client, schema)?;
+
+    let id = fields
+        .get("similar_to")
+        .ok_or_else(|| CompassError::ConversionError("similar_to is missing".to_owned()))?;
+    let id = Uuid::parse_str(id)
+        .map_err(|_| CompassError::ConversionError(format!("similar_to '{}' isn't a uuid", id)))?;
+
+    let (scope, tenant) = schema.tenant_scope(2)?;
+    let mut params: Vec<&(dyn ToSql + Sync)> = vec![&id];
+    params.extend(tenant.iter().map(|t| t as &(dyn ToSql + Sync)));
+
+    let reference: Value = client
+        .client()
+        .map_err(pg_error(schema))?
+        .query_opt(
+            format!(
+                "SELECT object FROM {} WHERE doc_id = $1{}",
+                schema.table, scope
+            )
+            .as_str(),
+            &params,
+        )
+        .map_err(pg_error(schema))?
+        .ok_or(CompassError::DocumentNotFound(id))?
+        .get(0);
+
+    let filters: HashMap<String, String> = fields
+        .iter()
+        .filter(|(k, _)| k.as_str() != "similar_to")
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect();
+    let filters = &*prepare_params(schema, &filters)?;
+    let (limit, offset) = page_bounds(schema, filters)?;
+
+    let mut plan = plan_where(schema, filters, 2, false)?;
+    let mut scores = Vec::new();
+
+    for (name, field) in schema.fields.iter() {
+        match field.query {
+            FieldQuery::Fulltext {
+                ref lang,
+                ref target,
+                ..
+            } => {
+                let key = target.as_ref().unwrap_or(name);
+                let terms = match reference.get(key).and_then(Value::as_str) {
+                    Some(text) => key_terms(text),
+                    None => continue,
+                };
+                if terms.is_empty() {
+                    continue;
+                }
+
+                let query = plan.bind(Binding::Text(terms.join(" | ")));
+                scores.push(format!(
+                    "ts_rank(to_tsvector({lang}, object->>'{key}'), to_tsquery({lang}, {query}))",
+                    lang = ts_config(lang),
+                    key = key,
+                    query = query
+                ));
+            }
+            FieldQuery::StringTag | FieldQuery::NumericTag { .. } | FieldQuery::AmbiguousTag => {
+                let values = match reference.get(name) {
+                    Some(v) => tag_values(v),
+                    None => continue,
+                };
+                for value in values {
+                    let scalar = plan.bind(Binding::Json(containing(name, value.clone())));
+                    let array =
+                        plan.bind(Binding::Json(containing(name, Value::Array(vec![value]))));
+                    scores.push(format!(
+                        "(object @> {} OR object @> {})::int",
+                        scalar, array
+                    ));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if scores.is_empty() {
+        return Ok(SearchResponse {
+            data: Vec::new(),
+            meta: SearchMeta {
+                ignored_params: plan.ignored_params,
+                total: None,
+                did_you_mean: None,
+                next_cursor: None,
+                truncated: false,
+                next_offset: None,
+            },
+            stats: None,
+        });
+    }
+
+    let reference_id = plan.bind(Binding::Text(id.to_string()));
+    plan.and_where(&format!("doc_id <> {}::uuid", reference_id));
+
+    let sql = format!(
+        "SELECT object || jsonb_build_object('_similarity', score) FROM (\
+         SELECT object, doc_id, ({scores}) AS score FROM {table} {where_clause}) scored \
+         WHERE score > 0 ORDER BY score DESC, doc_id LIMIT {limit} OFFSET {offset}",
+        scores = scores.join(" + "),
+        table = schema.table,
+        where_clause = plan.where_clause,
+        limit = limit,
+        offset = offset
+    );
+
+    let mut data = run_plan(client, schema, &sql, &plan)?;
+    for doc in data.iter_mut() {
+        convert_document(schema, doc);
+    }
+
+    Ok(SearchResponse {
+        data,
+        meta: SearchMeta {
+            ignored_params: plan.ignored_params,
+            total: None,
+            did_you_mean: None,
+            next_cursor: None,
+            truncated: false,
+            next_offset: None,
+        },
+        stats: None,
+    })
+}
@@ -0,0 +1,138 @@
+use serde::{Deserialize, Serialize};
+
+use chrono::Utc;
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use super::millis;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct SlowQueryConfig {
+    pub enabled: bool,
+    pub threshold_ms: u64,
+    pub capacity: usize, // how many recent slow queries to keep around
+    pub redact_params: bool,
+}
+
+impl Default for SlowQueryConfig {
+    fn default() -> Self {
+        SlowQueryConfig {
+            enabled: false,
+            threshold_ms: 1000,
+            capacity: 256,
+            redact_params: true,
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct SlowQuery {
+    pub table: String,
+    pub sql: String,
+    pub params: Vec<String>,
+    pub duration_ms: f64,
+    pub at: i64, // unix millis
+}
+
+// slow queries grouped by their sql text; values are always bound as parameters so the text is the shape
+#[derive(Serialize, Debug, Clone)]
+pub struct SlowQueryShape {
+    pub table: String,
+    pub sql: String,
+    pub count: usize,
+    pub max_ms: f64,
+    pub avg_ms: f64,
+    pub last_seen: i64,
+}
+
+#[derive(Debug)]
+pub struct SlowQueryLog {
+    threshold: Duration,
+    capacity: usize,
+    redact_params: bool,
+    entries: Mutex<VecDeque<SlowQuery>>,
+}
+
+impl SlowQueryLog {
+    pub fn new(config: &SlowQueryConfig) -> SlowQueryLog {
+        SlowQueryLog {
+            threshold: Duration::from_millis(config.threshold_ms),
+            capacity: config.capacity,
+            redact_params: config.redact_params,
+            entries: Mutex::new(VecDeque::with_capacity(config.capacity)),
+        }
+    }
+
+    // params are only rendered if the query actually crossed the threshold
+    pub fn record<F>(&self, table: &str, sql: &str, params: F, duration: Duration)
+    where
+        F: FnOnce() -> Vec<String>,
+    {
+        if duration < self.threshold {
+            return;
+        }
+
+        let params = if self.redact_params {
+            params()
+                .into_iter()
+                .map(|_| "<redacted>".to_owned())
+                .collect()
+        } else {
+            params()
+        };
+
+        let entry = SlowQuery {
+            table: table.to_owned(),
+            sql: sql.to_owned(),
+            params,
+            duration_ms: millis(duration),
+            at: Utc::now().timestamp_millis(),
+        };
+
+        if let Ok(line) = serde_json::to_string(&entry) {
+            eprintln!("compass slow query: {}", line);
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+
+    pub fn recent(&self) -> Vec<SlowQuery> {
+        self.entries.lock().unwrap().iter().cloned().collect()
+    }
+
+    // the `n` query shapes with the worst single run, slowest first
+    pub fn slowest_shapes(&self, n: usize) -> Vec<SlowQueryShape> {
+        let entries = self.entries.lock().unwrap();
+        let mut shapes: HashMap<(&str, &str), SlowQueryShape> = HashMap::new();
+
+        for entry in entries.iter() {
+            let shape = shapes
+                .entry((entry.table.as_str(), entry.sql.as_str()))
+                .or_insert_with(|| SlowQueryShape {
+                    table: entry.table.clone(),
+                    sql: entry.sql.clone(),
+                    count: 0,
+                    max_ms: 0.0,
+                    avg_ms: 0.0,
+                    last_seen: 0,
+                });
+            shape.avg_ms =
+                (shape.avg_ms * shape.count as f64 + entry.duration_ms) / (shape.count + 1) as f64;
+            shape.count += 1;
+            shape.max_ms = shape.max_ms.max(entry.duration_ms);
+            shape.last_seen = shape.last_seen.max(entry.at);
+        }
+
+        let mut shapes: Vec<SlowQueryShape> = shapes.into_values().collect();
+        shapes.sort_by(|a, b| b.max_ms.partial_cmp(&a.max_ms).unwrap());
+        shapes.truncate(n);
+        shapes
+    }
+}
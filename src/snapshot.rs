@@ -0,0 +1,361 @@
+use super::*;
+
+use chrono::{DateTime, Utc};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use postgres::fallible_iterator::FallibleIterator;
+use postgres::types::ToSql;
+use postgres::{Client, IsolationLevel};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+// bumped if the file layout ever changes, so restore can tell what it's reading
+pub const SNAPSHOT_FORMAT: u32 = 1;
+
+// the first line of a snapshot
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SnapshotHeader {
+    pub compass_snapshot: u32,
+    pub schema: String,
+    pub table: String,
+    pub schema_version: String, // see schema_version
+    pub definition: Value,      // the schema as it was when the snapshot was taken
+    pub taken_at: DateTime<Utc>,
+}
+
+// every line after it: a document as a client would see it (converted fields rendered, vector fields
+// back in the document), so restoring is the same as ingesting it again
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SnapshotDocument {
+    pub doc_id: Uuid,
+    pub object: Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>, // for schemas with a tenancy column
+}
+
+// a short hash of the schema's definition, to tell whether it changed between a snapshot and a restore
+pub fn schema_version(schema: &Schema) -> Result<String, CompassError> {
+    // through a Value, so maps come out with their keys sorted
+    let definition = serde_json::to_vec(&serde_json::to_value(schema)?)?;
+    Ok(Sha256::digest(&definition)
+        .iter()
+        .take(8)
+        .map(|b| format!("{:02x}", b))
+        .collect())
+}
+
+// writes every document of the schema as ndjson, after a header line, from one repeatable read
+// transaction so the snapshot is consistent however long it takes. rows are streamed, not collected.
+// it reads the whole table whatever the schema's tenant; connect as a role row level security doesn't
+// apply to. returns how many documents were written
+pub fn snapshot<W: Write>(
+    client: &mut Client,
+    name: &str,
+    schema: &Schema,
+    out: &mut W,
+) -> Result<usize, CompassError> {
+    let header = SnapshotHeader {
+        compass_snapshot: SNAPSHOT_FORMAT,
+        schema: name.to_owned(),
+        table: schema.table.clone(),
+        schema_version: schema_version(schema)?,
+        definition: serde_json::to_value(schema)?,
+        taken_at: Utc::now(),
+    };
+    serde_json::to_writer(&mut *out, &header)?;
+    out.write_all(b"\n")?;
+
+    let vectors = schema.vector_columns();
+    let mut columns = vec!["doc_id".to_owned(), "object".to_owned()];
+    columns.extend(
+        vectors
+            .iter()
+            .map(|(_, column, _)| format!("{}::text", column)),
+    );
+    let tenant_column = match schema.tenancy {
+        Some(Tenancy::Column(ref column)) => {
+            columns.push(column.clone());
+            true
+        }
+        _ => false,
+    };
+    let sql = format!(
+        "SELECT {} FROM {} ORDER BY doc_id",
+        columns.join(", "),
+        schema.table
+    );
+
+    let mut transaction = client
+        .build_transaction()
+        .isolation_level(IsolationLevel::RepeatableRead)
+        .read_only(true)
+        .start()
+        .map_err(pg_error(schema))?;
+
+    let no_params: Vec<&dyn ToSql> = Vec::new();
+    let mut rows = transaction
+        .query_raw(sql.as_str(), no_params.iter().copied())
+        .map_err(pg_error(schema))?;
+
+    let mut written = 0;
+    while let Some(row) = rows.next().map_err(pg_error(schema))? {
+        let mut object: Value = row.get(1);
+        for (key, conv) in schema.converter_plan() {
+            if let Some(value) = object.get_mut(key) {
+                convert_field(conv, value);
+            }
+        }
+        for (i, (name, _, _)) in vectors.iter().enumerate() {
+            // pgvector's text form is a json array already
+            if let Some(text) = row.get::<usize, Option<String>>(2 + i) {
+                object[*name] = serde_json::from_str(&text)?;
+            }
+        }
+        let tenant = if tenant_column {
+            row.get(2 + vectors.len())
+        } else {
+            None
+        };
+
+        let doc = SnapshotDocument {
+            doc_id: row.get(0),
+            object,
+            tenant,
+        };
+        serde_json::to_writer(&mut *out, &doc)?;
+        out.write_all(b"\n")?;
+        written += 1;
+    }
+    drop(rows);
+
+    transaction.commit().map_err(pg_error(schema))?;
+    Ok(written)
+}
+
+// snapshot into a file, compressed by its extension: zstd for .zst, gzip for .gz, plain otherwise
+pub fn snapshot_to_file(
+    client: &mut Client,
+    name: &str,
+    schema: &Schema,
+    path: &Path,
+) -> Result<usize, CompassError> {
+    let file = BufWriter::new(File::create(path)?);
+    let written = match path.extension().and_then(|e| e.to_str()) {
+        Some("zst") => {
+            let mut encoder =
+                zstd::stream::write::Encoder::new(file, zstd::DEFAULT_COMPRESSION_LEVEL)?;
+            let written = snapshot(client, name, schema, &mut encoder)?;
+            encoder.finish()?.flush()?;
+            written
+        }
+        Some("gz") => {
+            let mut encoder = GzEncoder::new(file, Compression::default());
+            let written = snapshot(client, name, schema, &mut encoder)?;
+            encoder.finish()?.flush()?;
+            written
+        }
+        _ => {
+            let mut file = file;
+            let written = snapshot(client, name, schema, &mut file)?;
+            file.flush()?;
+            written
+        }
+    };
+    Ok(written)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RestoreMode {
+    Replace, // empty the table, then load the snapshot
+    Merge, // documents in the snapshot overwrite the ones with the same id; the rest are left alone
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct RejectedDocument {
+    pub doc_id: Uuid,
+    pub reason: String,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct RestoreReport {
+    pub snapshot_version: String, // schema_version when the snapshot was taken
+    pub schema_version: String,   // and of the schema it was restored into
+    pub restored: usize,
+    pub rejected: Vec<RejectedDocument>, // documents the schema doesn't take any more; not restored
+}
+
+// a document from a snapshot, the way the schema would ingest it today: converters applied, vector
+// fields taken out, and every field checked against the type its query expects. the error is why not
+fn restore_document(schema: &Schema, object: &mut Value) -> Result<Vec<(String, String)>, String> {
+    prepare_document(schema, object).map_err(|e| match e {
+        CompassError::ConversionError(msg) => msg,
+        e => e.to_string(),
+    })?;
+    let embeddings = take_embeddings(schema, object).map_err(|e| match e {
+        CompassError::ConversionError(msg) => msg,
+        e => e.to_string(),
+    })?;
+
+    for (key, field) in schema.fields.iter() {
+        let pointer = format!("/{}", key.replace('.', "/"));
+        match object.pointer(&pointer) {
+            Some(Value::Null) | None => {}
+            Some(v) => {
+                if let Some(expected) = type_problem(&field.query, v) {
+                    return Err(format!("{}: expected {}", key, expected));
+                }
+            }
+        }
+    }
+
+    Ok(embeddings)
+}
+
+// loads a snapshot into the schema, which may have changed since it was taken: documents go through
+// the current converters and type checks, and the ones that don't pass anymore are reported instead of
+// restored. all in one transaction, so a failed restore leaves the table as it was
+pub fn restore<R: BufRead>(
+    client: &mut Client,
+    schema: &Schema,
+    input: R,
+    mode: RestoreMode,
+) -> Result<RestoreReport, CompassError> {
+    let mut lines = input.lines();
+    let header: SnapshotHeader = match lines.next() {
+        Some(line) => serde_json::from_str(&line?)
+            .map_err(|e| CompassError::InvalidSnapshot(format!("bad header: {}", e)))?,
+        None => return Err(CompassError::InvalidSnapshot("it's empty".to_owned())),
+    };
+    if header.compass_snapshot != SNAPSHOT_FORMAT {
+        return Err(CompassError::InvalidSnapshot(format!(
+            "format {} isn't one this version reads ({})",
+            header.compass_snapshot, SNAPSHOT_FORMAT
+        )));
+    }
+
+    // documents keep the tenant they were snapshotted with, rather than getting the schema's
+    let mut unscoped = schema.clone();
+    unscoped.tenancy = None;
+    let tenant_column = match schema.tenancy {
+        Some(Tenancy::Column(ref column)) => Some(column.as_str()),
+        _ => None,
+    };
+    let vectors = schema.vector_columns();
+    let detects_language = schema.detects_language();
+
+    let mut columns = vec!["doc_id".to_owned(), "object".to_owned()];
+    let mut values = vec!["$1".to_owned(), "$2".to_owned()];
+    for (_, column, _) in vectors.iter() {
+        columns.push(column.to_string());
+        values.push(format!("${}::text::vector", values.len() + 1));
+    }
+    if let Some(column) = tenant_column {
+        columns.push(column.to_owned());
+        values.push(format!("${}", values.len() + 1));
+    }
+    if detects_language {
+        columns.push(LANGUAGE_COLUMN.to_owned());
+        values.push(format!("${}::text::regconfig", values.len() + 1));
+    }
+    let updates: Vec<String> = columns
+        .iter()
+        .skip(1)
+        .map(|c| format!("{} = EXCLUDED.{}", c, c))
+        .collect();
+    let sql = format!(
+        "INSERT INTO {} ({}) VALUES ({}) ON CONFLICT (doc_id) DO UPDATE SET {}",
+        schema.table,
+        columns.join(", "),
+        values.join(", "),
+        updates.join(", ")
+    );
+
+    let mut transaction = client.transaction().map_err(pg_error(schema))?;
+    if mode == RestoreMode::Replace {
+        transaction
+            .batch_execute(&format!("TRUNCATE {}", schema.table))
+            .map_err(pg_error(schema))?;
+    }
+    let statement = transaction.prepare(&sql).map_err(pg_error(schema))?;
+
+    let mut report = RestoreReport {
+        snapshot_version: header.schema_version,
+        schema_version: schema_version(schema)?,
+        restored: 0,
+        rejected: Vec::new(),
+    };
+
+    for line in lines {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let mut doc: SnapshotDocument = serde_json::from_str(&line)
+            .map_err(|e| CompassError::InvalidSnapshot(format!("bad document: {}", e)))?;
+
+        let embeddings = match restore_document(&unscoped, &mut doc.object) {
+            Ok(embeddings) => embeddings,
+            Err(reason) => {
+                report.rejected.push(RejectedDocument {
+                    doc_id: doc.doc_id,
+                    reason,
+                });
+                continue;
+            }
+        };
+        let vector_values: Vec<Option<String>> = vectors
+            .iter()
+            .map(|(_, column, _)| {
+                embeddings
+                    .iter()
+                    .find(|(c, _)| c == column)
+                    .map(|(_, v)| v.clone())
+            })
+            .collect();
+        let language = detect_language(schema, &doc.object);
+
+        let mut params: Vec<&(dyn ToSql + Sync)> = vec![&doc.doc_id, &doc.object];
+        params.extend(vector_values.iter().map(|v| v as &(dyn ToSql + Sync)));
+        if tenant_column.is_some() {
+            params.push(&doc.tenant);
+        }
+        if detects_language {
+            params.push(&language);
+        }
+
+        transaction
+            .execute(&statement, &params)
+            .map_err(pg_error(schema))?;
+        report.restored += 1;
+    }
+
+    transaction.commit().map_err(pg_error(schema))?;
+    Ok(report)
+}
+
+// restore from a file snapshot_to_file wrote, decompressed by its extension the same way
+pub fn restore_from_file(
+    client: &mut Client,
+    schema: &Schema,
+    path: &Path,
+    mode: RestoreMode,
+) -> Result<RestoreReport, CompassError> {
+    let file = BufReader::new(File::open(path)?);
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("zst") => restore(
+            client,
+            schema,
+            BufReader::new(zstd::stream::read::Decoder::with_buffer(file)?),
+            mode,
+        ),
+        Some("gz") => restore(client, schema, BufReader::new(GzDecoder::new(file)), mode),
+        _ => restore(client, schema, file, mode),
+    }
+}
@@ -0,0 +1,145 @@
+use super::*;
+
+use postgres::Client;
+
+use std::collections::{BTreeMap, HashMap};
+
+// shorter words are too easy to "correct" into something unrelated
+const MIN_WORD_LENGTH: usize = 3;
+
+// rebuilds the terms dictionary behind did_you_mean from the documents currently in the table. run it
+// after big imports; until then, new words just don't get suggested
+pub fn refresh_terms(client: &mut Client, schema: &Schema) -> Result<(), CompassError> {
+    let mut transaction = client.transaction()?;
+
+    for (name, key) in schema.spelling_fields() {
+        // ts_stat takes the query to read vectors from as a string
+        let words = format!(
+            "SELECT to_tsvector('simple', object->>'{}') FROM {}",
+            key.replace('\'', "''"),
+            schema.table
+        );
+
+        transaction.execute(
+            format!("DELETE FROM {}_terms WHERE field = $1", schema.table).as_str(),
+            &[&name],
+        )?;
+        transaction.execute(
+            format!(
+                "INSERT INTO {}_terms (field, term, ndoc) SELECT $1, word, ndoc FROM ts_stat($2) WHERE length(word) >= {}",
+                schema.table, MIN_WORD_LENGTH
+            )
+            .as_str(),
+            &[&name, &words],
+        )?;
+    }
+
+    transaction.commit()?;
+    Ok(())
+}
+
+// the words of a fulltext query, lowercased, without operators
+fn query_words(v: &str) -> Vec<String> {
+    let mut words: Vec<String> = v
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|w| w.chars().count() >= MIN_WORD_LENGTH)
+        .map(str::to_lowercase)
+        .collect();
+    words.sort();
+    words.dedup();
+    words
+}
+
+// swaps every word of `v` found in `corrections`, keeping everything between words as it was
+fn apply_corrections(v: &str, corrections: &HashMap<String, String>) -> String {
+    let mut out = String::new();
+    let mut word = String::new();
+
+    let flush = |word: &mut String, out: &mut String| {
+        match corrections.get(&word.to_lowercase()) {
+            Some(fixed) => out.push_str(fixed),
+            None => out.push_str(word),
+        }
+        word.clear();
+    };
+
+    for c in v.chars() {
+        if c.is_alphanumeric() {
+            word.push(c);
+        } else {
+            flush(&mut word, &mut out);
+            out.push(c);
+        }
+    }
+    flush(&mut word, &mut out);
+
+    out
+}
+
+// for each did_you_mean field in the query, the query with its unknown words swapped for the closest
+// known ones by trigram similarity. None when there's nothing to suggest
+pub(crate) fn spelling_suggestions<C: Connection>(
+    client: &mut C,
+    schema: &Schema,
+    fields: &HashMap<String, String>,
+) -> Result<Option<BTreeMap<String, String>>, CompassError> {
+    let spelling = schema.spelling_fields();
+    // the terms table is built from every tenant's documents, so it'd suggest other tenants' words
+    if spelling.is_empty() || schema.tenancy.is_some() {
+        return Ok(None);
+    }
+
+    let mut suggestions = BTreeMap::new();
+
+    for (param, v) in fields.iter() {
+        // negated fields are left alone; a typo there can't be why nothing matched
+        let name = match schema.resolve_field(param) {
+            Some((name, FieldQuery::Fulltext { .. })) => name,
+            _ => continue,
+        };
+        if !spelling.iter().any(|(n, _)| *n == name) {
+            continue;
+        }
+
+        let words = query_words(v);
+        if words.is_empty() {
+            continue;
+        }
+
+        let rows = client
+            .client()
+            .map_err(pg_error(schema))?
+            .query(
+                format!(
+                    "SELECT w.word, (SELECT term FROM {table}_terms WHERE field = $1 AND term % w.word \
+                     ORDER BY similarity(term, w.word) DESC, ndoc DESC, term LIMIT 1) \
+                     FROM unnest($2::text[]) AS w(word) \
+                     WHERE NOT EXISTS (SELECT 1 FROM {table}_terms WHERE field = $1 AND term = w.word)",
+                    table = schema.table
+                )
+                .as_str(),
+                &[&name, &words],
+            )
+            .map_err(pg_error(schema))?;
+
+        let corrections: HashMap<String, String> = rows
+            .into_iter()
+            .filter_map(|r| {
+                Some((
+                    r.get::<usize, String>(0),
+                    r.get::<usize, Option<String>>(1)?,
+                ))
+            })
+            .collect();
+
+        if !corrections.is_empty() {
+            suggestions.insert(param.clone(), apply_corrections(v, &corrections));
+        }
+    }
+
+    Ok(if suggestions.is_empty() {
+        None
+    } else {
+        Some(suggestions)
+    })
+}
@@ -0,0 +1,82 @@
+use super::*;
+
+use serde_json::Value;
+
+use std::collections::HashMap;
+
+const DEFAULT_SUGGESTIONS: i64 = 10;
+
+// LIKE treats these specially
+fn escape_like(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+// typeahead: `field=playerName&prefix=jess` returns the most common values of a field starting with the
+// prefix (case-insensitively), as {value, count}, most common first. the field has to be marked
+// `suggest: true`, which gets it a prefix index from migrate; only string values are suggested, so
+// arrays are skipped. `limit` defaults to 10, and everything else filters like a search
+pub fn suggest<C: Connection>(
+    client: &mut C,
+    schema: &Schema,
+    fields: &HashMap<String, String>,
+) -> Result<Vec<Value>, CompassError> {
+    let client = &mut throttle_permit(client, schema)?;
+
+    let name = fields
+        .get("field")
+        .ok_or_else(|| CompassError::ConversionError("suggest needs a field".to_owned()))?;
+    let (name, field) = schema
+        .fields
+        .get_key_value(name.as_str())
+        .filter(|(_, f)| f.suggest)
+        .ok_or(CompassError::FieldNotFound)?;
+
+    let prefix = fields.get("prefix").map(String::as_str).unwrap_or("");
+    let prefix = match field.normalize {
+        Some(n) => n.apply(prefix),
+        None => prefix.to_owned(),
+    };
+
+    let limit = match fields.get("limit") {
+        Some(l) => l.parse::<i64>().map_err(CompassError::InvalidNumberError)?,
+        None => DEFAULT_SUGGESTIONS,
+    };
+    if limit < 1 || limit > schema.limits.max_limit {
+        return Err(CompassError::LimitOutOfRange {
+            value: limit,
+            max: schema.limits.max_limit,
+        });
+    }
+
+    let filters = without(fields, &["field", "prefix", "limit"]);
+    let mut plan = generate_where(schema, &filters, 2, false)?;
+
+    // matches the index expression exactly, so postgres can use it
+    let value = format!("lower(object #>> {})", path_literal(name));
+    let pattern = plan.bind(Binding::Text(format!(
+        "{}%",
+        escape_like(&prefix.to_lowercase())
+    )));
+    plan.and_where(&format!(
+        "{value} LIKE {pattern} AND jsonb_typeof(object #> {path}) = 'string'",
+        value = value,
+        pattern = pattern,
+        path = path_literal(name)
+    ));
+
+    // grouped by the lowercased value (so Jess and jess are one suggestion), shown as its most common spelling
+    let sql = format!(
+        "SELECT jsonb_build_object('value', (array_agg(original ORDER BY n DESC, original))[1], 'count', SUM(n)) \
+         FROM (SELECT {value} AS folded, object #>> {path} AS original, COUNT(*) AS n FROM {table} {where_clause} GROUP BY 1, 2) v \
+         GROUP BY folded ORDER BY SUM(n) DESC, folded LIMIT {limit}",
+        value = value,
+        path = path_literal(name),
+        table = schema.table,
+        where_clause = plan.where_clause,
+        limit = limit
+    );
+
+    run_plan(client, schema, &sql, &plan)
+}
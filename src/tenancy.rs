@@ -0,0 +1,111 @@
+use super::*;
+
+use postgres::Client;
+use serde::{Deserialize, Serialize};
+
+// the postgres setting row level security reads the tenant from, see scope_session
+pub const TENANT_SETTING: &str = "compass.tenant";
+
+// where a schema keeps each document's tenant: `tenancy: {column: tenant_id}` for a text column of its
+// own, or `tenancy: {field: owner.org}` for a field of the document
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Tenancy {
+    Column(String),
+    Field(String),
+}
+
+impl Tenancy {
+    // a row's tenant, as text. `qualifier` is the table or alias to read it from, when it's ambiguous
+    pub fn sql(&self, qualifier: Option<&str>) -> String {
+        let prefix = qualifier.map(|q| format!("{}.", q)).unwrap_or_default();
+        match self {
+            Tenancy::Column(column) => format!("{}{}", prefix, column),
+            Tenancy::Field(field) => format!("({}object #>> {})", prefix, path_literal(field)),
+        }
+    }
+
+    pub(crate) fn validate(&self) -> Result<(), CompassError> {
+        let name = match self {
+            Tenancy::Column(column) if !column.contains('.') => column,
+            Tenancy::Column(column) => {
+                return Err(CompassError::ConfigError(format!(
+                    "tenancy column '{}' has to be a plain column name",
+                    column
+                )))
+            }
+            Tenancy::Field(field) => field,
+        };
+        if !is_sql_identifier(name) {
+            return Err(CompassError::ConfigError(format!(
+                "tenancy '{}' has to be a plain name",
+                name
+            )));
+        }
+        Ok(())
+    }
+}
+
+// the column (if it's one), an index, and a row level security policy that only lets a session see and
+// write its own tenant's rows. compass filters by tenant itself either way; the policy is what keeps
+// other roles, and anything that gets past compass, to the tenant in compass.tenant. it doesn't apply to
+// the table's owner, so point database.read_only_url (or whatever the embedder queries with) at a role
+// that isn't
+pub fn provision_tenancy(client: &mut Client, schema: &Schema) -> Result<(), CompassError> {
+    let tenancy = match schema.tenancy {
+        Some(ref tenancy) => tenancy,
+        None => return Ok(()),
+    };
+
+    if let Tenancy::Column(ref column) = tenancy {
+        // rows written in a scoped session get its tenant; older rows stay null, and out of sight, until
+        // they're given one
+        client.batch_execute(&format!(
+            "ALTER TABLE {table} ADD COLUMN IF NOT EXISTS {column} TEXT DEFAULT current_setting('{setting}', true)",
+            table = schema.table,
+            column = column,
+            setting = TENANT_SETTING
+        ))?;
+    }
+
+    let name = schema.table.replace('.', "_");
+    let expression = tenancy.sql(None);
+    client.batch_execute(&format!(
+        "CREATE INDEX IF NOT EXISTS {name}_tenant_idx ON {table} ({expression}); \
+         ALTER TABLE {table} ENABLE ROW LEVEL SECURITY; \
+         DROP POLICY IF EXISTS {name}_tenant ON {table}; \
+         CREATE POLICY {name}_tenant ON {table} \
+         USING ({expression} = current_setting('{setting}', true)) \
+         WITH CHECK ({expression} = current_setting('{setting}', true));",
+        name = name,
+        table = schema.table,
+        expression = expression,
+        setting = TENANT_SETTING
+    ))?;
+
+    Ok(())
+}
+
+// sets compass.tenant for the rest of the session, so the row level security policies let through the
+// schema's tenant and nothing else. pooled connections should be scoped every time they're checked out,
+// since the setting outlives the request
+pub fn scope_session<C: Connection>(client: &mut C, schema: &Schema) -> Result<(), CompassError> {
+    if schema.tenancy.is_none() {
+        return Ok(());
+    }
+    let tenant = schema
+        .tenant
+        .as_deref()
+        .ok_or(CompassError::TenantRequired)?;
+
+    client
+        .client()
+        .map_err(pg_error(schema))?
+        .execute(
+            "SELECT set_config($1, $2, false)",
+            &[&TENANT_SETTING, &tenant],
+        )
+        .map_err(pg_error(schema))?;
+
+    Ok(())
+}
@@ -0,0 +1,74 @@
+// fixtures for the unit tests
+use super::*;
+
+use std::collections::HashMap;
+
+// a range, numeric and string tags, a nested field and a fulltext field
+pub(crate) fn test_schema() -> Schema {
+    serde_yaml::from_str(
+        r#"
+table: documents
+default_order_by: doc_id
+fields:
+  season:
+    name: season
+    sortable: true
+    query:
+      type: Range
+      min: season_min
+      max: season_max
+  type:
+    name: type
+    query:
+      type: NumericTag
+  name:
+    name: name
+    query:
+      type: StringTag
+  player:
+    name: player
+    query:
+      type: Nested
+  description:
+    name: description
+    query:
+      type: Fulltext
+      lang: english
+"#,
+    )
+    .expect("the test schema parses")
+}
+
+pub(crate) fn params(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+    pairs
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect()
+}
+
+// the jsonpath a search with these parameters filters with
+pub(crate) fn json_query(schema: &Schema, fields: &HashMap<String, String>) -> String {
+    generate_where(schema, fields, 2, false)
+        .expect("the parameters compile")
+        .json_query
+}
+
+// what season_min=n and season_max=n compile to
+pub(crate) fn season_above(n: i64) -> String {
+    format!(
+        "(($.season > {n}) || ($.season.type() == \"string\" && $.season.double() > {n}))",
+        n = n
+    )
+}
+
+pub(crate) fn season_below(n: i64) -> String {
+    format!(
+        "(($.season < {n}) || ($.season.type() == \"string\" && $.season.double() < {n}))",
+        n = n
+    )
+}
+
+// what type=n compiles to, as one term of a list
+pub(crate) fn type_is(n: i64) -> String {
+    format!("(($.type == {n}) || ($.type == \"{n}\"))", n = n)
+}
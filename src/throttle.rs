@@ -0,0 +1,161 @@
+use serde::{Deserialize, Serialize};
+
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use super::CompassError;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ThrottleConfig {
+    pub enabled: bool,
+    pub max_in_flight: usize, // per schema; queries past this get a 503 instead of queueing for a connection
+    pub slow_threshold_ms: u64, // a query slower than this counts against the breaker
+    pub trip_after: u32,      // consecutive slow queries before the breaker opens
+    pub open_secs: u64,       // how long it stays open before letting a probe query through
+}
+
+impl Default for ThrottleConfig {
+    fn default() -> Self {
+        ThrottleConfig {
+            enabled: false,
+            max_in_flight: 64,
+            slow_threshold_ms: 5000,
+            trip_after: 5,
+            open_secs: 10,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Breaker {
+    Closed { slow_streak: u32 },
+    Open { until: Instant },
+    HalfOpen, // one probe query is running; its latency decides whether to close again
+}
+
+#[derive(Debug)]
+struct ThrottleState {
+    in_flight: usize,
+    breaker: Breaker,
+}
+
+// caps concurrent queries against a schema and stops sending it queries for a while once they start
+// coming back slow, so a bad query pattern fails fast instead of piling up behind postgres
+#[derive(Debug)]
+pub struct Throttle {
+    config: RwLock<ThrottleConfig>,
+    state: Mutex<ThrottleState>,
+}
+
+pub struct ThrottlePermit<'a> {
+    throttle: &'a Throttle,
+    started: Instant,
+    probe: bool,
+}
+
+impl Throttle {
+    pub fn new(config: &ThrottleConfig) -> Throttle {
+        Throttle {
+            config: RwLock::new(config.clone()),
+            state: Mutex::new(ThrottleState {
+                in_flight: 0,
+                breaker: Breaker::Closed { slow_streak: 0 },
+            }),
+        }
+    }
+
+    // takes a reloaded config's limits without dropping the queries in flight or the breaker's state
+    pub fn reconfigure(&self, config: &ThrottleConfig) {
+        *self.config.write().unwrap() = config.clone();
+    }
+
+    pub fn acquire(&self) -> Result<ThrottlePermit<'_>, CompassError> {
+        let config = self.config.read().unwrap();
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+
+        let probe = match state.breaker {
+            Breaker::Open { until } if now < until => {
+                return Err(CompassError::Overloaded {
+                    retry_after_secs: (until - now).as_secs() + 1,
+                });
+            }
+            Breaker::Open { .. } => {
+                state.breaker = Breaker::HalfOpen;
+                true
+            }
+            Breaker::HalfOpen => {
+                return Err(CompassError::Overloaded {
+                    retry_after_secs: 1,
+                });
+            }
+            Breaker::Closed { .. } => false,
+        };
+
+        if !probe && state.in_flight >= config.max_in_flight {
+            return Err(CompassError::Overloaded {
+                retry_after_secs: 1,
+            });
+        }
+
+        state.in_flight += 1;
+        Ok(ThrottlePermit {
+            throttle: self,
+            started: now,
+            probe,
+        })
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.state.lock().unwrap().in_flight
+    }
+
+    pub fn is_open(&self) -> bool {
+        !matches!(self.state.lock().unwrap().breaker, Breaker::Closed { .. })
+    }
+
+    fn finish(&self, elapsed: Duration, probe: bool) {
+        let config = self.config.read().unwrap();
+        let mut state = self.state.lock().unwrap();
+        state.in_flight -= 1;
+
+        let slow = elapsed > Duration::from_millis(config.slow_threshold_ms);
+        let open = Breaker::Open {
+            until: Instant::now() + Duration::from_secs(config.open_secs),
+        };
+
+        state.breaker = match state.breaker {
+            Breaker::HalfOpen if probe => {
+                if slow {
+                    open
+                } else {
+                    Breaker::Closed { slow_streak: 0 }
+                }
+            }
+            Breaker::Closed { slow_streak } if slow => {
+                if slow_streak + 1 >= config.trip_after {
+                    eprintln!(
+                        "compass: {} slow queries in a row, refusing queries for {}s",
+                        slow_streak + 1,
+                        config.open_secs
+                    );
+                    open
+                } else {
+                    Breaker::Closed {
+                        slow_streak: slow_streak + 1,
+                    }
+                }
+            }
+            Breaker::Closed { .. } => Breaker::Closed { slow_streak: 0 },
+            // stragglers from before the breaker opened don't change anything
+            other => other,
+        };
+    }
+}
+
+impl<'a> Drop for ThrottlePermit<'a> {
+    fn drop(&mut self) {
+        self.throttle.finish(self.started.elapsed(), self.probe);
+    }
+}
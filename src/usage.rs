@@ -0,0 +1,144 @@
+use super::*;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+// what requests without a key are counted under
+pub const ANONYMOUS: &str = "anonymous";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct UsageConfig {
+    pub enabled: bool,
+    pub store: Option<PathBuf>, // counts are saved here as json every flush_secs, and read back on start
+    pub flush_secs: u64,
+}
+
+impl Default for UsageConfig {
+    fn default() -> Self {
+        UsageConfig {
+            enabled: false,
+            store: None,
+            flush_secs: 60,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct KeyUsage {
+    pub queries: u64,
+    pub rows: u64,  // documents (or aggregate rows) sent back
+    pub db_ms: f64, // time spent waiting on postgres
+    pub first_seen: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+}
+
+// keys are never stored or shown as they are, only as the start of their sha256, which an operator can
+// work out for a key they know
+pub fn key_fingerprint(key: &str) -> String {
+    Sha256::digest(key.as_bytes())
+        .iter()
+        .take(8)
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+// per api key totals, for telling which integrations are heavy with numbers instead of guesses
+#[derive(Debug, Default)]
+pub struct Usage {
+    store: Option<PathBuf>,
+    keys: Mutex<HashMap<String, KeyUsage>>,
+}
+
+impl Usage {
+    // reads what was saved last time, and keeps saving every flush_secs on a thread of its own until
+    // the last handle to it is dropped
+    pub fn start(config: &UsageConfig) -> Result<Arc<Usage>, CompassError> {
+        let keys = match config.store {
+            Some(ref path) if path.exists() => serde_json::from_str(&fs::read_to_string(path)?)?,
+            _ => HashMap::new(),
+        };
+        let usage = Arc::new(Usage {
+            store: config.store.clone(),
+            keys: Mutex::new(keys),
+        });
+
+        if usage.store.is_some() {
+            let weak = Arc::downgrade(&usage);
+            let every = Duration::from_secs(config.flush_secs.max(1));
+            thread::spawn(move || loop {
+                thread::sleep(every);
+                match weak.upgrade() {
+                    Some(usage) => {
+                        if let Err(e) = usage.flush() {
+                            eprintln!("compass: couldn't save usage: {}", e);
+                        }
+                    }
+                    None => return,
+                }
+            });
+        }
+
+        Ok(usage)
+    }
+
+    pub fn record(&self, fingerprint: &str, rows: usize, db_time: Duration) {
+        let now = Utc::now();
+        let mut keys = self.keys.lock().unwrap();
+        let usage = keys
+            .entry(fingerprint.to_owned())
+            .or_insert_with(|| KeyUsage {
+                queries: 0,
+                rows: 0,
+                db_ms: 0.0,
+                first_seen: now,
+                last_seen: now,
+            });
+        usage.queries += 1;
+        usage.rows += rows as u64;
+        usage.db_ms += millis(db_time);
+        usage.last_seen = now;
+    }
+
+    // every key's totals, by fingerprint
+    pub fn report(&self) -> BTreeMap<String, KeyUsage> {
+        self.keys
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect()
+    }
+
+    pub fn reset(&self, fingerprint: &str) -> bool {
+        self.keys.lock().unwrap().remove(fingerprint).is_some()
+    }
+
+    // written next to the store and renamed over it, like the webhook store
+    pub fn flush(&self) -> Result<(), CompassError> {
+        let path = match self.store {
+            Some(ref path) => path,
+            None => return Ok(()),
+        };
+        let body = serde_json::to_vec_pretty(&self.report())?;
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, body)?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
+
+// counts a query against the key the schema was scoped to, when usage is being tracked
+pub(crate) fn record_usage(schema: &Schema, rows: usize, db_time: Duration) {
+    if let Some(ref usage) = schema.usage {
+        usage.record(schema.key.as_deref().unwrap_or(ANONYMOUS), rows, db_time);
+    }
+}
@@ -0,0 +1,320 @@
+use super::*;
+
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::Sha256;
+use uuid::Uuid;
+
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct WebhookConfig {
+    pub store: Option<PathBuf>, // registered webhooks are saved here as json; kept in memory only without it
+    pub delivery_log: usize,    // delivery attempts remembered, across every webhook
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        WebhookConfig {
+            store: None,
+            delivery_log: 1000,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub backoff_ms: u64, // before the second attempt, doubling after that
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            backoff_ms: 1000,
+        }
+    }
+}
+
+// what gets registered. `events` filters what it's sent: "alert" or "scheduled" for every alert or
+// scheduled query, "alert:<id>" or "scheduled:<name>" for one of them. no events means all of them
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct WebhookSpec {
+    pub url: String,
+    pub secret: Option<String>, // signs each body: X-Compass-Signature: sha256=<hex hmac>
+    #[serde(default)]
+    pub events: Vec<String>,
+    #[serde(default)]
+    pub retry: RetryPolicy,
+}
+
+impl WebhookSpec {
+    fn validate(&self) -> Result<(), CompassError> {
+        if !self.url.starts_with("http://") && !self.url.starts_with("https://") {
+            return Err(CompassError::InvalidWebhook(
+                "url has to be http:// or https://".to_owned(),
+            ));
+        }
+        if self.retry.max_attempts == 0 {
+            return Err(CompassError::InvalidWebhook(
+                "retry.max_attempts must be at least 1".to_owned(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn wants(&self, kind: &str, name: &str) -> bool {
+        self.events.is_empty()
+            || self
+                .events
+                .iter()
+                .any(|e| e == kind || *e == format!("{}:{}", kind, name))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Webhook {
+    pub id: Uuid,
+    #[serde(flatten)]
+    pub spec: WebhookSpec,
+    pub created_at: DateTime<Utc>,
+}
+
+impl Webhook {
+    // for listing: the secret is only ever written, never read back
+    pub fn redacted(&self) -> Webhook {
+        let mut hook = self.clone();
+        if hook.spec.secret.is_some() {
+            hook.spec.secret = Some("<redacted>".to_owned());
+        }
+        hook
+    }
+}
+
+// one attempt at sending an event to a webhook
+#[derive(Serialize, Debug, Clone)]
+pub struct Delivery {
+    pub id: Uuid, // the same for every attempt at one event; sent as X-Compass-Delivery
+    pub webhook: Uuid,
+    pub event: String,
+    pub attempt: u32,
+    pub at: DateTime<Utc>,
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+// outbound webhooks that alerts and scheduled queries send their events to, besides their own targets.
+// changes are saved to webhooks.store straight away; the delivery log only lives in memory
+#[derive(Debug, Default)]
+pub struct Webhooks {
+    store: Option<PathBuf>,
+    log_size: usize,
+    hooks: Mutex<HashMap<Uuid, Webhook>>,
+    deliveries: Mutex<VecDeque<Delivery>>,
+}
+
+impl Webhooks {
+    pub fn open(config: &WebhookConfig) -> Result<Webhooks, CompassError> {
+        let hooks = match config.store {
+            Some(ref path) if path.exists() => {
+                let saved: Vec<Webhook> = serde_json::from_str(&fs::read_to_string(path)?)?;
+                saved.into_iter().map(|hook| (hook.id, hook)).collect()
+            }
+            _ => HashMap::new(),
+        };
+        Ok(Webhooks {
+            store: config.store.clone(),
+            log_size: config.delivery_log,
+            hooks: Mutex::new(hooks),
+            deliveries: Mutex::new(VecDeque::new()),
+        })
+    }
+
+    fn save(&self, hooks: &HashMap<Uuid, Webhook>) -> Result<(), CompassError> {
+        let path = match self.store {
+            Some(ref path) => path,
+            None => return Ok(()),
+        };
+        // written next to the store and renamed over it, so a crash can't leave half a file
+        let tmp = path.with_extension("tmp");
+        fs::write(
+            &tmp,
+            serde_json::to_vec_pretty(&hooks.values().collect::<Vec<_>>())?,
+        )?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    pub fn create(&self, spec: WebhookSpec) -> Result<Webhook, CompassError> {
+        spec.validate()?;
+        let hook = Webhook {
+            id: Uuid::new_v4(),
+            spec,
+            created_at: Utc::now(),
+        };
+        let mut hooks = self.hooks.lock().unwrap();
+        hooks.insert(hook.id, hook.clone());
+        self.save(&hooks)?;
+        Ok(hook)
+    }
+
+    pub fn get(&self, id: Uuid) -> Result<Webhook, CompassError> {
+        self.hooks
+            .lock()
+            .unwrap()
+            .get(&id)
+            .cloned()
+            .ok_or(CompassError::WebhookNotFound(id))
+    }
+
+    pub fn list(&self) -> Vec<Webhook> {
+        let mut hooks: Vec<Webhook> = self.hooks.lock().unwrap().values().cloned().collect();
+        hooks.sort_by_key(|hook| hook.created_at);
+        hooks
+    }
+
+    pub fn update(&self, id: Uuid, spec: WebhookSpec) -> Result<Webhook, CompassError> {
+        spec.validate()?;
+        let mut hooks = self.hooks.lock().unwrap();
+        let hook = hooks
+            .get_mut(&id)
+            .ok_or(CompassError::WebhookNotFound(id))?;
+        hook.spec = spec;
+        let hook = hook.clone();
+        self.save(&hooks)?;
+        Ok(hook)
+    }
+
+    pub fn delete(&self, id: Uuid) -> Result<(), CompassError> {
+        let mut hooks = self.hooks.lock().unwrap();
+        if hooks.remove(&id).is_none() {
+            return Err(CompassError::WebhookNotFound(id));
+        }
+        self.save(&hooks)
+    }
+
+    // the attempts logged for one webhook, newest first
+    pub fn deliveries(&self, id: Uuid) -> Vec<Delivery> {
+        self.deliveries
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .filter(|d| d.webhook == id)
+            .cloned()
+            .collect()
+    }
+
+    fn log(&self, delivery: Delivery) {
+        let mut deliveries = self.deliveries.lock().unwrap();
+        deliveries.push_back(delivery);
+        while deliveries.len() > self.log_size {
+            deliveries.pop_front();
+        }
+    }
+
+    // sends `body` to every webhook that wants this event, each on its own thread so retries don't hold
+    // up the caller. returns how many webhooks it's going to
+    pub fn dispatch(self: &Arc<Self>, kind: &str, name: &str, body: &Value) -> usize {
+        let targets: Vec<Webhook> = self
+            .hooks
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|hook| hook.spec.wants(kind, name))
+            .cloned()
+            .collect();
+
+        let event = format!("{}:{}", kind, name);
+        let body = match serde_json::to_vec(body) {
+            Ok(body) => Arc::new(body),
+            Err(e) => {
+                eprintln!("compass: couldn't serialize {} for webhooks: {}", event, e);
+                return 0;
+            }
+        };
+        for hook in targets.iter() {
+            let webhooks = self.clone();
+            let hook = hook.clone();
+            let event = event.clone();
+            let body = body.clone();
+            thread::spawn(move || webhooks.deliver(&hook, &event, &body));
+        }
+        targets.len()
+    }
+
+    fn deliver(&self, hook: &Webhook, event: &str, body: &[u8]) {
+        let id = Uuid::new_v4();
+        let mut backoff = Duration::from_millis(hook.spec.retry.backoff_ms);
+
+        for attempt in 1..=hook.spec.retry.max_attempts {
+            let mut request = ureq::post(&hook.spec.url)
+                .timeout(Duration::from_secs(10))
+                .set("Content-Type", "application/json")
+                .set("X-Compass-Event", event)
+                .set("X-Compass-Delivery", &id.to_string());
+            if let Some(ref secret) = hook.spec.secret {
+                request = request.set("X-Compass-Signature", &signature(secret, body));
+            }
+
+            let (ok, status, error) = match request.send_bytes(body) {
+                Ok(response) => (true, Some(response.status()), None),
+                Err(ureq::Error::Status(code, _)) => {
+                    (false, Some(code), Some(format!("status {}", code)))
+                }
+                Err(e) => (false, None, Some(e.to_string())),
+            };
+            self.log(Delivery {
+                id,
+                webhook: hook.id,
+                event: event.to_owned(),
+                attempt,
+                at: Utc::now(),
+                ok,
+                status,
+                error,
+            });
+
+            if ok {
+                return;
+            }
+            if attempt < hook.spec.retry.max_attempts {
+                thread::sleep(backoff);
+                backoff *= 2;
+            }
+        }
+        eprintln!(
+            "compass: webhook {} gave up on {} after {} attempts",
+            hook.id, event, hook.spec.retry.max_attempts
+        );
+    }
+}
+
+// sha256=<hex hmac of the body>, for receivers to check the body came from us
+fn signature(secret: &str, body: &[u8]) -> String {
+    // hmac takes keys of any length
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(body);
+    let hex: String = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    format!("sha256={}", hex)
+}